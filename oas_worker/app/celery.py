@@ -6,7 +6,14 @@ app = Celery('tasks', broker=config.redis_url, backend=config.redis_url, result_
 app.conf.update(
     #  result_backend=None,
     task_ignore_result=True,
+    # Default routes, the core sends tasks to the queue for their priority (e.g. oas.asr.high).
     task_routes=(
-        [("*", {"queue": "celery"})],
+        [
+            ("transcribe", {"queue": "oas.asr.normal"}),
+            ("*", {"queue": "oas.default.normal"}),
+        ],
     ),
+    # Consume the queues strictly in the order they are passed to the worker (-Q),
+    # so that tasks with a higher priority are run first.
+    broker_transport_options={"queue_order_strategy": "priority"},
 )
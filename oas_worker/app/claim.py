@@ -0,0 +1,99 @@
+"""
+Worker loop for the `couch` task queue.
+
+With the `couch` task queue, the core keeps queued jobs in CouchDB instead of
+sending them to Celery. This loop claims the next queued job from the core,
+runs it and reports the result (or the error) back to the core.
+
+Run with `python -m app.claim`.
+"""
+import os
+import socket
+import time
+import traceback
+
+import httpx
+
+from app.config import config
+from app.tasks.tasks import download, prepare, asr, nlp, download_media, nlp_post, post_job_result
+
+
+def worker_id():
+    return config.worker_id or f"{socket.gethostname()}-{os.getpid()}"
+
+
+def claim_job(worker, typs):
+    """
+    Claim the next queued job for one of the tasks in typs.
+    Returns None if no job is queued.
+    """
+    url = config.url("/jobs/claim")
+    claim = {"worker": worker, "typs": typs}
+    res = httpx.post(url, json=claim, headers=config.worker_headers())
+    res.raise_for_status()
+    return res.json()
+
+
+def run_transcribe(job):
+    args = job["args"]
+    opts = job.get("opts") or {}
+    media_id = args["media_id"]
+    job_id = job["$meta"]["id"]
+    # the tasks are called directly, so they run in this process
+    result = download({"media_url": args["media_url"], "media_id": media_id})
+    result = prepare(result, {"media_id": media_id, "samplerate": 16000})
+    result = asr(result, {"media_id": media_id, "engine": opts.get("engine") or "vosk", "job_id": job_id})
+    result = nlp(result, {"media_id": media_id, "pipeline": "ner", "job_id": job_id})
+    return {"transcript": result["asr"], "nlp": result.get("nlp")}
+
+
+def run_download(job):
+    return download_media(job["args"])
+
+
+def run_nlp(job):
+    opts = job.get("opts") or {}
+    return nlp_post(job["args"], opts.get("pipeline") or "ner")
+
+
+# Tasks that can be run from claimed jobs, by task name.
+RUNNERS = {
+    "transcribe": run_transcribe,
+    "download": run_download,
+    "nlp": run_nlp,
+}
+
+
+def run_job(worker, job):
+    job_id = job["$meta"]["id"]
+    typ = job["typ"]
+    print(f"start job {job_id} for task {typ}")
+    start = time.time()
+    try:
+        output = RUNNERS[typ](job)
+        result = {"worker": worker, "output": output, "took": time.time() - start}
+    except Exception as err:
+        traceback.print_exc()
+        result = {"worker": worker, "error": str(err) or repr(err), "took": time.time() - start}
+    res = post_job_result(job_id, result)
+    print("res", res)
+
+
+def main():
+    worker = worker_id()
+    typs = list(RUNNERS.keys())
+    print(f"worker {worker} claims jobs for tasks {typs}")
+    while True:
+        try:
+            job = claim_job(worker, typs)
+        except httpx.HTTPError as err:
+            print(f"failed to claim job: {err}")
+            job = None
+        if job is None:
+            time.sleep(config.claim_interval)
+            continue
+        run_job(worker, job)
+
+
+if __name__ == '__main__':
+    main()
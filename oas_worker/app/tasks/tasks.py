@@ -8,15 +8,17 @@ from pathlib import Path
 from urllib.parse import urlparse
 from base64 import b32encode
 
+import redis
 import requests
 from hashlib import sha256
 from mimetypes import guess_extension
 from celery import Celery, chain
+from celery.exceptions import Ignore
 from celery.utils.log import get_task_logger
 from app.tasks.models import *
 
 from app.config import config
-from app.util import pretty_bytes
+from app.util import pretty_bytes, uuid
 from app.celery import app
 
 from app.tasks.spacy_pipe import SpacyPipe
@@ -25,6 +27,16 @@ from app.tasks.transcribe_vosk import transcribe_vosk
 logger = get_task_logger(__name__)
 cache_path = os.path.join(config.storage_path, 'cache')
 
+# the core marks cancelled jobs with these keys (see REVOKED_KEY_PREFIX in the core)
+REVOKED_KEY_PREFIX = 'oas:revoked:'
+redis_client = redis.Redis.from_url(config.redis_url)
+
+def is_revoked(job_id) -> bool:
+    # with the couch task queue, cancelled jobs are not claimed in the first place
+    if not job_id or config.task_queue != 'celery':
+        return False
+    return redis_client.exists(REVOKED_KEY_PREFIX + job_id) > 0
+
 def file_path(filename):
     path = os.path.join(config.storage_path, filename)
     ensure_dir(path)
@@ -45,25 +57,39 @@ def url_to_path(url: str) -> str:
     return target_name
 
 
-@app.task(name="transcribe")
-def transcribe(args: dict, opts: dict):
+@app.task(name="transcribe", bind=True)
+def transcribe(self, args: dict, opts: dict):
     media_url = args['media_url']
     media_id = args['media_id']
     print("start transcribe task for media " + media_id)
     
-    nlp_opts = { 'media_id': media_id, 'pipeline': 'ner'}
-    asr_opts = { 'media_id': media_id, 'engine': 'vosk'}
+    # if set, results are reported to the job instead of patching the media
+    job_id = (opts or {}).get('job_id')
+    if is_revoked(job_id):
+        logger.info(f'Skipping revoked job {job_id}')
+        raise Ignore()
+
+    if job_id:
+        post_job_start(job_id, self.request.hostname)
+
+    nlp_opts = { 'media_id': media_id, 'pipeline': 'ner', 'job_id': job_id }
+    asr_opts = { 'media_id': media_id, 'engine': 'vosk', 'job_id': job_id }
     download_opts = { 'media_url': media_url, 'media_id': media_id }
     prepare_opts = { 'media_id': media_id, 'samplerate': 16000 }
-    save_task_state_opts = { 'media_id': media_id }
+    save_task_state_opts = { 'media_id': media_id, 'job_id': job_id }
 
-    result = chain(
+    # run the steps on the queue of this task, so that they keep its priority
+    queue = (self.request.delivery_info or {}).get('routing_key')
+    steps = [
         download.s(download_opts),
         prepare.s(prepare_opts),
         asr.s(asr_opts),
         nlp.s(nlp_opts),
         save_task_state.s(save_task_state_opts)
-        )()
+    ]
+    if queue:
+        steps = [step.set(queue=queue) for step in steps]
+    result = chain(*steps)()
     return result
     
 @app.task(name="download")
@@ -81,7 +107,7 @@ def download(opts):
     # target_path = task.file_path(
         # f'download/{target_name}', root=True)
     # temp_path = file_path('download.tmp')
-    temp_path = file_path(f'task/download/{download.request.id}/download.tmp')
+    temp_path = file_path(f'task/download/{download.request.id or uuid()}/download.tmp')
     # chunk size to write
     chunk_size = 1024*64
 
@@ -127,7 +153,7 @@ def download(opts):
 @app.task(name="prepare")
 def prepare(args, opts):
     samplerate = opts['samplerate']
-    dst = file_path(f'task/prepare/{prepare.request.id}/processed.wav')
+    dst = file_path(f'task/prepare/{prepare.request.id or uuid()}/processed.wav')
     # TODO: Find out why this pydub segment does not work.
     # sound = AudioSegment.from_file(args.file_path)
     # sound.set_frame_rate(opts.samplerate)
@@ -143,6 +169,10 @@ def prepare(args, opts):
 
 @app.task(name="asr")
 def asr(args, opts):
+    # the job may have been cancelled while the media was downloaded
+    if is_revoked(opts.get('job_id')):
+        logger.info(f'Skipping revoked job {opts["job_id"]}')
+        raise Ignore()
     engine = opts['engine']
 
     model_base_path = config.model_path or os.path.join(
@@ -155,11 +185,12 @@ def asr(args, opts):
         result = transcribe_vosk(opts["media_id"], args["prepare"]["file_path"], model_path)
         end = time.time()
 
-        # send change to core
-        patch = [
-            { "op": "replace", "path": "/transcript", "value": result },
-        ]
-        res = patch_media(opts['media_id'], patch)
+        # send change to core (with a job, the result is reported at the end)
+        if not opts.get('job_id'):
+            patch = [
+                { "op": "replace", "path": "/transcript", "value": result },
+            ]
+            res = patch_media(opts['media_id'], patch)
 
         # pass result on to next task
         args["asr"] = result
@@ -176,15 +207,26 @@ def asr(args, opts):
 def nlp(args, opts):
     spacy = SpacyPipe(opts['pipeline'])
     res = spacy.run(args['asr']['text'])
-    patch = [
-        { "op": "replace", "path": "/nlp", "value": res },
-    ]
-    res = patch_media(opts['media_id'], patch)
-    print("res", res)
+    args['nlp'] = res
+    if not opts.get('job_id'):
+        patch = [
+            { "op": "replace", "path": "/nlp", "value": res },
+        ]
+        res = patch_media(opts['media_id'], patch)
+        print("res", res)
     return args
 
 @app.task(bind=True, name="save_task_state")
 def save_task_state(self, args, opts):
+    if opts.get('job_id'):
+        result = {
+            "output": { "transcript": args["asr"], "nlp": args.get("nlp") },
+            "took": args["took"]
+        }
+        res = post_job_result(opts['job_id'], result)
+        print("res", res)
+        return res
+
     task_id = self.request.id.__str__()
     task_state = {
         "state": "finished",
@@ -199,9 +241,86 @@ def save_task_state(self, args, opts):
     print("res", res)
     return res
 
+def download_media(media: dict):
+    """
+    Download the file of a media record.
+    The download is cached by url, so the transcribe task does not download it again.
+    """
+    download({"media_url": media["contentUrl"], "media_id": media["$meta"]["id"]})
+    return {}
+
+def post_transcript(post: dict) -> str:
+    """
+    Join the transcripts of the resolved media of a post record.
+    """
+    texts = []
+    for media in post.get("media") or []:
+        # unresolved media are only ids
+        if isinstance(media, dict) and media.get("transcript"):
+            texts.append(media["transcript"]["text"])
+    return "\n".join(texts)
+
+def nlp_post(post: dict, pipeline: str):
+    """
+    Run NLP on the transcripts of a post record.
+    """
+    spacy = SpacyPipe(pipeline)
+    return spacy.run(post_transcript(post))
+
+def report_job(job_id, run):
+    """
+    Run a task for a job and report its output or error to the core.
+    """
+    start = time.time()
+    try:
+        result = {"output": run()}
+    except Exception as err:
+        logger.exception(err)
+        result = {"error": str(err) or repr(err)}
+    result["took"] = time.time() - start
+    res = post_job_result(job_id, result)
+    print("res", res)
+    return res
+
+@app.task(name="download2", bind=True)
+def download2(self, args: dict, opts: dict):
+    job_id = (opts or {}).get('job_id')
+    if is_revoked(job_id):
+        logger.info(f'Skipping revoked job {job_id}')
+        raise Ignore()
+    post_job_start(job_id, self.request.hostname)
+    return report_job(job_id, lambda: download_media(args))
+
+@app.task(name="nlp2", bind=True)
+def nlp2(self, args: dict, opts: dict):
+    opts = opts or {}
+    job_id = opts.get('job_id')
+    if is_revoked(job_id):
+        logger.info(f'Skipping revoked job {job_id}')
+        raise Ignore()
+    post_job_start(job_id, self.request.hostname)
+    pipeline = opts.get('pipeline') or 'ner'
+    return report_job(job_id, lambda: nlp_post(args, pipeline))
+
 def patch_media(media_id, patch):
     url = config.url(f"/media/{media_id}")
-    res = httpx.patch(url, json=patch)
+    res = httpx.patch(url, json=patch, headers=config.auth_headers())
+    res = res.json()
+    return res
+
+def post_job_start(job_id, worker):
+    """
+    Report the start of a job that was sent to this worker.
+    The task timeout of the core only counts from this start.
+    """
+    url = config.url(f"/job/{job_id}/start")
+    res = httpx.post(url, json={"worker": worker}, headers=config.worker_headers())
+    res.raise_for_status()
+    return res.json()
+
+def post_job_result(job_id, result):
+    url = config.url(f"/job/{job_id}/result")
+    res = httpx.post(url, json=result, headers=config.worker_headers())
     res = res.json()
     return res
 
@@ -230,7 +349,7 @@ def patch_media(media_id, patch):
 #          { "op": "replace", "path": "/tasks/asr", "value": task_state },
 #      ]
 #      print(json.dumps(patch));
-#      res = httpx.patch(url, json=patch)
+#      res = httpx.patch(url, json=patch, headers=config.auth_headers())
 #      res = res.json()
 #      print("res", res)
 #      return res
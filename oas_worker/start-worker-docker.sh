@@ -1,2 +1,5 @@
 #!/bin/sh
-celery -A app.tasks.tasks worker --loglevel=INFO --concurrency=${CONCURRENCY:=1}
+if [ "${TASK_QUEUE}" = "couch" ]; then
+  exec python -m app.claim
+fi
+celery -A app.tasks.tasks worker -Q ${QUEUES:=oas.asr.high,oas.default.high,oas.asr.normal,oas.default.normal,oas.asr.low,oas.default.low,celery} --loglevel=INFO --concurrency=${CONCURRENCY:=1}
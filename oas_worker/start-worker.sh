@@ -1,2 +1,5 @@
 #!/bin/sh
-poetry run celery -A app.tasks.tasks worker --loglevel=DEBUG --concurrency=${CONCURRENCY:=1}
+if [ "${TASK_QUEUE}" = "couch" ]; then
+  exec poetry run python -m app.claim
+fi
+poetry run celery -A app.tasks.tasks worker -Q ${QUEUES:=oas.asr.high,oas.default.high,oas.asr.normal,oas.default.normal,oas.asr.low,oas.default.low,celery} --loglevel=DEBUG --concurrency=${CONCURRENCY:=1}
@@ -15,6 +15,27 @@ impl RecordMap {
         Ok(this)
     }
 
+    /// Upcast and insert a list of untyped records, skipping records that fail to upcast.
+    ///
+    /// Returns the guids of the failed records together with the upcast errors.
+    pub fn from_untyped_lossy(records: Vec<UntypedRecord>) -> (Self, Vec<(String, anyhow::Error)>) {
+        let mut this = RecordMap::default();
+        let mut failed = vec![];
+        for record in records.into_iter() {
+            let guid = record.guid().to_string();
+            let res = match record.typ() {
+                Media::NAME => this.insert_untyped::<Media>(record),
+                Post::NAME => this.insert_untyped::<Post>(record),
+                Feed::NAME => this.insert_untyped::<Feed>(record),
+                _ => Ok(()),
+            };
+            if let Err(err) = res {
+                failed.push((guid, err));
+            }
+        }
+        (this, failed)
+    }
+
     pub fn insert_untyped_bulk(&mut self, records: Vec<UntypedRecord>) -> anyhow::Result<()> {
         for record in records.into_iter() {
             match record.typ() {
@@ -23,6 +23,48 @@ impl std::default::Default for TaskState {
     }
 }
 
+impl TaskState {
+    /// Get the previous attempts of the task.
+    pub fn history(&self) -> &[TaskAttempt] {
+        match self {
+            Self::Running(state) => &state.history,
+            Self::Finished(state) => &state.history,
+            _ => &[],
+        }
+    }
+}
+
+/// Priority of a task.
+///
+/// Tasks for new items have a high priority, tasks for older items (e.g. from crawling a feed
+/// backwards) a low priority.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskPriority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for TaskPriority {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
+impl TaskPriority {
+    /// Get the name of the priority, e.g. "high".
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        }
+    }
+}
+
 pub struct TaskFinishedModel {
     pub state: TaskFinishedState,
     pub result: serde_json::Value,
@@ -40,6 +82,9 @@ pub struct TaskFinishedState {
     // Time in seconds
     #[serde(default)]
     pub took: f32,
+    /// Previous attempts of the task that timed out or failed.
+    #[serde(default)]
+    pub history: Vec<TaskAttempt>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
@@ -47,6 +92,19 @@ pub struct TaskFinishedState {
 pub struct TaskRunningState {
     pub task_id: String,
     pub start: DateTime<Utc>,
+    /// Previous attempts of the task that timed out or failed.
+    #[serde(default)]
+    pub history: Vec<TaskAttempt>,
+}
+
+/// A previous attempt to run a task.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskAttempt {
+    pub task_id: String,
+    pub start: Option<DateTime<Utc>>,
+    pub end: DateTime<Utc>,
+    pub error: String,
 }
 
 pub trait TaskObject: TypedValue {
@@ -58,3 +116,41 @@ pub trait TaskObject: TypedValue {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn deserialize_state_without_history() {
+        let source = r#"
+        {
+            "state": "finished",
+            "taskId": "foo",
+            "success": true,
+            "took": 12.5
+        }
+        "#;
+        let state: TaskState = serde_json::from_str(source).expect("failed to deserialize");
+        assert!(matches!(state, TaskState::Finished(_)));
+        assert!(state.history().is_empty());
+
+        let source = r#"
+        {
+            "state": "running",
+            "taskId": "foo",
+            "start": "2021-07-01T10:00:00Z",
+            "history": [
+                {
+                    "taskId": "foo",
+                    "start": "2021-07-01T08:00:00Z",
+                    "end": "2021-07-01T10:00:00Z",
+                    "error": "Timed out"
+                }
+            ]
+        }
+        "#;
+        let state: TaskState = serde_json::from_str(source).expect("failed to deserialize");
+        assert_eq!(state.history().len(), 1);
+        assert_eq!(state.history()[0].error, "Timed out");
+    }
+}
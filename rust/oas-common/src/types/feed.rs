@@ -1,11 +1,13 @@
 use crate::mapping::Mappable;
 use crate::record::{TypedValue, ValidationError};
+use crate::task::TaskPriority;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 use super::media::MediaTasks;
 use super::post::PostTasks;
+use super::visibility::VisibilitySettings;
 
 // pub type Mapping = serde_json::Value;
 
@@ -51,6 +53,8 @@ pub struct Feed {
     pub url: String,
     pub settings: Option<FeedSettings>,
     pub task_defaults: Option<FeedTaskDefaults>,
+    /// Visibility of the posts of the feed that do not set their own.
+    pub default_visibility: Option<VisibilitySettings>,
     // #[serde(default)]
     // pub mapping: Mapping,
     // pub state: Option<FeedState>
@@ -61,6 +65,23 @@ pub struct Feed {
 pub struct FeedTaskDefaults {
     pub media: Option<MediaTasks>,
     pub post: Option<PostTasks>,
+    /// Priority of the tasks for new items of the feed (default: high). Tasks for older items
+    /// always have a low priority.
+    pub priority: Option<TaskPriority>,
+    /// Limits for the tasks of the feed.
+    pub quota: Option<FeedTaskQuota>,
+}
+
+/// Limits for the tasks of a feed.
+///
+/// Tasks of a feed with a quota are held back until the quota allows to run them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedTaskQuota {
+    /// Max number of tasks of the feed that are queued or running at the same time.
+    pub max_running: Option<u32>,
+    /// Max number of tasks of the feed that are queued per day.
+    pub max_per_day: Option<u32>,
 }
 
 impl Feed {
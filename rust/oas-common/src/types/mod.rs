@@ -1,8 +1,11 @@
 mod feed;
 mod media;
 mod post;
+mod visibility;
 
 pub use feed::Feed;
 pub use feed::FeedSettings;
-pub use media::{Media, Transcript, TranscriptPart};
-pub use post::Post;
+pub use feed::{FeedTaskDefaults, FeedTaskQuota};
+pub use media::{Media, MediaTasks, Transcript, TranscriptPart};
+pub use post::{Post, PostTasks};
+pub use visibility::{Visibility, VisibilitySettings};
@@ -1,4 +1,4 @@
-use super::{Feed, Media};
+use super::{Feed, Media, Visibility, VisibilitySettings};
 use crate::mapping::Mappable;
 use crate::record::TypedValue;
 use crate::reference::{self, Reference};
@@ -49,6 +49,15 @@ pub struct Post {
 
     pub nlp: Option<serde_json::Value>,
 
+    /// Visibility of the post. If not set, the most restrictive default of the feeds of the post
+    /// applies, or [Visibility::Public].
+    #[serde(default)]
+    pub visibility: Option<Visibility>,
+
+    /// End of the embargo, for [Visibility::Embargoed].
+    #[serde(default)]
+    pub embargo_until: Option<DateTime<Utc>>,
+
     #[serde(flatten)]
     pub other: serde_json::Map<String, serde_json::Value>,
     #[serde(default)]
@@ -61,6 +70,16 @@ impl TypedValue for Post {
 
 impl Mappable for Post {}
 
+impl Post {
+    /// Get the visibility settings of the post, if set.
+    pub fn visibility_settings(&self) -> Option<VisibilitySettings> {
+        self.visibility.map(|visibility| VisibilitySettings {
+            visibility,
+            embargo_until: self.embargo_until,
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
 pub struct PostTasks {
     #[serde(deserialize_with = "ser::deserialize_null_default")]
@@ -219,6 +238,12 @@ impl ElasticMapping for Post {
             },
             "feeds": {
                 "type":"keyword",
+            },
+            "visibility": {
+                "type":"keyword",
+            },
+            "embargoUntil": {
+                "type":"date"
             }
         })
     }
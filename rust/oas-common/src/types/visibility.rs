@@ -0,0 +1,69 @@
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Who may see a post and its media.
+///
+/// Values are ordered from the least to the most restrictive.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Visible to everyone and listed in search results.
+    Public,
+    /// Visible to everyone with a link, but not listed in search results for anonymous users.
+    Unlisted,
+    /// Only visible to users that may view private records.
+    Private,
+    /// Private until the embargo ends, public afterwards. Without an end date, the post stays
+    /// private.
+    Embargoed,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Self::Public
+    }
+}
+
+impl Visibility {
+    /// Resolve an embargo at a point in time.
+    ///
+    /// Returns [Visibility::Private] for an embargo that has not ended yet, and
+    /// [Visibility::Public] for an embargo that ended. Other values are returned unchanged.
+    pub fn at(self, embargo_until: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Self {
+        match (self, embargo_until) {
+            (Self::Embargoed, Some(until)) if until <= now => Self::Public,
+            (Self::Embargoed, _) => Self::Private,
+            (visibility, _) => visibility,
+        }
+    }
+
+    /// Check if records with this visibility can be accessed by anonymous users.
+    pub fn is_accessible(self) -> bool {
+        matches!(self, Self::Public | Self::Unlisted)
+    }
+
+    /// Check if records with this visibility are listed in search results for anonymous users.
+    pub fn is_listed(self) -> bool {
+        self == Self::Public
+    }
+}
+
+/// Visibility settings of a post, or the defaults for the posts of a feed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VisibilitySettings {
+    pub visibility: Visibility,
+    /// End of the embargo, for [Visibility::Embargoed].
+    #[serde(default)]
+    pub embargo_until: Option<DateTime<Utc>>,
+}
+
+impl VisibilitySettings {
+    /// Get the visibility at a point in time, see [Visibility::at].
+    pub fn at(&self, now: DateTime<Utc>) -> Visibility {
+        self.visibility.at(self.embargo_until, now)
+    }
+}
@@ -4,10 +4,10 @@ use futures::stream::StreamExt;
 use oas_common::types::Media;
 use oas_common::Record;
 use oas_core::rss::manager::FeedManagerOpts;
-use oas_core::server::{run_server, ServerOpts};
+use oas_core::server::{run_server, OidcOpts, RateLimitOpts, ServerOpts};
 use oas_core::types::Post;
 use oas_core::util::debug_print_records;
-use oas_core::{couch, index, rss, tasks};
+use oas_core::{couch, index, replay, rss, tasks};
 use oas_core::{Runtime, State};
 use std::env;
 use std::time;
@@ -21,7 +21,7 @@ struct Args {
     #[clap(long, env = "ELASTICSEARCH_URL")]
     pub elasticsearch_url: Option<String>,
 
-    /// CouchDB URL
+    /// CouchDB URL (or "memory:" / "file:///some/dir" for in-process databases)
     #[clap(long, env = "COUCHDB_URL")]
     pub couchdb_url: Option<String>,
 
@@ -29,6 +29,13 @@ struct Args {
     #[clap(long, env = "REDIS_URL")]
     pub redis_url: Option<String>,
 
+    /// Task queue backend ("celery" or "couch")
+    #[clap(long, env = "TASK_QUEUE", default_value = "celery")]
+    pub task_queue: String,
+
+    #[clap(flatten)]
+    pub supervisor: tasks::SupervisorOpts,
+
     /// Bind HTTP server to host
     #[clap(long, env = "HTTP_HOST")]
     pub http_host: Option<String>,
@@ -37,6 +44,12 @@ struct Args {
     #[clap(long, env = "HTTP_PORT")]
     pub http_port: Option<u16>,
 
+    #[clap(flatten)]
+    pub oidc: OidcOpts,
+
+    #[clap(flatten)]
+    pub rate_limit: RateLimitOpts,
+
     /// Path to mapping file
     #[clap(long, env = "MAPPING_FILE")]
     pub mapping_file: Option<String>,
@@ -63,6 +76,10 @@ enum Command {
     Task(tasks::TaskOpts),
     /// Run the HTTP API server
     Server(ServerOpts),
+    /// Inspect and reset checkpoints of changes consumers
+    Checkpoints(CheckpointCommands),
+    /// List, inspect and replay records that failed processing
+    DeadLetters(DeadLetterCommands),
     /// Delete all databases and indexes (dangerous!)
     Nuke,
     /// Run all services
@@ -76,6 +93,71 @@ struct FeedCommands {
     command: FeedCommand,
 }
 
+#[derive(Clap)]
+struct CheckpointCommands {
+    /// Subcommand
+    #[clap(subcommand)]
+    command: CheckpointCommand,
+}
+
+#[derive(Clap)]
+enum CheckpointCommand {
+    /// List the checkpoints of all changes consumers.
+    List,
+    /// Reset the checkpoint of a changes consumer.
+    Reset(ResetCheckpointOpts),
+}
+
+#[derive(Clap)]
+struct ResetCheckpointOpts {
+    /// Consumer name ("index", "tasks", "feeds")
+    consumer: String,
+    /// Seq to rewind to. If not set, the consumer starts from the beginning.
+    #[clap(long)]
+    seq: Option<String>,
+}
+
+#[derive(Clap)]
+struct DeadLetterCommands {
+    /// Subcommand
+    #[clap(subcommand)]
+    command: DeadLetterCommand,
+}
+
+#[derive(Clap)]
+enum DeadLetterCommand {
+    /// List all dead letters.
+    List(ListDeadLettersOpts),
+    /// Show a dead letter with its error and raw doc.
+    Show(DeadLetterIdOpts),
+    /// Process the record of a dead letter again.
+    Replay(ReplayDeadLettersOpts),
+    /// Remove a dead letter without replaying it.
+    Remove(DeadLetterIdOpts),
+}
+
+#[derive(Clap)]
+struct ListDeadLettersOpts {
+    /// Only list dead letters of this consumer ("index", "tasks", "feeds")
+    #[clap(long)]
+    consumer: Option<String>,
+}
+
+#[derive(Clap)]
+struct DeadLetterIdOpts {
+    /// Dead letter ID
+    id: String,
+}
+
+#[derive(Clap)]
+struct ReplayDeadLettersOpts {
+    /// Dead letter ID. If not set, all dead letters are replayed.
+    id: Option<String>,
+    /// Only replay dead letters of this consumer
+    #[clap(long)]
+    consumer: Option<String>,
+}
+
 #[derive(Clap)]
 struct RefetchOpts {
     /// Feed ID or URL
@@ -109,16 +191,6 @@ struct IndexOpts {
     post_id: Option<String>,
 }
 
-impl IndexOpts {
-    pub fn run_forever() -> Self {
-        Self {
-            daemon: true,
-            recreate: false,
-            post_id: None,
-        }
-    }
-}
-
 #[derive(Clap)]
 struct WatchOpts {
     /// Rev to start the watch stream at.
@@ -165,7 +237,8 @@ fn state_from_args(args: &Args) -> anyhow::Result<State> {
     let db_manager = couch::CouchManager::with_url(args.couchdb_url.as_deref())?;
     let db = db_manager.record_db().clone();
     let index_manager = index::IndexManager::with_url(args.elasticsearch_url.as_deref())?;
-    let task_manager = tasks::CeleryManager::with_url(args.redis_url.as_deref())?;
+    let task_manager =
+        tasks::TaskManager::with_backend(&args.task_queue, args.redis_url.as_deref(), &db_manager)?;
     let feed_manager_opts = FeedManagerOpts {
         mapping_file: args.mapping_file.clone(),
     };
@@ -192,6 +265,8 @@ async fn main() -> anyhow::Result<()> {
         Command::Feed(opts) => run_feed(state, opts.command).await,
         Command::Task(opts) => run_task(state, opts).await,
         Command::Server(opts) => run_server(state, opts).await,
+        Command::Checkpoints(opts) => run_checkpoints(state, opts.command).await,
+        Command::DeadLetters(opts) => run_dead_letters(state, opts.command).await,
         Command::Run => run_all(state, args).await,
         Command::Nuke => run_nuke(state, args).await,
     };
@@ -220,6 +295,8 @@ async fn run_all(mut state: State, args: Args) -> anyhow::Result<()> {
     let server_opts = ServerOpts {
         host: args.http_host,
         port: args.http_port,
+        oidc: args.oidc,
+        rate_limit: args.rate_limit,
     };
 
     state.init_all().await?;
@@ -227,15 +304,44 @@ async fn run_all(mut state: State, args: Args) -> anyhow::Result<()> {
     // A simple abstraction to run tasks and log their results in case of errors.
     let mut runtime = Runtime::new();
     runtime.spawn("server", run_server(state.clone(), server_opts));
-    runtime.spawn("index", run_index(state.clone(), IndexOpts::run_forever()));
+
+    // All services that consume the CouchDB changes stream share a single dispatcher.
+    let mut dispatcher = couch::ChangesDispatcher::new(state.db.clone(), true);
+    let index_changes = state
+        .index_manager
+        .register_changes(&state.db_manager, &mut dispatcher)
+        .await?;
+    let task_changes = tasks::changes::register_changes(&state, &mut dispatcher).await?;
+    let feed_changes = state
+        .feed_manager
+        .register_changes(&state.db_manager, &mut dispatcher)
+        .await?;
+    runtime.spawn("changes", dispatcher.run());
+
+    runtime.spawn("index", {
+        let state = state.clone();
+        async move {
+            state
+                .index_manager
+                .index_changes_from(&state.db, index_changes)
+                .await
+        }
+    });
     // Spawn task watcher.
     runtime.spawn(
         "tasks",
-        tasks::changes::process_changes(state.clone(), true),
+        tasks::changes::process_changes_from(state.clone(), task_changes),
     );
     runtime.spawn(
         "feed_watcher",
-        state.feed_manager.clone().run_watch(state.db.clone()), // run_feed(state, FeedCommand::Watch(feed_manager_opts)),
+        state
+            .feed_manager
+            .clone()
+            .run_watch_from(state.db.clone(), feed_changes),
+    );
+    runtime.spawn(
+        "task_supervisor",
+        tasks::TaskSupervisor::new(state.tasks.clone(), state.db.clone(), args.supervisor).run(),
     );
     // This calls std::process::exit() on ctrl_c signal.
     // TODO: We might need cancel signals into the tasks for some tasks.
@@ -317,6 +423,8 @@ async fn run_watch(state: State, opts: WatchOpts) -> anyhow::Result<()> {
 
 async fn run_index(state: State, opts: IndexOpts) -> anyhow::Result<()> {
     let manager = state.index_manager;
+    state.db_manager.init().await?;
+    let checkpoints = state.db_manager.checkpoints();
 
     let init_opts = match opts.recreate {
         true => index::InitOpts::delete_all(),
@@ -327,13 +435,18 @@ async fn run_index(state: State, opts: IndexOpts) -> anyhow::Result<()> {
         .init(init_opts)
         .await
         .with_context(|| format!("Failed to initializer Elasticsearch index"))?;
+    if opts.recreate {
+        checkpoints.reset(index::CONSUMER_NAME, None).await?;
+    }
     match opts.post_id {
         Some(post_id) => {
             let post_index = manager.post_index();
             post_index.index_post_by_id(&state.db, &post_id).await?;
         }
         None => {
-            manager.index_changes(&state.db, opts.daemon).await?;
+            manager
+                .index_changes(&state.db_manager, opts.daemon)
+                .await?;
         }
     }
     Ok(())
@@ -367,7 +480,8 @@ async fn run_feed(state: State, command: FeedCommand) -> anyhow::Result<()> {
             rss::ops::crawl_and_save(&state.db, &opts).await?;
         }
         FeedCommand::Watch(_opts) => {
-            state.feed_manager.run_watch(state.db).await?;
+            state.db_manager.init().await?;
+            state.feed_manager.run_watch(state.db_manager).await?;
         }
         FeedCommand::Refetch(opts) => {
             state
@@ -378,3 +492,77 @@ async fn run_feed(state: State, command: FeedCommand) -> anyhow::Result<()> {
     };
     Ok(())
 }
+
+async fn run_checkpoints(state: State, command: CheckpointCommand) -> anyhow::Result<()> {
+    state.db_manager.init().await?;
+    let checkpoints = state.db_manager.checkpoints();
+    match command {
+        CheckpointCommand::List => {
+            let last_seq = state.db.get_last_seq().await?;
+            println!("database is at seq {}", last_seq);
+            for record in checkpoints.list().await? {
+                println!(
+                    "{}: seq {} (updated {})",
+                    record.id(),
+                    record.value.seq.as_deref().unwrap_or("none"),
+                    record.value.updated
+                );
+            }
+        }
+        CheckpointCommand::Reset(opts) => {
+            checkpoints.reset(&opts.consumer, opts.seq).await?;
+            println!("checkpoint for {} reset", opts.consumer);
+        }
+    }
+    Ok(())
+}
+
+async fn run_dead_letters(state: State, command: DeadLetterCommand) -> anyhow::Result<()> {
+    state.db_manager.init().await?;
+    let dead_letters = state.db_manager.dead_letters();
+    match command {
+        DeadLetterCommand::List(opts) => {
+            for record in dead_letters.list(opts.consumer.as_deref()).await? {
+                let letter = &record.value;
+                println!(
+                    "{} [{}] {} (seq {}, {} failures): {}",
+                    record.id(),
+                    letter.consumer,
+                    letter.record_id,
+                    letter.seq.as_deref().unwrap_or("none"),
+                    letter.failures,
+                    letter.error
+                );
+            }
+        }
+        DeadLetterCommand::Show(opts) => {
+            let record = dead_letters
+                .get(&opts.id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Dead letter {} not found", opts.id))?;
+            println!("{}", serde_json::to_string_pretty(&record)?);
+        }
+        DeadLetterCommand::Replay(opts) => match opts.id {
+            Some(id) => {
+                let record = dead_letters
+                    .get(&id)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("Dead letter {} not found", id))?;
+                replay::replay_dead_letter(&state, record).await?;
+                println!("replayed {}", id);
+            }
+            None => {
+                let stats = replay::replay_dead_letters(&state, opts.consumer.as_deref()).await?;
+                println!(
+                    "replayed {} dead letters ({} failed)",
+                    stats.succeeded, stats.failed
+                );
+            }
+        },
+        DeadLetterCommand::Remove(opts) => {
+            dead_letters.remove(&opts.id).await?;
+            println!("removed {}", opts.id);
+        }
+    }
+    Ok(())
+}
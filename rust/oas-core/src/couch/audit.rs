@@ -0,0 +1,257 @@
+//! Audit log of record modifications.
+//!
+//! Each change to a record through the API is saved as an [AuditEntry] in the meta database,
+//! together with the user (and API token) that made the change, the endpoint, the revisions
+//! before and after the change, and a JSON patch from the previous to the new version. The
+//! entries are listed through views by record, by user and by time.
+
+use chrono::{DateTime, Utc};
+use oas_common::{util, Record, TypedValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{CouchDB, CouchResult, Doc, RecordUpdate, View, ViewQuery};
+
+/// Audit entries by record guid and time.
+const AUDIT_BY_RECORD: View = View {
+    typ: AuditEntry::NAME,
+    name: "by_record",
+    key: &["recordId", "timestamp"],
+};
+/// Audit entries by username and time.
+const AUDIT_BY_USER: View = View {
+    typ: AuditEntry::NAME,
+    name: "by_user",
+    key: &["username", "timestamp"],
+};
+/// Audit entries by time.
+const AUDIT_BY_TIME: View = View {
+    typ: AuditEntry::NAME,
+    name: "by_time",
+    key: &["timestamp"],
+};
+
+/// All views on the audit entries.
+const AUDIT_VIEWS: &[View] = &[AUDIT_BY_RECORD, AUDIT_BY_USER, AUDIT_BY_TIME];
+
+/// A change to a record.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    /// Name of the user that changed the record.
+    pub username: String,
+    /// Id of the API token that was used, if any.
+    pub token: Option<String>,
+    /// The endpoint of the request, e.g. `PATCH /post/<id>`.
+    pub endpoint: String,
+    /// The guid of the changed record.
+    pub record_id: String,
+    /// The rev of the record before the change, None if the record was created.
+    pub previous_rev: Option<String>,
+    /// The rev of the record after the change.
+    pub rev: String,
+    /// JSON patch from the previous to the new version of the record.
+    pub patch: serde_json::Value,
+    /// Time of the change.
+    pub timestamp: DateTime<Utc>,
+}
+
+impl TypedValue for AuditEntry {
+    const NAME: &'static str = "oas.AuditEntry";
+}
+
+impl AuditEntry {
+    /// Create an entry for a record that was changed with [CouchDB::update_record_tracked].
+    pub fn from_update<T: TypedValue>(
+        username: &str,
+        token: Option<&str>,
+        endpoint: &str,
+        update: RecordUpdate<T>,
+    ) -> Result<Self, serde_json::Error> {
+        let record_id = update.record.guid().to_string();
+        let previous = doc_value(&Doc::from_typed_record(update.previous));
+        let value = doc_value(&Doc::from_typed_record(update.record));
+        let patch = json_patch::diff(&previous, &value);
+        Ok(Self {
+            username: username.to_string(),
+            token: token.map(str::to_string),
+            endpoint: endpoint.to_string(),
+            record_id,
+            previous_rev: Some(update.previous_rev),
+            rev: update.rev,
+            patch: serde_json::to_value(patch)?,
+            timestamp: Utc::now(),
+        })
+    }
+
+    /// Check if the entry does not change anything.
+    pub fn is_empty(&self) -> bool {
+        matches!(&self.patch, Value::Array(ops) if ops.is_empty())
+    }
+}
+
+/// Filter for listing audit entries.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    /// Only entries for this record guid.
+    pub record_id: Option<String>,
+    /// Only entries of this user.
+    pub username: Option<String>,
+    /// Only entries at or after this time.
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl AuditFilter {
+    /// Get the view and query for the filter. Returns true as well if the view covers all
+    /// conditions of the filter, i.e. the rows need not be filtered further.
+    fn view_query(&self) -> (&'static View, ViewQuery, bool) {
+        let since = |prefix: Vec<Value>| match self.since {
+            Some(since) => ViewQuery::prefix_from(prefix, json!(since)),
+            None => ViewQuery::prefix(prefix),
+        };
+        match (&self.record_id, &self.username) {
+            (Some(record_id), username) => (
+                &AUDIT_BY_RECORD,
+                since(vec![json!(record_id)]),
+                username.is_none(),
+            ),
+            (None, Some(username)) => (&AUDIT_BY_USER, since(vec![json!(username)]), true),
+            (None, None) => (&AUDIT_BY_TIME, since(vec![]), true),
+        }
+    }
+
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.record_id
+            .as_ref()
+            .map_or(true, |record_id| &entry.record_id == record_id)
+            && self
+                .username
+                .as_ref()
+                .map_or(true, |username| &entry.username == username)
+            && self.since.map_or(true, |since| entry.timestamp >= since)
+    }
+}
+
+/// Store for the audit log.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    db: CouchDB,
+}
+
+impl AuditLog {
+    /// Create a new audit log on a database.
+    pub fn new(db: CouchDB) -> Self {
+        Self { db }
+    }
+
+    /// Create the views on the audit entries.
+    pub async fn init(&self) -> CouchResult<()> {
+        self.db.init_views(AUDIT_VIEWS).await
+    }
+
+    /// Save an audit entry.
+    pub async fn add(&self, entry: AuditEntry) -> CouchResult<()> {
+        let record = Record::from_id_and_value(util::id_from_uuid(), entry);
+        self.db.table::<AuditEntry>().put(record).await?;
+        Ok(())
+    }
+
+    /// List the audit entries that match a filter, ordered by time.
+    ///
+    /// The first `offset` entries are skipped and at most `limit` entries are returned.
+    pub async fn list(
+        &self,
+        filter: &AuditFilter,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> CouchResult<Vec<Record<AuditEntry>>> {
+        let (view, query, exact) = filter.view_query();
+        if exact {
+            let query = query.page(offset, limit);
+            return self.db.query_view_records(view, &query).await;
+        }
+        let records = self
+            .db
+            .query_view_records::<AuditEntry>(view, &query)
+            .await?;
+        let records = records
+            .into_iter()
+            .filter(|record| filter.matches(&record.value))
+            .skip(offset)
+            .take(limit.unwrap_or(usize::MAX))
+            .collect();
+        Ok(records)
+    }
+}
+
+/// Get the JSON value of a doc, without the CouchDB meta fields.
+pub fn doc_value(doc: &Doc) -> Value {
+    let mut value = doc.doc.clone();
+    value.remove("_id");
+    value.remove("_rev");
+    Value::Object(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn entry(record_id: &str, username: &str, timestamp: DateTime<Utc>) -> AuditEntry {
+        AuditEntry {
+            username: username.to_string(),
+            token: None,
+            endpoint: "PUT /test".to_string(),
+            record_id: record_id.to_string(),
+            previous_rev: None,
+            rev: "1-a".to_string(),
+            patch: json!([]),
+            timestamp,
+        }
+    }
+
+    #[tokio::test]
+    async fn list_entries() {
+        let audit = AuditLog::new(CouchDB::in_memory("meta"));
+        audit.init().await.unwrap();
+        let now = Utc::now();
+        let ago = |minutes| now - Duration::minutes(minutes);
+        audit.add(entry("a", "alice", ago(4))).await.unwrap();
+        audit.add(entry("b", "bob", ago(3))).await.unwrap();
+        audit.add(entry("a", "bob", ago(2))).await.unwrap();
+        audit.add(entry("a", "alice", ago(1))).await.unwrap();
+
+        let list = |filter: AuditFilter, offset, limit| {
+            let audit = audit.clone();
+            async move {
+                let records = audit.list(&filter, offset, limit).await.unwrap();
+                records
+                    .into_iter()
+                    .map(|record| record.value.timestamp)
+                    .collect::<Vec<_>>()
+            }
+        };
+        let all = list(AuditFilter::default(), 0, None).await;
+        assert_eq!(all, vec![ago(4), ago(3), ago(2), ago(1)]);
+        let page = list(AuditFilter::default(), 1, Some(2)).await;
+        assert_eq!(page, vec![ago(3), ago(2)]);
+        let record = AuditFilter {
+            record_id: Some("a".to_string()),
+            since: Some(ago(3)),
+            ..Default::default()
+        };
+        assert_eq!(list(record, 0, None).await, vec![ago(2), ago(1)]);
+        let user = AuditFilter {
+            username: Some("bob".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(list(user, 1, None).await, vec![ago(2)]);
+        let both = AuditFilter {
+            record_id: Some("a".to_string()),
+            username: Some("alice".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(list(both, 1, Some(5)).await, vec![ago(1)]);
+    }
+}
@@ -1,3 +1,4 @@
+use futures::stream::BoxStream;
 use futures::{ready, FutureExt, StreamExt, TryStreamExt};
 use futures::{Future, Stream};
 use futures_batch::ChunksTimeoutStreamExt;
@@ -16,7 +17,7 @@ use tokio_util::io::StreamReader;
 use crate::couch::ErrorDetails;
 
 use super::types::{ChangeEvent, Event};
-use super::{CouchDB, CouchError, CouchResult};
+use super::{CouchDB, CouchError, CouchResult, HttpStore};
 
 pub const BATCH_TIMEOUT: time::Duration = time::Duration::from_millis(200);
 pub const BATCH_MAX_LEN: usize = 1000;
@@ -29,10 +30,92 @@ const COUCH_MAX_TIMEOUT: usize = 60000;
 
 /// The stream for the `_changes` endpoint.
 ///
-/// This is returned from [Database::changes].
+/// This is returned from [CouchDB::changes]. The changes are read from the database's
+/// [RecordStore](super::RecordStore) once the stream is first polled.
 pub struct ChangesStream {
     last_seq: Option<String>,
     db: CouchDB,
+    infinite: bool,
+    inner: Option<BoxStream<'static, CouchResult<ChangeEvent>>>,
+}
+
+impl ChangesStream {
+    /// Create a new changes stream.
+    pub fn new(db: CouchDB, last_seq: Option<String>) -> Self {
+        Self {
+            last_seq,
+            db,
+            infinite: false,
+            inner: None,
+        }
+    }
+
+    /// Set the starting seq.
+    ///
+    /// Has no effect once the stream was polled.
+    pub fn set_last_seq(&mut self, last_seq: Option<String>) {
+        self.last_seq = last_seq;
+    }
+
+    /// Set infinite mode.
+    ///
+    /// If set to true, the changes stream will wait and poll for changes. Otherwise,
+    /// the stream will return all changes until now and then close. Has no effect once the
+    /// stream was polled.
+    pub fn set_infinite(&mut self, infinite: bool) {
+        self.infinite = infinite;
+    }
+
+    /// Get the last retrieved seq.
+    pub fn last_seq(&self) -> &Option<String> {
+        &self.last_seq
+    }
+
+    /// Whether this stream is running in infinite mode.
+    pub fn infinite(&self) -> bool {
+        self.infinite
+    }
+
+    pub fn batched_untyped_records(self) -> impl Stream<Item = UntypedRecordBatch> {
+        let batch_timeout = BATCH_TIMEOUT;
+        let batch_max_len = BATCH_MAX_LEN;
+        let changes = self.chunks_timeout(batch_max_len, batch_timeout);
+        changes.map(|batch| {
+            let last_seq = get_last_seq(&batch[..]);
+            let (records, failed) = changes_into_untyped_records_and_failures(batch);
+            UntypedRecordBatch {
+                last_seq,
+                records,
+                failed,
+            }
+        })
+    }
+}
+
+impl Stream for ChangesStream {
+    type Item = CouchResult<ChangeEvent>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.inner.is_none() {
+            let inner = self
+                .db
+                .store()
+                .changes(self.last_seq.clone(), self.infinite);
+            self.inner = Some(inner);
+        }
+        let item = ready!(self.inner.as_mut().unwrap().poll_next_unpin(cx));
+        if let Some(Ok(event)) = &item {
+            self.last_seq = Some(event.seq.clone());
+        }
+        Poll::Ready(item)
+    }
+}
+
+/// The stream for the `_changes` endpoint of a CouchDB server.
+///
+/// This is returned from [HttpStore::changes](super::RecordStore::changes).
+pub struct HttpChangesStream {
+    last_seq: Option<String>,
+    db: HttpStore,
     state: ChangesStreamState,
     params: HashMap<String, String>,
     infinite: bool,
@@ -48,22 +131,13 @@ enum ChangesStreamState {
     Reading(Pin<Box<dyn Stream<Item = io::Result<String>> + Send + 'static>>),
 }
 
-impl ChangesStream {
+impl HttpChangesStream {
     /// Create a new changes stream.
-    pub fn new(db: CouchDB, last_seq: Option<String>) -> Self {
+    pub fn new(db: HttpStore, last_seq: Option<String>) -> Self {
         let mut params = HashMap::new();
         params.insert("feed".to_string(), "continuous".to_string());
         params.insert("timeout".to_string(), "0".to_string());
         params.insert("include_docs".to_string(), "true".to_string());
-        Self::with_params(db, last_seq, params)
-    }
-
-    /// Create a new changes stream with params.
-    pub fn with_params(
-        db: CouchDB,
-        last_seq: Option<String>,
-        params: HashMap<String, String>,
-    ) -> Self {
         Self {
             db,
             params,
@@ -76,15 +150,7 @@ impl ChangesStream {
         }
     }
 
-    /// Set the starting seq.
-    pub fn set_last_seq(&mut self, last_seq: Option<String>) {
-        self.last_seq = last_seq;
-    }
-
     /// Set infinite mode.
-    ///
-    /// If set to true, the changes stream will wait and poll for changes. Otherwise,
-    /// the stream will return all changes until now and then close.
     pub fn set_infinite(&mut self, infinite: bool) {
         self.infinite = infinite;
         let timeout = match infinite {
@@ -93,38 +159,23 @@ impl ChangesStream {
         };
         self.params.insert("timeout".to_string(), timeout);
     }
-
-    /// Get the last retrieved seq.
-    pub fn last_seq(&self) -> &Option<String> {
-        &self.last_seq
-    }
-
-    /// Whether this stream is running in infinite mode.
-    pub fn infinite(&self) -> bool {
-        self.infinite
-    }
-
-    // pub fn batched_records<T: TypedValue>(self) -> RecordChangesStream<T> {
-    //     RecordChangesStream::new(self)
-    // }
-
-    pub fn batched_untyped_records(self) -> impl Stream<Item = UntypedRecordBatch> {
-        let batch_timeout = BATCH_TIMEOUT;
-        let batch_max_len = BATCH_MAX_LEN;
-        let changes = self.chunks_timeout(batch_max_len, batch_timeout);
-        let changes = changes.map(|batch| UntypedRecordBatch {
-            last_seq: get_last_seq(&batch[..]),
-            records: changes_into_untyped_records(batch),
-        });
-        changes
-    }
 }
 
 pub struct UntypedRecordBatch {
     records: Vec<UntypedRecord>,
+    failed: Vec<FailedChange>,
     last_seq: Option<String>,
 }
 
+/// A changed doc that could not be decoded into a record.
+#[derive(Debug, Clone)]
+pub struct FailedChange {
+    pub id: String,
+    pub seq: String,
+    pub error: String,
+    pub doc: Option<serde_json::Value>,
+}
+
 impl UntypedRecordBatch {
     pub fn last_seq(&self) -> Option<&str> {
         self.last_seq.as_deref()
@@ -134,18 +185,23 @@ impl UntypedRecordBatch {
         &self.records[..]
     }
 
+    /// The changed docs in this batch that could not be decoded into records.
+    pub fn failed(&self) -> &[FailedChange] {
+        &self.failed[..]
+    }
+
     pub fn into_inner(self) -> Vec<UntypedRecord> {
         self.records
     }
 }
 
-async fn get_changes(db: CouchDB, params: HashMap<String, String>) -> CouchResult<Response> {
+async fn get_changes(db: HttpStore, params: HashMap<String, String>) -> CouchResult<Response> {
     let req = db.request(Method::GET, "_changes").query(&params);
     let res = req.send().await?;
     Ok(res)
 }
 
-impl Stream for ChangesStream {
+impl Stream for HttpChangesStream {
     type Item = CouchResult<ChangeEvent>;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         loop {
@@ -256,16 +312,34 @@ impl Stream for ChangesStream {
     }
 }
 
-pub fn changes_into_untyped_records(batch: Vec<CouchResult<ChangeEvent>>) -> Vec<UntypedRecord> {
-    let records: Vec<_> = batch
-        .into_iter()
-        .filter_map(|ev| {
-            ev.ok()
-                .and_then(|ev| ev.doc)
-                .and_then(|doc| doc.into_untyped_record().ok())
-        })
-        .collect();
-    records
+/// Decode the docs of a batch of change events into records.
+///
+/// Docs that fail to decode are returned as [FailedChange]s. Deleted docs and design docs are
+/// skipped.
+pub fn changes_into_untyped_records_and_failures(
+    batch: Vec<CouchResult<ChangeEvent>>,
+) -> (Vec<UntypedRecord>, Vec<FailedChange>) {
+    let mut records = vec![];
+    let mut failed = vec![];
+    for event in batch.into_iter().filter_map(|ev| ev.ok()) {
+        if event.deleted || event.id.starts_with("_design/") {
+            continue;
+        }
+        let doc = match event.doc {
+            Some(doc) => doc,
+            None => continue,
+        };
+        match doc.clone().into_untyped_record() {
+            Ok(record) => records.push(record),
+            Err(err) => failed.push(FailedChange {
+                id: event.id,
+                seq: event.seq,
+                error: err.to_string(),
+                doc: serde_json::to_value(doc).ok(),
+            }),
+        }
+    }
+    (records, failed)
 }
 
 fn get_last_seq(batch: &[CouchResult<ChangeEvent>]) -> Option<String> {
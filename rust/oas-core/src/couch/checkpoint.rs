@@ -0,0 +1,158 @@
+//! Checkpoints for changes consumers.
+//!
+//! Every consumer of the changes stream (indexer, task processor, feed watcher) persists the
+//! latest CouchDB seq it processed as a [Checkpoint] record in the meta database. On restart, the
+//! consumer resumes from its checkpoint.
+
+use chrono::{DateTime, Utc};
+use oas_common::{Record, TypedValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{CouchDB, CouchResult};
+
+/// The checkpoint of a changes consumer.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Checkpoint {
+    /// The latest processed seq. If None, the consumer starts from the beginning.
+    pub seq: Option<String>,
+    /// Time of the last update.
+    pub updated: DateTime<Utc>,
+}
+
+impl TypedValue for Checkpoint {
+    const NAME: &'static str = "oas.Checkpoint";
+}
+
+/// Store for the checkpoints of all changes consumers.
+///
+/// The record id of a checkpoint is the consumer name.
+#[derive(Debug, Clone)]
+pub struct CheckpointStore {
+    db: CouchDB,
+}
+
+impl CheckpointStore {
+    /// Create a new checkpoint store on a database.
+    pub fn new(db: CouchDB) -> Self {
+        Self { db }
+    }
+
+    /// Get the checkpoint for a consumer.
+    ///
+    /// Returns None if the consumer did not save a checkpoint yet.
+    pub async fn get(&self, consumer: &str) -> CouchResult<Option<Checkpoint>> {
+        match self.db.table::<Checkpoint>().get(consumer).await {
+            Ok(record) => Ok(Some(record.value)),
+            Err(err) if err.status_code() == Some(404) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get the seq to resume from for a consumer.
+    ///
+    /// If the consumer has no checkpoint yet, `default` is returned.
+    pub async fn get_seq_or(
+        &self,
+        consumer: &str,
+        default: Option<String>,
+    ) -> CouchResult<Option<String>> {
+        let checkpoint = self.get(consumer).await?;
+        Ok(checkpoint.map(|c| c.seq).unwrap_or(default))
+    }
+
+    /// Save the checkpoint for a consumer.
+    pub async fn set(&self, consumer: &str, seq: Option<String>) -> CouchResult<()> {
+        let checkpoint = Checkpoint {
+            seq,
+            updated: Utc::now(),
+        };
+        let record = Record::from_id_and_value(consumer, checkpoint);
+        self.db.table::<Checkpoint>().put(record).await?;
+        Ok(())
+    }
+
+    /// Reset the checkpoint for a consumer to a seq.
+    ///
+    /// If seq is None, the consumer will start from the beginning on its next start.
+    pub async fn reset(&self, consumer: &str, seq: Option<String>) -> CouchResult<()> {
+        log::info!("Reset checkpoint for {} to seq {:?}", consumer, seq);
+        self.set(consumer, seq).await
+    }
+
+    /// List all checkpoints.
+    pub async fn list(&self) -> CouchResult<Vec<Record<Checkpoint>>> {
+        self.db.table::<Checkpoint>().get_all().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::couch::{ChangesDispatcher, CouchManager};
+    use oas_common::types::Post;
+
+    #[tokio::test]
+    async fn get_and_set() {
+        let store = CheckpointStore::new(CouchDB::in_memory("meta"));
+        assert!(store.get("indexer").await.unwrap().is_none());
+        let seq = store.get_seq_or("indexer", Some("1-a".into())).await;
+        assert_eq!(seq.unwrap(), Some("1-a".to_string()));
+
+        store.set("indexer", Some("3-c".into())).await.unwrap();
+        store.set("indexer", Some("5-e".into())).await.unwrap();
+        let checkpoint = store.get("indexer").await.unwrap().unwrap();
+        assert_eq!(checkpoint.seq, Some("5-e".to_string()));
+        let seq = store.get_seq_or("indexer", Some("1-a".into())).await;
+        assert_eq!(seq.unwrap(), Some("5-e".to_string()));
+
+        store.reset("indexer", None).await.unwrap();
+        let seq = store.get_seq_or("indexer", Some("1-a".into())).await;
+        assert_eq!(seq.unwrap(), None);
+        assert_eq!(store.list().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn resume_from_checkpoint() {
+        let db_manager = CouchManager::in_memory();
+        let db = db_manager.record_db().clone();
+        let post = Record::from_id_and_value("first", Post::default());
+        db.put_record(post).await.unwrap();
+
+        let mut dispatcher = ChangesDispatcher::new(db.clone(), false);
+        let mut changes = dispatcher
+            .register_with_checkpoint("test", &db_manager, None)
+            .await
+            .unwrap();
+        let consume = async {
+            let mut ids = vec![];
+            while let Some(batch) = changes.next().await {
+                ids.extend(batch.records().iter().map(|r| r.id().to_string()));
+                changes.commit(&batch).await.unwrap();
+            }
+            ids
+        };
+        let (res, ids) = futures::join!(dispatcher.run(), consume);
+        res.unwrap();
+        assert_eq!(ids, vec!["first".to_string()]);
+
+        let post = Record::from_id_and_value("second", Post::default());
+        db.put_record(post).await.unwrap();
+        let mut dispatcher = ChangesDispatcher::new(db, false);
+        let mut changes = dispatcher
+            .register_with_checkpoint("test", &db_manager, None)
+            .await
+            .unwrap();
+        let consume = async {
+            let mut ids = vec![];
+            while let Some(batch) = changes.next().await {
+                ids.extend(batch.records().iter().map(|r| r.id().to_string()));
+            }
+            ids
+        };
+        let (res, ids) = futures::join!(dispatcher.run(), consume);
+        res.unwrap();
+        assert_eq!(ids, vec!["second".to_string()]);
+    }
+}
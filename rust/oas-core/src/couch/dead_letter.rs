@@ -0,0 +1,135 @@
+//! Dead letters for changes consumers.
+//!
+//! Records that fail processing in a changes consumer (e.g. because they cannot be decoded or a
+//! task cannot be sent) are saved as [DeadLetter]s in the meta database, together with the error,
+//! the consumer name and the seq of the change. Once the cause is fixed, dead letters can be
+//! replayed (see [crate::replay]).
+
+use chrono::{DateTime, Utc};
+use oas_common::{Record, TypedValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{CouchDB, CouchResult};
+
+/// A record that failed processing in a changes consumer.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    /// Name of the consumer that failed to process the record.
+    pub consumer: String,
+    /// The guid of the failed record.
+    pub record_id: String,
+    /// The seq of the change that contained the record.
+    pub seq: Option<String>,
+    /// The error message of the latest failure.
+    pub error: String,
+    /// The raw doc, if the record could not be decoded.
+    pub doc: Option<serde_json::Value>,
+    /// Number of times processing this record failed.
+    pub failures: u32,
+    /// Time of the first failure.
+    pub created: DateTime<Utc>,
+    /// Time of the latest failure.
+    pub updated: DateTime<Utc>,
+}
+
+impl TypedValue for DeadLetter {
+    const NAME: &'static str = "oas.DeadLetter";
+}
+
+impl DeadLetter {
+    /// Get the id of the dead letter for a consumer and record.
+    pub fn id_for(consumer: &str, record_id: &str) -> String {
+        format!("{}.{}", consumer, record_id)
+    }
+}
+
+/// Store for the dead letters of all changes consumers.
+///
+/// There is at most one dead letter per consumer and record. Repeated failures update the
+/// existing dead letter.
+#[derive(Debug, Clone)]
+pub struct DeadLetterStore {
+    db: CouchDB,
+}
+
+impl DeadLetterStore {
+    /// Create a new dead letter store on a database.
+    pub fn new(db: CouchDB) -> Self {
+        Self { db }
+    }
+
+    /// Save a failed record.
+    pub async fn add(
+        &self,
+        consumer: &str,
+        record_id: &str,
+        seq: Option<String>,
+        error: String,
+        doc: Option<serde_json::Value>,
+    ) -> CouchResult<()> {
+        log::warn!(
+            "Failed to process record {} in {} (seq {:?}): {}",
+            record_id,
+            consumer,
+            seq,
+            error
+        );
+        let id = DeadLetter::id_for(consumer, record_id);
+        let now = Utc::now();
+        let letter = match self.get(&id).await? {
+            Some(mut record) => {
+                record.value.seq = seq;
+                record.value.error = error;
+                record.value.doc = doc;
+                record.value.failures += 1;
+                record.value.updated = now;
+                record.value
+            }
+            None => DeadLetter {
+                consumer: consumer.to_string(),
+                record_id: record_id.to_string(),
+                seq,
+                error,
+                doc,
+                failures: 1,
+                created: now,
+                updated: now,
+            },
+        };
+        let record = Record::from_id_and_value(id, letter);
+        self.db.table::<DeadLetter>().put(record).await?;
+        Ok(())
+    }
+
+    /// Get a dead letter by its id.
+    ///
+    /// Returns None if the dead letter does not exist.
+    pub async fn get(&self, id: &str) -> CouchResult<Option<Record<DeadLetter>>> {
+        match self.db.table::<DeadLetter>().get(id).await {
+            Ok(record) => Ok(Some(record)),
+            Err(err) if err.status_code() == Some(404) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// List all dead letters, optionally only those of a single consumer.
+    pub async fn list(&self, consumer: Option<&str>) -> CouchResult<Vec<Record<DeadLetter>>> {
+        let records = self.db.table::<DeadLetter>().get_all().await?;
+        let records = match consumer {
+            Some(consumer) => records
+                .into_iter()
+                .filter(|record| record.value.consumer == consumer)
+                .collect(),
+            None => records,
+        };
+        Ok(records)
+    }
+
+    /// Remove a dead letter, e.g. after it was replayed successfully.
+    pub async fn remove(&self, id: &str) -> CouchResult<()> {
+        self.db.table::<DeadLetter>().delete(id).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,515 @@
+//! Changes dispatcher
+//!
+//! The dispatcher reads the CouchDB changes stream once, decodes the changed docs into batches of
+//! [UntypedRecord]s and fans these batches out to all registered consumers (e.g. the indexer, the
+//! task processor and the feed watcher).
+//!
+//! Each consumer receives the batches through its own bounded channel, so batches do not pile up
+//! in memory. The dispatcher never waits for a single consumer: If a consumer's channel is full,
+//! the consumer is detached from the shared stream and continues on its own changes reader from
+//! the last batch it received. A slow consumer (e.g. the indexer) thus only slows down itself.
+//! Once a detached consumer caught up with the shared stream, it rejoins it and its own changes
+//! reader is closed.
+//! Consumers keep track of their own checkpoint seq, usually persisted in a [CheckpointStore].
+//! Records that a consumer fails to process are saved to a [DeadLetterStore].
+
+use futures::stream::StreamExt;
+use oas_common::UntypedRecord;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, error::SendError, error::TrySendError};
+use tokio::task::JoinHandle;
+
+use super::changes::UntypedRecordBatch;
+use super::{CheckpointStore, CouchDB, CouchManager, CouchResult, DeadLetterStore};
+
+/// Number of batches that may be queued for each consumer before it is detached from the shared
+/// stream.
+pub const CONSUMER_CHANNEL_CAPACITY: usize = 4;
+
+/// A batch of changes that is shared between all consumers.
+pub type SharedBatch = Arc<UntypedRecordBatch>;
+
+/// The changes dispatcher.
+pub struct ChangesDispatcher {
+    db: CouchDB,
+    infinite: bool,
+    consumers: Vec<ConsumerHandle>,
+}
+
+struct ConsumerHandle {
+    name: String,
+    last_seq: Option<String>,
+    sender: mpsc::Sender<SharedBatch>,
+}
+
+impl ConsumerHandle {
+    /// Check if the consumer already processed all changes of a batch.
+    fn has_seen(&self, batch: &UntypedRecordBatch) -> bool {
+        let consumer_seq = self.last_seq.as_deref().and_then(seq_number);
+        let batch_seq = batch.last_seq().and_then(seq_number);
+        match (consumer_seq, batch_seq) {
+            (Some(consumer_seq), Some(batch_seq)) => batch_seq <= consumer_seq,
+            _ => false,
+        }
+    }
+
+    /// Check if the consumer received all changes up to a seq number.
+    fn has_caught_up(&self, seq_number_dispatched: u64) -> bool {
+        self.last_seq
+            .as_deref()
+            .and_then(seq_number)
+            .map_or(false, |seq| seq >= seq_number_dispatched)
+    }
+
+    /// Read the changes after the consumer's last seq with an own changes stream.
+    ///
+    /// This is used for consumers that cannot keep up with the shared stream. Waiting for the
+    /// consumer here only slows down this stream. Once the consumer caught up with the shared
+    /// stream, it is handed back to the dispatcher.
+    async fn run_detached(mut self, mut detached: Detached) {
+        let mut changes = detached.db.changes(self.last_seq.clone());
+        changes.set_infinite(detached.infinite);
+        let mut batches = changes.batched_untyped_records();
+        while let Some(batch) = batches.next().await {
+            if batch.records().is_empty() && batch.last_seq().is_none() {
+                continue;
+            }
+            let seq = batch.last_seq().map(str::to_string);
+            if self.sender.send(Arc::new(batch)).await.is_err() {
+                log::warn!("changes consumer {} stopped", self.name);
+                break;
+            }
+            if seq.is_some() {
+                self.last_seq = seq;
+            }
+            if !self.has_caught_up(detached.dispatched.load(Ordering::SeqCst)) {
+                continue;
+            }
+            if let Some(rejoin) = detached.rejoin.take() {
+                match rejoin.send(self) {
+                    Ok(()) => {
+                        detached.running.fetch_sub(1, Ordering::SeqCst);
+                        return;
+                    }
+                    // The dispatcher finished, so continue on the own stream.
+                    Err(SendError(consumer)) => self = consumer,
+                }
+            }
+        }
+        detached.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// State that the dispatcher shares with its detached consumers.
+#[derive(Clone)]
+struct Detached {
+    db: CouchDB,
+    infinite: bool,
+    /// Seq number of the latest batch of the shared stream.
+    dispatched: Arc<AtomicU64>,
+    /// Number of detached consumers that did not rejoin or stop yet.
+    running: Arc<AtomicUsize>,
+    /// Channel to hand consumers that caught up back to the dispatcher.
+    rejoin: Option<mpsc::UnboundedSender<ConsumerHandle>>,
+}
+
+impl Detached {
+    /// Continue a consumer on its own changes stream.
+    fn spawn(&self, consumer: ConsumerHandle) -> JoinHandle<()> {
+        self.running.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(consumer.run_detached(self.clone()))
+    }
+}
+
+/// The receiving end for a consumer of the [ChangesDispatcher].
+pub struct ChangesReceiver {
+    name: String,
+    receiver: mpsc::Receiver<SharedBatch>,
+    checkpoints: Option<CheckpointStore>,
+    dead_letters: Option<DeadLetterStore>,
+    last_seq: Option<String>,
+}
+
+impl ChangesReceiver {
+    /// Receive the next batch of changes.
+    ///
+    /// Returns None once the dispatcher finished.
+    pub async fn next(&mut self) -> Option<SharedBatch> {
+        self.receiver.recv().await
+    }
+
+    /// The name of the consumer.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Save a record that failed processing as a dead letter.
+    ///
+    /// If the consumer has no [DeadLetterStore], the failure is only logged.
+    pub async fn reject(
+        &self,
+        batch: &UntypedRecordBatch,
+        record_id: &str,
+        error: impl Display,
+    ) -> CouchResult<()> {
+        let seq = batch.last_seq().map(|seq| seq.to_string());
+        match &self.dead_letters {
+            Some(dead_letters) => {
+                dead_letters
+                    .add(&self.name, record_id, seq, format!("{:#}", error), None)
+                    .await
+            }
+            None => {
+                log::error!(
+                    "Failed to process record {} in {}: {:#}",
+                    record_id,
+                    self.name,
+                    error
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Mark a batch as processed.
+    ///
+    /// Changed docs in the batch that could not be decoded are saved as dead letters. If the
+    /// consumer was registered with a [CheckpointStore], this then saves the batch's seq as the
+    /// new checkpoint.
+    pub async fn commit(&mut self, batch: &UntypedRecordBatch) -> CouchResult<()> {
+        let seq = match batch.last_seq() {
+            Some(seq) => seq,
+            None => return Ok(()),
+        };
+        if self.last_seq.as_deref() == Some(seq) {
+            return Ok(());
+        }
+        if let Some(dead_letters) = &self.dead_letters {
+            for failed in batch.failed() {
+                dead_letters
+                    .add(
+                        &self.name,
+                        &failed.id,
+                        Some(failed.seq.clone()),
+                        failed.error.clone(),
+                        failed.doc.clone(),
+                    )
+                    .await?;
+            }
+        }
+        if let Some(checkpoints) = &self.checkpoints {
+            log::trace!("save checkpoint for {} at seq {}", self.name, seq);
+            checkpoints.set(&self.name, Some(seq.to_string())).await?;
+        }
+        self.last_seq = Some(seq.to_string());
+        Ok(())
+    }
+}
+
+impl ChangesDispatcher {
+    /// Create a new dispatcher.
+    ///
+    /// If infinite is true, the dispatcher will wait for new changes forever. Otherwise, it stops
+    /// after all changes until now were dispatched.
+    pub fn new(db: CouchDB, infinite: bool) -> Self {
+        Self {
+            db,
+            infinite,
+            consumers: vec![],
+        }
+    }
+
+    /// Register a new consumer.
+    ///
+    /// The consumer will receive all changes after `last_seq`, or all changes if `last_seq` is
+    /// None.
+    pub fn register(&mut self, name: impl ToString, last_seq: Option<String>) -> ChangesReceiver {
+        let name = name.to_string();
+        let (sender, receiver) = mpsc::channel(CONSUMER_CHANNEL_CAPACITY);
+        self.consumers.push(ConsumerHandle {
+            name: name.clone(),
+            last_seq: last_seq.clone(),
+            sender,
+        });
+        ChangesReceiver {
+            name,
+            receiver,
+            checkpoints: None,
+            dead_letters: None,
+            last_seq,
+        }
+    }
+
+    /// Register a new consumer that resumes from its checkpoint.
+    ///
+    /// The checkpoint and the dead letters of the consumer are stored in the meta database of the
+    /// [CouchManager]. If the consumer has no checkpoint yet, it starts at `default_seq`.
+    /// Processed batches are saved as the consumer's checkpoint with [ChangesReceiver::commit].
+    pub async fn register_with_checkpoint(
+        &mut self,
+        name: impl ToString,
+        db_manager: &CouchManager,
+        default_seq: Option<String>,
+    ) -> CouchResult<ChangesReceiver> {
+        let name = name.to_string();
+        let checkpoints = db_manager.checkpoints();
+        let last_seq = checkpoints.get_seq_or(&name, default_seq).await?;
+        let mut receiver = self.register(name, last_seq);
+        receiver.checkpoints = Some(checkpoints);
+        receiver.dead_letters = Some(db_manager.dead_letters());
+        Ok(receiver)
+    }
+
+    /// Get the seq to start the changes stream at.
+    ///
+    /// This is the earliest seq of all consumers.
+    fn start_seq(&self) -> Option<String> {
+        let mut start: Option<(u64, String)> = None;
+        for consumer in self.consumers.iter() {
+            let seq = match &consumer.last_seq {
+                Some(seq) => seq,
+                None => return None,
+            };
+            let number = match seq_number(seq) {
+                Some(number) => number,
+                None => return None,
+            };
+            match &start {
+                Some((start_number, _)) if *start_number <= number => {}
+                _ => start = Some((number, seq.clone())),
+            }
+        }
+        start.map(|(_, seq)| seq)
+    }
+
+    /// Run the dispatcher.
+    ///
+    /// This reads the changes stream and dispatches the batches to all consumers. Runs until all
+    /// consumers are dropped or, if not running in infinite mode, until all current changes are
+    /// dispatched.
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        if self.consumers.is_empty() {
+            return Ok(());
+        }
+        let start_seq = self.start_seq();
+        log::debug!(
+            "start changes dispatcher at seq {:?} for consumers: {}",
+            start_seq,
+            self.consumer_names().join(", ")
+        );
+        let mut changes = self.db.changes(start_seq);
+        changes.set_infinite(self.infinite);
+        let mut batches = changes.batched_untyped_records();
+
+        let (rejoin_sender, mut rejoin) = mpsc::unbounded_channel();
+        let mut detached = Detached {
+            db: self.db.clone(),
+            infinite: self.infinite,
+            dispatched: Default::default(),
+            running: Default::default(),
+            rejoin: Some(rejoin_sender),
+        };
+        let mut tasks = vec![];
+        while let Some(batch) = batches.next().await {
+            if batch.records().is_empty() && batch.last_seq().is_none() {
+                continue;
+            }
+            self.rejoin_consumers(&mut rejoin, &detached, &mut tasks);
+            let batch = Arc::new(batch);
+            let mut consumers = Vec::with_capacity(self.consumers.len());
+            for mut consumer in self.consumers.drain(..) {
+                if consumer.has_seen(&batch) {
+                    consumers.push(consumer);
+                    continue;
+                }
+                match consumer.sender.try_send(batch.clone()) {
+                    Ok(()) => {
+                        if let Some(seq) = batch.last_seq() {
+                            consumer.last_seq = Some(seq.to_string());
+                        }
+                        consumers.push(consumer);
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        log::debug!(
+                            "changes consumer {} lags behind at seq {:?}, continue on own stream",
+                            consumer.name,
+                            consumer.last_seq
+                        );
+                        tasks.push(detached.spawn(consumer));
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        log::warn!("changes consumer {} stopped", consumer.name);
+                    }
+                }
+            }
+            self.consumers = consumers;
+            if let Some(number) = batch.last_seq().and_then(seq_number) {
+                detached.dispatched.store(number, Ordering::SeqCst);
+            }
+            // Detached consumers hand themselves back before they stop running, so all
+            // consumers are seen here.
+            let running = detached.running.load(Ordering::SeqCst);
+            self.rejoin_consumers(&mut rejoin, &detached, &mut tasks);
+            if self.consumers.is_empty() && running == 0 {
+                break;
+            }
+        }
+        // Consumers that did not rejoin yet continue on their own streams until they received
+        // all changes.
+        rejoin.close();
+        detached.rejoin = None;
+        self.rejoin_consumers(&mut rejoin, &detached, &mut tasks);
+        // Drop the senders of the remaining consumers so that they finish once they received
+        // all batches.
+        self.consumers.clear();
+        for task in tasks {
+            task.await?;
+        }
+        Ok(())
+    }
+
+    /// Add the detached consumers that caught up back to the shared stream.
+    ///
+    /// Consumers that fell behind again while they were handed back are detached again.
+    fn rejoin_consumers(
+        &mut self,
+        rejoin: &mut mpsc::UnboundedReceiver<ConsumerHandle>,
+        detached: &Detached,
+        tasks: &mut Vec<JoinHandle<()>>,
+    ) {
+        while let Ok(consumer) = rejoin.try_recv() {
+            if consumer.has_caught_up(detached.dispatched.load(Ordering::SeqCst)) {
+                log::debug!(
+                    "changes consumer {} caught up at seq {:?}, rejoin shared stream",
+                    consumer.name,
+                    consumer.last_seq
+                );
+                self.consumers.push(consumer);
+            } else {
+                tasks.push(detached.spawn(consumer));
+            }
+        }
+    }
+
+    fn consumer_names(&self) -> Vec<&str> {
+        self.consumers.iter().map(|c| c.name.as_str()).collect()
+    }
+}
+
+impl UntypedRecordBatch {
+    /// Iterate over the records of a type in this batch.
+    pub fn records_of_type<'a>(
+        &'a self,
+        typ: &'a str,
+    ) -> impl Iterator<Item = &'a UntypedRecord> + 'a {
+        self.records().iter().filter(move |r| r.typ() == typ)
+    }
+}
+
+/// Get the numeric part of a CouchDB seq.
+///
+/// CouchDB seqs are opaque strings in the form `{number}-{hash}`. While the full seq string is
+/// needed to resume a changes stream, the number can be used to compare seqs.
+pub fn seq_number(seq: &str) -> Option<u64> {
+    seq.split('-').next().and_then(|n| n.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::couch::changes::BATCH_MAX_LEN;
+    use oas_common::types::Post;
+    use oas_common::Record;
+    use std::time::Duration;
+
+    async fn put_posts(db: &CouchDB, count: usize) {
+        let records: Vec<_> = (0..count)
+            .map(|i| Record::from_id_and_value(format!("post{}", i), Post::default()))
+            .collect();
+        db.put_record_bulk(records)
+            .await
+            .expect("failed to put records");
+    }
+
+    async fn count_records(receiver: &mut ChangesReceiver) -> usize {
+        let mut count = 0;
+        while let Some(batch) = receiver.next().await {
+            count += batch.records().len();
+        }
+        count
+    }
+
+    #[tokio::test]
+    async fn dispatch_to_all_consumers() {
+        let db = CouchDB::in_memory("test");
+        put_posts(&db, 10).await;
+        let mut dispatcher = ChangesDispatcher::new(db, false);
+        let mut first = dispatcher.register("first", None);
+        let mut second = dispatcher.register("second", None);
+        let (res, first_count, second_count) = futures::join!(
+            dispatcher.run(),
+            count_records(&mut first),
+            count_records(&mut second)
+        );
+        res.expect("dispatcher failed");
+        assert_eq!(first_count, 10);
+        assert_eq!(second_count, 10);
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_does_not_block_others() {
+        let db = CouchDB::in_memory("test");
+        let total = (CONSUMER_CHANNEL_CAPACITY + 2) * BATCH_MAX_LEN;
+        put_posts(&db, total).await;
+        let mut dispatcher = ChangesDispatcher::new(db, false);
+        let mut fast = dispatcher.register("fast", None);
+        let mut slow = dispatcher.register("slow", None);
+        let dispatch = tokio::spawn(dispatcher.run());
+
+        // The slow consumer does not read at all until the fast consumer received everything.
+        let fast_count = tokio::time::timeout(Duration::from_secs(10), count_records(&mut fast))
+            .await
+            .expect("fast consumer was blocked by slow consumer");
+        assert_eq!(fast_count, total);
+
+        let slow_count = count_records(&mut slow).await;
+        assert_eq!(slow_count, total);
+        dispatch.await.unwrap().expect("dispatcher failed");
+    }
+
+    #[tokio::test]
+    async fn lagging_consumer_receives_later_changes() {
+        async fn read_records(receiver: &mut ChangesReceiver, count: usize) -> Vec<String> {
+            let mut ids = vec![];
+            while ids.len() < count {
+                let batch = receiver.next().await.expect("changes stopped");
+                ids.extend(batch.records().iter().map(|r| r.id().to_string()));
+            }
+            ids
+        }
+
+        let db = CouchDB::in_memory("test");
+        let total = (CONSUMER_CHANNEL_CAPACITY + 2) * BATCH_MAX_LEN;
+        put_posts(&db, total).await;
+        let mut dispatcher = ChangesDispatcher::new(db.clone(), true);
+        let mut fast = dispatcher.register("fast", None);
+        let mut slow = dispatcher.register("slow", None);
+        let dispatch = tokio::spawn(dispatcher.run());
+
+        let timeout = Duration::from_secs(10);
+        let fast_ids = tokio::time::timeout(timeout, read_records(&mut fast, total)).await;
+        assert_eq!(fast_ids.unwrap().len(), total);
+        // The slow consumer lagged behind, catches up and then gets the later changes as well.
+        let slow_ids = tokio::time::timeout(timeout, read_records(&mut slow, total)).await;
+        assert_eq!(slow_ids.unwrap().len(), total);
+        let record = Record::from_id_and_value("later", Post::default());
+        db.put_record(record).await.unwrap();
+        for receiver in [&mut fast, &mut slow].iter_mut() {
+            let ids = tokio::time::timeout(timeout, read_records(receiver, 1)).await;
+            assert_eq!(ids.unwrap(), vec!["later".to_string()]);
+        }
+        dispatch.abort();
+    }
+}
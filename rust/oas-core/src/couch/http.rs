@@ -0,0 +1,306 @@
+use anyhow::Context;
+use futures::stream::{BoxStream, StreamExt};
+use reqwest::{Method, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use url::Url;
+
+use super::changes::HttpChangesStream;
+use super::store::{RecordStore, View, ViewQuery};
+use super::types::{BulkGetItem, BulkGetResponse, DocResult, GetChangesResult, ViewResponse};
+use super::{
+    ChangeEvent, Config, CouchError, CouchResult, Doc, DocList, DocMeta, ErrorDetails, Object,
+    PutResponse, PutResult, RevInfo,
+};
+
+/// A [RecordStore] backed by a CouchDB server.
+///
+/// The store is stateless. It only contains a HTTP client and the config on how to connect to a
+/// database.
+#[derive(Debug, Clone)]
+pub struct HttpStore {
+    config: Arc<Config>,
+    client: Arc<reqwest::Client>,
+}
+
+impl HttpStore {
+    pub fn new(config: Config, client: reqwest::Client) -> Self {
+        Self {
+            config: Arc::new(config),
+            client: Arc::new(client),
+        }
+    }
+
+    /// Get all docs while passing a map of params.
+    async fn get_all_with_params(&self, params: &impl Serialize) -> CouchResult<DocList> {
+        let req = self.request(Method::GET, "_all_docs").query(params);
+        let docs: Value = self.send(req).await?;
+        let docs: DocList = serde_json::from_value(docs)?;
+        Ok(docs)
+    }
+
+    pub(super) fn request(&self, method: Method, path: impl AsRef<str>) -> RequestBuilder {
+        let url = self.path_to_url(path.as_ref());
+        let builder = self.client.request(method, url);
+        let builder = if let Some(username) = &self.config.user {
+            builder.basic_auth(username, self.config.password.as_ref())
+        } else {
+            builder
+        };
+        builder
+    }
+
+    fn path_to_url(&self, path: impl AsRef<str>) -> Url {
+        format!(
+            "{}/{}/{}",
+            self.config.host,
+            self.config.database,
+            path.as_ref()
+        )
+        .parse()
+        .unwrap()
+    }
+
+    async fn send<T>(&self, request: RequestBuilder) -> CouchResult<T>
+    where
+        T: DeserializeOwned,
+    {
+        let res = request.send().await?;
+        // let mut res = self.client.execute(request).await?;
+        match res.status().is_success() {
+            true => Ok(res.json::<T>().await?),
+            false => Err(CouchError::Couch(
+                res.status(),
+                res.json::<ErrorDetails>().await?,
+            )),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RecordStore for HttpStore {
+    fn name(&self) -> &str {
+        &self.config.database
+    }
+
+    async fn init(&self) -> anyhow::Result<()> {
+        let res: CouchResult<Value> = self.send(self.request(Method::GET, "")).await;
+        match res {
+            Ok(_res) => {
+                log::trace!("check database {}: ok", self.config.database);
+                Ok(())
+            }
+            Err(_) => {
+                let req = self.request(Method::PUT, "");
+                let _res: serde_json::Value = self.send(req).await.with_context(|| {
+                    format!("Failed to create database {}", self.config.database)
+                })?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn destroy_and_init(&self) -> CouchResult<()> {
+        let req = self.request(Method::DELETE, "");
+        let _: CouchResult<()> = self.send(req).await;
+        let req = self.request(Method::PUT, "");
+        self.send(req).await
+    }
+
+    async fn get_doc(&self, id: &str) -> CouchResult<Doc> {
+        let req = self.request(Method::GET, id);
+        let doc: Doc = self.send(req).await?;
+        Ok(doc)
+    }
+
+    async fn get_doc_at_rev(&self, id: &str, rev: &str) -> CouchResult<Doc> {
+        let req = self.request(Method::GET, id).query(&[("rev", rev)]);
+        let doc: Doc = self.send(req).await?;
+        Ok(doc)
+    }
+
+    async fn revisions(&self, id: &str) -> CouchResult<Vec<RevInfo>> {
+        let req = self
+            .request(Method::GET, id)
+            .query(&[("revs_info", "true")]);
+        let mut doc: Value = self.send(req).await?;
+        let revs_info = doc
+            .get_mut("_revs_info")
+            .map(Value::take)
+            .unwrap_or_else(|| Value::Array(vec![]));
+        let revs_info: Vec<RevInfo> = serde_json::from_value(revs_info)?;
+        Ok(revs_info)
+    }
+
+    async fn put_doc(&self, doc: Doc) -> CouchResult<PutResponse> {
+        let req = self.request(Method::PUT, doc.id()).json(&doc);
+        self.send(req).await
+    }
+
+    async fn delete_doc(&self, id: &str, rev: &str) -> CouchResult<PutResponse> {
+        let req = self.request(Method::DELETE, id).query(&[("rev", rev)]);
+        self.send(req).await
+    }
+
+    async fn put_bulk(&self, docs: Vec<Doc>) -> CouchResult<Vec<PutResult>> {
+        let body = serde_json::json!({ "docs": docs });
+        let req = self.request(Method::POST, "_bulk_docs").json(&body);
+        self.send(req).await
+    }
+
+    async fn bulk_get(&self, ids: &[&str]) -> CouchResult<Vec<Option<Doc>>> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let req_json: Vec<serde_json::Value> = ids.iter().map(|id| json!({ "id": id })).collect();
+        let req_json = json!({ "docs": req_json });
+        let req = self.request(Method::POST, "_bulk_get").json(&req_json);
+        let bulk_get: BulkGetResponse = self.send(req).await?;
+        let mut docs = vec![];
+        for (req_idx, sent_id) in ids.iter().enumerate() {
+            let result = bulk_get.results.get(req_idx);
+            let doc = match result {
+                Some(BulkGetItem { id, docs }) if id == sent_id && docs.len() == 1 => {
+                    match docs.get(0).unwrap() {
+                        DocResult::Ok(doc) => Some(doc.clone()),
+                        DocResult::Err(_err) => None,
+                    }
+                }
+                _ => {
+                    return Err(CouchError::Other(
+                        "Response does not match request".to_string(),
+                    ));
+                }
+            };
+            docs.push(doc);
+        }
+        Ok(docs)
+    }
+
+    async fn get_many(&self, ids: &[&str]) -> CouchResult<DocList> {
+        if ids.is_empty() {
+            return Ok(DocList::default());
+        }
+        let mut params = HashMap::new();
+        params.insert("include_docs", serde_json::to_string(&true).unwrap());
+        // let keys: String = ids.join(",");
+        // params.insert("keys", serde_json::to_value(keys).unwrap());
+        params.insert("keys", serde_json::to_string(ids).unwrap());
+        self.get_all_with_params(&params).await
+    }
+
+    async fn all_docs(&self, prefix: Option<&str>) -> CouchResult<DocList> {
+        let mut params = HashMap::new();
+        params.insert("include_docs", "true".to_string());
+        if let Some(prefix) = prefix {
+            if prefix.contains('\"') {
+                return Err(CouchError::Other("Prefix may not contain quotes".into()));
+            }
+            params.insert("startkey", format!("\"{}\"", prefix));
+            params.insert("endkey", format!("\"{}{}\"", prefix, "\u{ffff}"));
+        }
+        self.get_all_with_params(&params).await
+    }
+
+    async fn all_docs_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> CouchResult<DocList> {
+        let mut params = HashMap::new();
+        params.insert("include_docs", "true".to_string());
+        params.insert("limit", limit.to_string());
+        let endkey = format!("{}{}", prefix, "\u{ffff}");
+        params.insert("endkey", serde_json::to_string(&endkey)?);
+        match start_after {
+            Some(start_after) => {
+                params.insert("startkey", serde_json::to_string(start_after)?);
+                params.insert("skip", "1".to_string());
+            }
+            None => {
+                params.insert("startkey", serde_json::to_string(prefix)?);
+            }
+        }
+        self.get_all_with_params(&params).await
+    }
+
+    async fn init_views(&self, views: &[View]) -> CouchResult<()> {
+        let mut designs: BTreeMap<&str, Object> = BTreeMap::new();
+        for view in views {
+            designs
+                .entry(view.typ)
+                .or_default()
+                .insert(view.name.to_string(), json!({ "map": view.map_function() }));
+        }
+        for (typ, views) in designs.into_iter() {
+            let id = format!("_design/{}", typ);
+            let views = Value::Object(views);
+            let rev = match self.get_doc(&id).await {
+                Ok(doc) if doc.doc.get("views") == Some(&views) => continue,
+                Ok(doc) => doc.rev().map(|rev| rev.to_string()),
+                Err(err) if err.status_code() == Some(404) => None,
+                Err(err) => return Err(err),
+            };
+            let mut design = Object::new();
+            design.insert("language".to_string(), json!("javascript"));
+            design.insert("views".to_string(), views);
+            log::debug!("[{}] update design doc {}", self.config.database, id);
+            self.put_doc(Doc::new(DocMeta::new(id, rev), design))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn query_view(&self, view: &View, query: &ViewQuery) -> CouchResult<Vec<Doc>> {
+        let mut params = vec![("include_docs", "true".to_string())];
+        // CouchDB expects the keys in the order in which the rows are returned.
+        let (start, end) = match query.descending {
+            true => (&query.end, &query.start),
+            false => (&query.start, &query.end),
+        };
+        if let Some(start) = start {
+            params.push(("startkey", serde_json::to_string(start)?));
+        }
+        if let Some(end) = end {
+            params.push(("endkey", serde_json::to_string(end)?));
+        }
+        if query.descending {
+            params.push(("descending", "true".to_string()));
+        }
+        if query.skip > 0 {
+            params.push(("skip", query.skip.to_string()));
+        }
+        if let Some(limit) = query.limit {
+            params.push(("limit", limit.to_string()));
+        }
+        let path = format!("_design/{}/_view/{}", view.typ, view.name);
+        let req = self.request(Method::GET, path).query(&params);
+        let res: ViewResponse = self.send(req).await?;
+        let docs = res.rows.into_iter().filter_map(|row| row.doc).collect();
+        Ok(docs)
+    }
+
+    async fn get_last_seq(&self) -> CouchResult<String> {
+        let mut params = HashMap::new();
+        params.insert("descending", "true".to_string());
+        params.insert("limit", "1".to_string());
+        let path = "_changes";
+        let req = self.request(Method::GET, path).query(&params);
+        let res: GetChangesResult = self.send(req).await?;
+        Ok(res.last_seq)
+    }
+
+    fn changes(
+        &self,
+        last_seq: Option<String>,
+        infinite: bool,
+    ) -> BoxStream<'static, CouchResult<ChangeEvent>> {
+        let mut stream = HttpChangesStream::new(self.clone(), last_seq);
+        stream.set_infinite(infinite);
+        stream.boxed()
+    }
+}
@@ -1,14 +1,25 @@
+use anyhow::Context;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use url::Url;
 
-use super::{Config, CouchDB};
+use super::{AuditLog, CheckpointStore, Config, CouchDB, DeadLetterStore, MemoryStore};
 
 pub const RECORD_DB_NAME: &str = "records";
 pub const META_DB_NAME: &str = "meta";
 pub const SEPERATOR: &str = "$";
 
+/// Scheme of URLs for in-memory databases.
+pub const MEMORY_URL_SCHEME: &str = "memory";
+/// Scheme of URLs for in-process databases that are persisted to a directory.
+pub const FILE_URL_SCHEME: &str = "file";
+
 #[derive(Debug, Clone)]
 pub struct CouchManager {
-    config: Arc<Config>,
+    /// The CouchDB server config, None for in-process databases.
+    config: Option<Arc<Config>>,
+    /// The directory of in-process databases that are persisted.
+    dir: Option<PathBuf>,
     client: reqwest::Client,
     record_db: CouchDB,
     meta_db: CouchDB,
@@ -19,16 +30,61 @@ pub fn db_name(prefix: &str, name: &str) -> String {
 }
 
 impl CouchManager {
+    /// Create a new manager from a URL.
+    ///
+    /// Besides CouchDB server URLs, `memory:` creates in-memory databases and
+    /// `file:///some/dir` creates in-process databases that are persisted to a directory.
     pub fn with_url<S>(url: Option<S>) -> anyhow::Result<Self>
     where
         S: AsRef<str>,
     {
         let url = url.map(|s| s.as_ref().to_string());
+        if let Some(url) = &url {
+            if url.starts_with(&format!("{}:", MEMORY_URL_SCHEME)) {
+                return Ok(Self::in_memory());
+            }
+            if url.starts_with(&format!("{}:", FILE_URL_SCHEME)) {
+                let path = Url::parse(url)
+                    .ok()
+                    .and_then(|url| url.to_file_path().ok())
+                    .ok_or_else(|| anyhow::anyhow!("Invalid file URL: {}", url))?;
+                return Self::with_dir(path);
+            }
+        }
         let config = Config::from_url_or_default(url.as_deref())?;
         let db = Self::with_config(config)?;
         Ok(db)
     }
 
+    /// Create a new manager with empty in-memory databases.
+    pub fn in_memory() -> Self {
+        Self {
+            config: None,
+            dir: None,
+            client: reqwest::Client::new(),
+            record_db: CouchDB::in_memory(RECORD_DB_NAME),
+            meta_db: CouchDB::in_memory(META_DB_NAME),
+        }
+    }
+
+    /// Create a new manager with in-process databases that are persisted to a directory.
+    pub fn with_dir(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let open = |name: &str| -> anyhow::Result<CouchDB> {
+            let file = path.join(format!("{}.jsonl", name));
+            let store = MemoryStore::open(name, &file)
+                .with_context(|| format!("Failed to open database {}", file.display()))?;
+            Ok(CouchDB::with_store(store))
+        };
+        Ok(Self {
+            config: None,
+            dir: Some(path.to_path_buf()),
+            client: reqwest::Client::new(),
+            record_db: open(RECORD_DB_NAME)?,
+            meta_db: open(META_DB_NAME)?,
+        })
+    }
+
     pub fn with_config(config: Config) -> anyhow::Result<Self> {
         let client = reqwest::Client::new();
         let mut record_config = config.clone();
@@ -38,32 +94,49 @@ impl CouchManager {
         let meta_db = CouchDB::with_config_and_client(meta_config, client.clone());
         let record_db = CouchDB::with_config_and_client(record_config, client.clone());
         Ok(Self {
-            config: Arc::new(config),
+            config: Some(Arc::new(config)),
+            dir: None,
             client,
             record_db,
             meta_db,
         })
     }
 
-    fn db(&self, name: &str, include_prefix: bool) -> CouchDB {
-        let mut config = (*self.config).clone();
-        config.database = match include_prefix {
-            true => db_name(&config.database, name),
-            false => name.to_string(),
-        };
+    /// Create a manager for the databases of a tenant.
+    ///
+    /// The names of the databases of a tenant have the tenant id after the prefix of this
+    /// manager, e.g. `oas$acme$records`. In-process databases of a tenant are persisted to a
+    /// subdirectory.
+    pub fn for_tenant(&self, tenant: &str) -> anyhow::Result<Self> {
+        if let Some(config) = &self.config {
+            let mut config = config.as_ref().clone();
+            config.database = db_name(&config.database, tenant);
+            return Self::with_config(config);
+        }
+        match &self.dir {
+            Some(dir) => Self::with_dir(dir.join(tenant)),
+            None => Ok(Self::in_memory()),
+        }
+    }
+
+    fn server_db(&self, config: &Config, name: &str) -> CouchDB {
+        let mut config = config.clone();
+        config.database = name.to_string();
         CouchDB::with_config_and_client(config, self.client.clone())
     }
 
     pub async fn init(&self) -> anyhow::Result<()> {
         // Init system databases if they do not exist yet.
-        let res = futures::future::join_all(vec![
-            self.db("_users", false).init(),
-            self.db("_replicator", false).init(),
-            self.db("_global_changes", false).init(),
-        ])
-        .await;
-        for err in res.into_iter().filter_map(|r| r.err()) {
-            log::warn!("Failed to ensure system CouchDB: {}", err);
+        if let Some(config) = &self.config {
+            let res = futures::future::join_all(vec![
+                self.server_db(config, "_users").init(),
+                self.server_db(config, "_replicator").init(),
+                self.server_db(config, "_global_changes").init(),
+            ])
+            .await;
+            for err in res.into_iter().filter_map(|r| r.err()) {
+                log::warn!("Failed to ensure system CouchDB: {}", err);
+            }
         }
 
         let res =
@@ -71,6 +144,7 @@ impl CouchManager {
         for res in res {
             res?
         }
+        self.audit_log().init().await?;
         Ok(())
     }
 
@@ -93,4 +167,19 @@ impl CouchManager {
     pub fn meta_db(&self) -> &CouchDB {
         &self.meta_db
     }
+
+    /// Get the checkpoint store for changes consumers.
+    pub fn checkpoints(&self) -> CheckpointStore {
+        CheckpointStore::new(self.meta_db.clone())
+    }
+
+    /// Get the dead letter store for changes consumers.
+    pub fn dead_letters(&self) -> DeadLetterStore {
+        DeadLetterStore::new(self.meta_db.clone())
+    }
+
+    /// Get the audit log of record modifications.
+    pub fn audit_log(&self) -> AuditLog {
+        AuditLog::new(self.meta_db.clone())
+    }
 }
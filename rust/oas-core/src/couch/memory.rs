@@ -0,0 +1,729 @@
+//! In-process record store.
+//!
+//! The [MemoryStore] keeps all docs in memory and implements the CouchDB semantics that OAS
+//! relies on (revisions with conflict detection, ordered `_all_docs` scans and a `_changes` feed
+//! with seqs). It can be used in tests and for single-node setups without a CouchDB server.
+//!
+//! If opened with a path, all writes are appended to a log file (one JSON object per line) that
+//! is replayed and compacted when the store is opened again.
+//!
+//! Like CouchDB, the store keeps the bodies of previous revisions until compaction. They are
+//! kept in memory only, and at most [MAX_REVISIONS] per doc.
+
+use futures::stream::{self, BoxStream, StreamExt};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+use super::dispatcher::seq_number;
+use super::store::{RecordStore, View, ViewQuery};
+use super::{
+    Change, ChangeEvent, CouchError, CouchResult, Doc, DocList, DocListEntry, DocMeta,
+    ErrorDetails, PutResponse, PutResult, RevInfo, RevStatus,
+};
+
+/// Max number of previous revisions that are kept per doc.
+const MAX_REVISIONS: usize = 20;
+
+/// A [RecordStore] that keeps all docs in process.
+#[derive(Debug, Clone)]
+pub struct MemoryStore {
+    name: String,
+    inner: Arc<Mutex<MemoryInner>>,
+    seq_sender: Arc<watch::Sender<u64>>,
+    seq_receiver: watch::Receiver<u64>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryInner {
+    seq: u64,
+    docs: BTreeMap<String, StoredDoc>,
+    by_seq: BTreeMap<u64, String>,
+    /// Previous revisions of each doc, oldest first.
+    revisions: HashMap<String, VecDeque<StoredDoc>>,
+    log: Option<LogFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredDoc {
+    seq: u64,
+    #[serde(default)]
+    deleted: bool,
+    doc: Doc,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl MemoryStore {
+    /// Create a new, empty in-memory store.
+    pub fn new(name: impl ToString) -> Self {
+        let (seq_sender, seq_receiver) = watch::channel(0);
+        Self {
+            name: name.to_string(),
+            inner: Arc::new(Mutex::new(MemoryInner::default())),
+            seq_sender: Arc::new(seq_sender),
+            seq_receiver,
+        }
+    }
+
+    /// Open a store that is persisted to a log file.
+    ///
+    /// If the file exists, its docs are loaded and the file is compacted.
+    pub fn open(name: impl ToString, path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut inner = MemoryInner::default();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.lines() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                let stored: StoredDoc = serde_json::from_str(&line)?;
+                inner.insert(stored);
+            }
+        } else if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        inner.log = Some(LogFile::compact(path, &inner)?);
+        let (seq_sender, seq_receiver) = watch::channel(inner.seq);
+        Ok(Self {
+            name: name.to_string(),
+            inner: Arc::new(Mutex::new(inner)),
+            seq_sender: Arc::new(seq_sender),
+            seq_receiver,
+        })
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&mut MemoryInner) -> CouchResult<T>) -> CouchResult<T> {
+        let mut inner = self.inner.lock().unwrap();
+        let res = f(&mut inner);
+        let _ = self.seq_sender.send(inner.seq);
+        res
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&MemoryInner) -> T) -> T {
+        let inner = self.inner.lock().unwrap();
+        f(&inner)
+    }
+
+    /// Get the changes after a seq, at most `limit`.
+    fn changes_since(&self, since: u64, limit: usize) -> Vec<ChangeEvent> {
+        self.read(|inner| {
+            inner
+                .by_seq
+                .range(since + 1..)
+                .take(limit)
+                .filter_map(|(_seq, id)| inner.docs.get(id))
+                .map(|stored| stored.to_change_event())
+                .collect()
+        })
+    }
+}
+
+impl MemoryInner {
+    fn insert(&mut self, stored: StoredDoc) {
+        if stored.seq > self.seq {
+            self.seq = stored.seq;
+        }
+        let id = stored.doc.id().to_string();
+        self.by_seq.insert(stored.seq, id.clone());
+        if let Some(previous) = self.docs.insert(id.clone(), stored) {
+            self.by_seq.remove(&previous.seq);
+            let revisions = self.revisions.entry(id).or_default();
+            revisions.push_back(previous);
+            if revisions.len() > MAX_REVISIONS {
+                revisions.pop_front();
+            }
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<&StoredDoc> {
+        self.docs.get(id).filter(|stored| !stored.deleted)
+    }
+
+    /// Get all stored revisions of a doc, newest first.
+    fn revisions(&self, id: &str) -> impl Iterator<Item = &StoredDoc> {
+        self.docs.get(id).into_iter().chain(
+            self.revisions
+                .get(id)
+                .into_iter()
+                .flat_map(|revs| revs.iter().rev()),
+        )
+    }
+
+    fn write(&mut self, mut doc: Doc, deleted: bool) -> CouchResult<PutResponse> {
+        let id = doc.id().to_string();
+        let current = self.docs.get(&id);
+        let current_rev = current
+            .filter(|stored| !stored.deleted)
+            .and_then(|stored| stored.doc.rev());
+        if current_rev != doc.rev() {
+            return Err(conflict(&id));
+        }
+        let generation = current
+            .and_then(|stored| stored.doc.rev())
+            .and_then(seq_number)
+            .unwrap_or(0);
+        let rev = format!("{}-{}", generation + 1, uuid::Uuid::new_v4().to_simple());
+        doc.set_rev(Some(rev.clone()));
+        if deleted {
+            doc.doc.clear();
+        }
+        self.seq += 1;
+        let stored = StoredDoc {
+            seq: self.seq,
+            deleted,
+            doc,
+        };
+        if let Some(log) = self.log.as_mut() {
+            log.append(&stored)?;
+        }
+        self.insert(stored);
+        Ok(PutResponse { id, ok: true, rev })
+    }
+}
+
+impl StoredDoc {
+    fn to_change_event(&self) -> ChangeEvent {
+        ChangeEvent {
+            seq: format_seq(self.seq),
+            id: self.doc.id().to_string(),
+            changes: vec![Change {
+                rev: self.doc.rev().unwrap_or_default().to_string(),
+            }],
+            deleted: self.deleted,
+            doc: Some(self.doc.clone()),
+        }
+    }
+}
+
+impl LogFile {
+    /// Rewrite the log file with the current state of all docs.
+    fn compact(path: PathBuf, inner: &MemoryInner) -> io::Result<Self> {
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            for id in inner.by_seq.values() {
+                let stored = inner.docs.get(id).unwrap();
+                serde_json::to_writer(&mut tmp, stored)?;
+                tmp.write_all(b"\n")?;
+            }
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Self { path, file })
+    }
+
+    fn append(&mut self, stored: &StoredDoc) -> CouchResult<()> {
+        let mut line = serde_json::to_vec(stored)?;
+        line.push(b'\n');
+        self.file.write_all(&line).map_err(|err| {
+            log::error!("Failed to write to {}: {}", self.path.display(), err);
+            err
+        })?;
+        Ok(())
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        self.file = File::create(&self.path)?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl RecordStore for MemoryStore {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn init(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn destroy_and_init(&self) -> CouchResult<()> {
+        self.write(|inner| {
+            inner.docs.clear();
+            inner.by_seq.clear();
+            inner.revisions.clear();
+            inner.seq = 0;
+            if let Some(log) = inner.log.as_mut() {
+                log.truncate()?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn get_doc(&self, id: &str) -> CouchResult<Doc> {
+        self.read(|inner| inner.get(id).map(|stored| stored.doc.clone()))
+            .ok_or_else(|| not_found(id))
+    }
+
+    async fn get_doc_at_rev(&self, id: &str, rev: &str) -> CouchResult<Doc> {
+        self.read(|inner| {
+            inner
+                .revisions(id)
+                .find(|stored| stored.doc.rev() == Some(rev) && !stored.deleted)
+                .map(|stored| stored.doc.clone())
+        })
+        .ok_or_else(|| not_found(id))
+    }
+
+    async fn revisions(&self, id: &str) -> CouchResult<Vec<RevInfo>> {
+        self.read(|inner| {
+            inner.get(id)?;
+            let revs = inner
+                .revisions(id)
+                .map(|stored| RevInfo {
+                    rev: stored.doc.rev().unwrap_or_default().to_string(),
+                    status: if stored.deleted {
+                        RevStatus::Deleted
+                    } else {
+                        RevStatus::Available
+                    },
+                })
+                .collect();
+            Some(revs)
+        })
+        .ok_or_else(|| not_found(id))
+    }
+
+    async fn put_doc(&self, doc: Doc) -> CouchResult<PutResponse> {
+        self.write(|inner| inner.write(doc, false))
+    }
+
+    async fn delete_doc(&self, id: &str, rev: &str) -> CouchResult<PutResponse> {
+        let meta = DocMeta::with_id_and_rev(id.to_string(), rev.to_string());
+        let doc = Doc::new(meta, Default::default());
+        self.write(|inner| match inner.get(id) {
+            Some(_) => inner.write(doc, true),
+            None => Err(not_found(id)),
+        })
+    }
+
+    async fn put_bulk(&self, docs: Vec<Doc>) -> CouchResult<Vec<PutResult>> {
+        self.write(|inner| {
+            let results = docs
+                .into_iter()
+                .map(|doc| match inner.write(doc, false) {
+                    Ok(res) => Ok(PutResult::Ok(res)),
+                    Err(CouchError::Couch(_status, details)) => Ok(PutResult::Err(details)),
+                    Err(err) => Err(err),
+                })
+                .collect::<CouchResult<Vec<_>>>()?;
+            Ok(results)
+        })
+    }
+
+    async fn bulk_get(&self, ids: &[&str]) -> CouchResult<Vec<Option<Doc>>> {
+        let docs = self.read(|inner| {
+            ids.iter()
+                .map(|id| inner.get(id).map(|stored| stored.doc.clone()))
+                .collect()
+        });
+        Ok(docs)
+    }
+
+    async fn get_many(&self, ids: &[&str]) -> CouchResult<DocList> {
+        let rows: Vec<_> = self.read(|inner| {
+            ids.iter()
+                .filter_map(|id| inner.get(id))
+                .map(|stored| doc_list_entry(&stored.doc))
+                .collect()
+        });
+        Ok(doc_list(rows))
+    }
+
+    async fn all_docs(&self, prefix: Option<&str>) -> CouchResult<DocList> {
+        let prefix = prefix.unwrap_or("");
+        let rows: Vec<_> = self.read(|inner| {
+            inner
+                .docs
+                .range(prefix.to_string()..)
+                .take_while(|(id, _)| id.starts_with(prefix))
+                .filter(|(_, stored)| !stored.deleted)
+                .map(|(_, stored)| doc_list_entry(&stored.doc))
+                .collect()
+        });
+        Ok(doc_list(rows))
+    }
+
+    async fn all_docs_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> CouchResult<DocList> {
+        let start = match start_after {
+            Some(id) => Bound::Excluded(id.to_string()),
+            None => Bound::Included(prefix.to_string()),
+        };
+        let rows: Vec<_> = self.read(|inner| {
+            inner
+                .docs
+                .range((start, Bound::Unbounded))
+                .take_while(|(id, _)| id.starts_with(prefix))
+                .filter(|(_, stored)| !stored.deleted)
+                .take(limit)
+                .map(|(_, stored)| doc_list_entry(&stored.doc))
+                .collect()
+        });
+        Ok(doc_list(rows))
+    }
+
+    /// Views are computed on each query, so there is nothing to init.
+    async fn init_views(&self, _views: &[View]) -> CouchResult<()> {
+        Ok(())
+    }
+
+    async fn query_view(&self, view: &View, query: &ViewQuery) -> CouchResult<Vec<Doc>> {
+        let prefix = format!("{}_", view.typ);
+        let in_range = |key: &Value| {
+            query
+                .start
+                .as_ref()
+                .map_or(true, |start| collate(key, start) != Ordering::Less)
+                && query
+                    .end
+                    .as_ref()
+                    .map_or(true, |end| collate(key, end) != Ordering::Greater)
+        };
+        let mut rows: Vec<(Value, Doc)> = self.read(|inner| {
+            inner
+                .docs
+                .range(prefix.clone()..)
+                .take_while(|(id, _)| id.starts_with(&prefix))
+                .filter(|(_, stored)| !stored.deleted)
+                .filter_map(|(_, stored)| {
+                    let key = view.key_of(&stored.doc)?;
+                    match in_range(&key) {
+                        true => Some((key, stored.doc.clone())),
+                        false => None,
+                    }
+                })
+                .collect()
+        });
+        rows.sort_by(|(a_key, a_doc), (b_key, b_doc)| {
+            collate(a_key, b_key).then_with(|| a_doc.id().cmp(b_doc.id()))
+        });
+        if query.descending {
+            rows.reverse();
+        }
+        let docs = rows
+            .into_iter()
+            .skip(query.skip)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|(_key, doc)| doc)
+            .collect();
+        Ok(docs)
+    }
+
+    async fn get_last_seq(&self) -> CouchResult<String> {
+        Ok(self.read(|inner| format_seq(inner.seq)))
+    }
+
+    fn changes(
+        &self,
+        last_seq: Option<String>,
+        infinite: bool,
+    ) -> BoxStream<'static, CouchResult<ChangeEvent>> {
+        let since = last_seq.as_deref().and_then(seq_number).unwrap_or(0);
+        let state = ChangesState {
+            store: self.clone(),
+            seq_receiver: self.seq_receiver.clone(),
+            since,
+            infinite,
+            pending: VecDeque::new(),
+        };
+        stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(event) = state.pending.pop_front() {
+                    return Some((Ok(event), state));
+                }
+                let changes = state.store.changes_since(state.since, CHANGES_BATCH_LEN);
+                if let Some(last) = changes.last() {
+                    state.since = seq_number(&last.seq).unwrap_or(state.since);
+                    state.pending.extend(changes);
+                    continue;
+                }
+                if !state.infinite || state.seq_receiver.changed().await.is_err() {
+                    return None;
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+/// Max number of changes that are read from the store at once.
+const CHANGES_BATCH_LEN: usize = 1000;
+
+struct ChangesState {
+    store: MemoryStore,
+    seq_receiver: watch::Receiver<u64>,
+    since: u64,
+    infinite: bool,
+    pending: VecDeque<ChangeEvent>,
+}
+
+/// Compare two JSON values like CouchDB compares view keys.
+///
+/// Strings are compared by their bytes, not with the Unicode collation algorithm CouchDB uses.
+fn collate(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(false) => 1,
+            Value::Bool(true) => 2,
+            Value::Number(_) => 3,
+            Value::String(_) => 4,
+            Value::Array(_) => 5,
+            Value::Object(_) => 6,
+        }
+    }
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => {
+            let a = a.as_f64().unwrap_or_default();
+            let b = b.as_f64().unwrap_or_default();
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| collate(a, b))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Value::Object(a), Value::Object(b)) => a.len().cmp(&b.len()),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+fn format_seq(seq: u64) -> String {
+    format!("{}-memory", seq)
+}
+
+fn doc_list_entry(doc: &Doc) -> DocListEntry {
+    DocListEntry {
+        id: doc.id().to_string(),
+        key: doc.id().to_string(),
+        doc: doc.clone(),
+    }
+}
+
+fn doc_list(rows: Vec<DocListEntry>) -> DocList {
+    DocList {
+        total_rows: rows.len() as u32,
+        offset: Some(0),
+        rows,
+    }
+}
+
+fn not_found(id: &str) -> CouchError {
+    CouchError::Couch(
+        StatusCode::NOT_FOUND,
+        ErrorDetails::new("not_found", "missing", Some(id.to_string())),
+    )
+}
+
+fn conflict(id: &str) -> CouchError {
+    CouchError::Couch(
+        StatusCode::CONFLICT,
+        ErrorDetails::new(
+            "conflict",
+            "Document update conflict.",
+            Some(id.to_string()),
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    fn doc(id: &str, rev: Option<&str>, value: Value) -> Doc {
+        let meta = DocMeta::new(id.to_string(), rev.map(|rev| rev.to_string()));
+        Doc::new(meta, value.as_object().cloned().unwrap_or_default())
+    }
+
+    fn status(res: CouchResult<impl std::fmt::Debug>) -> Option<u16> {
+        res.expect_err("expected an error").status_code()
+    }
+
+    #[tokio::test]
+    async fn put_get_and_conflict() {
+        let store = MemoryStore::new("test");
+        let res = store
+            .put_doc(doc("a", None, json!({ "n": 1 })))
+            .await
+            .unwrap();
+        assert!(res.rev.starts_with("1-"));
+        let stored = store.get_doc("a").await.unwrap();
+        assert_eq!(stored.rev(), Some(res.rev.as_str()));
+        assert_eq!(stored.doc.get("n"), Some(&json!(1)));
+
+        // Puts without the current rev or with an outdated rev conflict.
+        let res2 = store.put_doc(doc("a", None, json!({ "n": 2 }))).await;
+        assert_eq!(status(res2), Some(409));
+        let updated = store
+            .put_doc(doc("a", Some(&res.rev), json!({ "n": 2 })))
+            .await
+            .unwrap();
+        assert!(updated.rev.starts_with("2-"));
+        let res3 = store
+            .put_doc(doc("a", Some(&res.rev), json!({ "n": 3 })))
+            .await;
+        assert_eq!(status(res3), Some(409));
+
+        assert_eq!(status(store.get_doc("missing").await), Some(404));
+    }
+
+    #[tokio::test]
+    async fn delete_and_recreate() {
+        let store = MemoryStore::new("test");
+        let res = store.put_doc(doc("a", None, json!({}))).await.unwrap();
+        assert_eq!(status(store.delete_doc("a", "1-wrong").await), Some(409));
+        store.delete_doc("a", &res.rev).await.unwrap();
+        assert_eq!(status(store.get_doc("a").await), Some(404));
+        assert_eq!(status(store.delete_doc("a", &res.rev).await), Some(404));
+        // A deleted doc can be recreated without a rev.
+        let res = store.put_doc(doc("a", None, json!({}))).await.unwrap();
+        assert!(res.rev.starts_with("3-"));
+    }
+
+    #[tokio::test]
+    async fn bulk_and_scans() {
+        let store = MemoryStore::new("test");
+        let docs = vec![
+            doc("oas.Post_b", None, json!({})),
+            doc("oas.Media_a", None, json!({})),
+            doc("oas.Post_a", None, json!({})),
+        ];
+        let res = store.put_bulk(docs).await.unwrap();
+        assert!(res.iter().all(|res| matches!(res, PutResult::Ok(_))));
+        // A conflict in a bulk put only fails the conflicting doc.
+        let docs = vec![
+            doc("oas.Post_a", None, json!({})),
+            doc("oas.Post_c", None, json!({})),
+        ];
+        let res = store.put_bulk(docs).await.unwrap();
+        assert!(matches!(res[0], PutResult::Err(_)));
+        assert!(matches!(res[1], PutResult::Ok(_)));
+
+        let ids =
+            |list: DocList| -> Vec<String> { list.rows.into_iter().map(|row| row.id).collect() };
+        let posts = store.all_docs(Some("oas.Post_")).await.unwrap();
+        assert_eq!(ids(posts), vec!["oas.Post_a", "oas.Post_b", "oas.Post_c"]);
+        let page = store.all_docs_page("oas.Post_", None, 2).await.unwrap();
+        assert_eq!(ids(page), vec!["oas.Post_a", "oas.Post_b"]);
+        let page = store
+            .all_docs_page("oas.Post_", Some("oas.Post_b"), 2)
+            .await
+            .unwrap();
+        assert_eq!(ids(page), vec!["oas.Post_c"]);
+
+        let docs = store.bulk_get(&["oas.Media_a", "missing"]).await.unwrap();
+        assert!(docs[0].is_some());
+        assert!(docs[1].is_none());
+        let many = store.get_many(&["oas.Post_c", "missing"]).await.unwrap();
+        assert_eq!(ids(many), vec!["oas.Post_c"]);
+    }
+
+    #[tokio::test]
+    async fn revisions() {
+        let store = MemoryStore::new("test");
+        let first = store
+            .put_doc(doc("a", None, json!({ "n": 1 })))
+            .await
+            .unwrap();
+        let second = store
+            .put_doc(doc("a", Some(&first.rev), json!({ "n": 2 })))
+            .await
+            .unwrap();
+        let revs = store.revisions("a").await.unwrap();
+        let revs: Vec<_> = revs.into_iter().map(|info| info.rev).collect();
+        assert_eq!(revs, vec![second.rev.clone(), first.rev.clone()]);
+        let old = store.get_doc_at_rev("a", &first.rev).await.unwrap();
+        assert_eq!(old.doc.get("n"), Some(&json!(1)));
+        assert_eq!(status(store.get_doc_at_rev("a", "9-nope").await), Some(404));
+    }
+
+    #[tokio::test]
+    async fn changes() {
+        let store = MemoryStore::new("test");
+        store.put_doc(doc("a", None, json!({}))).await.unwrap();
+        let b = store.put_doc(doc("b", None, json!({}))).await.unwrap();
+        store
+            .put_doc(doc("b", Some(&b.rev), json!({})))
+            .await
+            .unwrap();
+
+        // Only the latest change of each doc is in the changes feed.
+        let events: Vec<_> = store.changes(None, false).collect().await;
+        let ids: Vec<_> = events
+            .iter()
+            .map(|ev| ev.as_ref().unwrap().id.clone())
+            .collect();
+        assert_eq!(ids, vec!["a", "b"]);
+        let last_seq = store.get_last_seq().await.unwrap();
+        assert_eq!(seq_number(&last_seq), Some(3));
+        let events: Vec<_> = store.changes(Some(last_seq.clone()), false).collect().await;
+        assert!(events.is_empty());
+
+        // In infinite mode, the stream waits for new changes.
+        let mut stream = store.changes(Some(last_seq), true);
+        let writer = store.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            writer.put_doc(doc("c", None, json!({}))).await.unwrap();
+        });
+        let event = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("no change received")
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.id, "c");
+    }
+
+    #[tokio::test]
+    async fn persist_to_file() {
+        let dir = std::env::temp_dir().join(format!("oas-test-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("test.jsonl");
+        let rev = {
+            let store = MemoryStore::open("test", &path).unwrap();
+            let a = store
+                .put_doc(doc("a", None, json!({ "n": 1 })))
+                .await
+                .unwrap();
+            let a = store
+                .put_doc(doc("a", Some(&a.rev), json!({ "n": 2 })))
+                .await
+                .unwrap();
+            let b = store.put_doc(doc("b", None, json!({}))).await.unwrap();
+            store.delete_doc("b", &b.rev).await.unwrap();
+            a.rev
+        };
+        let store = MemoryStore::open("test", &path).unwrap();
+        let a = store.get_doc("a").await.unwrap();
+        assert_eq!(a.rev(), Some(rev.as_str()));
+        assert_eq!(a.doc.get("n"), Some(&json!(2)));
+        assert_eq!(status(store.get_doc("b").await), Some(404));
+        assert_eq!(seq_number(&store.get_last_seq().await.unwrap()), Some(4));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
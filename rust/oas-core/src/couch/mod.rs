@@ -2,12 +2,6 @@ use anyhow::Context;
 use clap::Clap;
 use oas_common::UntypedRecord;
 use oas_common::{Record, TypedValue};
-use reqwest::{Method, RequestBuilder};
-use serde::de::DeserializeOwned;
-use serde::Serialize;
-use serde_json::json;
-use serde_json::Value;
-use std::collections::HashMap;
 use std::sync::Arc;
 use url::Url;
 
@@ -15,16 +9,30 @@ pub type CouchResult<T> = std::result::Result<T, CouchError>;
 // TODO: Remove.
 pub type Result<T> = std::result::Result<T, CouchError>;
 
+pub mod audit;
 pub(crate) mod changes;
+pub mod checkpoint;
+pub mod dead_letter;
+pub mod dispatcher;
 pub(crate) mod error;
+mod http;
 mod manager;
+mod memory;
 pub mod resolver;
+mod store;
 mod table;
 pub(crate) mod types;
 
-pub use changes::ChangesStream;
+pub use audit::{AuditEntry, AuditFilter, AuditLog};
+pub use changes::{ChangesStream, FailedChange, UntypedRecordBatch};
+pub use checkpoint::{Checkpoint, CheckpointStore};
+pub use dead_letter::{DeadLetter, DeadLetterStore};
+pub use dispatcher::{ChangesDispatcher, ChangesReceiver};
 pub use error::CouchError;
+pub use http::HttpStore;
 pub use manager::*;
+pub use memory::MemoryStore;
+pub use store::{RecordStore, View, ViewQuery};
 pub use types::*;
 
 use self::table::Table;
@@ -33,6 +41,22 @@ pub const DEFAULT_DATABASE: &str = "oas";
 pub const DEFAULT_HOST: &str = "http://localhost:5984";
 // pub const DEFAULT_PORT: u16 = 5984;
 
+/// Max number of tries for [CouchDB::update_record] if the record is changed concurrently.
+const MAX_UPDATE_TRIES: usize = 5;
+
+/// A record that was changed with [CouchDB::update_record_tracked].
+#[derive(Debug, Clone)]
+pub struct RecordUpdate<T> {
+    /// The record before the change.
+    pub previous: Record<T>,
+    /// The rev of the record before the change.
+    pub previous_rev: String,
+    /// The record after the change.
+    pub record: Record<T>,
+    /// The rev of the record after the change.
+    pub rev: String,
+}
+
 /// CouchDB config.
 #[derive(Clap, Debug, Clone)]
 pub struct Config {
@@ -118,12 +142,12 @@ impl Config {
 
 /// CouchDB client.
 ///
-/// The client is stateless. It only contains a HTTP client and the config on how to connect to a
-/// database.
+/// The client is stateless. It wraps a [RecordStore] that performs the actual document
+/// operations: Usually a [HttpStore] that talks to a CouchDB server, or a [MemoryStore] for tests
+/// and single-node setups.
 #[derive(Debug, Clone)]
 pub struct CouchDB {
-    config: Arc<Config>,
-    client: Arc<reqwest::Client>,
+    store: Arc<dyn RecordStore>,
 }
 
 impl CouchDB {
@@ -131,20 +155,25 @@ impl CouchDB {
     /// TODO: Remove Ok-wrapping
     pub fn with_config(config: Config) -> anyhow::Result<Self> {
         let client = reqwest::Client::new();
-
-        Ok(Self {
-            config: Arc::new(config),
-            client: Arc::new(client),
-        })
+        Ok(Self::with_config_and_client(config, client))
     }
 
     pub fn with_config_and_client(config: Config, client: reqwest::Client) -> Self {
+        Self::with_store(HttpStore::new(config, client))
+    }
+
+    /// Create a new client on a [RecordStore].
+    pub fn with_store(store: impl RecordStore + 'static) -> Self {
         Self {
-            config: Arc::new(config),
-            client: Arc::new(client),
+            store: Arc::new(store),
         }
     }
 
+    /// Create a new client on an empty in-memory store.
+    pub fn in_memory(name: impl ToString) -> Self {
+        Self::with_store(MemoryStore::new(name))
+    }
+
     /// Create a new client with a CouchDB URL.
     ///
     /// The URL should have the following format:
@@ -161,39 +190,26 @@ impl CouchDB {
         Ok(db)
     }
 
+    /// The store of this client.
+    pub fn store(&self) -> &Arc<dyn RecordStore> {
+        &self.store
+    }
+
     /// Init the database.
     ///
     /// This creates the database if it does not exists. It should be called before calling other
     /// methods on the client.
     pub async fn init(&self) -> anyhow::Result<()> {
-        let res: Result<Value> = self.send(self.request(Method::GET, "")).await;
-        match res {
-            Ok(_res) => {
-                log::trace!("check database {}: ok", self.config.database);
-                Ok(())
-            }
-            Err(_) => {
-                let req = self.request(Method::PUT, "");
-                let _res: serde_json::Value = self.send(req).await.with_context(|| {
-                    format!("Failed to create database {}", self.config.database)
-                })?;
-                Ok(())
-            }
-        }
+        self.store.init().await
     }
 
     pub async fn destroy_and_init(&self) -> Result<()> {
-        let req = self.request(Method::DELETE, "");
-        let _: Result<()> = self.send(req).await;
-        let req = self.request(Method::PUT, "");
-        self.send(req).await
+        self.store.destroy_and_init().await
     }
 
     /// Get all docs from the database.
     pub async fn get_all(&self) -> Result<DocList> {
-        let mut params = HashMap::new();
-        params.insert("include_docs", "true");
-        self.get_all_with_params(&params).await
+        self.store.all_docs(None).await
     }
 
     /// Get many docs by their ID from the database.
@@ -201,12 +217,7 @@ impl CouchDB {
         if ids.is_empty() {
             return Ok(DocList::default());
         }
-        let mut params = HashMap::new();
-        params.insert("include_docs", serde_json::to_string(&true).unwrap());
-        // let keys: String = ids.join(",");
-        // params.insert("keys", serde_json::to_value(keys).unwrap());
-        params.insert("keys", serde_json::to_string(ids).unwrap());
-        self.get_all_with_params(&params).await
+        self.store.get_many(ids).await
     }
 
     /// Get all docs where the couch id starts with a prefix.
@@ -214,29 +225,37 @@ impl CouchDB {
     /// When the ids contain a type prefix (e.g. "oas.Media_someidstring", then
     /// this method can be used to get all docs with a type.
     pub async fn get_all_with_prefix(&self, prefix: &str) -> Result<DocList> {
-        let mut params = HashMap::new();
-        params.insert("include_docs", "true".to_string());
-        if prefix.contains('\"') {
-            return Err(CouchError::Other("Prefix may not contain quotes".into()));
-        }
-        params.insert("startkey", format!("\"{}\"", prefix));
-        params.insert("endkey", format!("\"{}{}\"", prefix, "\u{ffff}"));
-        self.get_all_with_params(&params).await
+        self.store.all_docs(Some(prefix)).await
     }
 
-    /// Get all docs while passing a map of params.
-    pub async fn get_all_with_params(&self, params: &impl Serialize) -> Result<DocList> {
-        let req = self.request(Method::GET, "_all_docs").query(params);
-        let docs: Value = self.send(req).await?;
-        let docs: DocList = serde_json::from_value(docs)?;
-        Ok(docs)
+    /// Get a page of the docs where the couch id starts with a prefix.
+    ///
+    /// The page has at most `limit` docs and starts after the doc with id `start_after`. Pass
+    /// the id of the last doc of a page to get the next page.
+    pub async fn get_page_with_prefix(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<DocList> {
+        self.store.all_docs_page(prefix, start_after, limit).await
     }
 
     /// Get a doc from the id by its id.
     pub async fn get_doc(&self, id: &str) -> Result<Doc> {
-        let req = self.request(Method::GET, id);
-        let doc: Doc = self.send(req).await?;
-        Ok(doc)
+        self.store.get_doc(id).await
+    }
+
+    /// Get a doc at a previous rev.
+    ///
+    /// Fails with a 404 if the rev is not available anymore, e.g. after compaction.
+    pub async fn get_doc_at_rev(&self, id: &str, rev: &str) -> Result<Doc> {
+        self.store.get_doc_at_rev(id, rev).await
+    }
+
+    /// List the revs of a doc, newest first.
+    pub async fn get_revisions(&self, id: &str) -> Result<Vec<RevInfo>> {
+        self.store.revisions(id).await
     }
 
     /// Put a doc into the database.
@@ -250,42 +269,55 @@ impl CouchDB {
                 }
             }
         }
-        let req = self.request(Method::PUT, &id).json(&doc);
-        let res = self.send(req).await;
+        let res = self.store.put_doc(doc).await;
         if let Err(err) = &res {
-            log::trace!("[{}] put ERR for {}: {:?}", self.config.database, id, err);
+            log::trace!("[{}] put ERR for {}: {:?}", self.store.name(), id, err);
         } else {
-            log::trace!("[{}] put OK for {}", self.config.database, id);
+            log::trace!("[{}] put OK for {}", self.store.name(), id);
         }
         res
     }
 
+    /// Delete a doc from the database.
+    ///
+    /// If rev is None, the latest rev of the doc is fetched first.
+    pub async fn delete_doc(&self, id: &str, rev: Option<&str>) -> Result<PutResponse> {
+        let rev = match rev {
+            Some(rev) => rev.to_string(),
+            None => {
+                let doc = self.get_doc(id).await?;
+                doc.rev()
+                    .map(|rev| rev.to_string())
+                    .ok_or_else(|| CouchError::Other(format!("Doc {} has no rev", id)))?
+            }
+        };
+        self.store.delete_doc(id, &rev).await
+    }
+
     /// Put a list of docs into the database in a single bulk operation.
     pub async fn put_bulk(&self, docs: Vec<Doc>) -> Result<Vec<PutResult>> {
-        let ids: Vec<_> = docs.iter().map(|d| d.id()).collect();
+        let ids: Vec<_> = docs.iter().map(|d| d.id().to_string()).collect();
         log::trace!(
             "[{}] bulk put start for {}: {:?}",
-            self.config.database,
+            self.store.name(),
             ids.len(),
             ids
         );
-        let body = serde_json::json!({ "docs": docs });
-        let req = self.request(Method::POST, "_bulk_docs").json(&body);
-        let res: Vec<PutResult> = self.send(req).await?;
+        let res = self.store.put_bulk(docs).await?;
         let mut errors = 0;
         let mut ok = 0;
         for res in res.iter() {
             match res {
                 PutResult::Ok(_) => ok += 1,
                 PutResult::Err(err) => {
-                    log::trace!("[{}] bulk put ERR: {}", self.config.database, err);
+                    log::trace!("[{}] bulk put ERR: {}", self.store.name(), err);
                     errors += 1;
                 }
             }
         }
         log::debug!(
             "[{}] bulk put {} ({} OK, {} ERR)",
-            self.config.database,
+            self.store.name(),
             res.len(),
             ok,
             errors
@@ -299,38 +331,20 @@ impl CouchDB {
     where
         T: Into<Doc>,
     {
-        let mut docs_without_rev = vec![];
         let mut docs: Vec<Doc> = docs.into_iter().map(|doc| doc.into()).collect();
-        for (i, doc) in docs.iter().enumerate() {
-            if doc.rev().is_none() {
-                docs_without_rev.push((doc.id().to_string(), i));
-            }
-        }
-        let req_json: Vec<serde_json::Value> = docs_without_rev
+        let docs_without_rev: Vec<(String, usize)> = docs
             .iter()
-            .map(|(id, _)| json!({ "id": id }))
+            .enumerate()
+            .filter(|(_i, doc)| doc.rev().is_none())
+            .map(|(i, doc)| (doc.id().to_string(), i))
             .collect();
-        let req_json = json!({ "docs": req_json });
-        let req = self.request(Method::POST, "_bulk_get").json(&req_json);
-        let bulk_get: BulkGetResponse = self.send(req).await?;
-        for (req_idx, (sent_id, doc_idx)) in docs_without_rev.iter().enumerate() {
-            let result = bulk_get.results.get(req_idx);
-            let rev = match result {
-                Some(BulkGetItem { id, docs }) if id == sent_id && docs.len() == 1 => {
-                    let doc = docs.get(0).unwrap();
-                    match doc {
-                        DocResult::Ok(doc) => doc.rev().map(|s| s.to_string()),
-                        DocResult::Err(_err) => None,
-                    }
-                }
-                _ => {
-                    return Err(CouchError::Other(
-                        "Response does not match request".to_string(),
-                    ));
-                }
-            };
-            if let Some(rev) = rev {
-                docs.get_mut(*doc_idx).unwrap().set_rev(Some(rev));
+        let ids: Vec<&str> = docs_without_rev.iter().map(|(id, _)| id.as_str()).collect();
+        let latest_docs = self.store.bulk_get(&ids).await?;
+        for (latest_doc, (_id, doc_idx)) in latest_docs.iter().zip(docs_without_rev.iter()) {
+            if let Some(rev) = latest_doc.as_ref().and_then(|doc| doc.rev()) {
+                docs.get_mut(*doc_idx)
+                    .unwrap()
+                    .set_rev(Some(rev.to_string()));
             }
         }
 
@@ -363,51 +377,35 @@ impl CouchDB {
         ChangesStream::new(self.clone(), last_seq)
     }
 
-    pub async fn get_last_seq(&self) -> anyhow::Result<String> {
-        let mut params = HashMap::new();
-        params.insert("descending", "true".to_string());
-        params.insert("limit", "1".to_string());
-        let path = "_changes";
-        let req = self.request(Method::GET, path).query(&params);
-        let res: GetChangesResult = self.send(req).await?;
-        Ok(res.last_seq)
-    }
-
-    fn request(&self, method: Method, path: impl AsRef<str>) -> RequestBuilder {
-        let url = self.path_to_url(path.as_ref());
-        let builder = self.client.request(method, url);
-        let builder = if let Some(username) = &self.config.user {
-            builder.basic_auth(username, self.config.password.as_ref())
-        } else {
-            builder
-        };
-        builder
+    /// Create or update the views of the database.
+    pub async fn init_views(&self, views: &[View]) -> Result<()> {
+        self.store.init_views(views).await
     }
 
-    fn path_to_url(&self, path: impl AsRef<str>) -> Url {
-        format!(
-            "{}/{}/{}",
-            self.config.host,
-            self.config.database,
-            path.as_ref()
-        )
-        .parse()
-        .unwrap()
+    /// Query a view. See [RecordStore::query_view].
+    pub async fn query_view(&self, view: &View, query: &ViewQuery) -> Result<Vec<Doc>> {
+        self.store.query_view(view, query).await
     }
 
-    async fn send<T>(&self, request: RequestBuilder) -> Result<T>
-    where
-        T: DeserializeOwned,
-    {
-        let res = request.send().await?;
-        // let mut res = self.client.execute(request).await?;
-        match res.status().is_success() {
-            true => Ok(res.json::<T>().await?),
-            false => Err(CouchError::Couch(
-                res.status(),
-                res.json::<ErrorDetails>().await?,
-            )),
-        }
+    /// Query a view and decode its docs into records.
+    ///
+    /// Docs that cannot be decoded are skipped.
+    pub async fn query_view_records<T: TypedValue>(
+        &self,
+        view: &View,
+        query: &ViewQuery,
+    ) -> Result<Vec<Record<T>>> {
+        let docs = self.query_view(view, query).await?;
+        let records = docs
+            .into_iter()
+            .filter_map(|doc| doc.into_typed_record::<T>().ok())
+            .collect();
+        Ok(records)
+    }
+
+    pub async fn get_last_seq(&self) -> anyhow::Result<String> {
+        let seq = self.store.get_last_seq().await?;
+        Ok(seq)
     }
 }
 
@@ -443,6 +441,20 @@ impl CouchDB {
         Ok(record)
     }
 
+    /// Get a single record together with the rev of its doc.
+    pub async fn get_record_with_rev<T: TypedValue>(
+        &self,
+        id: &str,
+    ) -> Result<(Record<T>, String)> {
+        let doc = self.get_doc(&id).await?;
+        let rev = doc
+            .rev()
+            .map(|rev| rev.to_string())
+            .ok_or_else(|| CouchError::Other(format!("Doc {} has no rev", id)))?;
+        let record = doc.into_typed_record::<T>()?;
+        Ok((record, rev))
+    }
+
     pub async fn get_record_untyped(&self, guid: &str) -> Result<UntypedRecord> {
         let doc = self.get_doc(&guid).await?;
         let record = doc.into_untyped_record()?;
@@ -479,6 +491,66 @@ impl CouchDB {
         self.put_doc(doc).await
     }
 
+    /// Put a single record, but only if its doc is still at `rev`.
+    ///
+    /// Fails with a conflict (409) if the doc was changed in the meantime.
+    pub async fn put_record_at_rev<T: TypedValue>(
+        &self,
+        record: Record<T>,
+        rev: &str,
+    ) -> Result<PutResponse> {
+        let mut doc = Doc::from_typed_record(record);
+        doc.set_rev(Some(rev.to_string()));
+        self.put_doc(doc).await
+    }
+
+    /// Update a single record with a function.
+    ///
+    /// The function is applied to the current version of the record, which is then saved at the
+    /// rev it was read at. If the record was changed in the meantime, the update is retried on
+    /// the new version. If the function returns an error, the record is not changed.
+    pub async fn update_record<T, F>(&self, guid: &str, update: F) -> anyhow::Result<Record<T>>
+    where
+        T: TypedValue,
+        F: FnMut(&mut Record<T>) -> anyhow::Result<()> + Send,
+    {
+        let update = self.update_record_tracked(guid, update).await?;
+        Ok(update.record)
+    }
+
+    /// Update a single record with a function, like [Self::update_record], and return both
+    /// versions of the record with their revs.
+    pub async fn update_record_tracked<T, F>(
+        &self,
+        guid: &str,
+        mut update: F,
+    ) -> anyhow::Result<RecordUpdate<T>>
+    where
+        T: TypedValue,
+        F: FnMut(&mut Record<T>) -> anyhow::Result<()> + Send,
+    {
+        let mut tries = 0;
+        loop {
+            let (previous, previous_rev) = self.get_record_with_rev::<T>(guid).await?;
+            let mut record = previous.clone();
+            update(&mut record)?;
+            match self.put_record_at_rev(record.clone(), &previous_rev).await {
+                Ok(response) => {
+                    return Ok(RecordUpdate {
+                        previous,
+                        previous_rev,
+                        record,
+                        rev: response.rev,
+                    })
+                }
+                Err(err) if err.status_code() == Some(409) && tries < MAX_UPDATE_TRIES => {
+                    tries += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
     /// Put a vector of records into the database in a single operation.
     pub async fn put_record_bulk<T: TypedValue>(
         &self,
@@ -0,0 +1,201 @@
+//! Record store backends.
+//!
+//! A [RecordStore] provides the primitive document operations that [CouchDB] is built on: Get
+//! and put single docs, bulk operations, `_all_docs` scans and the `_changes` feed. The
+//! [HttpStore](super::HttpStore) talks to a CouchDB server, the
+//! [MemoryStore](super::MemoryStore) keeps all docs in process (optionally persisted to disk).
+//!
+//! [CouchDB]: super::CouchDB
+
+use futures::stream::BoxStream;
+use serde_json::{json, Value};
+use std::fmt;
+
+use super::{ChangeEvent, CouchResult, Doc, DocList, PutResponse, PutResult, RevInfo};
+
+/// Primitive operations of a CouchDB-compatible document store.
+///
+/// Revisions follow the CouchDB semantics: To update or delete an existing doc, the doc's
+/// current rev has to be passed, otherwise the operation fails with a conflict (409). Missing
+/// docs fail with a 404.
+#[async_trait::async_trait]
+pub trait RecordStore: fmt::Debug + Send + Sync {
+    /// Name of the database.
+    fn name(&self) -> &str;
+
+    /// Create the database if it does not exist yet.
+    async fn init(&self) -> anyhow::Result<()>;
+
+    /// Delete all docs and recreate the database.
+    async fn destroy_and_init(&self) -> CouchResult<()>;
+
+    /// Get a doc by its id.
+    async fn get_doc(&self, id: &str) -> CouchResult<Doc>;
+
+    /// Get a doc at a previous rev.
+    ///
+    /// Fails with a 404 if the body of the rev is not stored anymore.
+    async fn get_doc_at_rev(&self, id: &str, rev: &str) -> CouchResult<Doc>;
+
+    /// List the revs of a doc, newest first (like `revs_info`).
+    async fn revisions(&self, id: &str) -> CouchResult<Vec<RevInfo>>;
+
+    /// Put a doc.
+    async fn put_doc(&self, doc: Doc) -> CouchResult<PutResponse>;
+
+    /// Delete a doc at a rev.
+    async fn delete_doc(&self, id: &str, rev: &str) -> CouchResult<PutResponse>;
+
+    /// Put a list of docs in a single operation.
+    async fn put_bulk(&self, docs: Vec<Doc>) -> CouchResult<Vec<PutResult>>;
+
+    /// Get the current version of a list of docs (like `_bulk_get`).
+    ///
+    /// The result has the same order as `ids`. Missing docs are returned as None.
+    async fn bulk_get(&self, ids: &[&str]) -> CouchResult<Vec<Option<Doc>>>;
+
+    /// Get a list of docs by their ids (like `_all_docs` with keys).
+    async fn get_many(&self, ids: &[&str]) -> CouchResult<DocList>;
+
+    /// Get all docs, or all docs whose id starts with a prefix, ordered by id.
+    async fn all_docs(&self, prefix: Option<&str>) -> CouchResult<DocList>;
+
+    /// Get a page of the docs whose id starts with a prefix, ordered by id.
+    ///
+    /// The page starts after the doc with id `start_after` and has at most `limit` docs.
+    async fn all_docs_page(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> CouchResult<DocList>;
+
+    /// Create or update the views of the database.
+    async fn init_views(&self, views: &[View]) -> CouchResult<()>;
+
+    /// Query a view.
+    ///
+    /// Returns the docs of the rows, ordered by key and doc id.
+    async fn query_view(&self, view: &View, query: &ViewQuery) -> CouchResult<Vec<Doc>>;
+
+    /// Get the current seq of the database.
+    async fn get_last_seq(&self) -> CouchResult<String>;
+
+    /// Open a stream of changes after `last_seq` (like `_changes` with `include_docs`).
+    ///
+    /// If infinite is true, the stream waits for new changes forever. Otherwise, it ends after
+    /// all current changes.
+    fn changes(
+        &self,
+        last_seq: Option<String>,
+        infinite: bool,
+    ) -> BoxStream<'static, CouchResult<ChangeEvent>>;
+}
+
+/// A view of the records of a type, sorted by the values of some of their fields.
+///
+/// On CouchDB, this is a view with a JavaScript map function in the design doc `_design/{typ}`.
+#[derive(Debug, Clone, Copy)]
+pub struct View {
+    /// Type name of the records in the view, e.g. `oas.Job`.
+    pub typ: &'static str,
+    /// Name of the view.
+    pub name: &'static str,
+    /// Fields of the records that make up the key of a row. Missing fields are null.
+    pub key: &'static [&'static str],
+}
+
+impl View {
+    /// Get the JavaScript map function of the view.
+    pub fn map_function(&self) -> String {
+        let key: Vec<String> = self
+            .key
+            .iter()
+            .map(|field| format!("doc[{:?}] === undefined ? null : doc[{:?}]", field, field))
+            .collect();
+        let prefix = format!("{}_", self.typ);
+        format!(
+            "function (doc) {{ if (doc._id.indexOf({:?}) === 0) {{ emit([{}], null); }} }}",
+            prefix,
+            key.join(", ")
+        )
+    }
+
+    /// Get the key of a doc in the view, or None if the doc is not a record of the view's type.
+    pub fn key_of(&self, doc: &Doc) -> Option<Value> {
+        let id = doc.id();
+        if !(id.starts_with(self.typ) && id[self.typ.len()..].starts_with('_')) {
+            return None;
+        }
+        let key = self
+            .key
+            .iter()
+            .map(|field| doc.doc.get(*field).cloned().unwrap_or(Value::Null))
+            .collect();
+        Some(Value::Array(key))
+    }
+}
+
+/// A query on a [View].
+///
+/// Keys are compared with the CouchDB collation rules (null < booleans < numbers < strings <
+/// arrays < objects), so an empty object sorts after all other values of a key.
+#[derive(Debug, Clone, Default)]
+pub struct ViewQuery {
+    /// Lowest key of the rows (inclusive).
+    pub start: Option<Value>,
+    /// Highest key of the rows (inclusive).
+    pub end: Option<Value>,
+    /// Return the rows with the highest keys first.
+    pub descending: bool,
+    /// Number of rows to skip.
+    pub skip: usize,
+    /// Max number of rows.
+    pub limit: Option<usize>,
+}
+
+impl ViewQuery {
+    /// Query all rows.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Query the rows whose keys start with the values of `prefix`.
+    pub fn prefix(prefix: Vec<Value>) -> Self {
+        let start = Value::Array(prefix.clone());
+        let mut end = prefix;
+        end.push(json!({}));
+        Self {
+            start: Some(start),
+            end: Some(Value::Array(end)),
+            ..Default::default()
+        }
+    }
+
+    /// Query the rows whose keys start with the values of `prefix`, followed by a value that is
+    /// at least `from`.
+    pub fn prefix_from(prefix: Vec<Value>, from: Value) -> Self {
+        let mut start = prefix.clone();
+        start.push(from);
+        let mut end = prefix;
+        end.push(json!({}));
+        Self {
+            start: Some(Value::Array(start)),
+            end: Some(Value::Array(end)),
+            ..Default::default()
+        }
+    }
+
+    /// Return the rows with the highest keys first.
+    pub fn descending(mut self) -> Self {
+        self.descending = true;
+        self
+    }
+
+    /// Skip `skip` rows and return at most `limit` rows.
+    pub fn page(mut self, skip: usize, limit: Option<usize>) -> Self {
+        self.skip = skip;
+        self.limit = limit;
+        self
+    }
+}
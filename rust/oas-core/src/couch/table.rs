@@ -53,6 +53,10 @@ where
         self.db.put_record(record).await
     }
 
+    pub async fn delete(&self, id: &str) -> CouchResult<PutResponse> {
+        self.db.delete_doc(&T::guid(id), None).await
+    }
+
     pub async fn put_bulk(&self, records: Vec<Record<T>>) -> CouchResult<Vec<PutResult>> {
         self.db.put_record_bulk(records).await
     }
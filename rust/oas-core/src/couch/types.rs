@@ -189,6 +189,19 @@ pub struct GetChangesResult {
     pub pending: Option<i64>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ViewResponse {
+    pub rows: Vec<ViewRow>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ViewRow {
+    pub id: String,
+    pub key: serde_json::Value,
+    #[serde(default)]
+    pub doc: Option<Doc>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub struct PutResponse {
     pub id: String,
@@ -196,6 +209,25 @@ pub struct PutResponse {
     pub rev: String,
 }
 
+/// A revision of a doc (like an entry of `_revs_info`).
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct RevInfo {
+    pub rev: String,
+    pub status: RevStatus,
+}
+
+/// Whether the body of a revision is still stored.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RevStatus {
+    /// The body of the revision can be fetched.
+    Available,
+    /// The body of the revision was removed, e.g. by compaction.
+    Missing,
+    /// The revision is a deletion.
+    Deleted,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ErrorDetails {
     pub error: String,
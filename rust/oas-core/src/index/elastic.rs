@@ -7,7 +7,7 @@ use elasticsearch::{
     },
     BulkOperation, BulkParts, Elasticsearch, Error, DEFAULT_ADDRESS,
 };
-use elasticsearch::{GetParts, IndexParts, SearchParts, UpdateByQueryParts};
+use elasticsearch::{GetParts, SearchParts, UpdateByQueryParts};
 use http::StatusCode;
 use oas_common::{Record, TypedValue, UntypedRecord};
 use rocket::serde::DeserializeOwned;
@@ -85,16 +85,6 @@ impl Index {
         }
     }
 
-    pub(super) async fn put_doc<T: Serialize>(&self, id: &str, doc: &T) -> Result<(), Error> {
-        let _res = self
-            .client()
-            .index(IndexParts::IndexId(self.name(), id))
-            .body(doc)
-            .send()
-            .await?;
-        Ok(())
-    }
-
     /// Put a list of [Record]s to the index.
     ///
     /// Internally the [Record]s are transformed to [UntypedRecord]s, serialized and saved in a
@@ -241,6 +231,29 @@ for (nested_doc in nested_docs) {
         Ok(records)
     }
 
+    /// Query the index and return the raw hits, including their sort values.
+    pub async fn query_hits<Q: Serialize + std::fmt::Debug>(
+        &self,
+        query: Q,
+    ) -> Result<Vec<Value>, Error> {
+        let mut response = self
+            .client
+            .search(SearchParts::Index(&[&self.index]))
+            .body(query)
+            .send()
+            .await?;
+
+        // turn the response into an Error if status code is unsuccessful
+        response = response.error_for_status_code()?;
+
+        let mut json: Value = response.json().await?;
+        let hits = match json["hits"]["hits"].take() {
+            Value::Array(hits) => hits,
+            _ => vec![],
+        };
+        Ok(hits)
+    }
+
     /// Simple string query on the index.
     pub async fn find_records_with_text_query(
         &self,
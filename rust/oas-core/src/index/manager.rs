@@ -1,18 +1,14 @@
 //! Index manager
 //!
 //! The index manager maintains a list of Elasticsearch indexes. It also maintains an "oas.meta"
-//! index which stores meta information about the indexing state, most importantly the latest
-//! CouchDB seq that was indexed.
+//! index which stores meta information about the indexing state. The latest CouchDB seq that was
+//! indexed is stored as a checkpoint in CouchDB (see [crate::couch::CheckpointStore]).
 
-use crate::couch::CouchDB;
+use crate::couch::{ChangesDispatcher, ChangesReceiver, CouchDB, CouchManager};
 use anyhow::Context;
 use elasticsearch::Elasticsearch;
-use futures::stream::StreamExt;
-use futures_batch::ChunksTimeoutStreamExt;
-use oas_common::UntypedRecord;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use std::time;
 
 use super::{elastic, Config, Index, PostIndex};
 
@@ -24,6 +20,8 @@ pub const META_INDEX_NAME: &str = "meta";
 pub const DATA_INDEX_NAME: &str = "data";
 /// Doc ID for the index state.
 pub const DOC_ID_INDEX_STATE: &str = "IndexMeta.data";
+/// Name of the indexer as a changes consumer.
+pub const CONSUMER_NAME: &str = "index";
 
 /// The index manager holds configuration, an HTTP client and the names of active indexes.
 #[derive(Debug, Clone)]
@@ -68,7 +66,8 @@ impl InitOpts {
 
 /// The indexing state.
 ///
-/// Currently only tracks the last indexed CouchDB seq to know from where to resume indexing.
+/// Previously used to track the last indexed CouchDB seq. This is now stored as a checkpoint in
+/// CouchDB, the state is only read to migrate existing deployments.
 #[derive(Serialize, Deserialize)]
 struct IndexState {
     last_seq: Option<String>,
@@ -76,7 +75,7 @@ struct IndexState {
 
 /// The meta index that holds meta information.
 ///
-/// Currently only used to persist the [[IndexState]].
+/// Currently only used to read the legacy [[IndexState]].
 #[derive(Debug)]
 struct MetaIndex {
     index: Index,
@@ -98,15 +97,6 @@ impl MetaIndex {
             Ok(None)
         }
     }
-
-    pub async fn set_latest_indexed_seq(&self, seq: &str) -> anyhow::Result<()> {
-        let id = DOC_ID_INDEX_STATE;
-        let index_state = IndexState {
-            last_seq: Some(seq.to_string()),
-        };
-        self.index.put_doc(id, &index_state).await?;
-        Ok(())
-    }
 }
 
 impl IndexManager {
@@ -130,6 +120,17 @@ impl IndexManager {
         })
     }
 
+    /// Create a manager for the indexes of a tenant.
+    ///
+    /// The names of the indexes of a tenant have the tenant id after the prefix of this manager,
+    /// e.g. `oas.acme.data`.
+    pub fn for_tenant(&self, tenant: &str) -> Result<Self, elasticsearch::Error> {
+        let mut config = self.config.clone();
+        let prefix = config.prefix.as_deref().unwrap_or(DEFAULT_PREFIX);
+        config.prefix = Some(format!("{}.{}", prefix, tenant));
+        Self::with_config(config)
+    }
+
     /// Create a new index manager from an Elasticsearch endpoint URL.
     pub fn with_url<S>(url: Option<S>) -> anyhow::Result<Self>
     where
@@ -159,41 +160,67 @@ impl IndexManager {
     //     &self.meta_index
     // }
 
-    pub async fn index_changes(&self, db: &CouchDB, infinite: bool) -> anyhow::Result<()> {
-        let latest_seq = self.meta_index.latest_indexed_seq().await?;
-        log::debug!("start change indexer from seq {:?}", latest_seq);
-        let real_latest = db.get_last_seq().await?;
-        log::debug!("db is at {:?}", real_latest);
+    /// Index all changes from the database.
+    ///
+    /// This runs a [ChangesDispatcher] with the indexer as its only consumer. When running all
+    /// services, use [Self::index_changes_from] with a shared dispatcher instead.
+    pub async fn index_changes(
+        &self,
+        db_manager: &CouchManager,
+        infinite: bool,
+    ) -> anyhow::Result<()> {
+        let db = db_manager.record_db();
+        let mut dispatcher = ChangesDispatcher::new(db.clone(), infinite);
+        let changes = self.register_changes(db_manager, &mut dispatcher).await?;
+        let (dispatch_res, index_res) =
+            futures::join!(dispatcher.run(), self.index_changes_from(db, changes));
+        dispatch_res?;
+        index_res?;
+        Ok(())
+    }
 
-        let mut changes = db.changes(latest_seq);
-        changes.set_infinite(infinite);
+    /// Register the indexer as a consumer on a [ChangesDispatcher].
+    pub async fn register_changes(
+        &self,
+        db_manager: &CouchManager,
+        dispatcher: &mut ChangesDispatcher,
+    ) -> anyhow::Result<ChangesReceiver> {
+        let legacy_seq = self.meta_index.latest_indexed_seq().await?;
+        let changes = dispatcher
+            .register_with_checkpoint(CONSUMER_NAME, db_manager, legacy_seq)
+            .await?;
+        Ok(changes)
+    }
 
-        let batch_timeout = time::Duration::from_millis(200);
-        let batch_max_len = 1000;
-        let mut batched_changes = changes.chunks_timeout(batch_max_len, batch_timeout);
+    /// Index the changes received from a [ChangesDispatcher].
+    pub async fn index_changes_from(
+        &self,
+        db: &CouchDB,
+        mut changes: ChangesReceiver,
+    ) -> anyhow::Result<()> {
+        log::debug!("start change indexer");
+        let real_latest = db.get_last_seq().await?;
+        log::debug!("db is at {:?}", real_latest);
 
-        while let Some(batch) = batched_changes.next().await {
-            // Filter out errors for now.
-            let batch: Vec<_> = batch.into_iter().filter_map(|e| e.ok()).collect();
-            if batch.is_empty() {
-                continue;
-            }
-            let len = batch.len();
-            let latest_seq = &batch.last().unwrap().seq.to_string();
-
-            let records: Vec<UntypedRecord> = batch
-                .into_iter()
-                .filter_map(|ev| ev.doc.and_then(|doc| doc.into_untyped_record().ok()))
-                .collect();
-            self.post_index
-                .index_changes(&db, &records[..])
+        while let Some(batch) = changes.next().await {
+            let latest_seq = match batch.last_seq() {
+                Some(seq) => seq,
+                None => continue,
+            };
+            let records = batch.records();
+            let failures = self
+                .post_index
+                .index_changes(&db, records)
                 .await
                 .context("Failed to index changes")?;
-            self.meta_index
-                .set_latest_indexed_seq(latest_seq)
+            for (guid, err) in failures {
+                changes.reject(&batch, &guid, err).await?;
+            }
+            changes
+                .commit(&batch)
                 .await
-                .context("Failed to update index meta state")?;
-            log::debug!("indexed {} (latest seq {:?})", len, latest_seq);
+                .context("Failed to save indexer checkpoint")?;
+            log::debug!("indexed {} (latest seq {:?})", records.len(), latest_seq);
         }
 
         Ok(())
@@ -7,5 +7,5 @@ mod post_index;
 pub use config::Config;
 pub use elastic::Index;
 pub use error::IndexError;
-pub use manager::{IndexManager, InitOpts};
+pub use manager::{IndexManager, InitOpts, CONSUMER_NAME};
 pub use post_index::PostIndex;
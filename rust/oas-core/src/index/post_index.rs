@@ -2,6 +2,7 @@ use anyhow::Context;
 use elasticsearch::Elasticsearch;
 use oas_common::types::{Media, Post, Transcript};
 use oas_common::{ElasticMapping, Record, RecordMap, Resolver, TypedValue, UntypedRecord};
+use serde::Deserialize;
 use serde_json::json;
 use std::collections::HashSet;
 use std::sync::Arc;
@@ -57,6 +58,35 @@ impl PostIndex {
         Ok(ids)
     }
 
+    /// Find the guids of the posts that match an Elasticsearch query.
+    ///
+    /// Returns a page of at most `size` guids, and the sort values of the last match. To get the
+    /// next page, pass these as `search_after`. Unlike paging with `from`, this is not limited to
+    /// the first 10000 matches.
+    pub async fn find_post_guids(
+        &self,
+        query: &serde_json::Value,
+        search_after: Option<&serde_json::Value>,
+        size: usize,
+    ) -> Result<(Vec<String>, Option<serde_json::Value>), IndexError> {
+        let mut query = json!({
+            "query": query,
+            "size": size,
+            "sort": [{ "_id": "asc" }]
+        });
+        if let Some(search_after) = search_after {
+            query["search_after"] = search_after.clone();
+        }
+        let hits = self.index.query_hits(query).await?;
+        let ids = hits
+            .iter()
+            .filter_map(|hit| UntypedRecord::deserialize(&hit["_source"]).ok())
+            .map(|record| record.guid().to_string())
+            .collect();
+        let search_after = hits.last().map(|hit| hit["sort"].clone());
+        Ok((ids, search_after))
+    }
+
     pub async fn index_post_by_id(
         &self,
         db: &CouchDB,
@@ -73,14 +103,16 @@ impl PostIndex {
         Ok(res)
     }
 
+    /// Index a batch of changed records.
+    ///
+    /// Returns the guids of the records that failed to index, together with their errors.
     pub async fn index_changes(
         &self,
         db: &CouchDB,
         changes: &[UntypedRecord],
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<Vec<(String, anyhow::Error)>> {
         let now = time::Instant::now();
-        let mut sorted =
-            RecordMap::from_untyped(changes.to_vec()).context("Failed to upcast records")?;
+        let (mut sorted, mut failures) = RecordMap::from_untyped_lossy(changes.to_vec());
         let mut posts = sorted.into_hashmap::<Post>();
         let medias = sorted.into_hashmap::<Media>();
         let posts_from_changes_len = posts.len();
@@ -168,6 +200,10 @@ impl PostIndex {
         let res = self.index.put_typed_records(&posts).await;
         report_indexing_results(&res);
         let res = res.context("Failed to write records to index")?;
+        for (id, err) in res.errors() {
+            let error = anyhow::anyhow!("{}: {}", err.r#type, err.reason);
+            failures.push((Post::guid(&id), error));
+        }
         let stats = res.stats();
         log::debug!(
             "indexed {} changes as {} posts in {} (errors {}, {} post direct updates, {} media updates resulting in {} post updates)", 
@@ -180,7 +216,7 @@ impl PostIndex {
             posts.len() - posts_from_changes_len
         );
 
-        Ok(())
+        Ok(failures)
     }
 }
 
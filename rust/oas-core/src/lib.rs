@@ -6,10 +6,12 @@ use anyhow::Context;
 pub mod couch;
 // pub mod couch2;
 pub mod index;
+pub mod replay;
 pub mod rss;
 mod runtime;
 pub mod server;
 pub mod tasks;
+pub mod tenant;
 pub mod util;
 
 use crate::rss::FeedManager;
@@ -28,7 +30,7 @@ pub struct State {
     pub db_manager: CouchManager,
     pub db: couch::CouchDB,
     pub index_manager: index::IndexManager,
-    pub tasks: tasks::CeleryManager,
+    pub tasks: tasks::TaskManager,
     did_init: Arc<AtomicBool>,
 }
 
@@ -37,7 +39,7 @@ impl State {
         db_manager: CouchManager,
         db: CouchDB,
         index_manager: index::IndexManager,
-        tasks: tasks::CeleryManager,
+        tasks: tasks::TaskManager,
         feed_manager: FeedManager,
     ) -> Self {
         Self {
@@ -49,6 +51,21 @@ impl State {
             did_init: Arc::new(AtomicBool::new(false)),
         }
     }
+
+    /// Create a state with in-memory databases and the couch task queue, for tests.
+    ///
+    /// Elasticsearch is not connected until it is used.
+    #[cfg(test)]
+    pub(crate) fn in_memory() -> Self {
+        let db_manager = CouchManager::in_memory();
+        let db = db_manager.record_db().clone();
+        let jobs = tasks::JobStore::new(db_manager.meta_db().clone());
+        let tasks = tasks::TaskManager::with_queue(tasks::CouchQueue::new(jobs.clone()), jobs);
+        let index_manager = index::IndexManager::with_url(None::<&str>).unwrap();
+        let feed_manager = FeedManager::new(Default::default());
+        Self::new(db_manager, db, index_manager, tasks, feed_manager)
+    }
+
     /// Asynchronously init all services.
     ///
     /// Currently errors on the first failing init.
@@ -75,7 +92,7 @@ impl State {
         self.tasks
             .init()
             .await
-            .context("Failed to initialize Celery/Redis task manager.")?;
+            .context("Failed to initialize task queue.")?;
         Ok(())
     }
 }
@@ -0,0 +1,95 @@
+//! Replay of dead letters.
+//!
+//! Replaying a [DeadLetter] loads the current version of the failed record and runs it through
+//! the processing of the consumer that failed. If processing succeeds, the dead letter is removed.
+//! Otherwise it is updated with the new error.
+
+use anyhow::Context;
+use oas_common::types::Feed;
+use oas_common::Record;
+
+use crate::couch::DeadLetter;
+use crate::{index, rss, tasks, State};
+
+/// Result of replaying multiple dead letters.
+#[derive(Debug, Default, Clone)]
+pub struct ReplayStats {
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+/// Replay a single dead letter.
+pub async fn replay_dead_letter(state: &State, letter: Record<DeadLetter>) -> anyhow::Result<()> {
+    let dead_letters = state.db_manager.dead_letters();
+    let consumer = letter.value.consumer.as_str();
+    let record_id = letter.value.record_id.as_str();
+    log::info!("Replay record {} in {}", record_id, consumer);
+
+    let failure = match process_record(state, consumer, record_id).await {
+        Ok(failures) => failures.into_iter().next().map(|(_guid, err)| err),
+        Err(err) => Some(err),
+    };
+    match failure {
+        None => {
+            dead_letters.remove(letter.id()).await?;
+            Ok(())
+        }
+        Some(err) => {
+            let message = format!("{:#}", err);
+            dead_letters
+                .add(consumer, record_id, letter.value.seq.clone(), message, None)
+                .await?;
+            Err(err.context(format!("Replay of {} in {} failed", record_id, consumer)))
+        }
+    }
+}
+
+/// Replay all dead letters, optionally only those of a single consumer.
+pub async fn replay_dead_letters(
+    state: &State,
+    consumer: Option<&str>,
+) -> anyhow::Result<ReplayStats> {
+    let letters = state.db_manager.dead_letters().list(consumer).await?;
+    let mut stats = ReplayStats::default();
+    for letter in letters.into_iter() {
+        match replay_dead_letter(state, letter).await {
+            Ok(()) => stats.succeeded += 1,
+            Err(err) => {
+                log::warn!("{:#}", err);
+                stats.failed += 1;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Run the processing of a consumer for a single record.
+///
+/// The feed watcher cannot process records outside of its changes stream. For the feed watcher,
+/// the record is saved again, which restarts its watcher through the changes stream.
+async fn process_record(
+    state: &State,
+    consumer: &str,
+    record_id: &str,
+) -> anyhow::Result<Vec<(String, anyhow::Error)>> {
+    let record = state
+        .db
+        .get_record_untyped(record_id)
+        .await
+        .with_context(|| format!("Failed to load record {}", record_id))?;
+    match consumer {
+        index::CONSUMER_NAME => {
+            let post_index = state.index_manager.post_index();
+            post_index.index_changes(&state.db, &[record]).await
+        }
+        tasks::changes::CONSUMER_NAME => {
+            tasks::changes::process_batch(&state.tasks, state.db.clone(), vec![record]).await
+        }
+        rss::manager::CONSUMER_NAME => {
+            let record = record.into_typed_record::<Feed>()?;
+            state.db.put_record(record).await?;
+            Ok(vec![])
+        }
+        _ => Err(anyhow::anyhow!("Unknown changes consumer: {}", consumer)),
+    }
+}
@@ -2,21 +2,39 @@ use clap::Clap;
 use oas_common::types;
 use oas_common::types::Feed;
 use oas_common::util::id_from_hashed_string;
-use oas_common::TypedRecord;
+use oas_common::{TypedRecord, TypedValue};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
 use tokio::task::JoinHandle;
-use tokio_stream::StreamExt;
 
 use super::error::RssError;
 use super::mapping::{AllMappings, MappingManager};
 use super::FeedWatcher;
-use crate::couch::CouchDB;
+use crate::couch::{ChangesDispatcher, ChangesReceiver, CouchDB, CouchManager};
 
 type Task<T> = JoinHandle<Result<T, RssError>>;
+
+/// Name of the feed watcher as a changes consumer.
+pub const CONSUMER_NAME: &str = "feeds";
 // type Store = HashMap<String, TypedRecord<types::Feed>>;
 
+/// Capacity of the feed error broadcast channel.
+const ERROR_CHANNEL_CAPACITY: usize = 64;
+
+/// An error that occured while watching a feed.
+///
+/// These are broadcasted to all subscribers, see [FeedManager::subscribe_errors].
+#[derive(Debug, Clone)]
+pub struct FeedErrorEvent {
+    /// Guid of the feed record (if the feed is backed by a record).
+    pub guid: Option<String>,
+    /// Feed URL
+    pub url: String,
+    /// Error message
+    pub error: String,
+}
+
 #[derive(Debug, Clone, Default, Clap)]
 pub struct FeedManagerOpts {
     #[clap(long)]
@@ -34,15 +52,29 @@ impl FeedManagerOpts {
 #[derive(Debug, Clone)]
 pub struct FeedManager {
     pub(crate) inner: Arc<Mutex<FeedManagerInner>>,
+    errors: broadcast::Sender<FeedErrorEvent>,
 }
 
 impl FeedManager {
     pub fn new(opts: FeedManagerOpts) -> Self {
+        let (errors, _) = broadcast::channel(ERROR_CHANNEL_CAPACITY);
         Self {
             inner: Arc::new(Mutex::new(FeedManagerInner::new(opts))),
+            errors,
         }
     }
 
+    /// Create a new feed manager without feeds and with the same options.
+    pub async fn with_same_opts(&self) -> Self {
+        let opts = self.inner.lock().await.opts.clone();
+        Self::new(opts)
+    }
+
+    /// Subscribe to errors that occur while watching feeds.
+    pub fn subscribe_errors(&self) -> broadcast::Receiver<FeedErrorEvent> {
+        self.errors.subscribe()
+    }
+
     /// Init initial feed state.
     pub async fn init(&self, db: &CouchDB) -> anyhow::Result<()> {
         self.inner.lock().await.init(db).await
@@ -58,8 +90,37 @@ impl FeedManager {
     /// and then look for incoming feeds in the [ChangesStream].
     /// It will periodically fetch the feeds and insert new items
     /// as [Post]s and [Media]s into the database.
-    pub async fn run_watch(self, db: CouchDB) -> anyhow::Result<()> {
-        run_watch(self, db).await
+    pub async fn run_watch(self, db_manager: CouchManager) -> anyhow::Result<()> {
+        let db = db_manager.record_db().clone();
+        let mut dispatcher = ChangesDispatcher::new(db.clone(), true);
+        let changes = self.register_changes(&db_manager, &mut dispatcher).await?;
+        let (dispatch_res, watch_res) =
+            futures::join!(dispatcher.run(), self.run_watch_from(db, changes));
+        dispatch_res?;
+        watch_res?;
+        Ok(())
+    }
+
+    /// Register the feed watcher as a consumer on a [ChangesDispatcher].
+    ///
+    /// Without a checkpoint, the feed watcher starts at the current seq because all existing feeds
+    /// are loaded on start anyway.
+    pub async fn register_changes(
+        &self,
+        db_manager: &CouchManager,
+        dispatcher: &mut ChangesDispatcher,
+    ) -> anyhow::Result<ChangesReceiver> {
+        let last_seq = db_manager.record_db().get_last_seq().await?;
+        let changes = dispatcher
+            .register_with_checkpoint(CONSUMER_NAME, db_manager, Some(last_seq))
+            .await?;
+        Ok(changes)
+    }
+
+    /// Like [Self::run_watch], but look for incoming feeds in the changes received from a
+    /// [ChangesDispatcher].
+    pub async fn run_watch_from(self, db: CouchDB, changes: ChangesReceiver) -> anyhow::Result<()> {
+        run_watch(self, db, changes).await
     }
 
     // pub async fn run_crawl(self, db: CouchDB) -> anyhow::Result<()> {
@@ -129,7 +190,11 @@ impl FeedManagerInner {
     }
 }
 
-async fn run_watch(manager: FeedManager, db: CouchDB) -> anyhow::Result<()> {
+async fn run_watch(
+    manager: FeedManager,
+    db: CouchDB,
+    changes: ChangesReceiver,
+) -> anyhow::Result<()> {
     let tasks = start_feed_tasks(&manager, db.clone()).await?;
     let mapping = {
         manager
@@ -139,19 +204,17 @@ async fn run_watch(manager: FeedManager, db: CouchDB) -> anyhow::Result<()> {
             .mapping_manager
             .to_field_hashmap()
     };
-    let watch_task = tokio::spawn({
-        let db = db.clone();
-        async move { watch_changes(mapping, db).await }
-    });
-    for handle in tasks.into_iter() {
-        handle.await??;
-    }
-    watch_task.await??;
+    let errors = manager.errors.clone();
+    watch_changes(mapping, db, changes, errors, tasks).await?;
     Ok(())
 }
 
-async fn start_feed_tasks(manager: &FeedManager, db: CouchDB) -> Result<Vec<Task<()>>, RssError> {
-    let mut tasks = Vec::new();
+async fn start_feed_tasks(
+    manager: &FeedManager,
+    db: CouchDB,
+) -> Result<HashMap<String, Task<()>>, RssError> {
+    let mut tasks = HashMap::new();
+    let manager_errors = manager.errors.clone();
     let manager = manager.inner.lock().await;
     let store = &manager.store;
     let mapping = manager.mapping_manager.to_field_hashmap();
@@ -168,26 +231,36 @@ async fn start_feed_tasks(manager: &FeedManager, db: CouchDB) -> Result<Vec<Task
             mapping.clone(),
             Some(feed.clone()),
         )?;
+        watcher.set_error_sender(manager_errors.clone());
         let db = db.clone();
-        tasks.push(tokio::spawn(async move { watcher.watch(db).await }));
+        tasks.insert(
+            id.clone(),
+            tokio::spawn(async move { watcher.watch(db).await }),
+        );
     }
     Ok(tasks)
 }
 
-async fn watch_changes(mapping: AllMappings, db: CouchDB) -> Result<(), RssError> {
-    let last_seq = db.get_last_seq().await?;
-    let mut stream = db.changes(Some(last_seq));
-    let mut tasks = Vec::new();
-    stream.set_infinite(true);
+/// Watch for new or changed feeds.
+///
+/// When a feed changes, its current watcher is stopped and a new one is started.
+async fn watch_changes(
+    mapping: AllMappings,
+    db: CouchDB,
+    mut changes: ChangesReceiver,
+    errors: broadcast::Sender<FeedErrorEvent>,
+    mut tasks: HashMap<String, Task<()>>,
+) -> anyhow::Result<()> {
     let client = reqwest::Client::new();
-    while let Some(event) = stream.next().await {
-        let event = event?;
-        if let Some(doc) = event.doc {
-            let _id = doc.id().to_string();
-            let record = doc.into_typed_record::<types::Feed>();
+    while let Some(batch) = changes.next().await {
+        for untyped in batch.records_of_type(types::Feed::NAME) {
+            let record = untyped.clone().into_typed_record::<types::Feed>();
             match record {
-                Err(_err) => {}
+                Err(err) => {
+                    changes.reject(&batch, untyped.guid(), err).await?;
+                }
                 Ok(record) => {
+                    let id = record.id().to_string();
                     let url = record.value.url.clone();
                     let settings = record.value.settings.clone();
                     let mut watcher = FeedWatcher::with_client(
@@ -197,11 +270,19 @@ async fn watch_changes(mapping: AllMappings, db: CouchDB) -> Result<(), RssError
                         mapping.clone(),
                         Some(record),
                     )?;
+                    watcher.set_error_sender(errors.clone());
                     let db = db.clone();
-                    tasks.push(tokio::spawn(async move { watcher.watch(db).await }));
+                    let task = tokio::spawn(async move { watcher.watch(db).await });
+                    if let Some(previous_task) = tasks.insert(id, task) {
+                        previous_task.abort();
+                    }
                 }
             }
         }
+        changes.commit(&batch).await?;
+    }
+    for (_id, task) in tasks.into_iter() {
+        task.await??;
     }
     Ok(())
 }
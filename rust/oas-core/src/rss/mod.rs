@@ -8,6 +8,7 @@ use rss::extension::ExtensionMap;
 use rss::Channel;
 use std::collections::HashMap;
 use std::time::Duration;
+use tokio::sync::broadcast;
 use url::{ParseError, Url};
 
 use crate::types::{FeedSettings, Media};
@@ -19,7 +20,7 @@ pub mod mapping;
 pub mod ops;
 
 pub use error::{RssError, RssResult};
-pub use manager::FeedManager;
+pub use manager::{FeedErrorEvent, FeedManager};
 pub use ops::{Crawler, FetchedFeedPage, Next};
 
 #[derive(Debug, Clone)]
@@ -30,6 +31,7 @@ pub struct FeedWatcher {
     settings: FeedSettings,
     mapping: HashMap<String, String>,
     feed_record: Option<Record<Feed>>,
+    errors: Option<broadcast::Sender<FeedErrorEvent>>,
 }
 
 impl FeedWatcher {
@@ -58,10 +60,16 @@ impl FeedWatcher {
             settings: settings.unwrap_or_default(),
             mapping,
             feed_record,
+            errors: None,
         };
         Ok(feed)
     }
 
+    /// Set a channel to report watch errors to.
+    pub fn set_error_sender(&mut self, errors: broadcast::Sender<FeedErrorEvent>) {
+        self.errors = Some(errors);
+    }
+
     pub fn url(&self) -> &Url {
         &self.url
     }
@@ -69,12 +77,33 @@ impl FeedWatcher {
         let duration = Duration::from_secs(self.settings.check_interval);
         let mut interval = tokio::time::interval(duration);
         loop {
-            self.load().await?;
-            self.save(&db, false).await?;
+            let res = self.load_and_save(&db).await;
+            if let Err(err) = res {
+                self.report_error(&err);
+                return Err(err);
+            }
             interval.tick().await;
         }
     }
 
+    async fn load_and_save(&mut self, db: &CouchDB) -> Result<(), RssError> {
+        self.load().await?;
+        self.save(&db, false).await?;
+        Ok(())
+    }
+
+    fn report_error(&self, err: &RssError) {
+        if let Some(errors) = &self.errors {
+            let event = FeedErrorEvent {
+                guid: self.feed_record.as_ref().map(|r| r.guid().to_string()),
+                url: self.url.to_string(),
+                error: format!("{}", err),
+            };
+            // Sending only fails if there are no subscribers.
+            let _ = errors.send(event);
+        }
+    }
+
     pub async fn save(
         &mut self,
         db: &CouchDB,
@@ -0,0 +1,176 @@
+use oas_common::types::Feed;
+use oas_common::Reference;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+use super::roles::Permission;
+use super::structs::UserInfo;
+use super::{LoginError, SessionInfo};
+use crate::server::error::AppError;
+use crate::tasks::FeedAccess;
+
+/// Get the session of a request if its user has a permission.
+///
+/// Fails with 401 Unauthorized if the request has no valid session, and with 403 Forbidden if
+/// the user does not have the permission.
+pub(super) async fn require_permission(
+    request: &Request<'_>,
+    permission: Permission,
+) -> Outcome<SessionInfo, LoginError> {
+    match request.guard::<SessionInfo>().await {
+        Outcome::Success(session) if session.has_permission(permission) => {
+            Outcome::Success(session)
+        }
+        Outcome::Success(_) => Outcome::Failure((Status::Forbidden, LoginError::Forbidden)),
+        Outcome::Failure(x) => Outcome::Failure(x),
+        Outcome::Forward(x) => Outcome::Forward(x),
+    }
+}
+
+/// Get the session of a request if its user has a permission and belongs to the instance.
+///
+/// Like [require_permission], but also fails with 403 Forbidden for users of a tenant. This is
+/// used for the task and job endpoints, because tasks only run for the records of the instance.
+pub(super) async fn require_instance_permission(
+    request: &Request<'_>,
+    permission: Permission,
+) -> Outcome<SessionInfo, LoginError> {
+    match require_permission(request, permission).await {
+        Outcome::Success(session) if session.user().tenant.is_some() => {
+            Outcome::Failure((Status::Forbidden, LoginError::Forbidden))
+        }
+        outcome => outcome,
+    }
+}
+
+/// Fail with 403 Forbidden unless the user owns one of the feeds.
+///
+/// Admins may access all feeds. Records without feeds can only be accessed by admins.
+fn ensure_owns_any_feed<'a>(
+    user: &UserInfo,
+    mut feed_guids: impl Iterator<Item = &'a str>,
+) -> Result<(), AppError> {
+    if user.role.has_all_feeds() || feed_guids.any(|guid| user.owns_feed(guid)) {
+        Ok(())
+    } else {
+        Err(AppError::Http(
+            Status::Forbidden,
+            format!("User {} does not own the feed", user.username),
+        ))
+    }
+}
+
+/// Request guard for users that may manage feeds.
+#[derive(Debug, Clone)]
+pub struct FeedManager {
+    session: SessionInfo,
+}
+
+impl FeedManager {
+    /// Get the name of the user.
+    pub fn username(&self) -> &str {
+        &self.session.user().username
+    }
+
+    /// Check if the user may access all feeds.
+    pub fn has_all_feeds(&self) -> bool {
+        self.session.user().role.has_all_feeds()
+    }
+
+    /// Get the session of the user.
+    pub fn session(&self) -> &SessionInfo {
+        &self.session
+    }
+
+    /// Fail with 403 Forbidden unless the user owns the feed.
+    pub fn ensure_feed(&self, feed_guid: &str) -> Result<(), AppError> {
+        ensure_owns_any_feed(self.session.user(), std::iter::once(feed_guid))
+    }
+}
+
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for FeedManager {
+    type Error = LoginError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<FeedManager, LoginError> {
+        require_permission(request, Permission::ManageFeeds)
+            .await
+            .map(|session| FeedManager { session })
+    }
+}
+
+/// Request guard for users that may edit posts and media.
+#[derive(Debug, Clone)]
+pub struct PostEditor {
+    session: SessionInfo,
+}
+
+impl PostEditor {
+    /// Get the session of the user.
+    pub fn session(&self) -> &SessionInfo {
+        &self.session
+    }
+
+    /// Fail with 403 Forbidden unless the user owns one of the feeds of a record.
+    pub fn ensure_feeds(&self, feeds: &[Reference<Feed>]) -> Result<(), AppError> {
+        ensure_owns_any_feed(self.session.user(), feeds.iter().map(|feed| feed.guid()))
+    }
+}
+
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for PostEditor {
+    type Error = LoginError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<PostEditor, LoginError> {
+        require_permission(request, Permission::EditPosts)
+            .await
+            .map(|session| PostEditor { session })
+    }
+}
+
+/// Request guard for users that may start and cancel tasks.
+///
+/// Fails with 403 Forbidden for users of a tenant, see [require_instance_permission].
+#[derive(Debug, Clone)]
+pub struct TaskTrigger {
+    session: SessionInfo,
+}
+
+impl TaskTrigger {
+    /// Get the name of the user.
+    pub fn username(&self) -> &str {
+        &self.session.user().username
+    }
+
+    /// Get the feeds on whose records the user may start and cancel tasks.
+    pub fn feed_access(&self) -> FeedAccess {
+        let user = self.session.user();
+        if user.role.has_all_feeds() {
+            FeedAccess::All
+        } else {
+            FeedAccess::Feeds(user.feeds.clone())
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for TaskTrigger {
+    type Error = LoginError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<TaskTrigger, LoginError> {
+        require_instance_permission(request, Permission::TriggerTasks)
+            .await
+            .map(|session| TaskTrigger { session })
+    }
+}
+
+/// Request guard for users that may view private fields.
+#[derive(Debug, Clone)]
+pub struct PrivateReader {}
+
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for PrivateReader {
+    type Error = LoginError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<PrivateReader, LoginError> {
+        require_permission(request, Permission::ViewPrivate)
+            .await
+            .map(|_session| PrivateReader {})
+    }
+}
@@ -1,9 +1,12 @@
-use rocket::http::{Cookie, CookieJar, Status};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
 use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::Redirect;
 use rocket::serde::json::Json;
 use rocket::State;
-use rocket::{catch, get, post};
+use rocket::{catch, get, patch, post};
 use rocket_okapi::openapi;
+use std::future::Future;
+use std::net::IpAddr;
 use std::sync::Arc;
 use time::{Duration, OffsetDateTime};
 // use okapi::openapi3::Responses;
@@ -15,17 +18,33 @@ use time::{Duration, OffsetDateTime};
 // use serde::Serialize;
 
 mod basic_auth;
+mod guards;
+mod oidc;
 mod password;
+mod roles;
 mod sessions;
 mod store;
 mod structs;
+mod tokens;
+mod worker;
 
 use basic_auth::BasicAuth;
+pub use guards::{FeedManager, PostEditor, PrivateReader, TaskTrigger};
+pub use oidc::{OidcClient, OidcError, OidcOpts};
+use oidc::{PendingLogin, OIDC_STATE_COOKIE};
+pub use roles::{Permission, Role};
 pub use sessions::{SessionInfo, Sessions};
-use store::UserStore;
-use structs::{LoginRequest, LoginResponse, RegisterRequest};
-
-use super::error::AppError;
+use store::{UserStore, ADMIN_USERNAME};
+pub use structs::UserInfo;
+use structs::{LoginRequest, LoginResponse, RegisterRequest, UpdateUserRequest, UserPublicInfo};
+use tokens::ApiTokens;
+pub use tokens::{ApiTokenInfo, CreateTokenRequest, CreateTokenResponse};
+pub use worker::WorkerUser;
+
+use super::error::{self, AppError};
+use super::rate_limit::{ClientIp, RateLimiter};
+use crate::couch::{CouchDB, CouchError, CouchResult};
+use crate::tenant::Tenants;
 
 pub const SESSION_COOKIE: &str = "oas_session_id";
 pub const SESSION_HEADER: &str = "X-Oas-Session-Id";
@@ -34,6 +53,12 @@ pub const SESSION_EXPIRATION: Duration = time::Duration::weeks(8);
 #[derive(Debug, Clone)]
 pub enum LoginError {
     Unauthorized,
+    /// The user does not have the permission for a request.
+    Forbidden,
+    /// The user or session store could not be accessed.
+    Internal,
+    /// Logins for the user or client IP are locked after too many failed attempts.
+    Locked,
 }
 
 /// A session id is a string that is either taken from a private cookie "oas_session_id" or from a
@@ -46,81 +71,147 @@ async fn try_login(
     cookies: &CookieJar<'_>,
     login_request: &LoginRequest,
     session_id: Option<&SessionId>,
-) -> Option<SessionId> {
-    try_logout(&auth, &cookies, session_id).await;
-    if let Some(session_id) = auth.login(&login_request).await {
-        let cookie = {
-            let mut cookie = Cookie::new(SESSION_COOKIE, session_id.clone());
-            cookie.set_secure(true);
-            cookie.set_http_only(true);
-            let mut expiration = OffsetDateTime::now_utc();
-            expiration += SESSION_EXPIRATION;
-            cookie.set_expires(expiration);
-            cookie
-        };
-        cookies.add(cookie);
-        Some(SessionId(session_id))
+) -> CouchResult<Option<SessionId>> {
+    try_logout(&auth, &cookies, session_id).await?;
+    if let Some(session_id) = auth.login(&login_request).await? {
+        cookies.add(session_cookie(session_id.clone()));
+        Ok(Some(SessionId(session_id)))
     } else {
-        None
+        Ok(None)
     }
 }
 
-async fn try_logout(auth: &State<Auth>, cookies: &CookieJar<'_>, session_id: Option<&SessionId>) {
+/// Create the cookie for a new session.
+fn session_cookie(session_id: String) -> Cookie<'static> {
+    let mut cookie = Cookie::new(SESSION_COOKIE, session_id);
+    cookie.set_secure(true);
+    cookie.set_http_only(true);
+    let mut expiration = OffsetDateTime::now_utc();
+    expiration += SESSION_EXPIRATION;
+    cookie.set_expires(expiration);
+    cookie
+}
+
+async fn try_logout(
+    auth: &State<Auth>,
+    cookies: &CookieJar<'_>,
+    session_id: Option<&SessionId>,
+) -> CouchResult<()> {
     if let Some(session_id) = session_id {
-        auth.logout(&session_id.0).await;
+        auth.logout(&session_id.0).await?;
     }
 
     if let Some(session_cookie) = cookies.get(SESSION_COOKIE) {
-        auth.logout(session_cookie.value()).await;
+        auth.logout(session_cookie.value()).await?;
         cookies.remove(session_cookie.clone());
     }
+    Ok(())
+}
+
+/// Count a login attempt for the login lockout.
+async fn count_login(limiter: &RateLimiter, username: &str, ip: Option<IpAddr>, success: bool) {
+    if success {
+        limiter.login_succeeded(username, ip).await
+    } else {
+        limiter.login_failed(username, ip).await
+    }
+}
+
+/// Fail a request guard because the user or session store could not be accessed.
+fn internal_error<T>(err: CouchError) -> Outcome<T, LoginError> {
+    log::error!("Failed to access user or session store: {}", err);
+    Outcome::Failure((Status::InternalServerError, LoginError::Internal))
+}
+
+/// Outcome of a request guard, saved in the request-local cache.
+struct CachedOutcome<T>(Result<Option<T>, (Status, LoginError)>);
+
+impl<T: Clone> CachedOutcome<T> {
+    fn new(outcome: Outcome<T, LoginError>) -> Self {
+        Self(match outcome {
+            Outcome::Success(value) => Ok(Some(value)),
+            Outcome::Forward(()) => Ok(None),
+            Outcome::Failure(failure) => Err(failure),
+        })
+    }
+
+    fn outcome(&self) -> Outcome<T, LoginError> {
+        match &self.0 {
+            Ok(Some(value)) => Outcome::Success(value.clone()),
+            Ok(None) => Outcome::Forward(()),
+            Err(failure) => Outcome::Failure(failure.clone()),
+        }
+    }
+}
+
+/// Run a request guard only once per request.
+///
+/// The guards for sessions are used by several guards of a handler. Caching them makes sure
+/// that a basic auth login is only verified (and counted) once.
+async fn cached_outcome<T, F>(request: &Request<'_>, guard: F) -> Outcome<T, LoginError>
+where
+    T: Clone + Send + Sync + 'static,
+    F: Future<Output = Outcome<T, LoginError>>,
+{
+    request
+        .local_cache_async(async { CachedOutcome::new(guard.await) })
+        .await
+        .outcome()
 }
 
 #[async_trait::async_trait]
 impl<'r> FromRequest<'r> for SessionId {
     type Error = LoginError;
     async fn from_request(request: &'r Request<'_>) -> Outcome<SessionId, LoginError> {
-        let auth = request
-            .guard::<&State<Auth>>()
-            .await
-            .expect("Session state not registered");
-
-        // Check if a session id is set as a cookie.
-        let session_cookie = request.cookies().get(SESSION_COOKIE);
-        let session_id = session_cookie.map(|cookie| SessionId(cookie.value().to_string()));
-
-        // Check if a session id is set as a header.
-        let session_header = request.headers().get_one(SESSION_HEADER);
-        let session_id = if let Some(session_header) = session_header {
-            Some(SessionId(session_header.to_string()))
-        } else {
-            session_id
-        };
+        cached_outcome(request, session_id_from_request(request)).await
+    }
+}
 
-        // Allow to login via basic auth.
-        // If using basic auth while also having a session id set,
-        // first delete the session.
-        let session_id = if session_id.is_none() {
-            let basic_auth = request.guard::<BasicAuth>().await;
-            if let Outcome::Success(basic_auth) = basic_auth {
-                try_login(
-                    &auth,
-                    request.cookies(),
-                    &basic_auth.into(),
-                    session_id.as_ref(),
-                )
-                .await
-            } else {
-                None
-            }
-        } else {
-            session_id
-        };
+async fn session_id_from_request(request: &Request<'_>) -> Outcome<SessionId, LoginError> {
+    // Check if a session id is set as a cookie.
+    let session_cookie = request.cookies().get(SESSION_COOKIE);
+    let session_id = session_cookie.map(|cookie| SessionId(cookie.value().to_string()));
 
-        match session_id {
-            Some(id) => Outcome::Success(id),
-            None => Outcome::Forward(()),
-        }
+    // Check if a session id is set as a header.
+    let session_header = request.headers().get_one(SESSION_HEADER);
+    let session_id = if let Some(session_header) = session_header {
+        Some(SessionId(session_header.to_string()))
+    } else {
+        session_id
+    };
+
+    match session_id {
+        Some(id) => Outcome::Success(id),
+        None => Outcome::Forward(()),
+    }
+}
+
+/// Verify the credentials of a basic auth request.
+///
+/// No session is saved for basic auth, the session info only lives for the request.
+async fn basic_auth_session(
+    request: &Request<'_>,
+    auth: &Auth,
+    basic_auth: BasicAuth,
+) -> Outcome<SessionInfo, LoginError> {
+    let limiter = request
+        .guard::<&State<RateLimiter>>()
+        .await
+        .expect("Rate limiter not registered");
+    let login_request: LoginRequest = basic_auth.into();
+    let ip = limiter.client_ip(request);
+    if let Err(limit) = limiter.check_login(&login_request.username, ip).await {
+        limit.mark(request);
+        return Outcome::Failure((Status::TooManyRequests, LoginError::Locked));
+    }
+    let user = match auth.users.login(&login_request).await {
+        Ok(user) => user,
+        Err(err) => return internal_error(err),
+    };
+    count_login(&limiter, &login_request.username, ip, user.is_some()).await;
+    match user {
+        Some(user) => Outcome::Success(SessionInfo::for_user(user)),
+        None => Outcome::Failure((Status::Unauthorized, LoginError::Unauthorized)),
     }
 }
 
@@ -128,29 +219,99 @@ impl<'r> FromRequest<'r> for SessionId {
 impl<'r> FromRequest<'r> for SessionInfo {
     type Error = LoginError;
     async fn from_request(request: &'r Request<'_>) -> Outcome<SessionInfo, LoginError> {
-        let auth = request
-            .guard::<&State<Auth>>()
-            .await
-            .expect("Session state not registered");
+        cached_outcome(request, session_info_from_request(request)).await
+    }
+}
 
-        let session_id = request.guard::<SessionId>().await;
+async fn session_info_from_request(request: &Request<'_>) -> Outcome<SessionInfo, LoginError> {
+    let auth = request
+        .guard::<&State<Auth>>()
+        .await
+        .expect("Session state not registered");
+
+    // Check if an API token is set as a bearer token. The worker token is handled by the
+    // WorkerUser guard.
+    let bearer_token = request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|header| header.strip_prefix("Bearer "));
+    if let Some(token) = bearer_token {
+        if !auth.check_worker_token(token) {
+            return match auth.token_session(token).await {
+                Ok(Some(session)) => Outcome::Success(session),
+                Ok(None) => Outcome::Failure((Status::Unauthorized, LoginError::Unauthorized)),
+                Err(err) => internal_error(err),
+            };
+        }
+    }
 
-        match session_id {
-            Outcome::Success(session_id) => {
-                if let Some(session) = auth.sessions.get(&session_id.0).await {
-                    Outcome::Success((*session).clone())
-                } else {
-                    Outcome::Failure((Status::Unauthorized, LoginError::Unauthorized))
-                }
-            }
+    let session_id = request.guard::<SessionId>().await;
+
+    match session_id {
+        Outcome::Success(session_id) => match auth.session(&session_id.0).await {
+            Ok(Some(session)) => Outcome::Success(session),
+            Ok(None) => Outcome::Failure((Status::Unauthorized, LoginError::Unauthorized)),
+            Err(err) => internal_error(err),
+        },
+        // Allow to authenticate each request with basic auth.
+        _ => match request.guard::<BasicAuth>().await {
+            Outcome::Success(basic_auth) => basic_auth_session(request, auth, basic_auth).await,
             _ => Outcome::Failure((Status::Unauthorized, LoginError::Unauthorized)),
-        }
+        },
     }
 }
 
-/// Request guard marker struct for logged in users with "is_admin == true".
+/// Request guard for logged in users with the admin role.
 #[derive(Debug, Clone)]
-pub struct AdminUser {}
+pub struct AdminUser {
+    session: SessionInfo,
+}
+
+impl AdminUser {
+    /// Get the name of the user.
+    pub fn username(&self) -> &str {
+        &self.session.user().username
+    }
+
+    /// Get the session of the user.
+    pub fn session(&self) -> &SessionInfo {
+        &self.session
+    }
+
+    /// Get the tenant of the user, None for admins of the instance.
+    pub fn tenant(&self) -> Option<&str> {
+        self.session.user().tenant.as_deref()
+    }
+
+    /// Fail with 403 Forbidden unless the user is an admin of the instance.
+    pub fn ensure_instance_admin(&self) -> Result<(), AppError> {
+        match self.tenant() {
+            None => Ok(()),
+            Some(tenant) => Err(AppError::Http(
+                Status::Forbidden,
+                format!("User {} is an admin of tenant {}", self.username(), tenant),
+            )),
+        }
+    }
+
+    /// Fail with 403 Forbidden unless the user may manage users of a tenant.
+    ///
+    /// Admins of the instance may manage all users, admins of a tenant only the users of their
+    /// tenant.
+    pub fn ensure_tenant(&self, tenant: Option<&str>) -> Result<(), AppError> {
+        match self.tenant() {
+            Some(own) if Some(own) != tenant => Err(AppError::Http(
+                Status::Forbidden,
+                format!(
+                    "User {} may only manage users of tenant {}",
+                    self.username(),
+                    own
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
 
 #[async_trait::async_trait]
 impl<'r> FromRequest<'r> for AdminUser {
@@ -160,9 +321,9 @@ impl<'r> FromRequest<'r> for AdminUser {
         match session {
             Outcome::Success(session) => {
                 if session.is_admin() {
-                    Outcome::Success(AdminUser {})
+                    Outcome::Success(AdminUser { session })
                 } else {
-                    Outcome::Failure((Status::Unauthorized, LoginError::Unauthorized))
+                    Outcome::Failure((Status::Forbidden, LoginError::Forbidden))
                 }
             }
             Outcome::Failure(x) => Outcome::Failure(x),
@@ -172,54 +333,205 @@ impl<'r> FromRequest<'r> for AdminUser {
 }
 
 /// The auth state contains user and session stores.
+///
+/// Users and sessions are saved in the meta database, so they survive restarts and are shared
+/// between all core instances.
 #[derive(Debug, Clone)]
 pub struct Auth {
     pub(crate) sessions: Sessions,
     pub(crate) users: UserStore,
+    pub(crate) tokens: ApiTokens,
+    worker_token: Option<String>,
+    oidc: Option<OidcClient>,
 }
 
 impl Auth {
-    /// Create a new, empty auth state.
-    pub fn new() -> Self {
+    /// Create a new auth state on a database.
+    pub fn new(db: CouchDB) -> Self {
         Self {
-            sessions: Sessions::new(),
-            users: UserStore::new(),
+            sessions: Sessions::new(db.clone()),
+            users: UserStore::new(db.clone()),
+            tokens: ApiTokens::new(db),
+            worker_token: None,
+            oidc: None,
+        }
+    }
+
+    /// Set the OpenID Connect client for logins through an identity provider.
+    pub fn set_oidc(&mut self, oidc: Option<OidcClient>) {
+        self.oidc = oidc;
+    }
+
+    /// Get the OpenID Connect client, or fail with 404 if OpenID Connect is not configured.
+    fn oidc(&self) -> Result<&OidcClient, AppError> {
+        self.oidc.as_ref().ok_or_else(|| {
+            AppError::Http(
+                Status::NotFound,
+                "OpenID Connect login is not configured".to_string(),
+            )
+        })
+    }
+
+    /// Set the token that workers use to authenticate.
+    pub fn set_worker_token(&mut self, token: Option<String>) {
+        self.worker_token = token;
+    }
+
+    /// Check if a token is the worker token.
+    pub fn check_worker_token(&self, token: &str) -> bool {
+        match &self.worker_token {
+            Some(worker_token) => {
+                worker::constant_time_eq(worker_token.as_bytes(), token.as_bytes())
+            }
+            None => false,
         }
     }
 
-    pub async fn ensure_admin_user(&self, password: &str) {
-        self.users.add_admin_user(password).await;
+    /// Make sure that the admin user exists with a password.
+    ///
+    /// If the password of the admin user changed, all sessions of the admin user are removed.
+    pub async fn ensure_admin_user(&self, password: &str) -> CouchResult<()> {
+        if self.users.add_admin_user(password).await? {
+            let removed = self.sessions.remove_for_user(ADMIN_USERNAME).await?;
+            if removed > 0 {
+                log::info!("Admin password changed: Removed {} admin sessions", removed);
+            }
+        }
+        Ok(())
     }
 
     /// Try to login with username and password. Returns a new, random session ID in case of
     /// success.
-    pub async fn login(&self, req: &LoginRequest) -> Option<String> {
-        let user = self.users.login(&req).await;
+    pub async fn login(&self, req: &LoginRequest) -> CouchResult<Option<String>> {
+        let user = self.users.login(&req).await?;
         if let Some(user) = user {
-            // let user_info = UserInfo {
-            //     username: username.to_string(),
-            // };
             let session_id = generate_session_id();
-            // let user = AdminUser {};
-            let info = SessionInfo {
-                is_admin: true,
-                user: Arc::new(user),
-            };
-            self.sessions.insert(&session_id, info).await;
-            Some(session_id)
+            self.sessions.insert(&session_id, &user.username).await?;
+            Ok(Some(session_id))
         } else {
-            None
+            Ok(None)
         }
     }
 
+    /// Finish an OpenID Connect login. Returns a new, random session ID in case of success.
+    pub async fn oidc_login(
+        &self,
+        login: &PendingLogin,
+        code: &str,
+        state: &str,
+    ) -> Result<String, AppError> {
+        let oidc_user = self.oidc()?.finish_login(login, code, state).await?;
+        let user = self.users.upsert_oidc_user(oidc_user).await?;
+        let session_id = generate_session_id();
+        self.sessions.insert(&session_id, &user.username).await?;
+        log::info!("User {} logged in through OpenID Connect", user.username);
+        Ok(session_id)
+    }
+
     /// Get the session info for an active session.
-    pub async fn session(&self, session_id: &str) -> Option<Arc<SessionInfo>> {
-        self.sessions.get(session_id).await
+    ///
+    /// Returns None if the session does not exist, is expired or its user was deleted.
+    pub async fn session(&self, session_id: &str) -> CouchResult<Option<SessionInfo>> {
+        let session = match self.sessions.get(session_id).await? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        let user = self.users.get(&session.username).await?;
+        Ok(user.map(SessionInfo::for_user))
+    }
+
+    /// Get the session info for an API token.
+    ///
+    /// Returns None if the token is invalid, expired or revoked, or if its user was deleted.
+    pub async fn token_session(&self, token: &str) -> CouchResult<Option<SessionInfo>> {
+        let token = match self.tokens.verify(token).await? {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let user = self.users.get(&token.value.username).await?;
+        let token_id = token.id().to_string();
+        Ok(user.map(|user| SessionInfo {
+            user: Arc::new(user),
+            scopes: Some(Arc::new(token.value.scopes)),
+            token: Some(token_id),
+        }))
+    }
+
+    /// Create a new API token for the user of a session.
+    ///
+    /// The scopes of the token are limited to the permissions of the session.
+    pub async fn create_token(
+        &self,
+        session: &SessionInfo,
+        req: CreateTokenRequest,
+    ) -> Result<CreateTokenResponse, AppError> {
+        if let Some(scope) = req
+            .scopes
+            .iter()
+            .find(|scope| !session.has_permission(**scope))
+        {
+            return Err(AppError::Http(
+                Status::Forbidden,
+                format!("Permission {:?} cannot be granted", scope),
+            ));
+        }
+        let response = self.tokens.create(&session.user().username, req).await?;
+        Ok(response)
+    }
+
+    /// List the API tokens of the user of a session, or all tokens for admins.
+    pub async fn list_tokens(&self, session: &SessionInfo) -> CouchResult<Vec<ApiTokenInfo>> {
+        // Admins of tenants only see their own tokens, as tokens are not scoped to tenants.
+        let username = if session.is_admin() && session.user().tenant.is_none() {
+            None
+        } else {
+            Some(session.user().username.as_str())
+        };
+        let tokens = self.tokens.list(username).await?;
+        Ok(tokens.into_iter().map(ApiTokenInfo::from).collect())
+    }
+
+    /// Revoke an API token. Users can revoke their own tokens, admins of the instance can revoke
+    /// all tokens.
+    pub async fn revoke_token(&self, session: &SessionInfo, id: &str) -> Result<(), AppError> {
+        let token = self.tokens.get(id).await?;
+        match token {
+            Some(token)
+                if (session.is_admin() && session.user().tenant.is_none())
+                    || token.value.username == session.user().username =>
+            {
+                self.tokens.revoke(id).await?;
+                Ok(())
+            }
+            _ => Err(AppError::Http(
+                Status::NotFound,
+                format!("Token {} not found", id),
+            )),
+        }
     }
 
     /// Logout a user by session id.
-    pub async fn logout(&self, session_id: &str) {
-        self.sessions.remove(&session_id).await;
+    pub async fn logout(&self, session_id: &str) -> CouchResult<()> {
+        self.sessions.remove(&session_id).await
+    }
+
+    /// Update a user. If the password is changed, all sessions of the user are removed.
+    pub async fn update_user(
+        &self,
+        username: &str,
+        req: UpdateUserRequest,
+    ) -> Result<UserPublicInfo, AppError> {
+        let password_changed = req.password.is_some();
+        let user = self.users.update(username, req).await?;
+        if password_changed {
+            self.sessions.remove_for_user(username).await?;
+        }
+        Ok(user.into_public())
+    }
+
+    /// Add a feed to the feeds that a user owns.
+    pub async fn add_feed_owner(&self, username: &str, feed_guid: &str) -> CouchResult<()> {
+        self.users.add_feed(username, feed_guid).await
     }
 }
 
@@ -242,23 +554,31 @@ pub async fn get_login(session: Option<SessionInfo>) -> Json<LoginResponse> {
 #[post("/login", data = "<data>")]
 pub async fn post_login(
     auth: &State<Auth>,
+    limiter: &State<RateLimiter>,
     cookies: &CookieJar<'_>,
     data: Json<LoginRequest>,
     session_id: Option<SessionId>,
-) -> Json<LoginResponse> {
-    let session_id = try_login(&auth, &cookies, &data, session_id.as_ref()).await;
-    if let Some(session_id) = session_id {
-        let session = auth.session(&session_id.0).await.unwrap();
+    ip: ClientIp,
+) -> error::Result<LoginResponse> {
+    let ip = ip.0;
+    limiter.check_login(&data.username, ip).await?;
+    let session_id = try_login(&auth, &cookies, &data, session_id.as_ref()).await?;
+    count_login(&limiter, &data.username, ip, session_id.is_some()).await;
+    let session = match session_id {
+        Some(session_id) => auth.session(&session_id.0).await?,
+        None => None,
+    };
+    if let Some(session) = session {
         let public_user_info = session.user().into_public();
-        Json(LoginResponse {
+        Ok(Json(LoginResponse {
             ok: true,
             user: Some(public_user_info),
-        })
+        }))
     } else {
-        Json(LoginResponse {
+        Ok(Json(LoginResponse {
             ok: false,
             user: None,
-        })
+        }))
     }
 }
 
@@ -268,20 +588,65 @@ pub async fn logout(
     auth: &State<Auth>,
     cookies: &CookieJar<'_>,
     session_id: Option<SessionId>,
-) -> Json<()> {
-    try_logout(&auth, &cookies, session_id.as_ref()).await;
-    Json(())
+) -> error::Result<()> {
+    try_logout(&auth, &cookies, session_id.as_ref()).await?;
+    Ok(Json(()))
 }
 
 #[openapi(tag = "Login")]
 #[post("/register", data = "<user>")]
 pub async fn register(
-    _is_admin: AdminUser,
+    admin: AdminUser,
     auth: &State<Auth>,
+    tenants: &State<Tenants>,
     user: Json<RegisterRequest>,
-) -> Json<()> {
-    auth.users.register(user.into_inner(), false).await;
-    Json(())
+) -> error::Result<()> {
+    let mut user = user.into_inner();
+    // Users registered by admins of a tenant belong to the same tenant.
+    if user.tenant.is_none() {
+        user.tenant = admin.tenant().map(str::to_string);
+    }
+    admin.ensure_tenant(user.tenant.as_deref())?;
+    if let Some(tenant) = &user.tenant {
+        if tenants.get(tenant).await?.is_none() {
+            return Err(AppError::Http(
+                Status::UnprocessableEntity,
+                format!("Tenant {} does not exist", tenant),
+            ));
+        }
+    }
+    auth.users.register(user).await?;
+    Ok(Json(()))
+}
+
+#[openapi(tag = "Login")]
+#[get("/users")]
+pub async fn get_users(admin: AdminUser, auth: &State<Auth>) -> error::Result<Vec<UserPublicInfo>> {
+    let users = auth.users.list().await?;
+    Ok(Json(
+        users
+            .iter()
+            .filter(|user| admin.ensure_tenant(user.tenant.as_deref()).is_ok())
+            .map(|user| user.into_public())
+            .collect(),
+    ))
+}
+
+/// Update the password, email, role or feeds of a user
+#[openapi(tag = "Login")]
+#[patch("/user/<username>", data = "<data>")]
+pub async fn patch_user(
+    admin: AdminUser,
+    auth: &State<Auth>,
+    username: String,
+    data: Json<UpdateUserRequest>,
+) -> error::Result<UserPublicInfo> {
+    if let Some(user) = auth.users.get(&username).await? {
+        admin.ensure_tenant(user.tenant.as_deref())?;
+    }
+    let user = auth.update_user(&username, data.into_inner()).await?;
+    log::info!("User {} updated by {}", username, admin.username());
+    Ok(Json(user))
 }
 
 #[openapi(tag = "Login")]
@@ -290,11 +655,63 @@ pub async fn private(_user: AdminUser) -> String {
     format!("you're logged in!")
 }
 
+/// Login through the OpenID Connect identity provider
+///
+/// Redirects to the identity provider, which redirects back to `/oidc/callback`.
+#[openapi(skip)]
+#[get("/oidc/login")]
+pub async fn oidc_login(auth: &State<Auth>, cookies: &CookieJar<'_>) -> Result<Redirect, AppError> {
+    let login = PendingLogin::generate();
+    let url = auth.oidc()?.authorization_url(&login).await?;
+    let mut cookie = Cookie::new(OIDC_STATE_COOKIE, login.to_cookie_value());
+    cookie.set_secure(true);
+    cookie.set_http_only(true);
+    // The callback is a cross-site redirect from the identity provider.
+    cookie.set_same_site(SameSite::Lax);
+    cookies.add(cookie);
+    Ok(Redirect::to(url))
+}
+
+/// Callback for the OpenID Connect identity provider
+///
+/// Creates a session like `POST /login` and redirects to the frontend.
+#[openapi(skip)]
+#[get("/oidc/callback?<code>&<state>&<error>")]
+pub async fn oidc_callback(
+    auth: &State<Auth>,
+    cookies: &CookieJar<'_>,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    session_id: Option<SessionId>,
+) -> Result<Redirect, AppError> {
+    let login = cookies
+        .get(OIDC_STATE_COOKIE)
+        .and_then(|cookie| PendingLogin::from_cookie_value(cookie.value()));
+    cookies.remove(Cookie::named(OIDC_STATE_COOKIE));
+    if let Some(error) = error {
+        return Err(OidcError::Denied(error).into());
+    }
+    let (login, code, state) = match (login, code, state) {
+        (Some(login), Some(code), Some(state)) => (login, code, state),
+        _ => return Err(OidcError::Invalid("Missing code or state".into()).into()),
+    };
+    try_logout(&auth, &cookies, session_id.as_ref()).await?;
+    let session_id = auth.oidc_login(&login, &code, &state).await?;
+    cookies.add(session_cookie(session_id));
+    Ok(Redirect::to("/"))
+}
+
 #[catch(401)]
 pub fn unauthorized(_req: &rocket::Request) -> AppError {
     AppError::Unauthorized
 }
 
+#[catch(403)]
+pub fn forbidden(_req: &rocket::Request) -> AppError {
+    AppError::Http(Status::Forbidden, "Forbidden".to_string())
+}
+
 // /// Helper enum to implement an okapi responder.
 // /// This only sets an Unauthorized status code for the second variant.
 //
@@ -354,3 +771,63 @@ fn generate_session_id() -> String {
     let encoded = base32::encode(base32::Alphabet::Crockford, &random_bytes[..]);
     encoded.to_lowercase()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::rate_limit::{MemoryRateLimitStore, RateLimitOpts};
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use sessions::Session;
+
+    #[get("/user")]
+    fn user(session: SessionInfo) -> String {
+        session.user().username.clone()
+    }
+
+    #[tokio::test]
+    async fn basic_auth_without_session() {
+        let db = CouchDB::in_memory("meta");
+        let auth = Auth::new(db.clone());
+        let user_request = RegisterRequest {
+            password: "secret".to_string(),
+            username: "alice".to_string(),
+            email: None,
+            role: Role::default(),
+            feeds: vec![],
+            tenant: None,
+        };
+        auth.users.register(user_request).await.unwrap();
+        let opts = RateLimitOpts::default();
+        let limiter = RateLimiter::with_store(MemoryRateLimitStore::new(), &opts);
+        let rocket = rocket::build()
+            .manage(auth)
+            .manage(limiter)
+            .mount("/", rocket::routes![user]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let basic_auth = |credentials: &str| {
+            let value = format!("Basic {}", base64::encode(credentials));
+            Header::new("Authorization", value)
+        };
+        for _ in 0..2 {
+            let res = client
+                .get("/user")
+                .header(basic_auth("alice:secret"))
+                .dispatch()
+                .await;
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.into_string().await.as_deref(), Some("alice"));
+        }
+        let res = client
+            .get("/user")
+            .header(basic_auth("alice:wrong"))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Unauthorized);
+
+        // Requests with basic auth don't save sessions.
+        let sessions = db.table::<Session>().get_all().await.unwrap();
+        assert!(sessions.is_empty());
+    }
+}
@@ -0,0 +1,652 @@
+//! OpenID Connect login.
+//!
+//! Users are redirected to the identity provider (IdP) and come back with an authorization code,
+//! which is exchanged for an ID token at the token endpoint of the IdP. The ID token is received
+//! directly from the IdP over TLS, so (as allowed by OpenID Connect Core 3.1.3.7) its issuer is
+//! validated through the connection instead of the token signature. This is why the issuer and
+//! its endpoints must be https URLs, unless `--oidc-allow-insecure` is set for development.
+//! Users are created or updated on each login, with a role mapped from a claim of the IdP.
+
+use chrono::Utc;
+use clap::Clap;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+use super::roles::Role;
+
+/// Cookie that holds the state and nonce of a pending login.
+pub const OIDC_STATE_COOKIE: &str = "oas_oidc_state";
+
+const DEFAULT_USERNAME_CLAIM: &str = "preferred_username";
+const DEFAULT_ROLE_CLAIM: &str = "groups";
+
+/// OpenID Connect config.
+#[derive(Clap, Default, Clone, Debug)]
+pub struct OidcOpts {
+    /// OpenID Connect issuer URL. If set, users can login through the identity provider.
+    #[clap(long, env = "OIDC_ISSUER")]
+    pub oidc_issuer: Option<String>,
+    /// OpenID Connect client id
+    #[clap(long, env = "OIDC_CLIENT_ID")]
+    pub oidc_client_id: Option<String>,
+    /// OpenID Connect client secret
+    #[clap(long, env = "OIDC_CLIENT_SECRET")]
+    pub oidc_client_secret: Option<String>,
+    /// URL of the login callback, e.g. https://oas.example.org/api/v1/oidc/callback
+    #[clap(long, env = "OIDC_REDIRECT_URL")]
+    pub oidc_redirect_url: Option<String>,
+    /// Claim to take the username from (default: preferred_username)
+    #[clap(long, env = "OIDC_USERNAME_CLAIM")]
+    pub oidc_username_claim: Option<String>,
+    /// Claim to map to roles (default: groups)
+    #[clap(long, env = "OIDC_ROLE_CLAIM")]
+    pub oidc_role_claim: Option<String>,
+    /// Mapping of role claim values to roles, e.g. "oas-admins=admin,oas-staff=editor"
+    #[clap(long, env = "OIDC_ROLE_MAPPING")]
+    pub oidc_role_mapping: Option<String>,
+    /// Role of users without a mapped claim value. If not set, these users cannot login.
+    #[clap(long, env = "OIDC_DEFAULT_ROLE")]
+    pub oidc_default_role: Option<String>,
+    /// Allow an issuer and endpoints without https. The ID token is then not protected, so only
+    /// use this for development.
+    #[clap(long)]
+    pub oidc_allow_insecure: bool,
+}
+
+/// Errors of the OpenID Connect login.
+#[derive(Error, Debug)]
+pub enum OidcError {
+    #[error("OpenID Connect config: {0}")]
+    Config(String),
+    #[error("Identity provider: {0}")]
+    Provider(String),
+    #[error("Invalid login: {0}")]
+    Invalid(String),
+    #[error("Login denied: {0}")]
+    Denied(String),
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Provider(err.to_string())
+    }
+}
+
+/// The endpoints of an identity provider, from its discovery document.
+#[derive(Deserialize, Debug, Clone)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+    id_token: String,
+}
+
+/// A user that logged in through the identity provider.
+#[derive(Debug, Clone)]
+pub struct OidcUser {
+    /// The subject identifier of the user at the identity provider.
+    pub subject: String,
+    pub username: String,
+    pub email: Option<String>,
+    pub role: Role,
+}
+
+/// A pending login, saved in a cookie between the redirect to the IdP and the callback.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingLogin {
+    pub state: String,
+    pub nonce: String,
+}
+
+impl PendingLogin {
+    /// Create a new pending login with a random state and nonce.
+    pub fn generate() -> Self {
+        Self {
+            state: super::generate_session_id(),
+            nonce: super::generate_session_id(),
+        }
+    }
+
+    /// Encode the pending login as a cookie value.
+    pub fn to_cookie_value(&self) -> String {
+        format!("{}.{}", self.state, self.nonce)
+    }
+
+    /// Decode a pending login from a cookie value.
+    pub fn from_cookie_value(value: &str) -> Option<Self> {
+        let (state, nonce) = value.split_once('.')?;
+        Some(Self {
+            state: state.to_string(),
+            nonce: nonce.to_string(),
+        })
+    }
+}
+
+/// OpenID Connect client.
+#[derive(Debug, Clone)]
+pub struct OidcClient {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    username_claim: String,
+    role_claim: String,
+    role_mapping: HashMap<String, Role>,
+    default_role: Option<Role>,
+    allow_insecure: bool,
+    client: reqwest::Client,
+    metadata: Arc<RwLock<Option<Arc<ProviderMetadata>>>>,
+}
+
+impl OidcClient {
+    /// Create a client from the config.
+    ///
+    /// Returns None if no issuer is configured.
+    pub fn from_opts(opts: OidcOpts) -> Result<Option<Self>, OidcError> {
+        let issuer = match opts.oidc_issuer {
+            Some(issuer) => issuer.trim_end_matches('/').to_string(),
+            None => return Ok(None),
+        };
+        let require = |value: Option<String>, name: &str| {
+            value.ok_or_else(|| OidcError::Config(format!("{} is required", name)))
+        };
+        if !opts.oidc_allow_insecure {
+            require_https(&issuer, "OIDC_ISSUER").map_err(OidcError::Config)?;
+        }
+        let client_id = require(opts.oidc_client_id, "OIDC_CLIENT_ID")?;
+        let client_secret = require(opts.oidc_client_secret, "OIDC_CLIENT_SECRET")?;
+        let redirect_url = require(opts.oidc_redirect_url, "OIDC_REDIRECT_URL")?;
+        let role_mapping = parse_role_mapping(opts.oidc_role_mapping.as_deref().unwrap_or(""))?;
+        let default_role = opts
+            .oidc_default_role
+            .as_deref()
+            .map(parse_role)
+            .transpose()?;
+        Ok(Some(Self {
+            issuer,
+            client_id,
+            client_secret,
+            redirect_url,
+            username_claim: opts
+                .oidc_username_claim
+                .unwrap_or_else(|| DEFAULT_USERNAME_CLAIM.to_string()),
+            role_claim: opts
+                .oidc_role_claim
+                .unwrap_or_else(|| DEFAULT_ROLE_CLAIM.to_string()),
+            role_mapping,
+            default_role,
+            allow_insecure: opts.oidc_allow_insecure,
+            client: reqwest::Client::new(),
+            metadata: Default::default(),
+        }))
+    }
+
+    /// Get the URL of the identity provider to redirect a user to for a login.
+    pub async fn authorization_url(&self, login: &PendingLogin) -> Result<String, OidcError> {
+        let metadata = self.metadata().await?;
+        let mut url = url::Url::parse(&metadata.authorization_endpoint)
+            .map_err(|err| OidcError::Provider(err.to_string()))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", "openid profile email")
+            .append_pair("state", &login.state)
+            .append_pair("nonce", &login.nonce);
+        Ok(url.to_string())
+    }
+
+    /// Finish a login with the authorization code from the callback.
+    pub async fn finish_login(
+        &self,
+        login: &PendingLogin,
+        code: &str,
+        state: &str,
+    ) -> Result<OidcUser, OidcError> {
+        if !super::worker::constant_time_eq(login.state.as_bytes(), state.as_bytes()) {
+            return Err(OidcError::Invalid("State mismatch".into()));
+        }
+        let metadata = self.metadata().await?;
+        let params = [
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.redirect_url.as_str()),
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+        ];
+        let res = self
+            .client
+            .post(&metadata.token_endpoint)
+            .form(&params)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            return Err(OidcError::Provider(format!(
+                "Token request failed: {} {}",
+                status, body
+            )));
+        }
+        let tokens: TokenResponse = res.json().await?;
+
+        let mut claims = decode_id_token(&tokens.id_token)?;
+        self.validate_id_token(&metadata, &claims, &login.nonce)?;
+        if let Some(userinfo_endpoint) = &metadata.userinfo_endpoint {
+            let userinfo: Map<String, Value> = self
+                .client
+                .get(userinfo_endpoint)
+                .bearer_auth(&tokens.access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            if userinfo.get("sub") != claims.get("sub") {
+                return Err(OidcError::Invalid("Userinfo subject mismatch".into()));
+            }
+            for (key, value) in userinfo.into_iter() {
+                claims.entry(key).or_insert(value);
+            }
+        }
+        self.user_from_claims(&claims)
+    }
+
+    fn validate_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        claims: &Map<String, Value>,
+        nonce: &str,
+    ) -> Result<(), OidcError> {
+        let invalid = |message: &str| Err(OidcError::Invalid(message.to_string()));
+        let issuer = claims.get("iss").and_then(Value::as_str).unwrap_or("");
+        if issuer.trim_end_matches('/') != metadata.issuer.trim_end_matches('/') {
+            return invalid("Issuer mismatch");
+        }
+        let audience_matches = match claims.get("aud") {
+            Some(Value::String(aud)) => aud == &self.client_id,
+            Some(Value::Array(aud)) => aud.iter().any(|aud| aud == &self.client_id),
+            _ => false,
+        };
+        if !audience_matches {
+            return invalid("Audience mismatch");
+        }
+        let expires = claims.get("exp").and_then(Value::as_i64).unwrap_or(0);
+        if expires <= Utc::now().timestamp() {
+            return invalid("ID token expired");
+        }
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return invalid("Nonce mismatch");
+        }
+        Ok(())
+    }
+
+    fn user_from_claims(&self, claims: &Map<String, Value>) -> Result<OidcUser, OidcError> {
+        let claim = |name: &str| claims.get(name).and_then(Value::as_str).map(str::to_string);
+        let subject = claim("sub").ok_or_else(|| OidcError::Invalid("Missing subject".into()))?;
+        let username = claim(&self.username_claim)
+            .ok_or_else(|| OidcError::Invalid(format!("Missing claim {}", self.username_claim)))?;
+        let values: Vec<&str> = match claims.get(&self.role_claim) {
+            Some(Value::String(value)) => vec![value.as_str()],
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        let role = values
+            .iter()
+            .filter_map(|value| self.role_mapping.get(*value))
+            .min()
+            .copied()
+            .or(self.default_role)
+            .ok_or_else(|| OidcError::Denied(format!("No role for user {}", username)))?;
+        Ok(OidcUser {
+            subject,
+            username,
+            email: claim("email"),
+            role,
+        })
+    }
+
+    /// Get the provider metadata, fetching the discovery document on first use.
+    async fn metadata(&self) -> Result<Arc<ProviderMetadata>, OidcError> {
+        if let Some(metadata) = self.metadata.read().await.as_ref() {
+            return Ok(metadata.clone());
+        }
+        let url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let metadata: ProviderMetadata = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        self.check_metadata(&metadata)?;
+        let metadata = Arc::new(metadata);
+        *self.metadata.write().await = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Check that the discovery document belongs to the configured issuer, and that the
+    /// endpoints use https.
+    fn check_metadata(&self, metadata: &ProviderMetadata) -> Result<(), OidcError> {
+        if metadata.issuer.trim_end_matches('/') != self.issuer {
+            return Err(OidcError::Provider(format!(
+                "Discovery document is for issuer {}",
+                metadata.issuer
+            )));
+        }
+        if self.allow_insecure {
+            return Ok(());
+        }
+        let endpoints = [
+            Some((&metadata.authorization_endpoint, "authorization_endpoint")),
+            Some((&metadata.token_endpoint, "token_endpoint")),
+            metadata
+                .userinfo_endpoint
+                .as_ref()
+                .map(|endpoint| (endpoint, "userinfo_endpoint")),
+        ];
+        for (url, name) in endpoints.iter().flatten() {
+            require_https(url, name).map_err(OidcError::Provider)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fail unless a URL uses https.
+fn require_https(url: &str, name: &str) -> Result<(), String> {
+    match url::Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "https" => Ok(()),
+        _ => Err(format!("{} must be a https URL: {}", name, url)),
+    }
+}
+
+/// Decode the claims of an ID token, without verifying its signature.
+///
+/// Only use this for ID tokens that were received from the token endpoint over https.
+fn decode_id_token(id_token: &str) -> Result<Map<String, Value>, OidcError> {
+    let payload = id_token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| OidcError::Invalid("Malformed ID token".into()))?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| OidcError::Invalid("Malformed ID token".into()))?;
+    serde_json::from_slice(&payload).map_err(|_| OidcError::Invalid("Malformed ID token".into()))
+}
+
+fn parse_role(name: &str) -> Result<Role, OidcError> {
+    serde_json::from_value(Value::String(name.trim().to_string()))
+        .map_err(|_| OidcError::Config(format!("Unknown role: {}", name)))
+}
+
+/// Parse a role mapping of the form "claim-value=role,other-value=role".
+fn parse_role_mapping(mapping: &str) -> Result<HashMap<String, Role>, OidcError> {
+    mapping
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let (value, role) = part
+                .rsplit_once('=')
+                .ok_or_else(|| OidcError::Config(format!("Invalid role mapping: {}", part)))?;
+            Ok((value.trim().to_string(), parse_role(role)?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+
+    fn opts(issuer: &str) -> OidcOpts {
+        OidcOpts {
+            oidc_issuer: Some(issuer.to_string()),
+            oidc_client_id: Some("oas".to_string()),
+            oidc_client_secret: Some("secret".to_string()),
+            oidc_redirect_url: Some("https://oas.example.org/api/v1/oidc/callback".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn require_https_issuer() {
+        assert!(OidcClient::from_opts(opts("https://idp.example.org/")).is_ok());
+        let err = OidcClient::from_opts(opts("http://idp.example.org")).unwrap_err();
+        assert!(matches!(err, OidcError::Config(_)));
+        let insecure = OidcOpts {
+            oidc_allow_insecure: true,
+            ..opts("http://localhost:8081")
+        };
+        assert!(OidcClient::from_opts(insecure).is_ok());
+    }
+
+    #[test]
+    fn check_metadata() {
+        let client = OidcClient::from_opts(opts("https://idp.example.org"))
+            .unwrap()
+            .unwrap();
+        let mut metadata = ProviderMetadata {
+            issuer: "https://idp.example.org/".to_string(),
+            authorization_endpoint: "https://idp.example.org/auth".to_string(),
+            token_endpoint: "https://idp.example.org/token".to_string(),
+            userinfo_endpoint: None,
+        };
+        assert!(client.check_metadata(&metadata).is_ok());
+        metadata.userinfo_endpoint = Some("http://idp.example.org/userinfo".to_string());
+        assert!(client.check_metadata(&metadata).is_err());
+        metadata.userinfo_endpoint = None;
+        metadata.issuer = "https://evil.example.org".to_string();
+        assert!(client.check_metadata(&metadata).is_err());
+    }
+
+    /// A local identity provider that answers the discovery, token and userinfo requests.
+    struct MockIdp {
+        issuer: String,
+        claims: Arc<Mutex<Value>>,
+        userinfo: Arc<Mutex<Value>>,
+    }
+
+    impl MockIdp {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let claims = Arc::new(Mutex::new(Value::Null));
+            let userinfo = Arc::new(Mutex::new(Value::Null));
+            let idp = Self {
+                issuer: issuer.clone(),
+                claims: claims.clone(),
+                userinfo: userinfo.clone(),
+            };
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let claims = claims.lock().unwrap().clone();
+                    let userinfo = userinfo.lock().unwrap().clone();
+                    handle_idp_request(stream, &issuer, &claims, &userinfo);
+                }
+            });
+            idp
+        }
+
+        fn client(&self, role_mapping: &str, default_role: Option<&str>) -> OidcClient {
+            let opts = OidcOpts {
+                oidc_allow_insecure: true,
+                oidc_role_mapping: Some(role_mapping.to_string()),
+                oidc_default_role: default_role.map(str::to_string),
+                ..opts(&self.issuer)
+            };
+            OidcClient::from_opts(opts).unwrap().unwrap()
+        }
+
+        /// Set the claims of the ID token and of the userinfo response.
+        fn set_claims(&self, claims: Value, userinfo: Value) {
+            *self.claims.lock().unwrap() = claims;
+            *self.userinfo.lock().unwrap() = userinfo;
+        }
+    }
+
+    fn handle_idp_request(stream: TcpStream, issuer: &str, claims: &Value, userinfo: &Value) {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let path = request_line.split(' ').nth(1).unwrap_or("").to_string();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        let body = String::from_utf8(body).unwrap();
+
+        let (status, response) = match path.as_str() {
+            "/.well-known/openid-configuration" => (
+                "200 OK",
+                json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{}/auth", issuer),
+                    "token_endpoint": format!("{}/token", issuer),
+                    "userinfo_endpoint": format!("{}/userinfo", issuer),
+                }),
+            ),
+            "/token" if body.contains("code=valid-code") => {
+                let encode = |value: &Value| {
+                    base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+                };
+                let id_token = format!("{}.{}.", encode(&json!({ "alg": "none" })), encode(claims));
+                (
+                    "200 OK",
+                    json!({ "access_token": "access-token", "id_token": id_token }),
+                )
+            }
+            "/token" => ("400 Bad Request", json!({ "error": "invalid_grant" })),
+            "/userinfo" => ("200 OK", userinfo.clone()),
+            _ => ("404 Not Found", json!({})),
+        };
+        let response = response.to_string();
+        let mut stream = reader.into_inner();
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            response.len(),
+            response
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn finish_login_with_mock_idp() {
+        let idp = MockIdp::start();
+        let login = PendingLogin {
+            state: "state".to_string(),
+            nonce: "nonce".to_string(),
+        };
+        let claims = |changes: Value| {
+            let mut claims = json!({
+                "iss": idp.issuer,
+                "aud": "oas",
+                "sub": "user-1",
+                "exp": Utc::now().timestamp() + 300,
+                "nonce": "nonce",
+                "preferred_username": "alice",
+            });
+            for (key, value) in changes.as_object().unwrap() {
+                claims[key] = value.clone();
+            }
+            claims
+        };
+        let userinfo = json!({
+            "sub": "user-1",
+            "email": "alice@example.org",
+            "groups": ["oas-staff", "other"],
+        });
+        let finish = |client: OidcClient, code: &'static str, state: &'static str| {
+            let login = login.clone();
+            async move { client.finish_login(&login, code, state).await }
+        };
+
+        // The role is mapped from the groups of the userinfo response.
+        idp.set_claims(claims(json!({})), userinfo.clone());
+        let client = idp.client("oas-staff=editor", None);
+        let user = finish(client.clone(), "valid-code", "state").await.unwrap();
+        assert_eq!(user.subject, "user-1");
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.email.as_deref(), Some("alice@example.org"));
+        assert_eq!(user.role, Role::Editor);
+
+        // Users without a mapped group get the default role, or are denied without one.
+        let user = finish(
+            idp.client("oas-admins=admin", Some("reader")),
+            "valid-code",
+            "state",
+        )
+        .await
+        .unwrap();
+        assert_eq!(user.role, Role::Reader);
+        let err = finish(idp.client("oas-admins=admin", None), "valid-code", "state")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OidcError::Denied(_)), "{:?}", err);
+
+        // The state is checked before the code is exchanged, and invalid codes are rejected.
+        let err = finish(client.clone(), "valid-code", "other")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OidcError::Invalid(_)), "{:?}", err);
+        let err = finish(client.clone(), "other-code", "state")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OidcError::Provider(_)), "{:?}", err);
+
+        // The claims of the ID token are validated.
+        let invalid_claims = [
+            json!({ "nonce": "other" }),
+            json!({ "aud": "other" }),
+            json!({ "iss": "http://evil.example.org" }),
+            json!({ "exp": Utc::now().timestamp() - 60 }),
+        ];
+        for changes in invalid_claims.iter() {
+            idp.set_claims(claims(changes.clone()), userinfo.clone());
+            let err = finish(client.clone(), "valid-code", "state")
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, OidcError::Invalid(_)),
+                "{}: {:?}",
+                changes,
+                err
+            );
+        }
+        idp.set_claims(claims(json!({ "aud": ["other", "oas"] })), userinfo.clone());
+        assert!(finish(client.clone(), "valid-code", "state").await.is_ok());
+
+        // The userinfo response has to be for the subject of the ID token.
+        let mut other_user = userinfo.clone();
+        other_user["sub"] = json!("user-2");
+        idp.set_claims(claims(json!({})), other_user);
+        let err = finish(client, "valid-code", "state").await.unwrap_err();
+        assert!(matches!(err, OidcError::Invalid(_)), "{:?}", err);
+    }
+}
@@ -5,27 +5,25 @@ use argon2::{
 use lazy_static::lazy_static;
 use rand_core::OsRng;
 
-lazy_static! {
-    static ref SALT: SaltString = {
-        let salt = SaltString::generate(&mut OsRng);
-        salt
-    };
-}
-
 lazy_static! {
     static ref ARGON2: Argon2<'static> = Argon2::default();
 }
 
+/// Hash a password with a new random salt.
 pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
     ARGON2
-        .hash_password_simple(password.as_bytes(), SALT.as_ref())
+        .hash_password_simple(password.as_bytes(), salt.as_ref())
         .unwrap()
         .to_string()
 }
 
+/// Verify a password against a hash. Returns false if the hash cannot be parsed.
 pub fn verify_password(password_hash: &str, password: &str) -> bool {
-    let parsed_hash = PasswordHash::new(&password_hash).unwrap();
-    ARGON2
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok()
+    match PasswordHash::new(&password_hash) {
+        Ok(parsed_hash) => ARGON2
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok(),
+        Err(_) => false,
+    }
 }
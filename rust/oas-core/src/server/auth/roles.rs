@@ -0,0 +1,78 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The role of a user.
+///
+/// The role determines which [Permission]s a user has. Roles are ordered from most to least
+/// privileged.
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Full access, including user management.
+    Admin,
+    /// Manages feeds, edits posts and media, and triggers tasks.
+    Editor,
+    /// Edits posts and media.
+    Curator,
+    /// Reports task results.
+    Worker,
+    /// Read-only access, including private fields.
+    Reader,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Self::Reader
+    }
+}
+
+/// An action that a user may be permitted to do.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Permission {
+    /// Register and manage users, and access admin endpoints.
+    ManageUsers,
+    /// Create feeds and change owned feeds.
+    ManageFeeds,
+    /// Create and change posts and media of owned feeds.
+    EditPosts,
+    /// Start and cancel tasks, and view jobs.
+    TriggerTasks,
+    /// Report the results of jobs.
+    ReportResults,
+    /// View private fields of records, e.g. task states and feed settings.
+    ViewPrivate,
+}
+
+impl Role {
+    /// Get the permissions of this role.
+    pub fn permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Self::Admin => &[
+                ManageUsers,
+                ManageFeeds,
+                EditPosts,
+                TriggerTasks,
+                ReportResults,
+                ViewPrivate,
+            ],
+            Self::Editor => &[ManageFeeds, EditPosts, TriggerTasks, ViewPrivate],
+            Self::Curator => &[EditPosts, ViewPrivate],
+            Self::Worker => &[ReportResults, ViewPrivate],
+            Self::Reader => &[ViewPrivate],
+        }
+    }
+
+    /// Check if this role has a permission.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+
+    /// Check if this role may access all feeds, not only owned feeds.
+    pub fn has_all_feeds(&self) -> bool {
+        *self == Self::Admin
+    }
+}
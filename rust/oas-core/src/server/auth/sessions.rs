@@ -1,57 +1,187 @@
-use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use oas_common::{util, Record, TypedValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
 
+use super::roles::{Permission, Role};
 use super::structs::UserInfo;
+use super::SESSION_EXPIRATION;
+use crate::couch::{CouchDB, CouchResult};
+
+/// Interval in which expired sessions are removed from the database.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 /// Session info that's stored for each active session.
+///
+/// For requests that authenticate with an API token, the permissions are limited to the scopes
+/// of the token, and the id of the token is kept.
 #[derive(Debug, Clone)]
 pub struct SessionInfo {
-    pub(super) is_admin: bool,
     pub(super) user: Arc<UserInfo>,
+    pub(super) scopes: Option<Arc<Vec<Permission>>>,
+    pub(super) token: Option<String>,
 }
 
 impl SessionInfo {
+    /// Create a session for a user, with all permissions of the user.
+    pub fn for_user(user: UserInfo) -> Self {
+        Self {
+            user: Arc::new(user),
+            scopes: None,
+            token: None,
+        }
+    }
+
     pub fn is_admin(&self) -> bool {
-        self.is_admin
+        self.user.role == Role::Admin && self.in_scope(Permission::ManageUsers)
     }
 
     pub fn user(&self) -> &Arc<UserInfo> {
         &self.user
     }
+
+    /// Get the id of the API token of the session, if any.
+    pub fn token_id(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    /// Check if the user of the session has a permission.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.user.has_permission(permission) && self.in_scope(permission)
+    }
+
+    fn in_scope(&self, permission: Permission) -> bool {
+        self.scopes
+            .as_ref()
+            .map_or(true, |scopes| scopes.contains(&permission))
+    }
+}
+
+/// A login session that is saved in the meta database.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    /// Name of the user that is logged in.
+    pub username: String,
+    /// Time of the login.
+    pub created: DateTime<Utc>,
+    /// Time after which the session is no longer valid.
+    pub expires: DateTime<Utc>,
+}
+
+impl TypedValue for Session {
+    const NAME: &'static str = "oas.Session";
+}
+
+impl Session {
+    /// Check if the session is expired.
+    pub fn is_expired(&self) -> bool {
+        self.expires <= Utc::now()
+    }
 }
 
-/// This is a very simple session store. It does not yet feature expiration.
-/// TODO: Add expiration.
-/// TODO: Use redis.
+/// Store for login sessions.
+///
+/// Sessions are saved as records in the meta database, so that they survive restarts and are
+/// shared between all core instances. The record id is a hash of the session id, so that the
+/// database does not contain usable session ids.
 #[derive(Debug, Clone)]
 pub struct Sessions {
-    sessions: Arc<RwLock<HashMap<String, Arc<SessionInfo>>>>,
+    db: CouchDB,
 }
 
 impl Sessions {
-    pub fn new() -> Self {
-        Self {
-            sessions: Arc::new(RwLock::new(HashMap::new())),
+    /// Create a new session store on a database.
+    pub fn new(db: CouchDB) -> Self {
+        Self { db }
+    }
+
+    /// Save a new session for a user. The session expires after [SESSION_EXPIRATION].
+    pub async fn insert(&self, session_id: &str, username: &str) -> CouchResult<()> {
+        let created = Utc::now();
+        let expires = created + chrono::Duration::seconds(SESSION_EXPIRATION.whole_seconds());
+        let session = Session {
+            username: username.to_string(),
+            created,
+            expires,
+        };
+        let record = Record::from_id_and_value(record_id(session_id), session);
+        self.db.table::<Session>().put(record).await?;
+        Ok(())
+    }
+
+    /// Get an active session.
+    ///
+    /// Returns None if the session does not exist or is expired. Expired sessions are removed.
+    pub async fn get(&self, session_id: &str) -> CouchResult<Option<Session>> {
+        let id = record_id(session_id);
+        let session = match self.db.table::<Session>().get(&id).await {
+            Ok(record) => record.value,
+            Err(err) if err.status_code() == Some(404) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if session.is_expired() {
+            self.remove_by_record_id(&id).await?;
+            Ok(None)
+        } else {
+            Ok(Some(session))
         }
     }
 
-    pub async fn insert(&self, id: &str, info: SessionInfo) {
-        self.sessions
-            .write()
-            .await
-            .insert(id.to_string(), Arc::new(info));
+    /// Remove a session.
+    pub async fn remove(&self, session_id: &str) -> CouchResult<()> {
+        self.remove_by_record_id(&record_id(session_id)).await
     }
 
-    pub async fn get(&self, session_id: &str) -> Option<Arc<SessionInfo>> {
-        self.sessions
-            .read()
+    /// Remove all sessions of a user.
+    pub async fn remove_for_user(&self, username: &str) -> CouchResult<usize> {
+        self.remove_where(|session| session.username == username)
             .await
-            .get(session_id)
-            .map(|session_info| session_info.clone())
     }
 
-    pub async fn remove(&self, session_id: &str) {
-        self.sessions.write().await.remove(session_id);
+    /// Remove all expired sessions.
+    pub async fn remove_expired(&self) -> CouchResult<usize> {
+        self.remove_where(|session| session.is_expired()).await
+    }
+
+    /// Remove expired sessions in an interval, forever.
+    pub async fn run_cleanup(self) {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.remove_expired().await {
+                Ok(0) => {}
+                Ok(count) => log::debug!("Removed {} expired sessions", count),
+                Err(err) => log::error!("Failed to remove expired sessions: {}", err),
+            }
+        }
     }
+
+    async fn remove_where<F>(&self, filter: F) -> CouchResult<usize>
+    where
+        F: Fn(&Session) -> bool,
+    {
+        let sessions = self.db.table::<Session>().get_all().await?;
+        let mut count = 0;
+        for record in sessions.into_iter().filter(|record| filter(&record.value)) {
+            self.remove_by_record_id(record.id()).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    async fn remove_by_record_id(&self, id: &str) -> CouchResult<()> {
+        match self.db.table::<Session>().delete(id).await {
+            Ok(_) => Ok(()),
+            Err(err) if err.status_code() == Some(404) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Get the record id for a session id.
+fn record_id(session_id: &str) -> String {
+    util::id_from_hashed_string(session_id)
 }
@@ -1,67 +1,196 @@
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use oas_common::Record;
+use rocket::http::Status;
 
+use super::oidc::OidcUser;
 use super::password;
-use super::structs::{LoginRequest, RegisterRequest, UserInfo};
+use super::roles::Role;
+use super::structs::{LoginRequest, RegisterRequest, UpdateUserRequest, UserInfo};
+use crate::couch::{CouchDB, CouchResult};
+use crate::server::error::AppError;
 
+/// Name of the admin user that is created on startup.
+pub const ADMIN_USERNAME: &str = "admin";
+
+/// Store for user accounts.
+///
+/// Users are saved as records in the meta database. The record id is the username.
 #[derive(Debug, Clone)]
 pub struct UserStore {
-    users: Arc<RwLock<HashMap<String, UserInfo>>>,
+    db: CouchDB,
 }
 
 impl UserStore {
-    pub fn new() -> Self {
-        Self {
-            users: Arc::new(RwLock::new(HashMap::new())),
-        }
+    /// Create a new user store on a database.
+    pub fn new(db: CouchDB) -> Self {
+        Self { db }
     }
 
-    pub async fn _with_admin_user(password: &str) -> Self {
-        let this = Self::new();
-        this.add_admin_user(&password).await;
-        this
-    }
-
-    pub async fn add_admin_user(&self, password: &str) {
-        let req = RegisterRequest {
-            username: "admin".to_string(),
-            password: password.to_string(),
+    /// Make sure that the admin user exists and has `password` as its password.
+    ///
+    /// Returns true if the admin user was created or its password was changed.
+    pub async fn add_admin_user(&self, password: &str) -> CouchResult<bool> {
+        if let Some(user) = self.get(ADMIN_USERNAME).await? {
+            if user.role == Role::Admin && password::verify_password(&user.password, password) {
+                return Ok(false);
+            }
+        }
+        let user = UserInfo {
+            username: ADMIN_USERNAME.to_string(),
+            password: password::hash_password(password),
             email: None,
+            role: Role::Admin,
+            feeds: vec![],
+            oidc_subject: None,
+            tenant: None,
         };
-        self.register(req, true).await
+        self.put(user).await?;
+        Ok(true)
     }
 
-    pub async fn register(&self, req: RegisterRequest, is_admin: bool) {
-        let password = password::hash_password(&req.password);
-        let username = req.username;
+    /// Register a new user.
+    ///
+    /// Fails if the username is invalid or already taken.
+    pub async fn register(&self, req: RegisterRequest) -> Result<(), AppError> {
+        if !is_valid_username(&req.username) {
+            return Err(AppError::Http(
+                Status::UnprocessableEntity,
+                "Usernames may only contain letters, digits, \"-\", \"_\" and \".\"".to_string(),
+            ));
+        }
+        if self.get(&req.username).await?.is_some() {
+            return Err(AppError::Http(
+                Status::Conflict,
+                format!("User {} already exists", req.username),
+            ));
+        }
         let user = UserInfo {
-            username,
-            password,
+            username: req.username,
+            password: password::hash_password(&req.password),
             email: req.email,
-            is_admin,
+            role: req.role,
+            feeds: req.feeds,
+            oidc_subject: None,
+            tenant: req.tenant,
         };
-        self.users
-            .write()
-            .await
-            .insert(user.username.to_string(), user);
+        self.put(user).await?;
+        Ok(())
     }
 
-    pub async fn login(&self, req: &LoginRequest) -> Option<UserInfo> {
-        self.verify_and_get(&req.username, &req.password).await
+    /// Update a user.
+    ///
+    /// Returns the updated user.
+    pub async fn update(
+        &self,
+        username: &str,
+        req: UpdateUserRequest,
+    ) -> Result<UserInfo, AppError> {
+        let mut user = self.get(username).await?.ok_or_else(|| {
+            AppError::Http(Status::NotFound, format!("User {} not found", username))
+        })?;
+        if let Some(password) = req.password {
+            user.password = password::hash_password(&password);
+        }
+        if let Some(email) = req.email {
+            user.email = Some(email);
+        }
+        if let Some(role) = req.role {
+            user.role = role;
+        }
+        if let Some(feeds) = req.feeds {
+            user.feeds = feeds;
+        }
+        self.put(user.clone()).await?;
+        Ok(user)
     }
 
-    pub async fn verify_and_get(&self, username: &str, password: &str) -> Option<UserInfo> {
-        let store = self.users.read().await;
-        let user = store.get(username);
-        if let Some(user) = user {
-            if password::verify_password(&user.password, password) {
-                Some((*user).clone())
-            } else {
-                None
+    /// Create or update a user that logged in through the OpenID Connect identity provider.
+    ///
+    /// The role and email of the user are updated from the identity provider. Fails if a local
+    /// user or a user of another subject has the same username.
+    pub async fn upsert_oidc_user(&self, oidc_user: OidcUser) -> Result<UserInfo, AppError> {
+        if !is_valid_username(&oidc_user.username) {
+            return Err(AppError::Http(
+                Status::UnprocessableEntity,
+                format!("Invalid username: {}", oidc_user.username),
+            ));
+        }
+        let user = match self.get(&oidc_user.username).await? {
+            Some(user) if user.oidc_subject.as_ref() != Some(&oidc_user.subject) => {
+                return Err(AppError::Http(
+                    Status::Conflict,
+                    format!("User {} already exists", oidc_user.username),
+                ));
             }
-        } else {
-            None
+            Some(user) => UserInfo {
+                role: oidc_user.role,
+                email: oidc_user.email,
+                ..user
+            },
+            // Users of the identity provider have no local password.
+            None => UserInfo {
+                username: oidc_user.username,
+                password: String::new(),
+                email: oidc_user.email,
+                role: oidc_user.role,
+                feeds: vec![],
+                oidc_subject: Some(oidc_user.subject),
+                tenant: None,
+            },
+        };
+        self.put(user.clone()).await?;
+        Ok(user)
+    }
+
+    /// Add a feed to the feeds that a user owns.
+    pub async fn add_feed(&self, username: &str, feed_guid: &str) -> CouchResult<()> {
+        if let Some(mut user) = self.get(username).await? {
+            if !user.feeds.iter().any(|guid| guid == feed_guid) {
+                user.feeds.push(feed_guid.to_string());
+                self.put(user).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// List all users.
+    pub async fn list(&self) -> CouchResult<Vec<UserInfo>> {
+        let records = self.db.table::<UserInfo>().get_all().await?;
+        Ok(records.into_iter().map(|record| record.value).collect())
+    }
+
+    /// Get a user by its username.
+    pub async fn get(&self, username: &str) -> CouchResult<Option<UserInfo>> {
+        match self.db.table::<UserInfo>().get(username).await {
+            Ok(record) => Ok(Some(record.value)),
+            Err(err) if err.status_code() == Some(404) => Ok(None),
+            Err(err) => Err(err),
         }
     }
+
+    pub async fn login(&self, req: &LoginRequest) -> CouchResult<Option<UserInfo>> {
+        self.verify_and_get(&req.username, &req.password).await
+    }
+
+    pub async fn verify_and_get(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> CouchResult<Option<UserInfo>> {
+        let user = self.get(username).await?;
+        Ok(user.filter(|user| password::verify_password(&user.password, password)))
+    }
+
+    async fn put(&self, user: UserInfo) -> CouchResult<()> {
+        let record = Record::from_id_and_value(user.username.clone(), user);
+        self.db.table::<UserInfo>().put(record).await?;
+        Ok(())
+    }
+}
+
+/// Check if a username can be used as a record id.
+fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
 }
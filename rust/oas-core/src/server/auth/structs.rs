@@ -1,11 +1,32 @@
+use oas_common::TypedValue;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use super::roles::{Permission, Role};
+
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
 pub struct RegisterRequest {
     pub password: String,
     pub username: String,
     pub email: Option<String>,
+    /// Role of the new user (default: reader).
+    #[serde(default)]
+    pub role: Role,
+    /// Guids of the feeds that the new user owns.
+    #[serde(default)]
+    pub feeds: Vec<String>,
+    /// Id of the tenant of the new user. Users without a tenant use the records of the instance.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+/// Changes to a user. Fields that are not set are not changed.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
+pub struct UpdateUserRequest {
+    pub password: Option<String>,
+    pub email: Option<String>,
+    pub role: Option<Role>,
+    pub feeds: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -14,6 +35,10 @@ pub struct UserPublicInfo {
     pub username: String,
     pub email: Option<String>,
     pub is_admin: bool,
+    pub role: Role,
+    pub permissions: Vec<Permission>,
+    pub feeds: Vec<String>,
+    pub tenant: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Clone)]
@@ -21,8 +46,23 @@ pub struct UserPublicInfo {
 pub struct UserInfo {
     pub password: String,
     pub username: String,
-    pub is_admin: bool,
+    #[serde(default)]
+    pub role: Role,
+    /// Guids of the feeds that the user owns.
+    #[serde(default)]
+    pub feeds: Vec<String>,
     pub email: Option<String>,
+    /// Subject identifier of the user at the OpenID Connect identity provider, for users that
+    /// login through the identity provider.
+    #[serde(default)]
+    pub oidc_subject: Option<String>,
+    /// Id of the tenant that the user belongs to, None for users of the instance itself.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+impl TypedValue for UserInfo {
+    const NAME: &'static str = "oas.User";
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -41,6 +81,16 @@ impl UserInfo {
     pub fn into_public(&self) -> UserPublicInfo {
         self.clone().into()
     }
+
+    /// Check if the user has a permission.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.role.has_permission(permission)
+    }
+
+    /// Check if the user owns a feed, or may access all feeds.
+    pub fn owns_feed(&self, feed_guid: &str) -> bool {
+        self.role.has_all_feeds() || self.feeds.iter().any(|guid| guid == feed_guid)
+    }
 }
 
 impl From<UserInfo> for UserPublicInfo {
@@ -48,7 +98,11 @@ impl From<UserInfo> for UserPublicInfo {
         Self {
             username: user.username,
             email: user.email,
-            is_admin: user.is_admin,
+            is_admin: user.role == Role::Admin,
+            role: user.role,
+            permissions: user.role.permissions().to_vec(),
+            feeds: user.feeds,
+            tenant: user.tenant,
         }
     }
 }
@@ -0,0 +1,203 @@
+use chrono::{DateTime, Utc};
+use oas_common::{util, Record, TypedValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::roles::Permission;
+use super::worker::constant_time_eq;
+use crate::couch::{CouchDB, CouchResult};
+
+/// Minimum time between two updates of the last-used timestamp of a token, in seconds.
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// An API token for machine clients.
+///
+/// Clients send the token in an `Authorization: Bearer <token>` header. The token string is
+/// `<id>.<secret>`, where the id is the record id of the token. Only a hash of the secret is
+/// saved.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiToken {
+    /// A name to identify the token, e.g. the name of the client that uses it.
+    pub name: String,
+    /// Name of the user the token acts for.
+    pub username: String,
+    /// Permissions that the token grants. Permissions that the user does not have are ignored.
+    pub scopes: Vec<Permission>,
+    /// Hash of the token secret.
+    pub secret_hash: String,
+    /// Time the token was created.
+    pub created: DateTime<Utc>,
+    /// Time the token was last used.
+    pub last_used: Option<DateTime<Utc>>,
+    /// Time after which the token is no longer valid.
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl TypedValue for ApiToken {
+    const NAME: &'static str = "oas.ApiToken";
+}
+
+impl ApiToken {
+    /// Check if the token is expired.
+    pub fn is_expired(&self) -> bool {
+        self.expires.map_or(false, |expires| expires <= Utc::now())
+    }
+}
+
+/// Request to create a new API token.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateTokenRequest {
+    /// A name to identify the token.
+    pub name: String,
+    /// Permissions that the token grants.
+    pub scopes: Vec<Permission>,
+    /// Number of days after which the token expires. If not set, the token does not expire.
+    pub expires_in_days: Option<u32>,
+}
+
+/// Info about an API token, without its secret.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub username: String,
+    pub scopes: Vec<Permission>,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl From<Record<ApiToken>> for ApiTokenInfo {
+    fn from(record: Record<ApiToken>) -> Self {
+        let id = record.id().to_string();
+        let token = record.value;
+        Self {
+            id,
+            name: token.name,
+            username: token.username,
+            scopes: token.scopes,
+            created: token.created,
+            last_used: token.last_used,
+            expires: token.expires,
+        }
+    }
+}
+
+/// Response for a newly created API token.
+///
+/// The token string is only returned once and cannot be retrieved later.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateTokenResponse {
+    pub token: String,
+    pub info: ApiTokenInfo,
+}
+
+/// Store for API tokens.
+///
+/// Tokens are saved as records in the meta database.
+#[derive(Debug, Clone)]
+pub struct ApiTokens {
+    db: CouchDB,
+}
+
+impl ApiTokens {
+    /// Create a new token store on a database.
+    pub fn new(db: CouchDB) -> Self {
+        Self { db }
+    }
+
+    /// Create a new token for a user.
+    pub async fn create(
+        &self,
+        username: &str,
+        req: CreateTokenRequest,
+    ) -> CouchResult<CreateTokenResponse> {
+        let id = util::id_from_uuid();
+        let secret = super::generate_session_id();
+        let created = Utc::now();
+        let expires = req
+            .expires_in_days
+            .map(|days| created + chrono::Duration::days(days as i64));
+        let token = ApiToken {
+            name: req.name,
+            username: username.to_string(),
+            scopes: req.scopes,
+            secret_hash: hash_secret(&secret),
+            created,
+            last_used: None,
+            expires,
+        };
+        let record = Record::from_id_and_value(id.clone(), token);
+        self.db.table::<ApiToken>().put(record.clone()).await?;
+        Ok(CreateTokenResponse {
+            token: format!("{}.{}", id, secret),
+            info: record.into(),
+        })
+    }
+
+    /// Verify a token string.
+    ///
+    /// Returns the token record if it exists, matches and is not expired. The last-used timestamp
+    /// of the token is updated.
+    pub async fn verify(&self, token: &str) -> CouchResult<Option<Record<ApiToken>>> {
+        let (id, secret) = match token.split_once('.') {
+            Some(parts) => parts,
+            None => return Ok(None),
+        };
+        let mut record = match self.get(id).await? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let secret_hash = hash_secret(secret);
+        if !constant_time_eq(secret_hash.as_bytes(), record.value.secret_hash.as_bytes())
+            || record.value.is_expired()
+        {
+            return Ok(None);
+        }
+        let now = Utc::now();
+        let is_stale = record.value.last_used.map_or(true, |last_used| {
+            now - last_used > chrono::Duration::seconds(LAST_USED_RESOLUTION)
+        });
+        if is_stale {
+            record.value.last_used = Some(now);
+            if let Err(err) = self.db.table::<ApiToken>().put(record.clone()).await {
+                log::debug!("Failed to update last use of token {}: {}", id, err);
+            }
+        }
+        Ok(Some(record))
+    }
+
+    /// Get a token by its id.
+    pub async fn get(&self, id: &str) -> CouchResult<Option<Record<ApiToken>>> {
+        match self.db.table::<ApiToken>().get(id).await {
+            Ok(record) => Ok(Some(record)),
+            Err(err) if err.status_code() == Some(404) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// List all tokens, optionally only those of a user.
+    pub async fn list(&self, username: Option<&str>) -> CouchResult<Vec<Record<ApiToken>>> {
+        let records = self.db.table::<ApiToken>().get_all().await?;
+        let records = records
+            .into_iter()
+            .filter(|record| username.map_or(true, |username| record.value.username == username))
+            .collect();
+        Ok(records)
+    }
+
+    /// Revoke a token.
+    pub async fn revoke(&self, id: &str) -> CouchResult<()> {
+        self.db.table::<ApiToken>().delete(id).await?;
+        Ok(())
+    }
+}
+
+/// Hash a token secret.
+fn hash_secret(secret: &str) -> String {
+    util::id_from_hashed_string(secret)
+}
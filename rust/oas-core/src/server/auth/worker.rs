@@ -0,0 +1,48 @@
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
+
+use super::guards::require_instance_permission;
+use super::roles::Permission;
+use super::{Auth, LoginError};
+
+/// Request guard for workers that report job results.
+///
+/// Workers authenticate with the worker token (set via the `OAS_WORKER_TOKEN` environment
+/// variable) in an `Authorization: Bearer <token>` header. Users and API tokens with the
+/// permission to report results (workers and admins) are accepted as well, unless they belong to
+/// a tenant.
+#[derive(Debug, Clone)]
+pub struct WorkerUser {}
+
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for WorkerUser {
+    type Error = LoginError;
+    async fn from_request(request: &'r Request<'_>) -> Outcome<WorkerUser, LoginError> {
+        let auth = request
+            .guard::<&State<Auth>>()
+            .await
+            .expect("Session state not registered");
+
+        let bearer_token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        if let Some(token) = bearer_token {
+            if auth.check_worker_token(token) {
+                return Outcome::Success(WorkerUser {});
+            }
+        }
+
+        require_instance_permission(request, Permission::ReportResults)
+            .await
+            .map(|_session| WorkerUser {})
+    }
+}
+
+/// Compare two strings in constant time (for equal lengths).
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -1,4 +1,6 @@
-use crate::couch::CouchError;
+use crate::couch::{CouchError, Doc};
+use crate::server::auth::OidcError;
+use crate::tasks::{TaskControlError, TaskError};
 use oas_common::{DecodingError, EncodingError, ValidationError};
 use okapi::openapi3::Responses;
 use rocket::http::Status;
@@ -34,6 +36,18 @@ pub enum AppError {
     ValidationError(ValidationError),
     #[error("Unauthorized")]
     Unauthorized,
+    /// The record was changed concurrently. Contains the current version of the record, if it
+    /// still exists.
+    #[error("{message}")]
+    Conflict {
+        status: Status,
+        message: String,
+        current: Option<Box<Doc>>,
+    },
+    /// A rate limit was exceeded. Contains the number of seconds until the next request is
+    /// allowed.
+    #[error("Too many requests, retry after {0} seconds")]
+    TooManyRequests(u64),
 }
 
 impl<'r> Responder<'r, 'static> for AppError {
@@ -46,6 +60,8 @@ impl<'r> Responder<'r, 'static> for AppError {
             AppError::ValidationError(_) => Status::UnprocessableEntity,
             AppError::Elastic(err) => map_u16_status(err.status_code().map(|code| code.as_u16())),
             AppError::Unauthorized => Status::Unauthorized,
+            AppError::Conflict { status, .. } => *status,
+            AppError::TooManyRequests(_) => Status::TooManyRequests,
             _ => Status::InternalServerError,
         };
 
@@ -54,7 +70,20 @@ impl<'r> Responder<'r, 'static> for AppError {
             _ => format!("{}", self),
         };
 
-        let response = ErrorResponse { error: message };
+        let (current, etag) = match &self {
+            AppError::Conflict {
+                current: Some(doc), ..
+            } => (
+                serde_json::to_value(doc).ok(),
+                doc.rev().map(|rev| format!("\"{}\"", rev)),
+            ),
+            _ => (None, None),
+        };
+
+        let response = ErrorResponse {
+            error: message,
+            current,
+        };
 
         // let json = json!({ "error": message });
         let json_string = serde_json::to_string(&response).unwrap();
@@ -65,6 +94,12 @@ impl<'r> Responder<'r, 'static> for AppError {
             Err(res) => Err(res),
             Ok(mut res) => {
                 res.set_status(code);
+                if let Some(etag) = etag {
+                    res.set_raw_header("ETag", etag);
+                }
+                if let AppError::TooManyRequests(secs) = &self {
+                    res.set_raw_header("Retry-After", secs.to_string());
+                }
                 match self {
                     Self::Unauthorized => {
                         // TODO: This is nice as it makes the API accessible in regular browsers.
@@ -89,6 +124,35 @@ impl<'r> Responder<'r, 'static> for AppError {
     }
 }
 
+impl From<TaskControlError> for AppError {
+    fn from(err: TaskControlError) -> Self {
+        match err {
+            TaskControlError::Task(TaskError::Unknown(_)) | TaskControlError::NotFound(_) => {
+                AppError::Http(Status::NotFound, err.to_string())
+            }
+            TaskControlError::Task(TaskError::Invalid { .. }) => {
+                AppError::Http(Status::UnprocessableEntity, err.to_string())
+            }
+            TaskControlError::Conflict(_) => AppError::Http(Status::Conflict, err.to_string()),
+            TaskControlError::Forbidden(_) => AppError::Http(Status::Forbidden, err.to_string()),
+            TaskControlError::Couch(err) => AppError::Couch(err),
+            TaskControlError::Other(err) => AppError::Other(format!("{:#}", err)),
+        }
+    }
+}
+
+impl From<OidcError> for AppError {
+    fn from(err: OidcError) -> Self {
+        let status = match err {
+            OidcError::Config(_) => Status::InternalServerError,
+            OidcError::Provider(_) => Status::BadGateway,
+            OidcError::Invalid(_) => Status::BadRequest,
+            OidcError::Denied(_) => Status::Forbidden,
+        };
+        AppError::Http(status, err.to_string())
+    }
+}
+
 fn map_u16_status(status: Option<u16>) -> Status {
     status
         .map(|code| Status::from_code(code).unwrap())
@@ -98,6 +162,9 @@ fn map_u16_status(status: Option<u16>) -> Status {
 #[derive(Serialize, JsonSchema, Debug, Default)]
 struct ErrorResponse {
     error: String,
+    /// The current version of the record, for conflicts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current: Option<serde_json::Value>,
 }
 
 impl OpenApiResponderInner for AppError {
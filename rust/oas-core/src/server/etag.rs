@@ -0,0 +1,335 @@
+//! Optimistic concurrency control with revisions.
+//!
+//! The rev of a record is sent as `ETag` header. Clients can send it back in an `If-Match`
+//! header when changing the record. If the record was changed in the meantime, the change fails
+//! with 412 Precondition Failed, and the response contains the current version of the record.
+
+use oas_common::{Record, TypedValue};
+use okapi::openapi3::Responses;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::response::OpenApiResponderInner;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::couch::{CouchDB, Doc, PutResponse};
+use crate::server::auth::SessionInfo;
+use crate::server::error::AppError;
+use crate::server::handlers::audit::put_doc_audited;
+use crate::State;
+
+pub type TaggedResult<T> = std::result::Result<Tagged<T>, AppError>;
+
+/// Max number of tries for a patch if the record is changed concurrently.
+const MAX_PATCH_TRIES: usize = 5;
+
+/// Request guard for the rev in an `If-Match` header.
+///
+/// The guard never fails. Weak tags (`W/"..."`) are treated like strong tags, and `*` is
+/// treated like a missing header.
+#[derive(Debug, Clone, Default)]
+pub struct IfMatch(Option<String>);
+
+impl IfMatch {
+    /// Get the expected rev, if set.
+    pub fn rev(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+
+    /// Fail with 412 Precondition Failed unless the current doc is at the expected rev.
+    pub fn check(&self, current: Option<&Doc>) -> Result<(), AppError> {
+        match self.rev() {
+            Some(rev) if current.and_then(|doc| doc.rev()) != Some(rev) => {
+                Err(conflict_error(Status::PreconditionFailed, current.cloned()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> Outcome<IfMatch, ()> {
+        let rev = request
+            .headers()
+            .get_one("If-Match")
+            .map(|value| value.trim())
+            .map(|value| value.strip_prefix("W/").unwrap_or(value))
+            .map(|value| value.trim_matches('"'))
+            .filter(|value| !value.is_empty() && *value != "*")
+            .map(str::to_string);
+        Outcome::Success(IfMatch(rev))
+    }
+}
+
+/// Responder for a JSON body with the rev of a record as `ETag` header.
+#[derive(Debug, Clone)]
+pub struct Tagged<T> {
+    value: T,
+    rev: Option<String>,
+}
+
+impl<T> Tagged<T> {
+    pub fn new(value: T, rev: Option<String>) -> Self {
+        Self { value, rev }
+    }
+}
+
+impl<T: TypedValue> From<(Record<T>, String)> for Tagged<Record<T>> {
+    fn from((record, rev): (Record<T>, String)) -> Self {
+        Self::new(record, Some(rev))
+    }
+}
+
+impl From<PutResponse> for Tagged<PutResponse> {
+    fn from(res: PutResponse) -> Self {
+        let rev = Some(res.rev.clone());
+        Self::new(res, rev)
+    }
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Tagged<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut res = Json(self.value).respond_to(req)?;
+        if let Some(rev) = self.rev {
+            res.set_raw_header("ETag", format!("\"{}\"", rev));
+        }
+        Ok(res)
+    }
+}
+
+impl<T: Serialize + JsonSchema> OpenApiResponderInner for Tagged<T> {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        Json::<T>::responses(gen)
+    }
+}
+
+/// Save a doc if it is still at the rev of the `If-Match` header.
+///
+/// Without an `If-Match` header, the doc overwrites the latest rev. The change is added to the
+/// audit log.
+pub async fn put_if_match(
+    state: &State,
+    session: &SessionInfo,
+    endpoint: &str,
+    if_match: &IfMatch,
+    mut doc: Doc,
+) -> Result<PutResponse, AppError> {
+    let guid = doc.id().to_string();
+    if let Some(rev) = if_match.rev() {
+        let current = get_current(&state.db, &guid).await?;
+        if_match.check(current.as_ref())?;
+        doc.set_rev(Some(rev.to_string()));
+    }
+    match put_doc_audited(state, session, endpoint, doc).await {
+        Err(err) if is_conflict(&err) => Err(conflict(&state.db, &guid).await),
+        res => res,
+    }
+}
+
+/// Apply a JSON patch to a record.
+///
+/// The patch is applied to the current version of the record and saved at its rev. If the
+/// record is changed in the meantime, the patch is applied again to the new version, unless the
+/// request has an `If-Match` header. `check` is called with the patched record before saving.
+pub async fn patch_if_match<T, F>(
+    state: &State,
+    session: &SessionInfo,
+    endpoint: &str,
+    if_match: &IfMatch,
+    guid: &str,
+    patch: &json_patch::Patch,
+    check: F,
+) -> Result<PutResponse, AppError>
+where
+    T: TypedValue,
+    F: Fn(&Record<T>) -> Result<(), AppError>,
+{
+    let mut tries = 0;
+    loop {
+        let current = state.db.get_doc(guid).await?;
+        if_match.check(Some(&current))?;
+        let rev = current.rev().map(str::to_string);
+        let mut record = current.into_untyped_record()?;
+        record.apply_json_patch(patch)?;
+        let record = record.into_typed_record::<T>()?;
+        check(&record)?;
+        let mut doc = Doc::from_typed_record(record);
+        doc.set_rev(rev);
+        let can_retry = if_match.rev().is_none() && tries < MAX_PATCH_TRIES;
+        match put_doc_audited(state, session, endpoint, doc).await {
+            Err(err) if is_conflict(&err) && can_retry => {
+                tries += 1;
+            }
+            Err(err) if is_conflict(&err) => return Err(conflict(&state.db, guid).await),
+            res => return res,
+        }
+    }
+}
+
+fn is_conflict(err: &AppError) -> bool {
+    matches!(err, AppError::Couch(err) if err.status_code() == Some(409))
+}
+
+/// Get the current version of a doc, or None if it does not exist.
+async fn get_current(db: &CouchDB, guid: &str) -> Result<Option<Doc>, AppError> {
+    match db.get_doc(guid).await {
+        Ok(doc) => Ok(Some(doc)),
+        Err(err) if err.status_code() == Some(404) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Create a 409 Conflict error with the current version of a doc.
+async fn conflict(db: &CouchDB, guid: &str) -> AppError {
+    match get_current(db, guid).await {
+        Ok(current) => conflict_error(Status::Conflict, current),
+        Err(err) => err,
+    }
+}
+
+fn conflict_error(status: Status, current: Option<Doc>) -> AppError {
+    let message = match &current {
+        Some(doc) => format!(
+            "Record {} was changed, the current rev is {}",
+            doc.id(),
+            doc.rev().unwrap_or_default()
+        ),
+        None => "Record does not exist".to_string(),
+    };
+    AppError::Conflict {
+        status,
+        message,
+        current: current.map(Box::new),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::auth::{Role, UserInfo};
+    use oas_common::types::Media;
+    use serde_json::json;
+
+    fn session() -> SessionInfo {
+        SessionInfo::for_user(UserInfo {
+            password: String::new(),
+            username: "editor".to_string(),
+            role: Role::Editor,
+            feeds: vec![],
+            email: None,
+            oidc_subject: None,
+            tenant: None,
+        })
+    }
+
+    fn media(url: &str) -> Doc {
+        let media = Media {
+            content_url: url.to_string(),
+            ..Default::default()
+        };
+        Doc::from_typed_record(Record::from_id_and_value("m1", media))
+    }
+
+    fn current_rev(err: &AppError) -> (Status, Option<String>) {
+        match err {
+            AppError::Conflict {
+                status, current, ..
+            } => (
+                *status,
+                current
+                    .as_ref()
+                    .and_then(|doc| doc.rev())
+                    .map(str::to_string),
+            ),
+            err => panic!("Unexpected error {:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn put_with_if_match() {
+        let state = State::in_memory();
+        let session = session();
+        let first = state.db.put_doc(media("http://a")).await.unwrap();
+        let second = state.db.put_doc(media("http://b")).await.unwrap();
+
+        let stale = IfMatch(Some(first.rev.clone()));
+        let err = put_if_match(&state, &session, "PUT", &stale, media("http://c"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            current_rev(&err),
+            (Status::PreconditionFailed, Some(second.rev.clone()))
+        );
+
+        let fresh = IfMatch(Some(second.rev.clone()));
+        let res = put_if_match(&state, &session, "PUT", &fresh, media("http://c"))
+            .await
+            .unwrap();
+        let (record, rev) = state
+            .db
+            .get_record_with_rev::<Media>("oas.Media_m1")
+            .await
+            .unwrap();
+        assert_eq!(record.value.content_url, "http://c");
+        assert_eq!(rev, res.rev);
+    }
+
+    #[tokio::test]
+    async fn put_at_stale_rev() {
+        let state = State::in_memory();
+        let first = state.db.put_doc(media("http://a")).await.unwrap();
+        let second = state.db.put_doc(media("http://b")).await.unwrap();
+
+        // A doc at an old rev conflicts even without an If-Match header.
+        let mut doc = media("http://c");
+        doc.set_rev(Some(first.rev));
+        let err = put_if_match(&state, &session(), "PUT", &IfMatch::default(), doc)
+            .await
+            .unwrap_err();
+        assert_eq!(current_rev(&err), (Status::Conflict, Some(second.rev)));
+    }
+
+    #[tokio::test]
+    async fn patch_with_if_match() {
+        let state = State::in_memory();
+        let session = session();
+        let first = state.db.put_doc(media("http://a")).await.unwrap();
+        let patch: json_patch::Patch = serde_json::from_value(json!([
+            { "op": "replace", "path": "/contentUrl", "value": "http://b" }
+        ]))
+        .unwrap();
+        let guid = "oas.Media_m1";
+        let check = |_: &Record<Media>| -> Result<(), AppError> { Ok(()) };
+
+        let fresh = IfMatch(Some(first.rev.clone()));
+        let second = patch_if_match(&state, &session, "PATCH", &fresh, guid, &patch, check)
+            .await
+            .unwrap();
+        let err = patch_if_match(&state, &session, "PATCH", &fresh, guid, &patch, check)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            current_rev(&err),
+            (Status::PreconditionFailed, Some(second.rev.clone()))
+        );
+
+        // Without If-Match, the patch is applied to the latest version.
+        let res = patch_if_match(
+            &state,
+            &session,
+            "PATCH",
+            &IfMatch::default(),
+            guid,
+            &patch,
+            check,
+        )
+        .await
+        .unwrap();
+        assert_ne!(res.rev, second.rev);
+    }
+}
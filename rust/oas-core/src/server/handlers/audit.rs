@@ -0,0 +1,118 @@
+use chrono::{DateTime, Utc};
+use oas_common::{Record, TypedValue};
+use rocket::get;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket_okapi::openapi;
+use serde_json::Value;
+
+use crate::couch::{AuditEntry, AuditFilter, Doc, PutResponse};
+use crate::server::auth::{AdminUser, SessionInfo};
+use crate::server::error::AppError;
+use crate::server::handlers::page_size;
+use crate::State;
+
+pub use crate::couch::audit::doc_value;
+
+/// Get the audit log of record modifications
+///
+/// Entries can be filtered by record guid, username and time. `since` is a RFC 3339 timestamp,
+/// e.g. `2021-06-01T00:00:00Z`. The entries are ordered by time. The first `offset` entries are
+/// skipped and at most `limit` entries are returned (default 100, max 1000).
+#[openapi(tag = "Audit")]
+#[get("/audit?<record>&<user>&<since>&<offset>&<limit>")]
+pub async fn get_audit(
+    _admin: AdminUser,
+    state: &State,
+    record: Option<String>,
+    user: Option<String>,
+    since: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> Result<Json<Vec<Record<AuditEntry>>>, AppError> {
+    let since = match since {
+        Some(since) => Some(
+            DateTime::parse_from_rfc3339(&since)
+                .map_err(|err| {
+                    AppError::Http(
+                        Status::BadRequest,
+                        format!("Invalid timestamp {}: {}", since, err),
+                    )
+                })?
+                .with_timezone(&Utc),
+        ),
+        None => None,
+    };
+    let filter = AuditFilter {
+        record_id: record,
+        username: user,
+        since,
+    };
+    let entries = state
+        .db_manager
+        .audit_log()
+        .list(&filter, offset.unwrap_or_default(), Some(page_size(limit)))
+        .await?;
+    Ok(Json(entries))
+}
+
+/// Save a record and add an entry for the change to the audit log.
+///
+/// The record is saved even if the audit entry cannot be written. In this case, the error is
+/// logged.
+pub async fn put_audited<T: TypedValue>(
+    state: &State,
+    session: &SessionInfo,
+    endpoint: &str,
+    record: Record<T>,
+) -> Result<PutResponse, AppError> {
+    put_doc_audited(state, session, endpoint, Doc::from_typed_record(record)).await
+}
+
+/// Save a doc and add an entry for the change to the audit log.
+///
+/// See [put_audited]. If the doc has no rev, it is saved with the rev of the loaded previous
+/// doc, so that a concurrent change fails with a conflict instead of being missed by the audit
+/// entry.
+pub async fn put_doc_audited(
+    state: &State,
+    session: &SessionInfo,
+    endpoint: &str,
+    mut doc: Doc,
+) -> Result<PutResponse, AppError> {
+    let guid = doc.id().to_string();
+    let previous = match state.db.get_doc(&guid).await {
+        Ok(doc) => Some(doc),
+        Err(err) if err.status_code() == Some(404) => None,
+        Err(err) => return Err(err.into()),
+    };
+    let previous_rev = previous
+        .as_ref()
+        .and_then(|doc| doc.rev())
+        .map(str::to_string);
+    if doc.rev().is_none() {
+        doc.set_rev(previous_rev.clone());
+    }
+    let value = doc_value(&doc);
+    let response = state.db.put_doc(doc).await?;
+
+    let previous_value = previous
+        .as_ref()
+        .map(doc_value)
+        .unwrap_or_else(|| Value::Object(Default::default()));
+    let patch = json_patch::diff(&previous_value, &value);
+    let entry = AuditEntry {
+        username: session.user().username.clone(),
+        token: session.token_id().map(str::to_string),
+        endpoint: endpoint.to_string(),
+        record_id: guid,
+        previous_rev,
+        rev: response.rev.clone(),
+        patch: serde_json::to_value(patch)?,
+        timestamp: Utc::now(),
+    };
+    if let Err(err) = state.db_manager.audit_log().add(entry).await {
+        log::error!("Failed to write audit entry for {}: {}", response.id, err);
+    }
+    Ok(response)
+}
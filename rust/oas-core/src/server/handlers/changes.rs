@@ -0,0 +1,452 @@
+//! Server-Sent Events stream of record changes.
+//!
+//! Clients connect to `GET /api/v1/changes/stream` and receive typed events (e.g.
+//! `post.created`, `media.asr.finished`) derived from the CouchDB changes feed. The event id is
+//! the CouchDB seq, so that clients can resume after a reconnect by sending a `Last-Event-ID`
+//! header.
+
+use chrono::Utc;
+use futures::stream::StreamExt;
+use oas_common::task::TaskState;
+use oas_common::types::{Feed, Media, Post};
+use oas_common::TypedValue;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::stream::{Event, EventStream};
+use rocket::{get, Shutdown};
+use rocket_okapi::openapi;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::couch::{ChangeEvent, CouchDB, Doc, RevStatus};
+use crate::rss::FeedErrorEvent;
+use crate::server::auth::{Permission, SessionInfo};
+use crate::server::visibility::doc_visibility;
+use crate::State;
+
+/// Fields that are removed from records sent to clients that may not view private fields.
+const PRIVATE_FIELDS: &[&str] = &["tasks", "taskDefaults"];
+
+/// The value of the `Last-Event-ID` header, if set.
+pub struct LastEventId(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let header = request.headers().get_one("Last-Event-ID");
+        Outcome::Success(LastEventId(header.map(|s| s.to_string())))
+    }
+}
+
+/// The kind of a change event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    PostCreated,
+    PostUpdated,
+    MediaCreated,
+    MediaUpdated,
+    MediaAsrRunning,
+    MediaAsrFinished,
+    FeedCreated,
+    FeedUpdated,
+    FeedError,
+}
+
+impl ChangeKind {
+    /// The event name as sent to clients.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PostCreated => "post.created",
+            Self::PostUpdated => "post.updated",
+            Self::MediaCreated => "media.created",
+            Self::MediaUpdated => "media.updated",
+            Self::MediaAsrRunning => "media.asr.running",
+            Self::MediaAsrFinished => "media.asr.finished",
+            Self::FeedCreated => "feed.created",
+            Self::FeedUpdated => "feed.updated",
+            Self::FeedError => "feed.error",
+        }
+    }
+
+    /// Derive the event kind from a changed doc and the previous rev of the doc.
+    ///
+    /// The ASR events are only emitted when the ASR task of a media changed its state or was
+    /// started again, so that other updates of a media with a finished transcript are sent as
+    /// `media.updated`. Returns None for docs that are not records of a known type.
+    fn from_doc(doc: &Doc, previous: Option<&Doc>) -> Option<Self> {
+        let is_first_rev = doc.is_first_rev().unwrap_or(false);
+        let typ = doc
+            .doc
+            .get("$meta")
+            .and_then(|meta| meta.get("type"))
+            .and_then(|typ| typ.as_str())?;
+        let kind = match typ {
+            Post::NAME if is_first_rev => Self::PostCreated,
+            Post::NAME => Self::PostUpdated,
+            Media::NAME => match asr_state(doc) {
+                Some(asr) if previous.and_then(asr_state).as_ref() != Some(&asr) => asr.0,
+                _ if is_first_rev => Self::MediaCreated,
+                _ => Self::MediaUpdated,
+            },
+            Feed::NAME if is_first_rev => Self::FeedCreated,
+            Feed::NAME => Self::FeedUpdated,
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+/// Get the ASR event kind and task id of a media doc whose ASR task is running or finished.
+fn asr_state(doc: &Doc) -> Option<(ChangeKind, String)> {
+    let asr = doc.doc.get("tasks").and_then(|tasks| tasks.get("asr"))?;
+    match serde_json::from_value::<TaskState>(asr.clone()).ok()? {
+        TaskState::Running(state) => Some((ChangeKind::MediaAsrRunning, state.task_id)),
+        TaskState::Finished(state) => Some((ChangeKind::MediaAsrFinished, state.task_id)),
+        _ => None,
+    }
+}
+
+/// Derive the event kind of a changed doc.
+///
+/// The previous rev is only fetched for media with a running or finished ASR task. If it is not
+/// available anymore, the change is treated as a state change.
+async fn change_kind(db: &CouchDB, doc: &Doc) -> Option<ChangeKind> {
+    let kind = ChangeKind::from_doc(doc, None)?;
+    if !matches!(
+        kind,
+        ChangeKind::MediaAsrRunning | ChangeKind::MediaAsrFinished
+    ) {
+        return Some(kind);
+    }
+    let previous = previous_doc(db, doc).await;
+    ChangeKind::from_doc(doc, previous.as_ref())
+}
+
+/// Get the rev of a doc before the given rev, if still available.
+async fn previous_doc(db: &CouchDB, doc: &Doc) -> Option<Doc> {
+    let rev = doc.rev()?;
+    let revs = match db.get_revisions(doc.id()).await {
+        Ok(revs) => revs,
+        Err(err) => {
+            log::debug!("Failed to get revisions of {}: {}", doc.id(), err);
+            return None;
+        }
+    };
+    let previous = revs
+        .iter()
+        .skip_while(|info| info.rev != rev)
+        .nth(1)
+        .filter(|info| info.status == RevStatus::Available)?;
+    db.get_doc_at_rev(doc.id(), &previous.rev).await.ok()
+}
+
+/// Filter for event kinds, parsed from a comma-separated list of event names or prefixes
+/// (e.g. `post.created,media.asr`).
+#[derive(Debug, Clone, Default)]
+struct EventFilter {
+    prefixes: Vec<String>,
+}
+
+impl EventFilter {
+    fn parse(events: Option<&str>) -> Self {
+        let prefixes = events
+            .map(|events| {
+                events
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { prefixes }
+    }
+
+    fn matches(&self, kind: ChangeKind) -> bool {
+        self.prefixes.is_empty()
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| kind.as_str().starts_with(prefix.as_str()))
+    }
+}
+
+/// A record change as sent to clients.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ChangeMessage {
+    guid: String,
+    rev: Option<String>,
+    deleted: bool,
+    record: Option<Value>,
+}
+
+impl ChangeMessage {
+    fn from_event(event: ChangeEvent, is_public: bool) -> Self {
+        let rev = event.changes.get(0).map(|change| change.rev.clone());
+        let record = event.doc.map(|doc| {
+            let mut value = doc.doc;
+            if is_public {
+                for field in PRIVATE_FIELDS {
+                    value.remove(*field);
+                }
+            }
+            Value::Object(value)
+        });
+        Self {
+            guid: event.id,
+            rev,
+            deleted: event.deleted,
+            record,
+        }
+    }
+}
+
+/// A feed error as sent to clients.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct FeedErrorMessage {
+    guid: Option<String>,
+    url: String,
+    error: Option<String>,
+}
+
+impl FeedErrorMessage {
+    fn from_event(event: FeedErrorEvent, is_public: bool) -> Self {
+        Self {
+            guid: event.guid,
+            url: event.url,
+            error: if is_public { None } else { Some(event.error) },
+        }
+    }
+}
+
+fn change_to_sse(
+    event: ChangeEvent,
+    kind: ChangeKind,
+    filter: &EventFilter,
+    is_public: bool,
+) -> Option<Event> {
+    if !filter.matches(kind) {
+        return None;
+    }
+    let seq = event.seq.clone();
+    let message = ChangeMessage::from_event(event, is_public);
+    Some(Event::json(&message).event(kind.as_str()).id(seq))
+}
+
+/// Check if the record of a change is listed for anonymous users.
+async fn is_listed(db: &CouchDB, event: &ChangeEvent) -> bool {
+    let doc = match &event.doc {
+        Some(doc) if !event.deleted => doc,
+        _ => return true,
+    };
+    match doc_visibility(db, doc, Utc::now()).await {
+        Ok(visibility) => visibility.is_listed(),
+        Err(err) => {
+            log::debug!("Failed to get visibility of {}: {}", event.id, err);
+            false
+        }
+    }
+}
+
+enum Next {
+    Change(ChangeEvent),
+    FeedError(FeedErrorEvent),
+    Skip,
+    End,
+}
+
+/// Stream record changes as Server-Sent Events.
+///
+/// The optional `events` query parameter is a comma-separated list of event names or prefixes to
+/// filter by (e.g. `?events=post.created,media.asr`). Clients without the permission to view
+/// private fields receive records without private fields, and no events for hidden posts and
+/// media.
+#[openapi(skip)]
+#[get("/changes/stream?<events>")]
+pub async fn changes_stream(
+    state: &State,
+    session: Option<SessionInfo>,
+    last_event_id: LastEventId,
+    events: Option<String>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let is_public = !session.map_or(false, |session| {
+        session.has_permission(Permission::ViewPrivate)
+    });
+    let filter = EventFilter::parse(events.as_deref());
+    let since = match last_event_id.0 {
+        Some(seq) => Some(seq),
+        None => state.db.get_last_seq().await.ok(),
+    };
+    let db = state.db.clone();
+    let mut changes = state.db.changes(since);
+    changes.set_infinite(true);
+    let mut feed_errors = state.feed_manager.subscribe_errors();
+
+    EventStream! {
+        loop {
+            let next = tokio::select! {
+                change = changes.next() => match change {
+                    Some(Ok(change)) => Next::Change(change),
+                    Some(Err(err)) => {
+                        log::debug!("Changes stream error: {}", err);
+                        Next::Skip
+                    }
+                    None => Next::End,
+                },
+                feed_error = feed_errors.recv() => match feed_error {
+                    Ok(feed_error) => Next::FeedError(feed_error),
+                    Err(broadcast::error::RecvError::Lagged(_)) => Next::Skip,
+                    Err(broadcast::error::RecvError::Closed) => Next::End,
+                },
+                _ = &mut shutdown => Next::End,
+            };
+            match next {
+                Next::Change(change) => {
+                    if is_public && !is_listed(&db, &change).await {
+                        continue;
+                    }
+                    let kind = match &change.doc {
+                        Some(doc) => change_kind(&db, doc).await,
+                        None => None,
+                    };
+                    if let Some(event) = kind
+                        .and_then(|kind| change_to_sse(change, kind, &filter, is_public))
+                    {
+                        yield event;
+                    }
+                }
+                Next::FeedError(feed_error) => {
+                    if filter.matches(ChangeKind::FeedError) {
+                        let message = FeedErrorMessage::from_event(feed_error, is_public);
+                        yield Event::json(&message).event(ChangeKind::FeedError.as_str());
+                    }
+                }
+                Next::Skip => {}
+                Next::End => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::couch::Change;
+    use oas_common::Record;
+    use serde_json::json;
+
+    fn media(asr: Option<Value>) -> Doc {
+        let mut doc = Doc::from_typed_record(Record::from_id_and_value("m1", Media::default()));
+        if let Some(asr) = asr {
+            doc.doc.insert("tasks".to_string(), json!({ "asr": asr }));
+        }
+        doc
+    }
+
+    fn running(task_id: &str) -> Option<Value> {
+        Some(json!({ "state": "running", "taskId": task_id, "start": Utc::now() }))
+    }
+
+    fn finished(task_id: &str) -> Option<Value> {
+        Some(json!({ "state": "finished", "taskId": task_id, "success": true }))
+    }
+
+    async fn put_and_get_kind(db: &CouchDB, doc: Doc) -> Option<ChangeKind> {
+        let res = db.put_doc(doc).await.unwrap();
+        let doc = db.get_doc_at_rev(&res.id, &res.rev).await.unwrap();
+        change_kind(db, &doc).await
+    }
+
+    #[tokio::test]
+    async fn asr_events_on_state_change() {
+        let state = State::in_memory();
+        let db = &state.db;
+        let kinds = vec![
+            put_and_get_kind(db, media(None)).await,
+            put_and_get_kind(db, media(running("t1"))).await,
+            put_and_get_kind(db, media(running("t1"))).await,
+            put_and_get_kind(db, media(finished("t1"))).await,
+            put_and_get_kind(db, media(finished("t1"))).await,
+            put_and_get_kind(db, media(running("t2"))).await,
+            put_and_get_kind(db, media(finished("t2"))).await,
+        ];
+        let kinds: Vec<_> = kinds
+            .into_iter()
+            .map(|kind| kind.unwrap().as_str())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "media.created",
+                "media.asr.running",
+                "media.updated",
+                "media.asr.finished",
+                "media.updated",
+                "media.asr.running",
+                "media.asr.finished",
+            ]
+        );
+
+        // A media that is created with a finished transcript is an ASR event.
+        let mut created = media(finished("t3"));
+        created.set_rev(Some("1-a".to_string()));
+        assert_eq!(
+            ChangeKind::from_doc(&created, None),
+            Some(ChangeKind::MediaAsrFinished)
+        );
+    }
+
+    #[test]
+    fn map_record_types() {
+        let doc = |typ: &str, rev: &str| {
+            let mut doc = media(None);
+            doc.doc.insert("$meta".to_string(), json!({ "type": typ }));
+            doc.set_rev(Some(rev.to_string()));
+            doc
+        };
+        let kind = |typ: &str, rev: &str| ChangeKind::from_doc(&doc(typ, rev), None);
+        assert_eq!(kind(Post::NAME, "1-a"), Some(ChangeKind::PostCreated));
+        assert_eq!(kind(Post::NAME, "2-a"), Some(ChangeKind::PostUpdated));
+        assert_eq!(kind(Feed::NAME, "1-a"), Some(ChangeKind::FeedCreated));
+        assert_eq!(kind(Feed::NAME, "2-a"), Some(ChangeKind::FeedUpdated));
+        assert_eq!(kind(Media::NAME, "2-a"), Some(ChangeKind::MediaUpdated));
+        assert_eq!(kind("oas.Other", "1-a"), None);
+
+        let filter = EventFilter::parse(Some("post.created, media.asr"));
+        assert!(filter.matches(ChangeKind::PostCreated));
+        assert!(!filter.matches(ChangeKind::PostUpdated));
+        assert!(filter.matches(ChangeKind::MediaAsrFinished));
+        assert!(EventFilter::parse(None).matches(ChangeKind::FeedError));
+    }
+
+    #[test]
+    fn strip_private_fields() {
+        let mut doc = media(finished("t1"));
+        doc.doc
+            .insert("taskDefaults".to_string(), json!({ "asr": true }));
+        let event = |doc: Doc| ChangeEvent {
+            seq: "1".to_string(),
+            id: doc.id().to_string(),
+            changes: vec![Change {
+                rev: "1-a".to_string(),
+            }],
+            deleted: false,
+            doc: Some(doc),
+        };
+
+        let public = ChangeMessage::from_event(event(doc.clone()), true);
+        let record = public.record.unwrap();
+        assert!(record.get("tasks").is_none());
+        assert!(record.get("taskDefaults").is_none());
+        assert!(record.get("contentUrl").is_some());
+        assert_eq!(public.rev.as_deref(), Some("1-a"));
+
+        let private = ChangeMessage::from_event(event(doc), false);
+        let record = private.record.unwrap();
+        assert!(record.get("tasks").is_some());
+        assert!(record.get("taskDefaults").is_some());
+    }
+}
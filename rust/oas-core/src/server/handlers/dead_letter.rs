@@ -0,0 +1,75 @@
+use oas_common::Record;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{delete, get, post};
+use rocket_okapi::openapi;
+
+use crate::couch::DeadLetter;
+use crate::server::auth::AdminUser;
+use crate::server::error::AppError;
+use crate::State;
+
+/// Get all records that failed processing, optionally filtered by consumer
+#[openapi(tag = "Dead letter")]
+#[get("/dead-letters?<consumer>")]
+pub async fn get_dead_letters(
+    _user: AdminUser,
+    state: &State,
+    consumer: Option<String>,
+) -> Result<Json<Vec<Record<DeadLetter>>>, AppError> {
+    let dead_letters = state.db_manager.dead_letters();
+    let records = dead_letters.list(consumer.as_deref()).await?;
+    Ok(Json(records))
+}
+
+/// Get a dead letter by its id
+#[openapi(tag = "Dead letter")]
+#[get("/dead-letter/<id>")]
+pub async fn get_dead_letter(
+    _user: AdminUser,
+    state: &State,
+    id: String,
+) -> Result<Json<Record<DeadLetter>>, AppError> {
+    let record = load_dead_letter(state, &id).await?;
+    Ok(Json(record))
+}
+
+/// Replay a dead letter
+///
+/// Processes the current version of the record again. On success, the dead letter is removed.
+#[openapi(tag = "Dead letter")]
+#[post("/dead-letter/<id>/replay")]
+pub async fn post_dead_letter_replay(
+    _user: AdminUser,
+    state: &State,
+    id: String,
+) -> Result<(), AppError> {
+    let record = load_dead_letter(state, &id).await?;
+    crate::replay::replay_dead_letter(state, record)
+        .await
+        .map_err(|err| AppError::Other(format!("{:#}", err)))
+}
+
+/// Delete a dead letter without replaying it
+#[openapi(tag = "Dead letter")]
+#[delete("/dead-letter/<id>")]
+pub async fn delete_dead_letter(
+    _user: AdminUser,
+    state: &State,
+    id: String,
+) -> Result<(), AppError> {
+    load_dead_letter(state, &id).await?;
+    state.db_manager.dead_letters().remove(&id).await?;
+    Ok(())
+}
+
+async fn load_dead_letter(state: &State, id: &str) -> Result<Record<DeadLetter>, AppError> {
+    let dead_letters = state.db_manager.dead_letters();
+    match dead_letters.get(id).await? {
+        Some(record) => Ok(record),
+        None => Err(AppError::Http(
+            Status::NotFound,
+            format!("Dead letter {} not found", id),
+        )),
+    }
+}
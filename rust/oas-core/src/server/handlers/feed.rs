@@ -1,67 +1,108 @@
 use oas_common::{types, util, Record, TypedValue};
+use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::{get, post, put};
 use rocket_okapi::openapi;
 
-use crate::couch::types::PutResponse;
-use crate::server::auth::AdminUser;
+use crate::couch::types::{Doc, PutResponse};
+use crate::server::auth::{Auth, FeedManager, PrivateReader};
 use crate::server::error::AppError;
+use crate::server::etag::{put_if_match, IfMatch, TaggedResult};
 use crate::State;
 
 /// Create a new feed
+///
+/// The feed is owned by the user that created it.
 #[openapi(tag = "Feed")]
 #[post("/feed", data = "<body>")]
 pub async fn post_feed(
-    _user: AdminUser,
-    state: &rocket::State<State>,
+    user: FeedManager,
+    state: &State,
+    auth: &rocket::State<Auth>,
     body: Json<types::Feed>,
-) -> Result<Json<PutResponse>, AppError> {
+) -> TaggedResult<PutResponse> {
     // rocket::debug!("url: {}", body.into_inner().url);
     let feed = body.into_inner();
     let feed = Record::from_id_and_value(util::id_from_hashed_string(&feed.url), feed);
     match feed.validate() {
-        Ok(_) => {
-            let result = state.db.put_record(feed).await?;
-            Ok(Json(result))
-        }
+        Ok(_) => save_feed(&user, state, auth, "POST /feed", &IfMatch::default(), feed).await,
         Err(err) => Err(AppError::ValidationError(err)),
     }
 }
 
 /// Put feed info by feed ID
+///
+/// Existing feeds can only be changed by their owners.
 #[openapi(tag = "Feed")]
 #[put("/feed/<id>", data = "<body>")]
 pub async fn put_feed(
-    _user: AdminUser,
-    state: &rocket::State<State>,
+    user: FeedManager,
+    state: &State,
+    auth: &rocket::State<Auth>,
+    if_match: IfMatch,
     id: String,
     body: Json<types::Feed>,
-) -> Result<Json<PutResponse>, AppError> {
+) -> TaggedResult<PutResponse> {
     let feed = body.into_inner();
     let feed = Record::from_id_and_value(id, feed);
-    let result = state.db.put_record(feed).await?;
+    save_feed(&user, state, auth, "PUT /feed/<id>", &if_match, feed).await
+}
 
-    Ok(Json(result))
+/// Save a feed if the user owns it, or if it is new. New feeds are added to the user's feeds.
+///
+/// Tasks are not run for the records of tenants, so their feeds may not have task defaults.
+async fn save_feed(
+    user: &FeedManager,
+    state: &State,
+    auth: &Auth,
+    endpoint: &str,
+    if_match: &IfMatch,
+    feed: Record<types::Feed>,
+) -> TaggedResult<PutResponse> {
+    if user.session().user().tenant.is_some() && feed.value.task_defaults.is_some() {
+        return Err(AppError::Http(
+            Status::BadRequest,
+            "Feeds of tenants cannot have task defaults".into(),
+        ));
+    }
+    let guid = feed.guid().to_string();
+    let exists = match state.db.get_doc(&guid).await {
+        Ok(_) => true,
+        Err(err) if err.status_code() == Some(404) => false,
+        Err(err) => return Err(err.into()),
+    };
+    if exists {
+        user.ensure_feed(&guid)?;
+    }
+    let doc = Doc::from_typed_record(feed);
+    let result = put_if_match(state, user.session(), endpoint, if_match, doc).await?;
+    if !exists && !user.has_all_feeds() {
+        auth.add_feed_owner(user.username(), &guid).await?;
+    }
+    Ok(result.into())
 }
 
 /// Get a feed by its id
 #[openapi(tag = "Feed")]
 #[get("/feed/<id>")]
 pub async fn get_feed(
-    _user: AdminUser,
-    state: &rocket::State<State>,
+    _user: PrivateReader,
+    state: &State,
     id: String,
-) -> Result<Json<Record<types::Feed>>, AppError> {
-    let feed = state.db.get_record(&types::Feed::guid(&id)).await?;
-    Ok(Json(feed))
+) -> TaggedResult<Record<types::Feed>> {
+    let (feed, rev) = state
+        .db
+        .get_record_with_rev(&types::Feed::guid(&id))
+        .await?;
+    Ok((feed, rev).into())
 }
 
 /// Get all feeds
 #[openapi(tag = "Feed")]
 #[get("/feed")]
 pub async fn get_feeds(
-    _user: AdminUser,
-    state: &rocket::State<State>,
+    _user: PrivateReader,
+    state: &State,
 ) -> Result<Json<Vec<Record<types::Feed>>>, AppError> {
     let feeds = state.db.get_all_records().await?;
     Ok(Json(feeds))
@@ -0,0 +1,180 @@
+use oas_common::Record;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{delete, get, post};
+use rocket_okapi::openapi;
+
+use crate::server::auth::{TaskTrigger, WorkerUser};
+use crate::server::error::AppError;
+use crate::server::handlers::page_size;
+use crate::tasks::{
+    CancelJobResponse, FeedAccess, Job, JobClaim, JobResult, JobResultError, JobStart, JobStatus,
+};
+use crate::State;
+
+/// Get jobs, optionally filtered by status and task name
+///
+/// The jobs are sorted by creation time, newest first. `offset` and `limit` select a page of the
+/// jobs (by default the first 100). Users that may not access all feeds only get the jobs of
+/// their feeds.
+#[openapi(tag = "Job")]
+#[get("/jobs?<status>&<typ>&<offset>&<limit>")]
+pub async fn get_jobs(
+    user: TaskTrigger,
+    state: &rocket::State<State>,
+    status: Option<String>,
+    typ: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> Result<Json<Vec<Record<Job>>>, AppError> {
+    let status = match status {
+        Some(status) => Some(JobStatus::from_name(&status).ok_or_else(|| {
+            AppError::Http(
+                Status::BadRequest,
+                format!("Invalid job status: {}", status),
+            )
+        })?),
+        None => None,
+    };
+    let offset = offset.unwrap_or_default();
+    let limit = Some(page_size(limit));
+    let jobs = state.tasks.jobs();
+    let records = match user.feed_access() {
+        FeedAccess::All => jobs.list(status, typ.as_deref(), offset, limit).await?,
+        FeedAccess::Feeds(feeds) => {
+            jobs.list_for_feeds(&feeds, status, typ.as_deref(), offset, limit)
+                .await?
+        }
+    };
+    Ok(Json(records))
+}
+
+/// Get a job by its id
+///
+/// Users that may not access all feeds only get the jobs of their feeds.
+#[openapi(tag = "Job")]
+#[get("/job/<id>")]
+pub async fn get_job(
+    user: TaskTrigger,
+    state: &rocket::State<State>,
+    id: String,
+) -> Result<Json<Record<Job>>, AppError> {
+    match state.tasks.jobs().get(&id).await? {
+        Some(record) if user.feed_access().allows_job(&record.value) => Ok(Json(record)),
+        Some(_) => Err(AppError::Http(
+            Status::Forbidden,
+            format!("Job {} belongs to a feed of another user", id),
+        )),
+        None => Err(AppError::Http(
+            Status::NotFound,
+            format!("Job {} not found", id),
+        )),
+    }
+}
+
+/// Cancel a held, queued or running job
+///
+/// The job is removed from the queue if the queue backend supports this. Otherwise a worker may
+/// still run the job, but its result is rejected. The task state on the target record is
+/// cleared.
+#[openapi(tag = "Job")]
+#[delete("/job/<id>")]
+pub async fn delete_job(
+    user: TaskTrigger,
+    state: &rocket::State<State>,
+    id: String,
+) -> Result<Json<CancelJobResponse>, AppError> {
+    let response = state
+        .tasks
+        .cancel_job(&state.db, &id, user.username(), &user.feed_access())
+        .await?;
+    Ok(Json(response))
+}
+
+/// Claim the next queued job
+///
+/// Only available with the `couch` task queue, where workers claim jobs themselves. Workers
+/// authenticate with the worker token as a bearer token. Jobs with a higher priority are claimed
+/// first, then the oldest jobs. The claimed job is marked as running. Returns null if there is no
+/// queued job.
+#[openapi(tag = "Job")]
+#[post("/jobs/claim", data = "<claim>")]
+pub async fn post_job_claim(
+    _worker: WorkerUser,
+    state: &rocket::State<State>,
+    claim: Json<JobClaim>,
+) -> Result<Json<Option<Record<Job>>>, AppError> {
+    if !state.tasks.queue().claims_jobs() {
+        return Err(AppError::Http(
+            Status::BadRequest,
+            format!(
+                "Jobs cannot be claimed from the {} task queue",
+                state.tasks.queue().name()
+            ),
+        ));
+    }
+    let record = state
+        .tasks
+        .claim(&claim)
+        .await
+        .map_err(|err| AppError::Other(format!("{:#}", err)))?;
+    Ok(Json(record))
+}
+
+/// Report the start of a job
+///
+/// Only used with the `celery` task queue, where jobs are sent to the workers. A sent job waits
+/// in the queue until a worker reports its start, and the task timeout only counts from this
+/// start. Workers authenticate with the worker token as a bearer token.
+#[openapi(tag = "Job")]
+#[post("/job/<id>/start", data = "<start>")]
+pub async fn post_job_start(
+    _worker: WorkerUser,
+    state: &rocket::State<State>,
+    id: String,
+    start: Json<JobStart>,
+) -> Result<Json<Record<Job>>, AppError> {
+    let record = state
+        .tasks
+        .report_start(&id, start.into_inner())
+        .await
+        .map_err(job_error)?;
+    Ok(Json(record))
+}
+
+/// Submit the result of a job
+///
+/// Workers authenticate with the worker token as a bearer token. The output is validated against
+/// the output type of the job's task. Then the target record is updated with the output and the
+/// finished task state, and the job is marked as finished (or failed, if an error is set). The
+/// change of the target record is added to the audit log.
+#[openapi(tag = "Job")]
+#[post("/job/<id>/result", data = "<result>")]
+pub async fn post_job_result(
+    _worker: WorkerUser,
+    state: &rocket::State<State>,
+    id: String,
+    result: Json<JobResult>,
+) -> Result<Json<Record<Job>>, AppError> {
+    let record = state
+        .tasks
+        .submit_result(
+            &state.db,
+            &state.db_manager.audit_log(),
+            &id,
+            result.into_inner(),
+        )
+        .await
+        .map_err(job_error)?;
+    Ok(Json(record))
+}
+
+fn job_error(err: JobResultError) -> AppError {
+    match err {
+        JobResultError::NotFound(_) => AppError::Http(Status::NotFound, err.to_string()),
+        JobResultError::Conflict(..) => AppError::Http(Status::Conflict, err.to_string()),
+        JobResultError::Invalid(_) => AppError::Http(Status::UnprocessableEntity, err.to_string()),
+        JobResultError::Couch(err) => AppError::Couch(err),
+        JobResultError::Other(err) => AppError::Other(format!("{:#}", err)),
+    }
+}
@@ -1,3 +1,4 @@
+use chrono::Utc;
 use oas_common::types::Media;
 use oas_common::{util, Record, TypedValue};
 use rocket::serde::json::Json;
@@ -5,34 +6,47 @@ use rocket::{get, patch, post, put};
 use rocket_okapi::openapi;
 use serde_json::Value;
 
-use crate::couch::PutResponse;
-use crate::server::auth::AdminUser;
+use crate::couch::{Doc, PutResponse};
+use crate::server::auth::{PostEditor, PrivateReader};
 use crate::server::error::{AppError, Result};
+use crate::server::etag::{patch_if_match, put_if_match, IfMatch, TaggedResult};
+use crate::server::handlers::audit::put_audited;
 use crate::server::proxy;
+use crate::server::visibility::{ensure_accessible, media_visibility};
 
 // pub fn routes() -> Vec<Route> {
 //     routes![get_media, post_media, put_media, patch_media]
 // }
 
 /// Get a media record by id.
+///
+/// Media of hidden posts are only returned to users that may view private records.
 #[openapi(tag = "Media")]
 #[get("/media/<id>")]
-pub async fn get_media(state: &rocket::State<crate::State>, id: String) -> Result<Record<Media>> {
-    let record = state.db.get_record(&Media::guid(&id)).await?;
-    Ok(Json(record))
+pub async fn get_media(
+    state: &crate::State,
+    reader: Option<PrivateReader>,
+    id: String,
+) -> TaggedResult<Record<Media>> {
+    let (record, rev) = state.db.get_record_with_rev(&Media::guid(&id)).await?;
+    let visibility = media_visibility(&state.db, &record.value, Utc::now()).await?;
+    ensure_accessible(visibility, reader.as_ref(), record.guid())?;
+    Ok((record, rev).into())
 }
 
 /// Create a new media record
 #[openapi(tag = "Media")]
 #[post("/media", data = "<value>")]
 pub async fn post_media(
-    _user: AdminUser,
-    state: &rocket::State<crate::State>,
+    user: PostEditor,
+    state: &crate::State,
     value: Json<Media>,
 ) -> Result<PutResponse> {
     let value = value.into_inner();
     let record = Record::from_id_and_value(util::id_from_hashed_string(&value.content_url), value);
-    let res = state.db.put_record(record).await?;
+    user.ensure_feeds(&record.value.feeds)?;
+    ensure_can_edit(&user, state, record.guid()).await?;
+    let res = put_audited(state, user.session(), "POST /media", record).await?;
     Ok(Json(res))
 }
 
@@ -40,37 +54,50 @@ pub async fn post_media(
 #[openapi(tag = "Media")]
 #[put("/media/<id>", data = "<value>")]
 pub async fn put_media(
-    _user: AdminUser,
-    state: &rocket::State<crate::State>,
+    user: PostEditor,
+    state: &crate::State,
+    if_match: IfMatch,
     id: String,
     value: Json<Media>,
-) -> Result<PutResponse> {
+) -> TaggedResult<PutResponse> {
     let (_typ, id) = util::split_and_check_guid::<Media>(&id)?;
     let record = Record::from_id_and_value(id, value.into_inner());
-    let res = state.db.put_record(record).await?;
-    Ok(Json(res))
+    user.ensure_feeds(&record.value.feeds)?;
+    ensure_can_edit(&user, state, record.guid()).await?;
+    let doc = Doc::from_typed_record(record);
+    let endpoint = "PUT /media/<id>";
+    let res = put_if_match(state, user.session(), endpoint, &if_match, doc).await?;
+    Ok(res.into())
 }
 
 /// Patch (update) a media record.
+///
+/// Workers should report task results with `POST /job/<id>/result` instead.
 #[openapi(tag = "Media")]
 #[patch("/media/<id>", data = "<value>")]
 pub async fn patch_media(
-    _user: AdminUser,
-    state: &rocket::State<crate::State>,
+    user: PostEditor,
+    state: &crate::State,
+    if_match: IfMatch,
     id: String,
     value: Json<Value>,
-) -> Result<PutResponse> {
-    let db = &state.db;
+) -> TaggedResult<PutResponse> {
     let guid = Media::guid(&id);
+    ensure_can_edit(&user, state, &guid).await?;
 
     let patch: json_patch::Patch = serde_json::from_value(value.into_inner())?;
-    let mut existing = db.get_doc(&guid).await?.into_untyped_record()?;
-    let res = existing.apply_json_patch(&patch);
-    res?;
-    let res = existing.into_typed_record::<Media>();
-    let record = res?;
-    let res = db.put_record(record).await?;
-    Ok(Json(res))
+    let endpoint = "PATCH /media/<id>";
+    let res = patch_if_match(
+        state,
+        user.session(),
+        endpoint,
+        &if_match,
+        &guid,
+        &patch,
+        |record: &Record<Media>| user.ensure_feeds(&record.value.feeds),
+    )
+    .await?;
+    Ok(res.into())
 }
 
 /// Get a media record by id.
@@ -79,10 +106,13 @@ pub async fn patch_media(
 #[get("/media/<id>/data")]
 pub async fn get_media_data(
     headers: proxy::Headers<'_>,
-    state: &rocket::State<crate::State>,
+    state: &crate::State,
+    reader: Option<PrivateReader>,
     id: String,
 ) -> std::result::Result<proxy::ReqwestResponse, AppError> {
     let record: Record<Media> = state.db.get_record(&Media::guid(&id)).await?;
+    let visibility = media_visibility(&state.db, &record.value, Utc::now()).await?;
+    ensure_accessible(visibility, reader.as_ref(), record.guid())?;
     let url = record.value.content_url;
     let client = reqwest::Client::new();
     let mut req = client.get(url).build().unwrap();
@@ -95,3 +125,18 @@ pub async fn get_media_data(
         Err(err) => Err(AppError::Other(format!("{}", err))),
     }
 }
+
+/// Fail with 403 Forbidden unless the user owns one of the feeds of the existing media.
+///
+/// Passes if the media does not exist yet.
+async fn ensure_can_edit(
+    user: &PostEditor,
+    state: &crate::State,
+    guid: &str,
+) -> std::result::Result<(), AppError> {
+    match state.db.get_record::<Media>(guid).await {
+        Ok(existing) => user.ensure_feeds(&existing.value.feeds),
+        Err(err) if err.status_code() == Some(404) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
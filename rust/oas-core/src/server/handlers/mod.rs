@@ -1,6 +1,22 @@
+pub mod audit;
+pub mod changes;
+pub mod dead_letter;
 pub mod feed;
+pub mod job;
 pub mod media;
 pub mod post;
 pub mod record;
 pub mod search;
 pub mod task;
+pub mod tenant;
+pub mod token;
+
+/// Default number of items on a page of a list endpoint.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+/// Max number of items on a page of a list endpoint.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Get the page size for a list endpoint from the `limit` query param.
+pub fn page_size(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE)
+}
@@ -1,3 +1,4 @@
+use chrono::Utc;
 use oas_common::types::Post;
 use oas_common::{util, Record, TypedValue};
 use rocket::serde::json::Json;
@@ -5,32 +6,45 @@ use rocket::{get, patch, post, put};
 use rocket_okapi::openapi;
 use serde_json::Value;
 
-use crate::couch::PutResponse;
-use crate::server::auth::AdminUser;
-use crate::server::error::Result;
+use crate::couch::{Doc, PutResponse};
+use crate::server::auth::{PostEditor, PrivateReader};
+use crate::server::error::{AppError, Result};
+use crate::server::etag::{patch_if_match, put_if_match, IfMatch, TaggedResult};
+use crate::server::handlers::audit::put_audited;
+use crate::server::visibility::{ensure_accessible, post_visibility};
 
 /// Get a post record by id.
+///
+/// Private and embargoed posts are only returned to users that may view private records.
 #[openapi(tag = "Post")]
 #[get("/post/<id>")]
-pub async fn get_post(state: &rocket::State<crate::State>, id: String) -> Result<Record<Post>> {
-    let mut record = state.db.get_record(&Post::guid(&id)).await?;
+pub async fn get_post(
+    state: &crate::State,
+    reader: Option<PrivateReader>,
+    id: String,
+) -> TaggedResult<Record<Post>> {
+    let (mut record, rev) = state.db.get_record_with_rev(&Post::guid(&id)).await?;
+    let visibility = post_visibility(&state.db, &record.value, Utc::now()).await?;
+    ensure_accessible(visibility, reader.as_ref(), record.guid())?;
     let _ = record.resolve_refs(&state.db).await;
-    Ok(Json(record))
+    Ok((record, rev).into())
 }
 
 /// Create a new post record
 #[openapi(tag = "Post")]
 #[post("/post", data = "<value>")]
 pub async fn post_post(
-    _user: AdminUser,
-    state: &rocket::State<crate::State>,
+    user: PostEditor,
+    state: &crate::State,
     value: Json<Post>,
 ) -> Result<PutResponse> {
     let mut value = value.into_inner();
     let id = value.identifier.unwrap_or_else(|| util::id_from_uuid());
     value.identifier = Some(id.clone());
     let record = Record::from_id_and_value(id, value);
-    let res = state.db.put_record(record).await?;
+    user.ensure_feeds(&record.value.feeds)?;
+    ensure_can_edit(&user, state, record.guid()).await?;
+    let res = put_audited(state, user.session(), "POST /post", record).await?;
     Ok(Json(res))
 }
 
@@ -38,34 +52,61 @@ pub async fn post_post(
 #[openapi(tag = "Post")]
 #[put("/post/<id>", data = "<value>")]
 pub async fn put_post(
-    _user: AdminUser,
-    state: &rocket::State<crate::State>,
+    user: PostEditor,
+    state: &crate::State,
+    if_match: IfMatch,
     id: String,
     value: Json<Post>,
-) -> Result<PutResponse> {
+) -> TaggedResult<PutResponse> {
     let (_typ, id) = util::split_and_check_guid::<Post>(&id)?;
     let record = Record::from_id_and_value(id, value.into_inner());
-    let res = state.db.put_record(record).await?;
-    Ok(Json(res))
+    user.ensure_feeds(&record.value.feeds)?;
+    ensure_can_edit(&user, state, record.guid()).await?;
+    let doc = Doc::from_typed_record(record);
+    let endpoint = "PUT /post/<id>";
+    let res = put_if_match(state, user.session(), endpoint, &if_match, doc).await?;
+    Ok(res.into())
 }
 
 /// Patch (update) a post record.
 #[openapi(tag = "Post")]
 #[patch("/post/<id>", data = "<value>")]
 pub async fn patch_post(
-    _user: AdminUser,
-    state: &rocket::State<crate::State>,
+    user: PostEditor,
+    state: &crate::State,
+    if_match: IfMatch,
     id: String,
     value: Json<Value>,
-) -> Result<PutResponse> {
-    let db = &state.db;
+) -> TaggedResult<PutResponse> {
     let guid = Post::guid(&id);
+    ensure_can_edit(&user, state, &guid).await?;
 
     let patch: json_patch::Patch = serde_json::from_value(value.into_inner())?;
-    let mut existing = db.get_doc(&guid).await?.into_untyped_record()?;
+    let endpoint = "PATCH /post/<id>";
+    let res = patch_if_match(
+        state,
+        user.session(),
+        endpoint,
+        &if_match,
+        &guid,
+        &patch,
+        |record: &Record<Post>| user.ensure_feeds(&record.value.feeds),
+    )
+    .await?;
+    Ok(res.into())
+}
 
-    existing.apply_json_patch(&patch)?;
-    let record = existing.into_typed_record::<Post>()?;
-    let res = db.put_record(record).await?;
-    Ok(Json(res))
+/// Fail with 403 Forbidden unless the user owns one of the feeds of the existing post.
+///
+/// Passes if the post does not exist yet.
+async fn ensure_can_edit(
+    user: &PostEditor,
+    state: &crate::State,
+    guid: &str,
+) -> std::result::Result<(), AppError> {
+    match state.db.get_record::<Post>(guid).await {
+        Ok(existing) => user.ensure_feeds(&existing.value.feeds),
+        Err(err) if err.status_code() == Some(404) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
 }
@@ -1,13 +1,21 @@
-use oas_common::types::{Feed, Media};
+use chrono::{DateTime, Utc};
+use oas_common::types::{Feed, Media, Post};
 use oas_common::{TypedValue, UntypedRecord};
+use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::{get, post};
 use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
 
-use crate::couch::Doc;
+use crate::couch::{AuditFilter, Doc, PutResponse, RevStatus};
 use crate::server::error::{AppError, Result};
-use crate::server::auth::AdminUser;
+use crate::server::auth::{AdminUser, PostEditor, PrivateReader, SessionInfo};
+use crate::server::etag::{put_if_match, IfMatch};
+use crate::server::handlers::audit::{doc_value, put_audited};
+use crate::server::visibility::{doc_visibility, ensure_accessible};
 
 // pub fn routes() -> Vec<Route> {
 //     routes![get_record, post_record]
@@ -17,9 +25,15 @@ use crate::server::auth::AdminUser;
 // #[openapi(tag = "Record")]
 #[openapi(skip)]
 #[get("/record/<guid>")]
-pub async fn get_record(state: &rocket::State<crate::State>, guid: String) -> Result<Doc> {
+pub async fn get_record(
+    state: &crate::State,
+    reader: Option<PrivateReader>,
+    guid: String,
+) -> Result<Doc> {
     let db = &state.db;
     let doc = db.get_doc(&guid).await?;
+    let visibility = doc_visibility(db, &doc, Utc::now()).await?;
+    ensure_accessible(visibility, reader.as_ref(), &guid)?;
     Ok(doc.into())
 }
 
@@ -28,24 +42,285 @@ pub async fn get_record(state: &rocket::State<crate::State>, guid: String) -> Re
 #[openapi(skip)]
 #[post("/record", data = "<record>")]
 pub async fn post_record(
-    _user: AdminUser,
-    state: &rocket::State<crate::State>,
+    user: AdminUser,
+    state: &crate::State,
     record: Json<UntypedRecord>,
 ) -> Result<serde_json::Value> {
-    let db = &state.db;
-
     let record = record.into_inner();
     match record.typ() {
         Media::NAME => {
             let record = record.into_typed_record::<Media>()?;
-            db.put_record(record).await?;
+            put_audited(state, user.session(), "POST /record", record).await?;
             Ok(Value::Bool(true).into())
         }
         Feed::NAME => {
             let record = record.into_typed_record::<Feed>()?;
-            db.put_record(record).await?;
+            put_audited(state, user.session(), "POST /record", record).await?;
             Ok(Value::Bool(true).into())
         }
         _ => Err(AppError::Other("Unknown type".to_string())),
     }
 }
+
+/// A revision of a record.
+///
+/// Author and time are taken from the audit log. They are not set for changes that were not
+/// made through the API, e.g. by the task queue.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Revision {
+    pub rev: String,
+    pub status: RevStatus,
+    pub username: Option<String>,
+    pub token: Option<String>,
+    pub endpoint: Option<String>,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+/// Get the revisions of a record, newest first
+///
+/// Old revisions may not be available anymore, e.g. after the database was compacted.
+#[openapi(tag = "Record")]
+#[get("/record/<guid>/history")]
+pub async fn get_record_history(
+    _user: PrivateReader,
+    state: &crate::State,
+    guid: String,
+) -> Result<Vec<Revision>> {
+    Ok(Json(record_history(state, guid).await?))
+}
+
+/// Get the revisions of a record with their authors from the audit log, newest first.
+async fn record_history(
+    state: &crate::State,
+    guid: String,
+) -> std::result::Result<Vec<Revision>, AppError> {
+    let revs = state.db.get_revisions(&guid).await?;
+    let filter = AuditFilter {
+        record_id: Some(guid),
+        ..Default::default()
+    };
+    let mut entries: HashMap<_, _> = state
+        .db_manager
+        .audit_log()
+        .list(&filter, 0, None)
+        .await?
+        .into_iter()
+        .map(|record| (record.value.rev.clone(), record.value))
+        .collect();
+    let history = revs
+        .into_iter()
+        .map(|info| {
+            let entry = entries.remove(&info.rev);
+            Revision {
+                rev: info.rev,
+                status: info.status,
+                username: entry.as_ref().map(|entry| entry.username.clone()),
+                token: entry.as_ref().and_then(|entry| entry.token.clone()),
+                endpoint: entry.as_ref().map(|entry| entry.endpoint.clone()),
+                timestamp: entry.as_ref().map(|entry| entry.timestamp),
+            }
+        })
+        .collect();
+    Ok(history)
+}
+
+/// Get a record at a previous revision
+#[openapi(tag = "Record")]
+#[get("/record/<guid>/history/<rev>")]
+pub async fn get_record_revision(
+    _user: PrivateReader,
+    state: &crate::State,
+    guid: String,
+    rev: String,
+) -> Result<UntypedRecord> {
+    let doc = state.db.get_doc_at_rev(&guid, &rev).await?;
+    Ok(Json(doc.into_untyped_record()?))
+}
+
+/// Get the changes between two revisions of a record as a JSON patch
+///
+/// If `to` is not set, the changes up to the current revision are returned.
+#[openapi(tag = "Record")]
+#[get("/record/<guid>/diff?<from>&<to>")]
+pub async fn get_record_diff(
+    _user: PrivateReader,
+    state: &crate::State,
+    guid: String,
+    from: String,
+    to: Option<String>,
+) -> Result<Value> {
+    let db = &state.db;
+    let from = db.get_doc_at_rev(&guid, &from).await?;
+    let to = match to {
+        Some(to) => db.get_doc_at_rev(&guid, &to).await?,
+        None => db.get_doc(&guid).await?,
+    };
+    let patch = json_patch::diff(&doc_value(&from), &doc_value(&to));
+    Ok(Json(serde_json::to_value(patch)?))
+}
+
+/// Revert a record to a previous revision
+///
+/// The previous version is saved as a new revision. Posts and media can be reverted by their
+/// editors, other records only by admins. If the record is changed while reverting, the revert
+/// fails with 409 Conflict and the response contains the current version of the record.
+#[openapi(tag = "Record")]
+#[post("/record/<guid>/revert/<rev>")]
+pub async fn post_record_revert(
+    user: PostEditor,
+    state: &crate::State,
+    guid: String,
+    rev: String,
+) -> Result<PutResponse> {
+    let db = &state.db;
+    let current = db.get_doc(&guid).await?;
+    let current_rev = current.rev().map(str::to_string);
+    let mut previous = db.get_doc_at_rev(&guid, &rev).await?;
+    let typ = current.clone().into_untyped_record()?.typ().to_string();
+    match typ.as_str() {
+        Media::NAME => {
+            user.ensure_feeds(&current.into_typed_record::<Media>()?.value.feeds)?;
+            user.ensure_feeds(&previous.clone().into_typed_record::<Media>()?.value.feeds)?;
+        }
+        Post::NAME => {
+            user.ensure_feeds(&current.into_typed_record::<Post>()?.value.feeds)?;
+            user.ensure_feeds(&previous.clone().into_typed_record::<Post>()?.value.feeds)?;
+        }
+        _ if user.session().is_admin() => {}
+        _ => {
+            return Err(AppError::Http(
+                Status::Forbidden,
+                format!("Only admins can revert records of type {}", typ),
+            ))
+        }
+    }
+    let res = revert_doc(state, user.session(), current_rev, previous).await?;
+    Ok(Json(res))
+}
+
+/// Save a previous version of a doc as a new revision on top of the current revision.
+///
+/// Fails with 409 Conflict if the doc was changed since `current_rev`.
+async fn revert_doc(
+    state: &crate::State,
+    session: &SessionInfo,
+    current_rev: Option<String>,
+    mut previous: Doc,
+) -> std::result::Result<PutResponse, AppError> {
+    previous.set_rev(current_rev);
+    let endpoint = "POST /record/<guid>/revert/<rev>";
+    put_if_match(state, session, endpoint, &IfMatch::default(), previous).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::auth::{Role, UserInfo};
+    use crate::State;
+    use oas_common::Record;
+
+    fn session(username: &str) -> SessionInfo {
+        SessionInfo::for_user(UserInfo {
+            password: String::new(),
+            username: username.to_string(),
+            role: Role::Editor,
+            feeds: vec![],
+            email: None,
+            oidc_subject: None,
+            tenant: None,
+        })
+    }
+
+    fn media(url: &str) -> Record<Media> {
+        let media = Media {
+            content_url: url.to_string(),
+            ..Default::default()
+        };
+        Record::from_id_and_value("m1", media)
+    }
+
+    async fn content_url(state: &State) -> String {
+        let media = state.db.get_record::<Media>("oas.Media_m1").await.unwrap();
+        media.value.content_url
+    }
+
+    #[tokio::test]
+    async fn history_with_authors() {
+        let state = State::in_memory();
+        let guid = "oas.Media_m1".to_string();
+        let first = put_audited(&state, &session("alice"), "POST /media", media("http://a"))
+            .await
+            .unwrap();
+        let second = put_audited(&state, &session("bob"), "POST /media", media("http://b"))
+            .await
+            .unwrap();
+        // A change that was not made through the API has no audit entry.
+        let mut doc = Doc::from_typed_record(media("http://c"));
+        doc.set_rev(Some(second.rev.clone()));
+        let third = state.db.put_doc(doc).await.unwrap();
+
+        let history = record_history(&state, guid).await.unwrap();
+        let revs: Vec<&str> = history.iter().map(|rev| rev.rev.as_str()).collect();
+        assert_eq!(
+            revs,
+            vec![third.rev.as_str(), second.rev.as_str(), first.rev.as_str()]
+        );
+        let usernames: Vec<Option<&str>> =
+            history.iter().map(|rev| rev.username.as_deref()).collect();
+        assert_eq!(usernames, vec![None, Some("bob"), Some("alice")]);
+        assert_eq!(history[1].endpoint.as_deref(), Some("POST /media"));
+        assert!(history[0].timestamp.is_none());
+    }
+
+    #[tokio::test]
+    async fn revert_to_previous_rev() {
+        let state = State::in_memory();
+        let guid = "oas.Media_m1".to_string();
+        let first = state.db.put_record(media("http://a")).await.unwrap();
+        let second = state.db.put_record(media("http://b")).await.unwrap();
+
+        let previous = state.db.get_doc_at_rev(&guid, &first.rev).await.unwrap();
+        let res = revert_doc(&state, &session("alice"), Some(second.rev), previous)
+            .await
+            .unwrap();
+        assert_eq!(content_url(&state).await, "http://a");
+        let history = record_history(&state, guid.clone()).await.unwrap();
+        assert_eq!(history[0].rev, res.rev);
+        assert_eq!(history[0].username.as_deref(), Some("alice"));
+
+        // Reverting to the current rev saves the same version again.
+        let current = state.db.get_doc_at_rev(&guid, &res.rev).await.unwrap();
+        let again = revert_doc(&state, &session("alice"), Some(res.rev.clone()), current)
+            .await
+            .unwrap();
+        assert_ne!(again.rev, res.rev);
+        assert_eq!(content_url(&state).await, "http://a");
+    }
+
+    #[tokio::test]
+    async fn revert_conflicts_with_concurrent_change() {
+        let state = State::in_memory();
+        let guid = "oas.Media_m1".to_string();
+        let first = state.db.put_record(media("http://a")).await.unwrap();
+        let second = state.db.put_record(media("http://b")).await.unwrap();
+        let previous = state.db.get_doc_at_rev(&guid, &first.rev).await.unwrap();
+        // The record is changed after the current rev was loaded for the revert.
+        let third = state.db.put_record(media("http://c")).await.unwrap();
+
+        let err = revert_doc(&state, &session("alice"), Some(second.rev), previous)
+            .await
+            .unwrap_err();
+        match err {
+            AppError::Conflict {
+                status, current, ..
+            } => {
+                assert_eq!(status, Status::Conflict);
+                let current = current.unwrap();
+                assert_eq!(current.rev(), Some(third.rev.as_str()));
+            }
+            err => panic!("Unexpected error {:?}", err),
+        }
+        assert_eq!(content_url(&state).await, "http://c");
+    }
+}
@@ -1,14 +1,24 @@
+use crate::server::auth::PrivateReader;
 use crate::server::error::AppError;
+use crate::server::visibility::{add_search_filter, listed_posts_filter};
+use chrono::Utc;
 use rocket::http::Status;
 use rocket::post;
 use rocket_okapi::openapi;
+use serde_json::{json, Value};
 
 static SEARCH_METHODS: &[&str; 2] = &["_search", "_msearch"];
 
+/// Search the post index.
+///
+/// For users that may not view private records, only listed posts are searched (see
+/// [crate::server::visibility]). The searches of `_msearch` requests always run on the post index
+/// of the tenant of the request.
 #[openapi(skip)]
 #[post("/search/<index_name>/<search_method>", data = "<body>")]
 pub async fn search(
-    state: &rocket::State<crate::State>,
+    state: &crate::State,
+    reader: Option<PrivateReader>,
     index_name: String,
     search_method: String,
     body: String,
@@ -30,6 +40,18 @@ pub async fn search(
     let index = &state.index_manager.post_index();
     let client = &index.client();
 
+    let body = match search_method.as_str() {
+        "_msearch" => scope_msearch(&body, index.name())?,
+        _ => body,
+    };
+    let body = match reader {
+        Some(_) => body,
+        None => {
+            let filter = listed_posts_filter(&state.db, Utc::now()).await?;
+            add_search_filter(&body, &search_method, &filter)?
+        }
+    };
+
     let path = format!("{}/{}", index.name(), search_method);
     let res = client
         .send::<_, String>(
@@ -45,3 +67,65 @@ pub async fn search(
     let string = res.text().await?;
     Ok(string)
 }
+
+/// Run all searches of a `_msearch` body on an index.
+///
+/// The body is newline-delimited JSON of alternating header and query lines. The index of each
+/// header is set to `index`, and its routing is removed.
+fn scope_msearch(body: &str, index: &str) -> Result<String, AppError> {
+    let mut lines = vec![];
+    let non_empty_lines = body.lines().filter(|line| !line.trim().is_empty());
+    for (i, line) in non_empty_lines.enumerate() {
+        if i % 2 == 1 {
+            lines.push(line.to_string());
+            continue;
+        }
+        let mut header: Value = serde_json::from_str(line).map_err(|err| {
+            AppError::Http(
+                Status::BadRequest,
+                format!("Invalid search header: {}", err),
+            )
+        })?;
+        let object = header.as_object_mut().ok_or_else(|| {
+            AppError::Http(
+                Status::BadRequest,
+                "Search header must be a JSON object".into(),
+            )
+        })?;
+        object.insert("index".into(), json!(index));
+        object.remove("routing");
+        lines.push(serde_json::to_string(&header)?);
+    }
+    lines.push(String::new());
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scope_msearch_headers() {
+        let body = r#"{"index":"oas.other.data","routing":"a","preference":"p"}
+{"query":{"match_all":{}}}
+{}
+{}
+"#;
+        let lines: Vec<Value> = scope_msearch(body, "oas.acme.data")
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                json!({ "index": "oas.acme.data", "preference": "p" }),
+                json!({ "query": { "match_all": {} } }),
+                json!({ "index": "oas.acme.data" }),
+                json!({}),
+            ]
+        );
+        assert!(scope_msearch("[]\n{}\n", "oas.acme.data").is_err());
+        assert!(scope_msearch("oas\n{}\n", "oas.acme.data").is_err());
+    }
+}
@@ -1,26 +1,118 @@
-use crate::server::auth::AdminUser;
+use crate::server::auth::TaskTrigger;
 use crate::server::error::AppError;
+use crate::tasks::{
+    BulkTaskRequest, BulkTaskResponse, StartTaskResponse, TaskError, TaskSchema, TaskStatsReport,
+    DEFAULT_STATS_HOURS, MAX_STATS_HOURS,
+};
 use crate::State;
 use oas_common::{types::Media, TypedValue};
-use rocket::post;
+use rocket::http::Status;
 use rocket::serde::json::Json;
+use rocket::{get, post};
 use rocket_okapi::openapi;
 
 /// Create a new transcribe job for media
 #[openapi(tag = "Task")]
 #[post("/task/transcribe-media/<id>")]
 pub async fn post_transcribe_media(
-    _user: AdminUser,
+    user: TaskTrigger,
     state: &rocket::State<State>,
     id: String,
 ) -> Result<Json<String>, AppError> {
     let media = state.db.get_record::<Media>(&Media::guid(&id)).await?;
+    user.feed_access().ensure(media.guid(), &media.value.feeds)?;
     match state.tasks.transcribe_media(&media).await {
         Ok(task_id) => Ok(Json(task_id)),
         Err(err) => Err(AppError::Other(format!("{}", err))),
     }
 }
 
+/// Start a task on a record
+///
+/// The record is a media or a post, depending on the task type. The task is sent right away if
+/// its dependencies are finished, otherwise it is marked as wanted and sent once they are.
+#[openapi(tag = "Task")]
+#[post("/task/<typ>/<id>", rank = 2)]
+pub async fn post_task(
+    user: TaskTrigger,
+    state: &rocket::State<State>,
+    typ: String,
+    id: String,
+) -> Result<Json<StartTaskResponse>, AppError> {
+    let response = state
+        .tasks
+        .start_task(&state.db, &typ, &id, user.username(), &user.feed_access())
+        .await?;
+    Ok(Json(response))
+}
+
+/// Get the JSON schemas of the options and output of all task types
+#[openapi(tag = "Task")]
+#[get("/tasks/schema")]
+pub async fn get_task_schema(
+    state: &rocket::State<State>,
+) -> Result<Json<Vec<TaskSchema>>, AppError> {
+    Ok(Json(state.tasks.registry().schemas()))
+}
+
+/// Create tasks of a type for all records that match a selector
+///
+/// With `dryRun`, the tasks are validated and the number and a sample of the selected records
+/// are returned, without creating any tasks. Users that may not access all feeds have to select
+/// the posts of one of their feeds.
+#[openapi(tag = "Task")]
+#[post("/tasks/bulk", data = "<data>")]
+pub async fn post_tasks_bulk(
+    user: TaskTrigger,
+    state: &rocket::State<State>,
+    data: Json<BulkTaskRequest>,
+) -> Result<Json<BulkTaskResponse>, AppError> {
+    let index = state.index_manager.post_index();
+    let request = data.into_inner();
+    request.selector.ensure_access(&user.feed_access())?;
+    match state.tasks.submit_bulk(&state.db, index, request).await {
+        Ok(response) => Ok(Json(response)),
+        Err(err) if err.is::<TaskError>() => {
+            Err(AppError::Http(Status::BadRequest, format!("{}", err)))
+        }
+        Err(err) => Err(AppError::Other(format!("{:#}", err))),
+    }
+}
+
+/// Get cost and throughput statistics of the tasks that finished in the last hours
+///
+/// The statistics are given per task type, and broken down by feed and by ASR engine. The
+/// time window defaults to 24 hours and is at most a year. Users that may not access all feeds only get the breakdown
+/// for their feeds.
+#[openapi(tag = "Task")]
+#[get("/tasks/stats?<hours>")]
+pub async fn get_task_stats(
+    user: TaskTrigger,
+    state: &rocket::State<State>,
+    hours: Option<u32>,
+) -> Result<Json<TaskStatsReport>, AppError> {
+    let hours = hours.unwrap_or(DEFAULT_STATS_HOURS);
+    if hours == 0 || hours > MAX_STATS_HOURS {
+        return Err(AppError::Http(
+            Status::BadRequest,
+            format!(
+                "The time window must be between 1 and {} hours",
+                MAX_STATS_HOURS
+            ),
+        ));
+    }
+    match state.tasks.stats(&state.db, hours).await {
+        Ok(mut report) => {
+            let access = user.feed_access();
+            report
+                .feeds
+                .retain(|stats| access.allows(stats.feed.as_deref().into_iter()));
+            Ok(Json(report))
+        }
+        Err(err) => Err(AppError::Other(format!("{:#}", err))),
+    }
+}
+
 // #[openapi(tag = "Task")]
 // #[post("/task/result/media/<media_guid>/<task_id>")]
 // pub async fn post_transcribe_media(
@@ -0,0 +1,66 @@
+use oas_common::Record;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, post};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::server::auth::AdminUser;
+use crate::server::error::AppError;
+use crate::tenant::{is_valid_tenant_id, Tenant, Tenants};
+
+/// Request to create a tenant.
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
+pub struct CreateTenantRequest {
+    /// Id of the tenant. May only contain lowercase letters, digits, "-" and "_".
+    pub id: String,
+    /// Display name of the tenant.
+    pub name: String,
+}
+
+/// Create a new tenant
+///
+/// Creates the databases and indexes of the tenant. Users are assigned to the tenant with the
+/// `tenant` field when they are registered. Only admins of the instance may create tenants.
+#[openapi(tag = "Tenant")]
+#[post("/tenants", data = "<data>")]
+pub async fn post_tenant(
+    admin: AdminUser,
+    tenants: &rocket::State<Tenants>,
+    data: Json<CreateTenantRequest>,
+) -> Result<Json<Record<Tenant>>, AppError> {
+    admin.ensure_instance_admin()?;
+    let CreateTenantRequest { id, name } = data.into_inner();
+    if !is_valid_tenant_id(&id) {
+        return Err(AppError::Http(
+            Status::UnprocessableEntity,
+            "Tenant ids may only contain lowercase letters, digits, \"-\" and \"_\", and have to start with a letter".to_string(),
+        ));
+    }
+    if tenants.get(&id).await?.is_some() {
+        return Err(AppError::Http(
+            Status::Conflict,
+            format!("Tenant {} already exists", id),
+        ));
+    }
+    let tenant = Tenant { name };
+    tenants
+        .create(&id, tenant.clone())
+        .await
+        .map_err(|err| AppError::Other(format!("{:#}", err)))?;
+    log::info!("Tenant {} created by {}", id, admin.username());
+    Ok(Json(Record::from_id_and_value(id, tenant)))
+}
+
+/// Get all tenants
+#[openapi(tag = "Tenant")]
+#[get("/tenants")]
+pub async fn get_tenants(
+    admin: AdminUser,
+    tenants: &rocket::State<Tenants>,
+) -> Result<Json<Vec<Record<Tenant>>>, AppError> {
+    admin.ensure_instance_admin()?;
+    let records = tenants.list().await?;
+    Ok(Json(records))
+}
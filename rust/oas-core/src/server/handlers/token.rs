@@ -0,0 +1,47 @@
+use rocket::serde::json::Json;
+use rocket::{delete, get, post};
+use rocket_okapi::openapi;
+
+use crate::server::auth::{
+    ApiTokenInfo, Auth, CreateTokenRequest, CreateTokenResponse, SessionInfo,
+};
+use crate::server::error::AppError;
+
+/// Create a new API token for the logged in user
+///
+/// The token is sent as `Authorization: Bearer <token>` header. Its scopes are a list of
+/// permissions and cannot exceed the permissions of the user. The token string is only returned
+/// in this response.
+#[openapi(tag = "Token")]
+#[post("/tokens", data = "<data>")]
+pub async fn post_token(
+    session: SessionInfo,
+    auth: &rocket::State<Auth>,
+    data: Json<CreateTokenRequest>,
+) -> Result<Json<CreateTokenResponse>, AppError> {
+    let response = auth.create_token(&session, data.into_inner()).await?;
+    Ok(Json(response))
+}
+
+/// Get the API tokens of the logged in user, or all tokens for admins of the instance
+#[openapi(tag = "Token")]
+#[get("/tokens")]
+pub async fn get_tokens(
+    session: SessionInfo,
+    auth: &rocket::State<Auth>,
+) -> Result<Json<Vec<ApiTokenInfo>>, AppError> {
+    let tokens = auth.list_tokens(&session).await?;
+    Ok(Json(tokens))
+}
+
+/// Revoke an API token
+#[openapi(tag = "Token")]
+#[delete("/token/<id>")]
+pub async fn delete_token(
+    session: SessionInfo,
+    auth: &rocket::State<Auth>,
+    id: String,
+) -> Result<Json<()>, AppError> {
+    auth.revoke_token(&session, &id).await?;
+    Ok(Json(()))
+}
@@ -1,3 +1,4 @@
+use crate::tenant::Tenants;
 use crate::State;
 use clap::Clap;
 use rocket::fairing::{Fairing, Info, Kind};
@@ -8,9 +9,16 @@ use rocket_okapi::swagger_ui::{make_swagger_ui, SwaggerUIConfig};
 
 mod auth;
 pub mod error;
+mod etag;
 mod handlers;
 mod proxy;
+mod rate_limit;
 mod static_dir;
+mod tenant;
+mod visibility;
+
+pub use auth::OidcOpts;
+pub use rate_limit::RateLimitOpts;
 
 const DEFAULT_PORT: u16 = 8080;
 const DEFAULT_HOST: &str = "0.0.0.0";
@@ -24,6 +32,10 @@ pub struct ServerOpts {
     /// Hostname to bind server to
     #[clap(long, env = "HTTP_PORT")]
     pub port: Option<u16>,
+    #[clap(flatten)]
+    pub oidc: OidcOpts,
+    #[clap(flatten)]
+    pub rate_limit: RateLimitOpts,
 }
 
 pub async fn run_server(mut state: State, opts: ServerOpts) -> anyhow::Result<()> {
@@ -39,13 +51,37 @@ pub async fn run_server(mut state: State, opts: ServerOpts) -> anyhow::Result<()
     let admin_password = &std::env::var("OAS_ADMIN_PASSWORD").unwrap_or("password".to_string());
 
     let cors = rocket_cors::CorsOptions::default().to_cors()?;
-    let auth = auth::Auth::new();
-    auth.ensure_admin_user(&admin_password).await;
+    let mut auth = auth::Auth::new(state.db_manager.meta_db().clone());
+    let worker_token = std::env::var("OAS_WORKER_TOKEN").ok();
+    if worker_token.is_none() {
+        log::warn!("OAS_WORKER_TOKEN is not set: Workers have to login as admin to report results");
+    }
+    auth.set_worker_token(worker_token);
+    let oidc = auth::OidcClient::from_opts(opts.oidc)?;
+    if oidc.is_some() {
+        log::info!("OpenID Connect login enabled");
+    }
+    auth.set_oidc(oidc);
+    auth.ensure_admin_user(&admin_password).await?;
+    tokio::spawn(auth.sessions.clone().run_cleanup());
+    let limiter = rate_limit::RateLimiter::from_opts(&opts.rate_limit).await?;
+    let tenants = Tenants::new(state.clone());
+    tokio::spawn({
+        let tenants = tenants.clone();
+        async move {
+            if let Err(err) = tenants.start_all().await {
+                log::error!("Failed to start tenants: {:#}", err);
+            }
+        }
+    });
 
     let app = rocket::custom(figment)
-        .manage(state)
+        .manage(state.clone())
+        .manage(tenants)
         .manage(auth)
+        .manage(limiter.clone())
         .attach(cors)
+        .attach(rate_limit::RateLimitFairing::new(limiter, "/api/"))
         .attach(OasFairing)
         .mount(
             "/api/v1",
@@ -53,6 +89,10 @@ pub async fn run_server(mut state: State, opts: ServerOpts) -> anyhow::Result<()
                 // /record routes
                 handlers::record::get_record,
                 handlers::record::post_record,
+                handlers::record::get_record_history,
+                handlers::record::get_record_revision,
+                handlers::record::get_record_diff,
+                handlers::record::post_record_revert,
                 // /media routes
                 handlers::media::put_media,
                 handlers::media::get_media,
@@ -73,11 +113,42 @@ pub async fn run_server(mut state: State, opts: ServerOpts) -> anyhow::Result<()
                 handlers::search::search,
                 // task routes
                 handlers::task::post_transcribe_media,
+                handlers::task::post_task,
+                handlers::task::get_task_schema,
+                handlers::task::post_tasks_bulk,
+                handlers::task::get_task_stats,
+                // job routes
+                handlers::job::get_jobs,
+                handlers::job::get_job,
+                handlers::job::delete_job,
+                handlers::job::post_job_claim,
+                handlers::job::post_job_start,
+                handlers::job::post_job_result,
+                // changes routes
+                handlers::changes::changes_stream,
+                // dead letter routes
+                handlers::dead_letter::get_dead_letters,
+                handlers::dead_letter::get_dead_letter,
+                handlers::dead_letter::post_dead_letter_replay,
+                handlers::dead_letter::delete_dead_letter,
+                // audit routes
+                handlers::audit::get_audit,
+                // tenant routes
+                handlers::tenant::post_tenant,
+                handlers::tenant::get_tenants,
+                // token routes
+                handlers::token::post_token,
+                handlers::token::get_tokens,
+                handlers::token::delete_token,
                 // login routes
                 auth::post_login,
+                auth::oidc_login,
+                auth::oidc_callback,
                 auth::get_login,
                 auth::logout,
                 auth::register,
+                auth::get_users,
+                auth::patch_user,
                 auth::private
             ],
         )
@@ -88,7 +159,7 @@ pub async fn run_server(mut state: State, opts: ServerOpts) -> anyhow::Result<()
                 ..Default::default()
             }),
         )
-        .register("/api/v1", catchers![auth::unauthorized]);
+        .register("/api/v1", catchers![auth::unauthorized, auth::forbidden]);
 
     // Mount either a proxy to a frontend dev server,
     // or included static dir.
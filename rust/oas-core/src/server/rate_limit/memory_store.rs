@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{Counter, RateLimitStore};
+
+/// Number of counters after which expired counters are removed on the next write.
+const CLEANUP_THRESHOLD: usize = 10_000;
+
+/// A [RateLimitStore] that keeps all counters in process.
+///
+/// The counters are not shared between core instances.
+#[derive(Debug, Default)]
+pub struct MemoryRateLimitStore {
+    counters: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl MemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn incr(&self, key: &str, window: Duration) -> anyhow::Result<Counter> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        if counters.len() > CLEANUP_THRESHOLD {
+            counters.retain(|_key, (_count, expires)| *expires > now);
+        }
+        let counter = counters.entry(key.to_string()).or_insert((0, now + window));
+        if counter.1 <= now {
+            *counter = (0, now + window);
+        }
+        counter.0 += 1;
+        Ok(Counter {
+            count: counter.0,
+            reset_in: counter.1 - now,
+        })
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Counter>> {
+        let now = Instant::now();
+        let counters = self.counters.lock().unwrap();
+        let counter = counters
+            .get(key)
+            .filter(|(_count, expires)| *expires > now)
+            .map(|(count, expires)| Counter {
+                count: *count,
+                reset_in: *expires - now,
+            });
+        Ok(counter)
+    }
+
+    async fn reset(&self, key: &str) -> anyhow::Result<()> {
+        self.counters.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
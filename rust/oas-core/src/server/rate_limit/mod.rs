@@ -0,0 +1,481 @@
+//! Rate limiting and brute-force protection.
+//!
+//! The [RateLimitFairing] counts the API requests of each client IP and each user session or API
+//! token in fixed windows of one minute. Requests over the limit are not routed, but answered
+//! with 429 Too Many Requests. Requests with the worker token are not limited. Failed logins are
+//! counted per username and client IP, and per client IP, and further logins are rejected for a
+//! lockout period once too many failed. The username lockout is kept per client IP, so that
+//! failed logins from other IPs cannot lock a user out.
+//!
+//! The client IP is the IP of the connection, unless a header with the client IP is configured
+//! (`RATE_LIMIT_IP_HEADER`). Only set this if the core runs behind a reverse proxy that sets the
+//! header, otherwise clients can choose their IP.
+//!
+//! The counters are kept in process, or in Redis if `RATE_LIMIT_REDIS_URL` is set, so that they
+//! are shared between core instances.
+
+use clap::Clap;
+use oas_common::util;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::Method;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::Responder;
+use rocket::{Data, Request, Response, State};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod memory_store;
+mod redis_store;
+
+pub use memory_store::MemoryRateLimitStore;
+pub use redis_store::RedisRateLimitStore;
+
+use super::auth::{Auth, SESSION_COOKIE, SESSION_HEADER};
+use super::error::AppError;
+
+/// Default max number of API requests per minute and client IP.
+const DEFAULT_IP_LIMIT: u64 = 600;
+/// Default max number of API requests per minute and user.
+const DEFAULT_USER_LIMIT: u64 = 1200;
+/// Default number of failed logins after which a username is locked out for a client IP.
+const DEFAULT_LOGIN_USER_FAILURES: u64 = 5;
+/// Default number of failed logins after which a client IP is locked out.
+const DEFAULT_LOGIN_IP_FAILURES: u64 = 20;
+/// Default duration of a login lockout in seconds.
+const DEFAULT_LOGIN_LOCKOUT: u64 = 15 * 60;
+
+/// Window of the request counters.
+const REQUEST_WINDOW: Duration = Duration::from_secs(60);
+
+/// Path that requests over the limit are rerouted to, so that no handler runs for them.
+const LIMITED_PATH: &str = "/__rate_limited";
+
+#[derive(Clap, Default, Clone, Debug)]
+pub struct RateLimitOpts {
+    /// Max number of API requests per minute and client IP (0 to disable) [default: 600]
+    #[clap(long, env = "RATE_LIMIT_IP")]
+    pub rate_limit_ip: Option<u64>,
+    /// Max number of API requests per minute and user session or API token (0 to disable)
+    /// [default: 1200]
+    #[clap(long, env = "RATE_LIMIT_USER")]
+    pub rate_limit_user: Option<u64>,
+    /// Number of failed logins after which a username is locked out for a client IP (0 to
+    /// disable) [default: 5]
+    #[clap(long, env = "LOGIN_MAX_FAILURES")]
+    pub login_max_failures: Option<u64>,
+    /// Number of failed logins after which a client IP is locked out (0 to disable) [default: 20]
+    #[clap(long, env = "LOGIN_MAX_FAILURES_IP")]
+    pub login_max_failures_ip: Option<u64>,
+    /// Duration of a login lockout in seconds [default: 900]
+    #[clap(long, env = "LOGIN_LOCKOUT")]
+    pub login_lockout: Option<u64>,
+    /// Redis URL to share rate limits between core instances
+    #[clap(long, env = "RATE_LIMIT_REDIS_URL")]
+    pub rate_limit_redis_url: Option<String>,
+    /// Header with the client IP that is set by a reverse proxy, e.g. X-Real-IP. If not set, the
+    /// IP of the connection is used
+    #[clap(long, env = "RATE_LIMIT_IP_HEADER")]
+    pub rate_limit_ip_header: Option<String>,
+}
+
+/// A counter in a rate limit store.
+#[derive(Debug, Clone, Copy)]
+pub struct Counter {
+    /// Number of hits in the current window.
+    pub count: u64,
+    /// Time until the current window ends.
+    pub reset_in: Duration,
+}
+
+/// Storage backend for rate limit counters.
+#[async_trait::async_trait]
+pub trait RateLimitStore: fmt::Debug + Send + Sync {
+    /// Increment a counter.
+    ///
+    /// If the counter does not exist or its window ended, a new window of length `window`
+    /// starts.
+    async fn incr(&self, key: &str, window: Duration) -> anyhow::Result<Counter>;
+
+    /// Get a counter, or None if it does not exist or its window ended.
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Counter>>;
+
+    /// Remove a counter.
+    async fn reset(&self, key: &str) -> anyhow::Result<()>;
+}
+
+/// A rate limit that was exceeded by a request.
+///
+/// This is saved in the request-local cache. The [RateLimitFairing] turns the response of such
+/// requests into a 429 Too Many Requests.
+#[derive(Debug, Clone, Copy)]
+pub struct LimitExceeded {
+    pub retry_after: Duration,
+}
+
+impl LimitExceeded {
+    /// Mark a request as over the limit.
+    pub fn mark(self, request: &Request<'_>) {
+        *limit_state(request).0.lock().unwrap() = Some(self);
+    }
+
+    /// Get the exceeded limit of a request, if any.
+    pub fn of(request: &Request<'_>) -> Option<Self> {
+        *limit_state(request).0.lock().unwrap()
+    }
+}
+
+/// Request-local state of the rate limit.
+#[derive(Debug, Default)]
+struct LimitState(Mutex<Option<LimitExceeded>>);
+
+fn limit_state<'a>(request: &'a Request<'_>) -> &'a LimitState {
+    request.local_cache(LimitState::default)
+}
+
+impl From<LimitExceeded> for AppError {
+    fn from(limit: LimitExceeded) -> Self {
+        // Round up, so that clients do not retry before the window ended.
+        let secs = limit.retry_after.as_secs() + (limit.retry_after.subsec_nanos() > 0) as u64;
+        AppError::TooManyRequests(secs)
+    }
+}
+
+/// Rate limiter for API requests and logins.
+///
+/// Errors of the store are logged, and the request is allowed.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    ip_limit: u64,
+    user_limit: u64,
+    login_user_failures: u64,
+    login_ip_failures: u64,
+    login_lockout: Duration,
+    ip_header: Option<String>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter with a store.
+    pub fn with_store(store: impl RateLimitStore + 'static, opts: &RateLimitOpts) -> Self {
+        Self {
+            store: Arc::new(store),
+            ip_limit: opts.rate_limit_ip.unwrap_or(DEFAULT_IP_LIMIT),
+            user_limit: opts.rate_limit_user.unwrap_or(DEFAULT_USER_LIMIT),
+            login_user_failures: opts
+                .login_max_failures
+                .unwrap_or(DEFAULT_LOGIN_USER_FAILURES),
+            login_ip_failures: opts
+                .login_max_failures_ip
+                .unwrap_or(DEFAULT_LOGIN_IP_FAILURES),
+            login_lockout: Duration::from_secs(opts.login_lockout.unwrap_or(DEFAULT_LOGIN_LOCKOUT)),
+            ip_header: opts.rate_limit_ip_header.clone(),
+        }
+    }
+
+    /// Create a rate limiter from options.
+    ///
+    /// Uses a Redis store if a Redis URL is set, and an in-process store otherwise.
+    pub async fn from_opts(opts: &RateLimitOpts) -> anyhow::Result<Self> {
+        match &opts.rate_limit_redis_url {
+            Some(url) => {
+                let store = RedisRateLimitStore::connect(url).await?;
+                Ok(Self::with_store(store, opts))
+            }
+            None => Ok(Self::with_store(MemoryRateLimitStore::new(), opts)),
+        }
+    }
+
+    /// Get the IP of the client of a request.
+    ///
+    /// If a client IP header is configured and set on the request, the IP is taken from the
+    /// header. If the header contains a list of IPs (like `X-Forwarded-For`), the last one,
+    /// which was added by the proxy, is used. Otherwise, the IP of the connection is used.
+    pub fn client_ip(&self, request: &Request<'_>) -> Option<IpAddr> {
+        let header_ip = self
+            .ip_header
+            .as_deref()
+            .and_then(|name| request.headers().get_one(name))
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        header_ip.or_else(|| request.remote().map(|addr| addr.ip()))
+    }
+
+    /// Count a request of a client IP and user.
+    ///
+    /// `user` identifies the session or API token of the request (see [user_key]).
+    pub async fn hit(&self, ip: Option<IpAddr>, user: Option<&str>) -> Result<(), LimitExceeded> {
+        if let Some(ip) = ip {
+            self.hit_key(&format!("ip:{}", ip), self.ip_limit).await?;
+        }
+        if let Some(user) = user {
+            self.hit_key(&format!("user:{}", user), self.user_limit)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Fail if logins for a username from a client IP, or all logins from the IP are locked.
+    pub async fn check_login(
+        &self,
+        username: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(), LimitExceeded> {
+        self.check_key(&login_user_key(username, ip), self.login_user_failures)
+            .await?;
+        if let Some(ip) = ip {
+            self.check_key(&login_ip_key(ip), self.login_ip_failures)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Count a failed login.
+    pub async fn login_failed(&self, username: &str, ip: Option<IpAddr>) {
+        log::warn!("Failed login for user {} from {:?}", username, ip);
+        self.incr(&login_user_key(username, ip), self.login_lockout)
+            .await;
+        if let Some(ip) = ip {
+            self.incr(&login_ip_key(ip), self.login_lockout).await;
+        }
+    }
+
+    /// Reset the failed logins of a username from a client IP after a successful login.
+    pub async fn login_succeeded(&self, username: &str, ip: Option<IpAddr>) {
+        if let Err(err) = self.store.reset(&login_user_key(username, ip)).await {
+            log::error!("Failed to reset rate limit: {}", err);
+        }
+    }
+
+    async fn hit_key(&self, key: &str, limit: u64) -> Result<(), LimitExceeded> {
+        if limit == 0 {
+            return Ok(());
+        }
+        match self.incr(key, REQUEST_WINDOW).await {
+            Some(counter) if counter.count > limit => Err(LimitExceeded {
+                retry_after: counter.reset_in,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    async fn check_key(&self, key: &str, limit: u64) -> Result<(), LimitExceeded> {
+        if limit == 0 {
+            return Ok(());
+        }
+        match self.store.get(key).await {
+            Ok(Some(counter)) if counter.count >= limit => Err(LimitExceeded {
+                retry_after: counter.reset_in,
+            }),
+            Ok(_) => Ok(()),
+            Err(err) => {
+                log::error!("Failed to check rate limit: {}", err);
+                Ok(())
+            }
+        }
+    }
+
+    async fn incr(&self, key: &str, window: Duration) -> Option<Counter> {
+        match self.store.incr(key, window).await {
+            Ok(counter) => Some(counter),
+            Err(err) => {
+                log::error!("Failed to update rate limit: {}", err);
+                None
+            }
+        }
+    }
+}
+
+fn login_user_key(username: &str, ip: Option<IpAddr>) -> String {
+    match ip {
+        Some(ip) => format!("login:user:{}:{}", ip, username),
+        None => format!("login:user::{}", username),
+    }
+}
+
+fn login_ip_key(ip: IpAddr) -> String {
+    format!("login:ip:{}", ip)
+}
+
+/// Get a key for the session or API token of a request.
+///
+/// The credentials are not checked, so that the session store is not accessed for each request.
+/// They are hashed, so that they are not saved in the rate limit store. Requests with basic auth
+/// are only limited per client IP.
+fn user_key(request: &Request<'_>) -> Option<String> {
+    let credential = bearer_token(request)
+        .or_else(|| request.headers().get_one(SESSION_HEADER))
+        .map(str::to_string)
+        .or_else(|| {
+            request
+                .cookies()
+                .get(SESSION_COOKIE)
+                .map(|cookie| cookie.value().to_string())
+        })?;
+    Some(util::id_from_hashed_string(credential))
+}
+
+fn bearer_token<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|header| header.strip_prefix("Bearer "))
+}
+
+/// Request guard for the IP of the client, see [RateLimiter::client_ip].
+///
+/// The guard never fails.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> Outcome<ClientIp, ()> {
+        let limiter = request
+            .guard::<&State<RateLimiter>>()
+            .await
+            .expect("Rate limiter not registered");
+        Outcome::Success(ClientIp(limiter.client_ip(request)))
+    }
+}
+
+/// Fairing that applies the rate limits to API requests.
+///
+/// Requests over the limit are rerouted to a path without a handler, and their response is
+/// replaced with a 429 Too Many Requests. This also applies to requests that were marked as over
+/// the limit by a request guard (see [LimitExceeded::mark]).
+#[derive(Debug, Clone)]
+pub struct RateLimitFairing {
+    limiter: RateLimiter,
+    prefix: String,
+}
+
+impl RateLimitFairing {
+    /// Create a fairing that limits the requests to paths that start with `prefix`.
+    pub fn new(limiter: RateLimiter, prefix: impl ToString) -> Self {
+        Self {
+            limiter,
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimitFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        if !request.uri().path().starts_with(&self.prefix) {
+            return;
+        }
+        let auth = request
+            .guard::<&State<Auth>>()
+            .await
+            .expect("Session state not registered");
+        if bearer_token(request).map_or(false, |token| auth.check_worker_token(token)) {
+            return;
+        }
+        let ip = self.limiter.client_ip(request);
+        let user = user_key(request);
+        if let Err(limit) = self.limiter.hit(ip, user.as_deref()).await {
+            limit.mark(request);
+        }
+        if LimitExceeded::of(request).is_some() {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(LIMITED_PATH).unwrap());
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if let Some(limit) = LimitExceeded::of(request) {
+            if let Ok(limited) = AppError::from(limit).respond_to(request) {
+                response.merge(limited);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    fn limiter(opts: RateLimitOpts) -> RateLimiter {
+        RateLimiter::with_store(MemoryRateLimitStore::new(), &opts)
+    }
+
+    #[test]
+    fn client_ip() {
+        let client = Client::debug(rocket::build()).unwrap();
+        let remote: IpAddr = "10.0.0.1".parse().unwrap();
+        let request = client
+            .get("/")
+            .remote((remote, 8000).into())
+            .header(Header::new("X-Real-IP", "1.2.3.4"))
+            .header(Header::new("X-Forwarded-For", "5.6.7.8, 9.9.9.9"));
+        let ip = |header: Option<&str>| {
+            let limiter = limiter(RateLimitOpts {
+                rate_limit_ip_header: header.map(str::to_string),
+                ..Default::default()
+            });
+            limiter.client_ip(request.inner())
+        };
+        assert_eq!(ip(None), Some(remote));
+        assert_eq!(ip(Some("X-Real-IP")), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(
+            ip(Some("X-Forwarded-For")),
+            Some("9.9.9.9".parse().unwrap())
+        );
+        assert_eq!(ip(Some("X-Client-IP")), Some(remote));
+    }
+
+    #[tokio::test]
+    async fn limit_requests() {
+        let limiter = limiter(RateLimitOpts {
+            rate_limit_ip: Some(2),
+            rate_limit_user: Some(1),
+            ..Default::default()
+        });
+        let ip = "1.2.3.4".parse().ok();
+        assert!(limiter.hit(ip, None).await.is_ok());
+        assert!(limiter.hit(ip, Some("a")).await.is_ok());
+        assert!(limiter.hit(ip, None).await.is_err());
+        let other_ip = "1.2.3.5".parse().ok();
+        assert!(limiter.hit(other_ip, Some("b")).await.is_ok());
+        assert!(limiter.hit(other_ip, Some("a")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn lock_logins_per_ip() {
+        let limiter = limiter(RateLimitOpts {
+            login_max_failures: Some(2),
+            login_max_failures_ip: Some(3),
+            ..Default::default()
+        });
+        let ip = "1.2.3.4".parse().ok();
+        let other_ip = "1.2.3.5".parse().ok();
+        limiter.login_failed("alice", ip).await;
+        limiter.login_failed("alice", ip).await;
+        assert!(limiter.check_login("alice", ip).await.is_err());
+        // Failed logins from another IP do not lock out the user.
+        assert!(limiter.check_login("alice", other_ip).await.is_ok());
+        assert!(limiter.check_login("bob", ip).await.is_ok());
+        // Too many failed logins from an IP lock all logins from it.
+        limiter.login_failed("bob", ip).await;
+        assert!(limiter.check_login("bob", ip).await.is_err());
+        assert!(limiter.check_login("carol", other_ip).await.is_ok());
+
+        limiter.login_succeeded("alice", other_ip).await;
+        limiter.login_failed("alice", other_ip).await;
+        assert!(limiter.check_login("alice", other_ip).await.is_ok());
+    }
+}
@@ -0,0 +1,93 @@
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, Script};
+use std::time::Duration;
+
+use super::{Counter, RateLimitStore};
+
+/// Prefix for the keys of all counters.
+const KEY_PREFIX: &str = "oas:ratelimit:";
+
+/// Increment a counter and set its expiration when it is created.
+///
+/// Returns the count and the remaining time of the window in milliseconds.
+const INCR_SCRIPT: &str = r#"
+local count = redis.call("INCR", KEYS[1])
+if count == 1 then
+    redis.call("PEXPIRE", KEYS[1], ARGV[1])
+end
+return { count, redis.call("PTTL", KEYS[1]) }
+"#;
+
+/// A [RateLimitStore] that keeps the counters in Redis.
+///
+/// The counters are shared between all core instances that use the same Redis server.
+#[derive(Clone)]
+pub struct RedisRateLimitStore {
+    conn: ConnectionManager,
+    incr_script: Script,
+}
+
+impl std::fmt::Debug for RedisRateLimitStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisRateLimitStore").finish()
+    }
+}
+
+impl RedisRateLimitStore {
+    /// Connect to a Redis server.
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        Ok(Self {
+            conn,
+            incr_script: Script::new(INCR_SCRIPT),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for RedisRateLimitStore {
+    async fn incr(&self, key: &str, window: Duration) -> anyhow::Result<Counter> {
+        let mut conn = self.conn.clone();
+        let (count, ttl): (u64, i64) = self
+            .incr_script
+            .key(redis_key(key))
+            .arg(window.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(Counter {
+            count,
+            reset_in: ttl_duration(ttl),
+        })
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Counter>> {
+        let mut conn = self.conn.clone();
+        let key = redis_key(key);
+        let count: Option<u64> = conn.get(&key).await?;
+        let count = match count {
+            Some(count) => count,
+            None => return Ok(None),
+        };
+        let ttl: i64 = redis::cmd("PTTL").arg(&key).query_async(&mut conn).await?;
+        Ok(Some(Counter {
+            count,
+            reset_in: ttl_duration(ttl),
+        }))
+    }
+
+    async fn reset(&self, key: &str) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        let _: () = conn.del(redis_key(key)).await?;
+        Ok(())
+    }
+}
+
+fn redis_key(key: &str) -> String {
+    format!("{}{}", KEY_PREFIX, key)
+}
+
+/// Convert a TTL in milliseconds to a duration. Negative TTLs (no expiration) are zero.
+fn ttl_duration(ttl: i64) -> Duration {
+    Duration::from_millis(ttl.max(0) as u64)
+}
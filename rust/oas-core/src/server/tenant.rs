@@ -0,0 +1,130 @@
+//! Routing of requests to the state of a tenant (see [crate::tenant]).
+//!
+//! Handlers that work on records take `&State` as request guard, which resolves to the state of
+//! the tenant of the request. Handlers for tasks and jobs use the state of the instance
+//! (`&rocket::State<State>`), because workers are shared by all tenants. Tasks only run for the
+//! records of the instance, so these handlers reject users of a tenant with 403 Forbidden.
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+use crate::server::auth::SessionInfo;
+use crate::tenant::Tenants;
+use crate::State;
+
+/// Header to select a tenant for anonymous requests and admins of the instance.
+pub const TENANT_HEADER: &str = "X-Oas-Tenant";
+
+/// The state of the tenant of a request, saved in the request-local cache.
+struct TenantState(Result<State, Status>);
+
+/// Request guard for the state of the tenant of a request.
+///
+/// Users that belong to a tenant always use the state of their tenant. Anonymous requests and
+/// admins of the instance may select a tenant with the `X-Oas-Tenant` header, and use the state
+/// of the instance otherwise. Other users of the instance always use the state of the instance.
+/// Fails with 404 Not Found for unknown tenants.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r State {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> Outcome<&'r State, ()> {
+        let cached = request
+            .local_cache_async(async { TenantState(tenant_state(request).await) })
+            .await;
+        match &cached.0 {
+            Ok(state) => Outcome::Success(state),
+            Err(status) => Outcome::Failure((*status, ())),
+        }
+    }
+}
+
+async fn tenant_state(request: &Request<'_>) -> Result<State, Status> {
+    let tenants = request
+        .guard::<&rocket::State<Tenants>>()
+        .await
+        .expect("Tenants not registered");
+    let session = request.guard::<SessionInfo>().await.succeeded();
+    let header = request.headers().get_one(TENANT_HEADER);
+    let tenant = request_tenant(session.as_ref(), header);
+    match tenants.state(tenant).await {
+        Ok(Some(state)) => Ok(state),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => {
+            log::error!("Failed to load tenant {:?}: {:#}", tenant, err);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Get the tenant of a request from its session and the value of the `X-Oas-Tenant` header.
+fn request_tenant<'a>(
+    session: Option<&'a SessionInfo>,
+    header: Option<&'a str>,
+) -> Option<&'a str> {
+    match session {
+        Some(session) => match session.user().tenant.as_deref() {
+            Some(tenant) => Some(tenant),
+            None if session.is_admin() => header,
+            None => None,
+        },
+        None => header,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::auth::{Role, UserInfo};
+    use crate::tenant::Tenant;
+    use oas_common::types::Feed;
+    use oas_common::Record;
+
+    fn session(role: Role, tenant: Option<&str>) -> SessionInfo {
+        SessionInfo::for_user(UserInfo {
+            password: String::new(),
+            username: "user".to_string(),
+            role,
+            feeds: vec![],
+            email: None,
+            oidc_subject: None,
+            tenant: tenant.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn select_tenant() {
+        let header = Some("acme");
+        assert_eq!(request_tenant(None, header), Some("acme"));
+        assert_eq!(request_tenant(None, None), None);
+
+        let admin = session(Role::Admin, None);
+        assert_eq!(request_tenant(Some(&admin), header), Some("acme"));
+        assert_eq!(request_tenant(Some(&admin), None), None);
+
+        let editor = session(Role::Editor, None);
+        assert_eq!(request_tenant(Some(&editor), header), None);
+
+        let tenant_user = session(Role::Editor, Some("other"));
+        assert_eq!(request_tenant(Some(&tenant_user), header), Some("other"));
+        let tenant_admin = session(Role::Admin, Some("other"));
+        assert_eq!(request_tenant(Some(&tenant_admin), header), Some("other"));
+    }
+
+    #[tokio::test]
+    async fn unknown_tenant() {
+        let state = State::in_memory();
+        let tenants = Tenants::new(state.clone());
+        let feed = Record::from_id_and_value("f1", Feed::default());
+        state.db.put_record(feed).await.unwrap();
+        let default = tenants.state(None).await.unwrap().unwrap();
+        assert!(default.db.table::<Feed>().get("f1").await.is_ok());
+
+        assert!(tenants.state(Some("acme")).await.unwrap().is_none());
+        let invalid = Tenant {
+            name: "Acme".to_string(),
+        };
+        assert!(tenants.create("Acme", invalid).await.is_err());
+        assert!(tenants.list().await.unwrap().is_empty());
+        assert!(tenants.start_all().await.is_ok());
+    }
+}
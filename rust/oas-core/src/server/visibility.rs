@@ -0,0 +1,390 @@
+//! Visibility of posts and their media.
+//!
+//! Posts can be public, unlisted, private or embargoed (see [Visibility]). Posts without own
+//! settings take the most restrictive default of their feeds, and media take the least
+//! restrictive visibility of their posts. Users that may view private records see everything.
+//! For other users, hidden records are answered with 404 Not Found, and search requests are
+//! restricted to the listed posts. Their search requests may not use the parts of the search API
+//! that are not restricted by the query (suggesters, post filters and global aggregations).
+
+use chrono::{DateTime, Utc};
+use oas_common::types::{Feed, Media, Post, Visibility};
+use oas_common::TypedValue;
+use rocket::http::Status;
+use serde_json::{json, Map, Value};
+
+use crate::couch::{CouchDB, CouchResult, Doc, View, ViewQuery};
+use crate::server::auth::PrivateReader;
+use crate::server::error::AppError;
+
+/// Keys of search bodies that anonymous users may not use.
+const RESTRICTED_SEARCH_KEYS: &[&str] = &["suggest", "post_filter"];
+/// Keys of search bodies and aggregations that contain aggregations.
+const AGGREGATION_KEYS: &[&str] = &["aggs", "aggregations"];
+
+/// Feeds by their default visibility settings. Feeds without defaults have a null key.
+const FEEDS_BY_DEFAULT_VISIBILITY: View = View {
+    typ: Feed::NAME,
+    name: "by_default_visibility",
+    key: &["defaultVisibility"],
+};
+
+/// Get the visibility of a post at a point in time.
+pub async fn post_visibility(
+    db: &CouchDB,
+    post: &Post,
+    now: DateTime<Utc>,
+) -> CouchResult<Visibility> {
+    if let Some(settings) = post.visibility_settings() {
+        return Ok(settings.at(now));
+    }
+    let feed_guids: Vec<&str> = post.feeds.iter().map(|feed| feed.guid()).collect();
+    if feed_guids.is_empty() {
+        return Ok(Visibility::Public);
+    }
+    let feeds = db.get_many_records::<Feed>(&feed_guids).await?;
+    let visibility = feeds
+        .iter()
+        .filter_map(|feed| feed.value.default_visibility)
+        .map(|settings| settings.at(now))
+        .max()
+        .unwrap_or_default();
+    Ok(visibility)
+}
+
+/// Get the visibility of a media at a point in time.
+///
+/// Media without (existing) posts are public.
+pub async fn media_visibility(
+    db: &CouchDB,
+    media: &Media,
+    now: DateTime<Utc>,
+) -> CouchResult<Visibility> {
+    let post_guids: Vec<&str> = media.posts.iter().map(|post| post.guid()).collect();
+    if post_guids.is_empty() {
+        return Ok(Visibility::Public);
+    }
+    let posts = db.get_many_records::<Post>(&post_guids).await?;
+    let mut visibilities = vec![];
+    for post in posts.iter() {
+        visibilities.push(post_visibility(db, &post.value, now).await?);
+    }
+    Ok(visibilities.into_iter().min().unwrap_or_default())
+}
+
+/// Get the visibility of a doc at a point in time.
+///
+/// Only posts and media can be hidden, all other records are public.
+pub async fn doc_visibility(
+    db: &CouchDB,
+    doc: &Doc,
+    now: DateTime<Utc>,
+) -> Result<Visibility, AppError> {
+    let record = doc.clone().into_untyped_record()?;
+    let visibility = match record.typ() {
+        Post::NAME => {
+            let record = record.into_typed_record::<Post>()?;
+            post_visibility(db, &record.value, now).await?
+        }
+        Media::NAME => {
+            let record = record.into_typed_record::<Media>()?;
+            media_visibility(db, &record.value, now).await?
+        }
+        _ => Visibility::Public,
+    };
+    Ok(visibility)
+}
+
+/// Fail with 404 Not Found if a record is hidden from a user.
+///
+/// `reader` is set for users that may view private records.
+pub fn ensure_accessible(
+    visibility: Visibility,
+    reader: Option<&PrivateReader>,
+    guid: &str,
+) -> Result<(), AppError> {
+    if reader.is_some() || visibility.is_accessible() {
+        Ok(())
+    } else {
+        Err(AppError::Http(
+            Status::NotFound,
+            format!("Record {} not found", guid),
+        ))
+    }
+}
+
+/// Build an Elasticsearch filter for the posts that are listed in search results for anonymous
+/// users.
+///
+/// Posts without own settings are listed unless one of their feeds has a default that is not
+/// listed.
+pub async fn listed_posts_filter(db: &CouchDB, now: DateTime<Utc>) -> CouchResult<Value> {
+    let unlisted_feeds = unlisted_feeds(db, now).await?;
+    Ok(json!({
+        "bool": {
+            "should": [
+                { "term": { "visibility": "public" } },
+                { "bool": {
+                    "filter": [
+                        { "term": { "visibility": "embargoed" } },
+                        { "range": { "embargoUntil": { "lte": now.to_rfc3339() } } }
+                    ]
+                } },
+                { "bool": {
+                    "must_not": [
+                        { "exists": { "field": "visibility" } },
+                        { "terms": { "feeds": unlisted_feeds } }
+                    ]
+                } }
+            ],
+            "minimum_should_match": 1
+        }
+    }))
+}
+
+/// Get the guids of the feeds whose posts are not listed by default.
+///
+/// Only the feeds with default visibility settings are loaded from a view. The view is created
+/// on first use.
+async fn unlisted_feeds(db: &CouchDB, now: DateTime<Utc>) -> CouchResult<Vec<String>> {
+    // All keys that are not null.
+    let query = ViewQuery {
+        start: Some(json!([false])),
+        ..Default::default()
+    };
+    let feeds = match db
+        .query_view_records::<Feed>(&FEEDS_BY_DEFAULT_VISIBILITY, &query)
+        .await
+    {
+        Err(err) if err.status_code() == Some(404) => {
+            db.init_views(&[FEEDS_BY_DEFAULT_VISIBILITY]).await?;
+            db.query_view_records::<Feed>(&FEEDS_BY_DEFAULT_VISIBILITY, &query)
+                .await?
+        }
+        res => res?,
+    };
+    let unlisted_feeds = feeds
+        .iter()
+        .filter(|feed| {
+            feed.value
+                .default_visibility
+                .map_or(false, |settings| !settings.at(now).is_listed())
+        })
+        .map(|feed| feed.guid().to_string())
+        .collect();
+    Ok(unlisted_feeds)
+}
+
+/// Add a filter to the queries of a search request body.
+///
+/// For `_msearch`, the body is newline-delimited JSON of alternating header and query lines, and
+/// the filter is added to each query.
+pub fn add_search_filter(
+    body: &str,
+    search_method: &str,
+    filter: &Value,
+) -> Result<String, AppError> {
+    if search_method != "_msearch" {
+        let query = add_query_filter(body, filter)?;
+        return Ok(serde_json::to_string(&query)?);
+    }
+    let mut lines = vec![];
+    let non_empty_lines = body.lines().filter(|line| !line.trim().is_empty());
+    for (i, line) in non_empty_lines.enumerate() {
+        if i % 2 == 0 {
+            lines.push(line.to_string());
+        } else {
+            let query = add_query_filter(line, filter)?;
+            lines.push(serde_json::to_string(&query)?);
+        }
+    }
+    lines.push(String::new());
+    Ok(lines.join("\n"))
+}
+
+fn add_query_filter(body: &str, filter: &Value) -> Result<Value, AppError> {
+    let mut body: Value = if body.trim().is_empty() {
+        json!({})
+    } else {
+        serde_json::from_str(body)?
+    };
+    let object = body.as_object_mut().ok_or_else(|| {
+        AppError::Http(
+            Status::BadRequest,
+            "Search body must be a JSON object".into(),
+        )
+    })?;
+    if let Some(key) = RESTRICTED_SEARCH_KEYS
+        .iter()
+        .find(|key| object.contains_key(**key))
+    {
+        return Err(AppError::Http(
+            Status::BadRequest,
+            format!("{} is not allowed in search requests", key),
+        ));
+    }
+    if has_global_aggregation(object) {
+        return Err(AppError::Http(
+            Status::BadRequest,
+            "Global aggregations are not allowed in search requests".into(),
+        ));
+    }
+    let query = object
+        .remove("query")
+        .unwrap_or_else(|| json!({ "match_all": {} }));
+    object.insert(
+        "query".into(),
+        json!({ "bool": { "must": [query], "filter": [filter] } }),
+    );
+    Ok(body)
+}
+
+/// Check if a search body or an aggregation contains a global aggregation, which is not
+/// restricted by the query.
+fn has_global_aggregation(object: &Map<String, Value>) -> bool {
+    AGGREGATION_KEYS
+        .iter()
+        .filter_map(|key| object.get(*key))
+        .filter_map(|aggs| aggs.as_object())
+        .flat_map(|aggs| aggs.values())
+        .filter_map(|agg| agg.as_object())
+        .any(|agg| agg.contains_key("global") || has_global_aggregation(agg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use oas_common::types::VisibilitySettings;
+    use oas_common::{Record, Reference};
+
+    fn feed(id: &str, visibility: Option<Visibility>) -> Record<Feed> {
+        let feed = Feed {
+            default_visibility: visibility.map(|visibility| VisibilitySettings {
+                visibility,
+                embargo_until: None,
+            }),
+            ..Default::default()
+        };
+        Record::from_id_and_value(id, feed)
+    }
+
+    fn post(feeds: &[&Record<Feed>], visibility: Option<Visibility>) -> Post {
+        Post {
+            feeds: feeds
+                .iter()
+                .map(|feed| Reference::Id(feed.guid().to_string()))
+                .collect(),
+            visibility,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn post_visibility_from_feeds() {
+        let db = CouchDB::in_memory("records");
+        let public = feed("public", None);
+        let unlisted = feed("unlisted", Some(Visibility::Unlisted));
+        let private = feed("private", Some(Visibility::Private));
+        for feed in [&public, &unlisted, &private].iter() {
+            db.put_record((*feed).clone()).await.unwrap();
+        }
+        let now = Utc::now();
+        let visibility = |post: Post| {
+            let db = db.clone();
+            async move { post_visibility(&db, &post, now).await.unwrap() }
+        };
+
+        assert_eq!(visibility(post(&[], None)).await, Visibility::Public);
+        assert_eq!(visibility(post(&[&public], None)).await, Visibility::Public);
+        let both = post(&[&public, &unlisted], None);
+        assert_eq!(visibility(both).await, Visibility::Unlisted);
+        let all = post(&[&public, &unlisted, &private], None);
+        assert_eq!(visibility(all).await, Visibility::Private);
+        let own = post(&[&private], Some(Visibility::Public));
+        assert_eq!(visibility(own).await, Visibility::Public);
+
+        let mut embargoed = post(&[], Some(Visibility::Embargoed));
+        embargoed.embargo_until = Some(now + Duration::days(1));
+        assert_eq!(visibility(embargoed.clone()).await, Visibility::Private);
+        embargoed.embargo_until = Some(now - Duration::days(1));
+        assert_eq!(visibility(embargoed).await, Visibility::Public);
+    }
+
+    #[tokio::test]
+    async fn unlisted_feeds_from_view() {
+        let db = CouchDB::in_memory("records");
+        let now = Utc::now();
+        let mut embargoed = feed("embargoed", Some(Visibility::Embargoed));
+        if let Some(settings) = embargoed.value.default_visibility.as_mut() {
+            settings.embargo_until = Some(now - Duration::days(1));
+        }
+        let feeds = [
+            feed("public", None),
+            feed("unlisted", Some(Visibility::Unlisted)),
+            feed("private", Some(Visibility::Private)),
+            embargoed,
+        ];
+        for feed in feeds.iter() {
+            db.put_record(feed.clone()).await.unwrap();
+        }
+
+        let mut guids = unlisted_feeds(&db, now).await.unwrap();
+        guids.sort();
+        assert_eq!(guids, vec![feeds[2].guid(), feeds[1].guid()]);
+    }
+
+    #[test]
+    fn search_filter() {
+        let filter = json!({ "term": { "visibility": "public" } });
+        let body = r#"{"query":{"match":{"title":"radio"}},"aggs":{"genres":{"terms":{"field":"genre"}}}}"#;
+        let body: Value =
+            serde_json::from_str(&add_search_filter(body, "_search", &filter).unwrap()).unwrap();
+        assert_eq!(
+            body["query"],
+            json!({ "bool": { "must": [{ "match": { "title": "radio" } }], "filter": [filter] } })
+        );
+        assert!(body["aggs"]["genres"].is_object());
+
+        let body = "{}\n{\"size\":1}\n{}\n{}\n";
+        let lines: Vec<Value> = add_search_filter(body, "_msearch", &filter)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1]["query"]["bool"]["filter"], json!([filter]));
+        assert_eq!(
+            lines[3]["query"]["bool"]["must"],
+            json!([{ "match_all": {} }])
+        );
+    }
+
+    #[test]
+    fn restricted_search_keys() {
+        let filter = json!({ "term": { "visibility": "public" } });
+        let rejected = [
+            json!({ "suggest": { "s": { "text": "a", "term": { "field": "title" } } } }),
+            json!({ "post_filter": { "match_all": {} } }),
+            json!({ "aggs": { "all": { "global": {} } } }),
+            json!({ "aggregations": { "genres": {
+                "terms": { "field": "genre" },
+                "aggs": { "all": { "global": {} } }
+            } } }),
+        ];
+        for body in rejected.iter() {
+            let body = serde_json::to_string(body).unwrap();
+            assert!(
+                add_search_filter(&body, "_search", &filter).is_err(),
+                "{}",
+                body
+            );
+            let msearch = format!("{{}}\n{}\n", body);
+            assert!(
+                add_search_filter(&msearch, "_msearch", &filter).is_err(),
+                "{}",
+                body
+            );
+        }
+    }
+}
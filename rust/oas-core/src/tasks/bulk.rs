@@ -0,0 +1,294 @@
+//! Bulk submission of tasks.
+//!
+//! A [BulkSelector] selects posts either by paging through all posts in CouchDB, or by paging
+//! through the results of an Elasticsearch query on the post index. The selected posts are
+//! filtered by the other fields of the selector. Depending on the task type, tasks are created
+//! for the selected posts or for their media. Users that may not access all feeds can only
+//! select the posts of one of their feeds.
+
+use chrono::{DateTime, Utc};
+use oas_common::types::{Feed, Media, Post};
+use oas_common::{Record, Resolver, TypedValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+
+use super::control::{FeedAccess, TaskControlError};
+use super::pipeline::{self, Target};
+use super::quota::FeedContext;
+use super::{TaskError, TaskManager, TaskMessage};
+use crate::couch::CouchDB;
+use crate::index::PostIndex;
+
+/// Number of posts that are loaded at once.
+pub const BULK_PAGE_SIZE: usize = 100;
+/// Number of record ids that are returned as a sample of the selected records.
+pub const BULK_SAMPLE_SIZE: usize = 20;
+
+/// Selects the records to create tasks for.
+///
+/// All fields are optional, an empty selector selects all posts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct BulkSelector {
+    /// Only select posts of the feed with this id.
+    pub feed: Option<String>,
+    /// Only select posts published at or after this date.
+    pub published_after: Option<DateTime<Utc>>,
+    /// Only select posts published before this date.
+    pub published_before: Option<DateTime<Utc>>,
+    /// Only select media without a transcript, or posts with such a media.
+    #[serde(default)]
+    pub missing_transcript: bool,
+    /// Only select posts in this language.
+    pub language: Option<String>,
+    /// Only select posts that match this Elasticsearch query on the post index.
+    pub query: Option<Value>,
+}
+
+impl BulkSelector {
+    fn matches_post(&self, post: &Record<Post>) -> bool {
+        let post = &post.value;
+        if let Some(feed) = &self.feed {
+            let guid = Feed::guid(feed);
+            if !post.feeds.iter().any(|feed| feed.guid() == guid) {
+                return false;
+            }
+        }
+        if self.published_after.is_some() || self.published_before.is_some() {
+            let published = match post.date_published {
+                Some(published) => published,
+                None => return false,
+            };
+            if matches!(self.published_after, Some(after) if published < after) {
+                return false;
+            }
+            if matches!(self.published_before, Some(before) if published >= before) {
+                return false;
+            }
+        }
+        if let Some(language) = &self.language {
+            match &post.in_language {
+                Some(in_language) if in_language.eq_ignore_ascii_case(language) => {}
+                _ => return false,
+            }
+        }
+        if self.missing_transcript && !media_of_post(post).any(|media| self.matches_media(media)) {
+            return false;
+        }
+        true
+    }
+
+    fn matches_media(&self, media: &Record<Media>) -> bool {
+        !self.missing_transcript || media.value.transcript.is_none()
+    }
+
+    /// Fail with [TaskControlError::Forbidden] unless the selector only selects posts of a feed
+    /// that may be accessed.
+    pub fn ensure_access(&self, access: &FeedAccess) -> Result<(), TaskControlError> {
+        let feed = self.feed.as_deref().map(Feed::guid);
+        match (access, feed) {
+            (FeedAccess::All, _) => Ok(()),
+            (access, Some(feed)) if access.allows(std::iter::once(feed.as_str())) => Ok(()),
+            _ => Err(TaskControlError::Forbidden(
+                "Bulk tasks may only be created for the posts of an own feed".to_string(),
+            )),
+        }
+    }
+}
+
+/// A request to create tasks of a type for all selected records.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct BulkTaskRequest {
+    /// Name of the task, e.g. "transcribe".
+    pub task: String,
+    /// Selects the records to create tasks for.
+    #[serde(default)]
+    pub selector: BulkSelector,
+    /// Options for all tasks.
+    #[serde(default)]
+    pub opts: Value,
+    /// Only count the selected records, without creating tasks.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Maximum number of tasks to create.
+    pub limit: Option<usize>,
+}
+
+/// The outcome of a bulk task request.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BulkTaskResponse {
+    /// Whether this was a dry run.
+    pub dry_run: bool,
+    /// Number of selected records.
+    pub count: usize,
+    /// Ids of the first selected records.
+    pub sample: Vec<String>,
+    /// Ids of the created jobs.
+    pub jobs: Vec<String>,
+    /// Errors for records whose tasks could not be created.
+    pub errors: Vec<String>,
+}
+
+impl TaskManager {
+    /// Create tasks of a type for all records that match a selector.
+    ///
+    /// Posts are loaded page by page. Tasks get their priority and feed like tasks that are
+    /// created for changed records. With a dry run, the tasks are only validated.
+    pub async fn submit_bulk(
+        &self,
+        db: &CouchDB,
+        index: &PostIndex,
+        request: BulkTaskRequest,
+    ) -> anyhow::Result<BulkTaskResponse> {
+        let target = pipeline::PIPELINE
+            .iter()
+            .find(|step| step.typ == request.task)
+            .map(|step| step.target)
+            .ok_or_else(|| TaskError::Unknown(request.task.clone()))?;
+        let selector = &request.selector;
+        let limit = request.limit.unwrap_or(usize::MAX);
+        let mut response = BulkTaskResponse {
+            dry_run: request.dry_run,
+            ..Default::default()
+        };
+        let mut seen = HashSet::new();
+        let mut pages = PostPages::new(selector.query.clone());
+
+        while response.count < limit {
+            let mut posts = match pages.next(db, index).await? {
+                Some(posts) => posts,
+                None => break,
+            };
+            db.resolve_all_refs(&mut posts).await?;
+            posts.retain(|post| selector.matches_post(post));
+
+            let context = if request.dry_run {
+                FeedContext::default()
+            } else {
+                let medias: Vec<Record<Media>> = posts
+                    .iter()
+                    .flat_map(|post| media_of_post(&post.value).cloned())
+                    .collect();
+                FeedContext::load(db, &posts, &medias).await?
+            };
+
+            let mut tasks: Vec<(String, TaskMessage)> = vec![];
+            match target {
+                Target::Media => {
+                    let medias = posts.iter().flat_map(|post| media_of_post(&post.value));
+                    for media in medias.filter(|media| selector.matches_media(media)) {
+                        if seen.insert(media.guid().to_string()) {
+                            let task = super::media_task(&request.task, media)?;
+                            let task = context.apply(task, media.guid(), &media.value.feeds);
+                            tasks.push((media.id().to_string(), task));
+                        }
+                    }
+                }
+                Target::Post => {
+                    for post in posts.iter() {
+                        let task = super::post_task(&request.task, post)?;
+                        let task = context.apply(task, post.guid(), &post.value.feeds);
+                        tasks.push((post.id().to_string(), task));
+                    }
+                }
+            }
+            tasks.truncate(limit - response.count);
+
+            for (id, mut task) in tasks.into_iter() {
+                if !request.opts.is_null() {
+                    task.opts = request.opts.clone();
+                }
+                response.count += 1;
+                if response.sample.len() < BULK_SAMPLE_SIZE {
+                    response.sample.push(id.clone());
+                }
+                if request.dry_run {
+                    self.registry.validate(&task)?;
+                    continue;
+                }
+                match self.send_task(task).await {
+                    Ok(job_id) => response.jobs.push(job_id),
+                    Err(err) => match err.downcast::<TaskError>() {
+                        Ok(err) => return Err(err.into()),
+                        Err(err) => response.errors.push(format!("{}: {:#}", id, err)),
+                    },
+                }
+            }
+        }
+        log::debug!(
+            "bulk task {}: {} selected records, {} jobs, {} errors",
+            request.task,
+            response.count,
+            response.jobs.len(),
+            response.errors.len()
+        );
+        Ok(response)
+    }
+}
+
+/// Pages through all posts, or through the posts that match a query.
+enum PostPages {
+    Couch(Option<String>),
+    Search(Value, Option<Value>),
+    Done,
+}
+
+impl PostPages {
+    fn new(query: Option<Value>) -> Self {
+        match query {
+            Some(query) => Self::Search(query, None),
+            None => Self::Couch(None),
+        }
+    }
+
+    /// Load the next page of posts, or None after the last page.
+    async fn next(
+        &mut self,
+        db: &CouchDB,
+        index: &PostIndex,
+    ) -> anyhow::Result<Option<Vec<Record<Post>>>> {
+        let (posts, len) = match self {
+            Self::Done => return Ok(None),
+            Self::Couch(start_after) => {
+                let prefix = format!("{}_", Post::NAME);
+                let docs = db
+                    .get_page_with_prefix(&prefix, start_after.as_deref(), BULK_PAGE_SIZE)
+                    .await?;
+                let len = docs.rows.len();
+                *start_after = docs.rows.last().map(|row| row.id.clone());
+                let posts = docs
+                    .rows
+                    .into_iter()
+                    .filter_map(|row| row.doc.into_typed_record::<Post>().ok())
+                    .collect();
+                (posts, len)
+            }
+            Self::Search(query, search_after) => {
+                let (guids, last) = index
+                    .find_post_guids(query, search_after.as_ref(), BULK_PAGE_SIZE)
+                    .await?;
+                *search_after = last;
+                let guids: Vec<&str> = guids.iter().map(|guid| guid.as_str()).collect();
+                let posts = db.get_many_records::<Post>(&guids).await?;
+                (posts, guids.len())
+            }
+        };
+        if len < BULK_PAGE_SIZE {
+            *self = Self::Done;
+        }
+        if len == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(posts))
+        }
+    }
+}
+
+/// Get the resolved media of a post.
+fn media_of_post(post: &Post) -> impl Iterator<Item = &Record<Media>> {
+    post.media.iter().filter_map(|media| media.record())
+}
@@ -0,0 +1,150 @@
+use celery::broker::RedisBroker;
+use celery::prelude::CeleryError;
+use celery::task::Signature;
+use celery::Celery;
+use oas_common::task::TaskPriority;
+use oas_common::Record;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde_json::Value;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use super::queue::{self, TaskQueue};
+use super::{create_celery_app, Config, Job, TaskRegistry};
+
+/// Prefix of the Redis keys that mark revoked jobs.
+///
+/// The Python worker checks for these keys and skips revoked jobs.
+pub const REVOKED_KEY_PREFIX: &str = "oas:revoked:";
+
+/// Time after which revoked jobs are forgotten.
+///
+/// Jobs that are still in a Celery queue after this time are run anyway, but their results are
+/// rejected.
+const REVOKED_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// A [TaskQueue] that sends tasks to Celery workers through Redis.
+///
+/// Jobs are revoked by marking them in Redis (see [REVOKED_KEY_PREFIX]). Workers skip marked jobs
+/// when they pick them up from the queue.
+#[derive(Clone)]
+pub struct CeleryQueue {
+    config: Config,
+    registry: TaskRegistry,
+    celery: Arc<RwLock<Option<Arc<Celery<RedisBroker>>>>>,
+    redis: Arc<RwLock<Option<ConnectionManager>>>,
+}
+
+impl fmt::Debug for CeleryQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CeleryQueue")
+    }
+}
+
+impl CeleryQueue {
+    pub fn with_config(config: Config) -> Self {
+        Self {
+            config,
+            registry: TaskRegistry::default(),
+            celery: Default::default(),
+            redis: Default::default(),
+        }
+    }
+
+    /// Get the Celery app.
+    ///
+    /// Fails if the queue was not initialized.
+    pub fn celery(&self) -> Result<Arc<Celery<RedisBroker>>, CeleryError> {
+        self.celery
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or(CeleryError::ForcedShutdown)
+    }
+
+    /// Get the Redis connection.
+    ///
+    /// Fails if the queue was not initialized.
+    fn redis(&self) -> anyhow::Result<ConnectionManager> {
+        self.redis
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("The Celery task queue is not initialized"))
+    }
+
+    pub(super) async fn send_task<T: celery::task::Task>(
+        &self,
+        task_sig: Signature<T>,
+        queue: &str,
+    ) -> Result<celery::task::AsyncResult, CeleryError> {
+        let res = self.celery()?.send_task(task_sig.with_queue(queue)).await?;
+        log::debug!(
+            "created task {} with id {} on queue {}",
+            Signature::<T>::task_name(),
+            res.task_id,
+            queue
+        );
+        Ok(res)
+    }
+}
+
+#[async_trait::async_trait]
+impl TaskQueue for CeleryQueue {
+    fn name(&self) -> &str {
+        "celery"
+    }
+
+    async fn init(&self) -> anyhow::Result<()> {
+        let celery = create_celery_app(&self.config).await?;
+        *self.celery.write().unwrap() = Some(celery);
+        let client = redis::Client::open(self.config.redis_url.as_str())?;
+        let redis = ConnectionManager::new(client).await?;
+        *self.redis.write().unwrap() = Some(redis);
+        Ok(())
+    }
+
+    async fn send(&self, job: &Record<Job>) -> anyhow::Result<String> {
+        let args = job.value.args.clone();
+        let opts = opts_with_job_id(job);
+        let queue = celery_queue_name(&job.value.typ, job.value.priority);
+        self.registry
+            .send_celery(self, &job.value.typ, args, opts, &queue)
+            .await
+    }
+
+    /// Mark the job as revoked in Redis, so that workers skip it.
+    async fn revoke(&self, job: &Record<Job>) -> anyhow::Result<bool> {
+        let mut redis = self.redis()?;
+        let key = format!("{}{}", REVOKED_KEY_PREFIX, job.id());
+        let _: () = redis.set_ex(key, 1, REVOKED_TTL.as_secs() as usize).await?;
+        Ok(true)
+    }
+}
+
+/// Get the Celery queue for a task with a priority, e.g. `oas.asr.high`.
+///
+/// The transcribe task is expensive, so it is routed to its own queues. All other tasks are
+/// routed to the default queues. Workers should consume the queues in the order of their
+/// priority.
+pub fn celery_queue_name(typ: &str, priority: TaskPriority) -> String {
+    let route = match typ {
+        queue::TRANSCRIBE => "asr",
+        _ => "default",
+    };
+    format!("oas.{}.{}", route, priority.name())
+}
+
+/// Add the job id to the task options, so that workers can report results for the job.
+fn opts_with_job_id(job: &Record<Job>) -> Value {
+    match job.value.opts.clone() {
+        Value::Object(mut opts) => {
+            opts.insert("job_id".to_string(), Value::String(job.id().to_string()));
+            Value::Object(opts)
+        }
+        Value::Null => serde_json::json!({ "job_id": job.id() }),
+        opts => opts,
+    }
+}
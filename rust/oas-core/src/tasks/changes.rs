@@ -1,19 +1,21 @@
 use anyhow::Context;
 use chrono::Utc;
-use futures::stream::StreamExt;
-use futures_batch::ChunksTimeoutStreamExt;
-use oas_common::task::{TaskRunningState, TaskState};
+use futures::Future;
+use oas_common::task::{TaskFinishedState, TaskRunningState, TaskState};
 use oas_common::types::{Media, Post};
 use oas_common::{Record, RecordMap, Resolver, TypedValue, UntypedRecord};
 use serde::{Deserialize, Serialize};
-use std::time;
 
-use super::taskdefs;
-use super::CeleryManager;
-use crate::couch::changes::changes_into_untyped_records;
-use crate::couch::{ChangeEvent, CouchDB, CouchResult};
+use super::pipeline::{self, Readiness, Step, Target};
+use super::quota::FeedContext;
+use super::TaskManager;
+use crate::couch::{ChangesDispatcher, ChangesReceiver, CouchDB};
 use crate::State;
 
+/// The legacy task process state.
+///
+/// The latest processed seq is now stored as a checkpoint, this is only read to migrate existing
+/// deployments.
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct TaskProcessState {
     latest_seq: String,
@@ -25,142 +27,252 @@ impl TypedValue for TaskProcessState {
 
 const TASK_STATE_ID: &str = "default";
 
+/// Name of the task processor as a changes consumer.
+pub const CONSUMER_NAME: &str = "tasks";
+
+/// Process all changes from the database.
+///
+/// This runs a [ChangesDispatcher] with the task processor as its only consumer. When running all
+/// services, use [process_changes_from] with a shared dispatcher instead.
 pub async fn process_changes(state: State, infinite: bool) -> anyhow::Result<()> {
-    let db = state.db_manager.record_db();
-    let celery = state.tasks;
-    let meta_db = state.db_manager.meta_db();
+    let db = state.db_manager.record_db().clone();
+    let mut dispatcher = ChangesDispatcher::new(db, infinite);
+    let changes = register_changes(&state, &mut dispatcher).await?;
+    let (dispatch_res, process_res) =
+        futures::join!(dispatcher.run(), process_changes_from(state, changes));
+    dispatch_res?;
+    process_res?;
+    Ok(())
+}
+
+/// Register the task processor as a consumer on a [ChangesDispatcher].
+pub async fn register_changes(
+    state: &State,
+    dispatcher: &mut ChangesDispatcher,
+) -> anyhow::Result<ChangesReceiver> {
+    let legacy_seq = get_legacy_latest_seq(state.db_manager.meta_db()).await;
+    let changes = dispatcher
+        .register_with_checkpoint(CONSUMER_NAME, &state.db_manager, legacy_seq)
+        .await?;
+    Ok(changes)
+}
 
-    let latest_seq = get_latest_seq(&meta_db).await;
-    log::debug!("start task process at couchdb seq {:?}", latest_seq);
-    let mut changes = db.changes(latest_seq.clone());
-    changes.set_infinite(infinite);
+/// Process the changes received from a [ChangesDispatcher].
+pub async fn process_changes_from(
+    state: State,
+    mut changes: ChangesReceiver,
+) -> anyhow::Result<()> {
+    let db = state.db_manager.record_db();
+    let tasks = state.tasks;
 
-    let batch_timeout = time::Duration::from_millis(200);
-    let batch_max_len = 1000;
-    let mut changes = changes.chunks_timeout(batch_max_len, batch_timeout);
+    log::debug!("start task process");
 
     while let Some(batch) = changes.next().await {
-        let last_seq = last_seq_of_batch(&batch);
-        let batch = changes_into_untyped_records(batch);
-        process_batch(&celery, db.clone(), batch)
+        let failures = process_batch(&tasks, db.clone(), batch.records().to_vec())
             .await
             .context("Failed to process changes batch for tasks")?;
-        if let Some(next_latest_seq) = last_seq {
-            if &next_latest_seq != latest_seq.as_deref().unwrap_or("") {
-                log::trace!(
-                    "save task process state at couchdb seq: {}",
-                    next_latest_seq
-                );
-                save_latest_seq(&meta_db, next_latest_seq)
-                    .await
-                    .context("Failed to save task meta state to CouchDB")?;
-            }
+        for (guid, err) in failures {
+            changes.reject(&batch, &guid, err).await?;
         }
+        changes
+            .commit(&batch)
+            .await
+            .context("Failed to save task checkpoint to CouchDB")?;
     }
     Ok(())
 }
 
-fn last_seq_of_batch(batch: &[CouchResult<ChangeEvent>]) -> Option<String> {
-    batch.last().and_then(|v| match v {
-        Ok(v) => Some(v.seq.to_string()),
-        _ => None,
-    })
-}
-
 async fn process_post(
-    celery: &CeleryManager,
-    _db: &CouchDB,
+    task_manager: &TaskManager,
+    db: &CouchDB,
+    context: &FeedContext,
     record: Record<Post>,
 ) -> anyhow::Result<()> {
-    if let Some(tasks) = record.task_states() {
-        if let TaskState::Wanted = &tasks.nlp {
-            let opts = serde_json::Value::Null;
-            celery
-                .send_task(taskdefs::nlp2::new(record.clone(), opts))
-                .await
-                .context("failed to send task")?;
+    let mut updates = vec![];
+    for step in pipeline::steps(Target::Post) {
+        if let Some(TaskState::Wanted) = pipeline::post_task(&record.value.tasks, step.typ) {
+            let readiness = step.check_post(&record);
+            let send = send_post_task(task_manager, context, step.typ, &record);
+            if let Some(state) = schedule(step, record.guid(), readiness, send).await? {
+                updates.push((step.typ, state));
+            }
         }
     }
+    if !updates.is_empty() {
+        db.update_record::<Post, _>(record.guid(), |record| {
+            for (typ, state) in updates.iter() {
+                // Keep states that changed since the task was sent, e.g. by a fast result.
+                if let Some(current) = pipeline::post_task_mut(&mut record.value.tasks, typ) {
+                    if matches!(current, TaskState::Wanted) {
+                        *current = state.clone();
+                    }
+                }
+            }
+            Ok(())
+        })
+        .await?;
+    }
     Ok(())
 }
 
 async fn process_media(
-    celery: &CeleryManager,
+    task_manager: &TaskManager,
     db: &CouchDB,
+    context: &FeedContext,
     record: Record<Media>,
 ) -> anyhow::Result<()> {
-    if let Some(tasks) = record.task_states() {
-        if let TaskState::Wanted = &tasks.asr {
-            let task_id = celery
-                .transcribe_media(&record)
-                .await
-                .context("failed to send task")?;
-            let mut next_record = record.clone();
-            let state = TaskRunningState {
-                task_id: task_id.to_string(),
+    let mut updates = vec![];
+    for step in pipeline::steps(Target::Media) {
+        if let Some(TaskState::Wanted) = pipeline::media_task(&record.value.tasks, step.typ) {
+            let readiness = step.check_media(&record);
+            let send = send_media_task(task_manager, context, step.typ, &record);
+            if let Some(state) = schedule(step, record.guid(), readiness, send).await? {
+                updates.push((step.typ, state));
+            }
+        }
+    }
+    if !updates.is_empty() {
+        db.update_record::<Media, _>(record.guid(), |record| {
+            for (typ, state) in updates.iter() {
+                // Keep states that changed since the task was sent, e.g. by a fast result.
+                if let Some(current) = pipeline::media_task_mut(&mut record.value.tasks, typ) {
+                    if matches!(current, TaskState::Wanted) {
+                        *current = state.clone();
+                    }
+                }
+            }
+            Ok(())
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+/// Send a wanted task if its dependencies are finished.
+///
+/// `send` is only awaited if the task is ready. Returns the new state of the task, or None if
+/// the task still waits for its dependencies.
+async fn schedule(
+    step: &Step,
+    guid: &str,
+    readiness: Readiness,
+    send: impl Future<Output = anyhow::Result<String>>,
+) -> anyhow::Result<Option<TaskState>> {
+    match readiness {
+        Readiness::Ready => {
+            let task_id = send.await.context("failed to send task")?;
+            Ok(Some(TaskState::Running(TaskRunningState {
+                task_id,
                 start: Utc::now(),
-            };
-            next_record.task_states_mut().unwrap().asr = TaskState::Running(state);
-            db.put_record(next_record).await?;
+                history: vec![],
+            })))
+        }
+        Readiness::Waiting(waiting) => {
+            log::trace!(
+                "Task {} on {} waits for {}",
+                step.typ,
+                guid,
+                waiting.join(", ")
+            );
+            Ok(None)
         }
-        if let TaskState::Wanted = &tasks.download {
-            let opts = serde_json::Value::Null;
-            celery
-                .send_task(taskdefs::download2::new(record.clone(), opts))
-                .await
-                .context("failed to send task")?;
+        Readiness::Failed(error) => {
+            log::debug!("Task {} on {} not started: {}", step.typ, guid, error);
+            Ok(Some(TaskState::Finished(TaskFinishedState {
+                task_id: String::new(),
+                success: false,
+                error: Some(error),
+                start: None,
+                end: Some(Utc::now()),
+                took: 0.,
+                history: vec![],
+            })))
         }
     }
-    Ok(())
 }
 
+async fn send_media_task(
+    task_manager: &TaskManager,
+    context: &FeedContext,
+    typ: &str,
+    record: &Record<Media>,
+) -> anyhow::Result<String> {
+    let task = super::media_task(typ, record)?;
+    let task = context.apply(task, record.guid(), &record.value.feeds);
+    task_manager.send_task(task).await
+}
+
+async fn send_post_task(
+    task_manager: &TaskManager,
+    context: &FeedContext,
+    typ: &str,
+    record: &Record<Post>,
+) -> anyhow::Result<String> {
+    let task = super::post_task(typ, record)?;
+    let task = context.apply(task, record.guid(), &record.value.feeds);
+    task_manager.send_task(task).await
+}
+
+/// Process a batch of changed records.
+///
+/// Posts whose tasks wait for a task on their media are not changed when the media task
+/// finishes, so they are loaded and processed together with their media.
+///
+/// Returns the guids of the records that failed processing, together with their errors.
 pub async fn process_batch(
-    celery: &CeleryManager,
+    tasks: &TaskManager,
     db: CouchDB,
     batch: Vec<UntypedRecord>,
-) -> anyhow::Result<()> {
-    let mut sorted = RecordMap::from_untyped(batch)?;
+) -> anyhow::Result<Vec<(String, anyhow::Error)>> {
+    let (mut sorted, mut failures) = RecordMap::from_untyped_lossy(batch);
     let mut posts = sorted.into_vec::<Post>();
     let medias = sorted.into_vec::<Media>();
+
+    let mut dependent_guids: Vec<&str> = medias
+        .iter()
+        .filter(|media| pipeline::media_has_finished_dependency(media))
+        .flat_map(|media| media.value.posts.iter().map(|post| post.guid()))
+        .filter(|guid| !posts.iter().any(|post| post.guid() == *guid))
+        .collect();
+    dependent_guids.sort_unstable();
+    dependent_guids.dedup();
+    if !dependent_guids.is_empty() {
+        let dependents = db
+            .get_many_records::<Post>(&dependent_guids)
+            .await
+            .context("failed to load posts of finished media")?;
+        posts.extend(dependents);
+    }
+
     db.resolve_all_refs(&mut posts)
         .await
         .context("failed to resolve refs")?;
+    let context = FeedContext::load(&db, &posts, &medias)
+        .await
+        .context("failed to load feeds")?;
 
     for record in posts.into_iter() {
-        let res = process_post(&celery, &db, record).await;
-        log_if_error(res);
+        let guid = record.guid().to_string();
+        if let Err(err) = process_post(tasks, &db, &context, record).await {
+            failures.push((guid, err));
+        }
     }
 
     for record in medias.into_iter() {
-        // log::trace!(
-        //     "task process touch media {}, task states: {:?}",
-        //     record.id(),
-        //     record.task_states()
-        // );
-        let res = process_media(&celery, &db, record).await;
-        log_if_error(res);
+        let guid = record.guid().to_string();
+        if let Err(err) = process_media(tasks, &db, &context, record).await {
+            failures.push((guid, err));
+        }
     }
 
-    Ok(())
+    Ok(failures)
 }
 
-fn log_if_error<A>(res: anyhow::Result<A>) {
-    match res {
-        Ok(_) => {}
-        Err(err) => log::error!("{:?}", err),
-    }
-}
-
-pub async fn get_latest_seq(db: &CouchDB) -> Option<String> {
+/// Get the latest seq from the legacy task process state.
+async fn get_legacy_latest_seq(db: &CouchDB) -> Option<String> {
     db.table::<TaskProcessState>()
         .get(TASK_STATE_ID)
         .await
         .map(|record| record.value.latest_seq)
         .ok()
 }
-
-pub async fn save_latest_seq(db: &CouchDB, latest_seq: String) -> anyhow::Result<()> {
-    let record = Record::from_id_and_value(TASK_STATE_ID, TaskProcessState { latest_seq });
-    db.table::<TaskProcessState>().put(record).await?;
-    Ok(())
-}
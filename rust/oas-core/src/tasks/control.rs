@@ -0,0 +1,409 @@
+//! Starting and cancelling tasks on request of a user.
+//!
+//! A task that is started for a record is sent right away if its dependencies are finished,
+//! otherwise it is marked as wanted and the task processor sends it once they are. Either way,
+//! the task state on the record reflects what happens to the task. When a job is cancelled, it
+//! is revoked from the queue if the backend supports this, and the task state on its target
+//! record is cleared. Users may only start and cancel tasks on records of their feeds, see
+//! [FeedAccess].
+
+use chrono::Utc;
+use oas_common::task::{TaskRunningState, TaskState};
+use oas_common::types::{Feed, Media, Post};
+use oas_common::{Record, Reference, TypedValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::pipeline::{self, Readiness, Target};
+use super::quota::FeedContext;
+use super::result::update_task_state;
+use super::{Job, TaskError, TaskManager};
+use crate::couch::{CouchDB, CouchError};
+
+/// Error when starting or cancelling a task.
+#[derive(Error, Debug)]
+pub enum TaskControlError {
+    #[error("{0}")]
+    Task(#[from] TaskError),
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    Couch(#[from] CouchError),
+    #[error("{0:#}")]
+    Other(#[from] anyhow::Error),
+}
+
+/// The feeds on whose records a user may start and cancel tasks.
+#[derive(Debug, Clone)]
+pub enum FeedAccess {
+    /// All records, including records without feeds.
+    All,
+    /// Only records of one of these feeds (by guid).
+    Feeds(Vec<String>),
+}
+
+impl FeedAccess {
+    /// Check if a record of some feeds may be accessed.
+    pub fn allows<'a>(&self, mut feed_guids: impl Iterator<Item = &'a str>) -> bool {
+        match self {
+            Self::All => true,
+            Self::Feeds(feeds) => feed_guids.any(|guid| feeds.iter().any(|feed| feed == guid)),
+        }
+    }
+
+    /// Check if a job may be accessed, by the feed of its target record.
+    pub fn allows_job(&self, job: &Job) -> bool {
+        self.allows(job.feed.as_deref().into_iter())
+    }
+
+    /// Fail with [TaskControlError::Forbidden] unless a record of some feeds may be accessed.
+    pub fn ensure(&self, guid: &str, feeds: &[Reference<Feed>]) -> Result<(), TaskControlError> {
+        if self.allows(feeds.iter().map(|feed| feed.guid())) {
+            Ok(())
+        } else {
+            Err(TaskControlError::Forbidden(format!(
+                "Tasks on {} may only be controlled by the owners of its feeds",
+                guid
+            )))
+        }
+    }
+}
+
+/// The outcome of starting a task.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StartTaskResponse {
+    /// Id of the created job, if the task was sent.
+    pub job: Option<String>,
+    /// The new state of the task on the record.
+    pub state: TaskState,
+}
+
+/// The outcome of cancelling a job.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelJobResponse {
+    /// The cancelled job.
+    pub job: Record<Job>,
+    /// Whether the job was removed from the queue. If not, a worker may still run it, but its
+    /// result is rejected.
+    pub revoked: bool,
+}
+
+impl TaskManager {
+    /// Start a task on a record.
+    ///
+    /// `id` is the id of the media or post, depending on the task. Fails with a conflict if the
+    /// task is already running or one of its dependencies failed, and with
+    /// [TaskControlError::Forbidden] if `access` does not include a feed of the record.
+    pub async fn start_task(
+        &self,
+        db: &CouchDB,
+        typ: &str,
+        id: &str,
+        user: &str,
+        access: &FeedAccess,
+    ) -> Result<StartTaskResponse, TaskControlError> {
+        let step = pipeline::PIPELINE
+            .iter()
+            .find(|step| step.typ == typ)
+            .ok_or_else(|| TaskError::Unknown(typ.to_string()))?;
+        let (guid, current, readiness, task) = match step.target {
+            Target::Media => {
+                let media = get_record::<Media>(db, id).await?;
+                access.ensure(media.guid(), &media.value.feeds)?;
+                let current = pipeline::media_task(&media.value.tasks, typ).cloned();
+                let context = FeedContext::load(db, &[], &[media.clone()]).await?;
+                let task = super::media_task(typ, &media)?;
+                let task = context.apply(task, media.guid(), &media.value.feeds);
+                (
+                    media.guid().to_string(),
+                    current,
+                    step.check_media(&media),
+                    task,
+                )
+            }
+            Target::Post => {
+                let mut post = get_record::<Post>(db, id).await?;
+                access.ensure(post.guid(), &post.value.feeds)?;
+                post.resolve_refs(db).await.map_err(anyhow::Error::from)?;
+                let current = pipeline::post_task(&post.value.tasks, typ).cloned();
+                let context = FeedContext::load(db, &[post.clone()], &[]).await?;
+                let task = super::post_task(typ, &post)?;
+                let task = context.apply(task, post.guid(), &post.value.feeds);
+                (
+                    post.guid().to_string(),
+                    current,
+                    step.check_post(&post),
+                    task,
+                )
+            }
+        };
+        if let Some(TaskState::Running(running)) = &current {
+            return Err(TaskControlError::Conflict(format!(
+                "Task {} on {} is already running as job {}",
+                typ, guid, running.task_id
+            )));
+        }
+
+        let (job, state) = match readiness {
+            Readiness::Ready => {
+                let job_id = self
+                    .send_task(task.with_triggered_by(user))
+                    .await
+                    .map_err(|err| match err.downcast::<TaskError>() {
+                        Ok(err) => TaskControlError::Task(err),
+                        Err(err) => TaskControlError::Other(err),
+                    })?;
+                let history = current.as_ref().map(|state| state.history().to_vec());
+                let state = TaskState::Running(TaskRunningState {
+                    task_id: job_id.clone(),
+                    start: Utc::now(),
+                    history: history.unwrap_or_default(),
+                });
+                (Some(job_id), state)
+            }
+            Readiness::Waiting(_) => (None, TaskState::Wanted),
+            Readiness::Failed(error) => return Err(TaskControlError::Conflict(error)),
+        };
+        let new_state = state.clone();
+        update_task_state(db, typ, &guid, |current| *current = new_state.clone()).await?;
+        log::info!(
+            "Task {} on {} started by {} ({})",
+            typ,
+            guid,
+            user,
+            job.as_deref().unwrap_or("wanted")
+        );
+        Ok(StartTaskResponse { job, state })
+    }
+
+    /// Cancel a job that is held, queued or running.
+    ///
+    /// The job is revoked from the queue if possible, and the task state on its target record is
+    /// cleared if it belongs to this job. Fails with [TaskControlError::Forbidden] if `access`
+    /// does not include a feed of the target record.
+    pub async fn cancel_job(
+        &self,
+        db: &CouchDB,
+        id: &str,
+        user: &str,
+        access: &FeedAccess,
+    ) -> Result<CancelJobResponse, TaskControlError> {
+        let job = self
+            .jobs()
+            .get(id)
+            .await?
+            .ok_or_else(|| TaskControlError::NotFound(format!("Job {}", id)))?;
+        if let FeedAccess::Feeds(_) = access {
+            let target = job.value.target.as_deref().unwrap_or_default();
+            let feeds = target_feeds(db, &job.value.typ, target).await?;
+            access.ensure(target, &feeds)?;
+        }
+        if !job.value.is_open() {
+            return Err(TaskControlError::Conflict(format!(
+                "Job {} is already {:?}",
+                id, job.value.status
+            )));
+        }
+        let job = self
+            .jobs()
+            .update(id, |job| {
+                if !job.is_open() {
+                    anyhow::bail!("Job {} is already {:?}", id, job.status);
+                }
+                job.cancel(user);
+                Ok(())
+            })
+            .await?;
+
+        let revoked = match self.queue().revoke(&job).await {
+            Ok(revoked) => revoked,
+            Err(err) => {
+                log::warn!("Failed to revoke job {}: {:#}", id, err);
+                false
+            }
+        };
+        if let Some(target) = &job.value.target {
+            update_task_state(db, &job.value.typ, target, |state| {
+                if matches!(state, TaskState::Running(running) if running.task_id == id) {
+                    *state = TaskState::None;
+                }
+            })
+            .await?;
+        }
+        log::info!(
+            "Job {} ({}) cancelled by {} (revoked: {})",
+            id,
+            job.value.typ,
+            user,
+            revoked
+        );
+        Ok(CancelJobResponse { job, revoked })
+    }
+}
+
+/// Get the feeds of the target record of a task.
+///
+/// Targets that do not exist (anymore) have no feeds.
+async fn target_feeds(
+    db: &CouchDB,
+    typ: &str,
+    guid: &str,
+) -> Result<Vec<Reference<Feed>>, TaskControlError> {
+    let target = pipeline::PIPELINE
+        .iter()
+        .find(|step| step.typ == typ)
+        .map(|step| step.target);
+    let feeds = match target {
+        Some(Target::Media) if guid.starts_with(&format!("{}_", Media::NAME)) => db
+            .get_record::<Media>(guid)
+            .await
+            .map(|record| record.value.feeds),
+        Some(Target::Post) if guid.starts_with(&format!("{}_", Post::NAME)) => db
+            .get_record::<Post>(guid)
+            .await
+            .map(|record| record.value.feeds),
+        _ => return Ok(vec![]),
+    };
+    match feeds {
+        Ok(feeds) => Ok(feeds),
+        Err(err) if err.status_code() == Some(404) => Ok(vec![]),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn get_record<T: TypedValue>(db: &CouchDB, id: &str) -> Result<Record<T>, TaskControlError> {
+    let guid = T::guid(id);
+    match db.get_record::<T>(&guid).await {
+        Ok(record) => Ok(record),
+        Err(err) if err.status_code() == Some(404) => Err(TaskControlError::NotFound(guid)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tasks::{queue, CouchQueue, JobStatus, JobStore, TaskMessage};
+    use serde_json::Value;
+
+    async fn setup() -> (CouchDB, TaskManager) {
+        let db = CouchDB::in_memory("records");
+        let jobs = JobStore::new(CouchDB::in_memory("meta"));
+        let tasks = TaskManager::with_queue(CouchQueue::new(jobs.clone()), jobs);
+        let feed = Record::from_id_and_value("a", Feed::default());
+        let media = Media {
+            feeds: vec![Reference::Id(feed.guid().to_string())],
+            ..Default::default()
+        };
+        db.put_record(feed).await.unwrap();
+        db.put_record(Record::from_id_and_value("m1", media))
+            .await
+            .unwrap();
+        (db, tasks)
+    }
+
+    async fn media_tasks(db: &CouchDB) -> oas_common::types::MediaTasks {
+        let media = db.get_record::<Media>("oas.Media_m1").await.unwrap();
+        media.value.tasks
+    }
+
+    #[tokio::test]
+    async fn start_task_once() {
+        let (db, tasks) = setup().await;
+        let res = tasks
+            .start_task(&db, queue::DOWNLOAD, "m1", "alice", &FeedAccess::All)
+            .await
+            .unwrap();
+        let job_id = res.job.unwrap();
+        assert!(matches!(&res.state, TaskState::Running(running) if running.task_id == job_id));
+        let job = tasks.jobs().get(&job_id).await.unwrap().unwrap();
+        assert_eq!(job.value.triggered_by.as_deref(), Some("alice"));
+        assert!(matches!(
+            media_tasks(&db).await.download,
+            TaskState::Running(_)
+        ));
+
+        let err = tasks
+            .start_task(&db, queue::DOWNLOAD, "m1", "alice", &FeedAccess::All)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TaskControlError::Conflict(_)), "{:?}", err);
+
+        // The transcription waits for the running download.
+        let res = tasks
+            .start_task(&db, queue::TRANSCRIBE, "m1", "alice", &FeedAccess::All)
+            .await
+            .unwrap();
+        assert!(res.job.is_none());
+        assert!(matches!(res.state, TaskState::Wanted));
+        assert!(matches!(media_tasks(&db).await.asr, TaskState::Wanted));
+    }
+
+    #[tokio::test]
+    async fn cancel_clears_own_state() {
+        let (db, tasks) = setup().await;
+        let res = tasks
+            .start_task(&db, queue::DOWNLOAD, "m1", "alice", &FeedAccess::All)
+            .await
+            .unwrap();
+        let job_id = res.job.unwrap();
+        // An older job of the same task, whose state was replaced by the new job.
+        let task =
+            TaskMessage::new(queue::DOWNLOAD, Value::Null, Value::Null).with_target("oas.Media_m1");
+        let old = Job::from_task(task).into_record();
+        tasks.jobs().create(old.clone()).await.unwrap();
+
+        let res = tasks
+            .cancel_job(&db, old.id(), "alice", &FeedAccess::All)
+            .await
+            .unwrap();
+        assert_eq!(res.job.value.status, JobStatus::Cancelled);
+        assert!(res.revoked);
+        let state = media_tasks(&db).await.download;
+        assert!(matches!(state, TaskState::Running(running) if running.task_id == job_id));
+
+        tasks
+            .cancel_job(&db, &job_id, "alice", &FeedAccess::All)
+            .await
+            .unwrap();
+        assert!(matches!(media_tasks(&db).await.download, TaskState::None));
+        let err = tasks
+            .cancel_job(&db, &job_id, "alice", &FeedAccess::All)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TaskControlError::Conflict(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn forbid_other_feeds() {
+        let (db, tasks) = setup().await;
+        let other = FeedAccess::Feeds(vec!["oas.Feed_b".to_string()]);
+        let own = FeedAccess::Feeds(vec!["oas.Feed_a".to_string()]);
+        let err = tasks
+            .start_task(&db, queue::DOWNLOAD, "m1", "bob", &other)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TaskControlError::Forbidden(_)), "{:?}", err);
+        assert!(matches!(media_tasks(&db).await.download, TaskState::None));
+
+        let res = tasks
+            .start_task(&db, queue::DOWNLOAD, "m1", "alice", &own)
+            .await
+            .unwrap();
+        let job_id = res.job.unwrap();
+        let err = tasks
+            .cancel_job(&db, &job_id, "bob", &other)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TaskControlError::Forbidden(_)), "{:?}", err);
+        let job = tasks.jobs().get(&job_id).await.unwrap().unwrap();
+        assert!(job.value.is_open());
+    }
+}
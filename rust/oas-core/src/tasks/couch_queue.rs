@@ -0,0 +1,101 @@
+use oas_common::Record;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::queue::TaskQueue;
+use super::{Job, JobStore};
+use crate::couch::CouchResult;
+
+/// A [TaskQueue] that keeps queued tasks as [Job] records in the database.
+///
+/// The [TaskManager](super::TaskManager) saves a job for each task, so sending a task only means
+/// leaving the job in the queue. Workers then [claim](CouchQueue::claim) queued jobs. This only
+/// needs a record store, so it works without Redis (and with an in-memory store in tests).
+#[derive(Debug, Clone)]
+pub struct CouchQueue {
+    jobs: JobStore,
+}
+
+impl CouchQueue {
+    /// Create a new queue on a job store.
+    pub fn new(jobs: JobStore) -> Self {
+        Self { jobs }
+    }
+
+    /// Claim the oldest queued job for a worker.
+    ///
+    /// See [JobStore::claim].
+    pub async fn claim(&self, worker: &str, typs: &[String]) -> CouchResult<Option<Record<Job>>> {
+        self.jobs.claim(worker, typs).await
+    }
+}
+
+/// A request of a worker to claim a job.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct JobClaim {
+    /// Id of the worker.
+    pub worker: String,
+    /// Names of the tasks the worker can run. If empty, jobs for all tasks are claimed.
+    #[serde(default)]
+    pub typs: Vec<String>,
+}
+
+#[async_trait::async_trait]
+impl TaskQueue for CouchQueue {
+    fn name(&self) -> &str {
+        "couch"
+    }
+
+    fn claims_jobs(&self) -> bool {
+        true
+    }
+
+    async fn init(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn send(&self, job: &Record<Job>) -> anyhow::Result<String> {
+        Ok(job.id().to_string())
+    }
+
+    /// Cancelled jobs are not claimed, so they are revoked once they are saved as cancelled.
+    async fn revoke(&self, _job: &Record<Job>) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::couch::CouchDB;
+    use crate::tasks::{JobStatus, TaskManager, TaskMessage};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn claim_sent_task() {
+        let jobs = JobStore::new(CouchDB::in_memory("meta"));
+        let tasks = TaskManager::with_queue(CouchQueue::new(jobs.clone()), jobs);
+        let args = json!({ "media_url": "http://example.org/a.mp3", "media_id": "a" });
+        let task = TaskMessage::new("transcribe", args, json!({}));
+        let id = tasks.send_task(task).await.unwrap();
+
+        // The job stays queued until a worker claims it.
+        let job = tasks.jobs().get(&id).await.unwrap().unwrap();
+        assert_eq!(job.value.status, JobStatus::Queued);
+
+        let claim = JobClaim {
+            worker: "w1".to_string(),
+            typs: vec!["nlp2".to_string()],
+        };
+        assert!(tasks.claim(&claim).await.unwrap().is_none());
+        let claim = JobClaim {
+            worker: "w1".to_string(),
+            typs: vec![],
+        };
+        let job = tasks.claim(&claim).await.unwrap().unwrap();
+        assert_eq!(job.id(), id);
+        assert_eq!(job.value.status, JobStatus::Running);
+        assert_eq!(job.value.worker.as_deref(), Some("w1"));
+        assert!(tasks.claim(&claim).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,628 @@
+//! Job records.
+//!
+//! Every task that is sent to a worker is tracked as a [Job] record in the meta database, no
+//! matter which [TaskQueue](super::TaskQueue) backend runs it. The job holds the task arguments,
+//! the target record and the lifecycle of the task (status, attempts, worker, result and logs).
+
+use chrono::{DateTime, Utc};
+use oas_common::task::TaskPriority;
+use oas_common::{util, Record, TypedValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cmp::Reverse;
+
+use super::TaskMessage;
+use crate::couch::{CouchDB, CouchResult, View, ViewQuery};
+
+/// Jobs by status and creation time.
+const JOBS_BY_STATUS: View = View {
+    typ: Job::NAME,
+    name: "by_status",
+    key: &["status", "created"],
+};
+/// Jobs by task name and creation time.
+const JOBS_BY_TYP: View = View {
+    typ: Job::NAME,
+    name: "by_typ",
+    key: &["typ", "created"],
+};
+/// Jobs by task name, status and creation time.
+const JOBS_BY_TYP_STATUS: View = View {
+    typ: Job::NAME,
+    name: "by_typ_status",
+    key: &["typ", "status", "created"],
+};
+/// Jobs by status, priority and creation time.
+const JOBS_BY_PRIORITY: View = View {
+    typ: Job::NAME,
+    name: "by_priority",
+    key: &["status", "priority", "created"],
+};
+/// Jobs by the feed of their target and creation time.
+const JOBS_BY_FEED: View = View {
+    typ: Job::NAME,
+    name: "by_feed",
+    key: &["feed", "created"],
+};
+/// Jobs by creation time.
+const JOBS_BY_CREATED: View = View {
+    typ: Job::NAME,
+    name: "by_created",
+    key: &["created"],
+};
+/// Jobs by the time they were released from being held.
+const JOBS_BY_RELEASED: View = View {
+    typ: Job::NAME,
+    name: "by_released",
+    key: &["released"],
+};
+/// Jobs by the time they finished or failed.
+const JOBS_BY_FINISHED: View = View {
+    typ: Job::NAME,
+    name: "by_finished",
+    key: &["finished"],
+};
+
+/// Number of queued jobs that are loaded at once when a job is claimed.
+const CLAIM_PAGE_SIZE: usize = 20;
+
+/// All views on the job records.
+const JOB_VIEWS: &[View] = &[
+    JOBS_BY_STATUS,
+    JOBS_BY_TYP,
+    JOBS_BY_TYP_STATUS,
+    JOBS_BY_PRIORITY,
+    JOBS_BY_FEED,
+    JOBS_BY_CREATED,
+    JOBS_BY_RELEASED,
+    JOBS_BY_FINISHED,
+];
+
+/// A task that was sent to the workers.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    /// Name of the task.
+    pub typ: String,
+    /// Guid of the record the task works on.
+    pub target: Option<String>,
+    /// Guid of the feed the target record belongs to.
+    pub feed: Option<String>,
+    /// Priority of the job.
+    #[serde(default)]
+    pub priority: TaskPriority,
+    /// Arguments of the task.
+    pub args: Value,
+    /// Options of the task.
+    pub opts: Value,
+    /// Current status of the job.
+    pub status: JobStatus,
+    /// Id of the task in the queue backend, if it differs from the job id.
+    pub task_id: Option<String>,
+    /// Number of times the job was started by a worker.
+    #[serde(default)]
+    pub attempts: u32,
+    /// Id of the worker that runs the job.
+    pub worker: Option<String>,
+    /// Time the job was created.
+    pub created: DateTime<Utc>,
+    /// Time a held job was released to the queue.
+    pub released: Option<DateTime<Utc>>,
+    /// Time of the latest change.
+    pub updated: DateTime<Utc>,
+    /// Time the job was last sent to the workers, if the workers don't claim jobs themselves.
+    pub sent: Option<DateTime<Utc>>,
+    /// Time the job was last started by a worker.
+    pub started: Option<DateTime<Utc>>,
+    /// Time the job finished or failed.
+    pub finished: Option<DateTime<Utc>>,
+    /// Time before which a re-queued job is not started again.
+    pub retry_at: Option<DateTime<Utc>>,
+    /// Summary of the result, once the job is finished.
+    pub result: Option<Value>,
+    /// Error message, if the job failed.
+    pub error: Option<String>,
+    /// Name of the user that created the job through the API.
+    pub triggered_by: Option<String>,
+    /// Log messages of the job.
+    #[serde(default)]
+    pub logs: Vec<JobLog>,
+}
+
+impl TypedValue for Job {
+    const NAME: &'static str = "oas.Job";
+}
+
+/// Status of a [Job].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// The job is held back until the quota of its feed allows to run it.
+    Held,
+    /// The job waits for a worker.
+    Queued,
+    /// The job was started by a worker.
+    Running,
+    /// The job finished successfully.
+    Finished,
+    /// The job failed.
+    Failed,
+    /// The job was cancelled.
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Parse a status from its name, e.g. "running".
+    pub fn from_name(name: &str) -> Option<Self> {
+        serde_json::from_value(Value::String(name.to_string())).ok()
+    }
+}
+
+/// A log message of a [Job].
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct JobLog {
+    pub time: DateTime<Utc>,
+    pub message: String,
+}
+
+impl Job {
+    /// Create a new job for a task.
+    ///
+    /// The job is queued, or held if the task should be held back.
+    pub fn from_task(task: TaskMessage) -> Self {
+        let now = Utc::now();
+        let status = if task.hold {
+            JobStatus::Held
+        } else {
+            JobStatus::Queued
+        };
+        Self {
+            typ: task.name,
+            target: task.target,
+            feed: task.feed,
+            priority: task.priority,
+            args: task.args,
+            opts: task.opts,
+            status,
+            task_id: None,
+            attempts: 0,
+            worker: None,
+            created: now,
+            released: None,
+            updated: now,
+            sent: None,
+            started: None,
+            finished: None,
+            retry_at: None,
+            result: None,
+            error: None,
+            triggered_by: task.triggered_by,
+            logs: vec![],
+        }
+    }
+
+    /// Create a job record with a random id.
+    pub fn into_record(self) -> Record<Job> {
+        Record::from_id_and_value(util::id_from_uuid(), self)
+    }
+
+    /// Add a log message.
+    pub fn log(&mut self, message: impl ToString) {
+        self.logs.push(JobLog {
+            time: Utc::now(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Release a held job to the queue.
+    pub fn release(&mut self) {
+        let now = Utc::now();
+        self.status = JobStatus::Queued;
+        self.released = Some(now);
+        self.updated = now;
+    }
+
+    /// Get the time the job was put into the queue.
+    pub fn queued_at(&self) -> DateTime<Utc> {
+        self.released.unwrap_or(self.created)
+    }
+
+    /// Mark the job as started by a worker.
+    pub fn start(&mut self, worker: Option<&str>) {
+        let now = Utc::now();
+        self.status = JobStatus::Running;
+        self.worker = worker.map(|worker| worker.to_string());
+        self.attempts += 1;
+        self.started = Some(now);
+        self.finished = None;
+        self.retry_at = None;
+        self.updated = now;
+    }
+
+    /// Mark the job as sent to workers that don't claim jobs themselves.
+    ///
+    /// The job counts as running, but it is only started once a worker reports its start (see
+    /// [Job::report_start]). Until then, it may still wait in the queue of the backend.
+    pub fn send(&mut self) {
+        let now = Utc::now();
+        self.status = JobStatus::Running;
+        self.worker = None;
+        self.attempts += 1;
+        self.sent = Some(now);
+        self.started = None;
+        self.finished = None;
+        self.retry_at = None;
+        self.updated = now;
+    }
+
+    /// Record that a worker started a job that was sent to it.
+    pub fn report_start(&mut self, worker: Option<&str>) {
+        let now = Utc::now();
+        self.worker = worker.map(|worker| worker.to_string());
+        self.started = Some(now);
+        self.updated = now;
+    }
+
+    /// Queue the job again after a failed attempt.
+    pub fn retry(&mut self, error: impl ToString, retry_at: DateTime<Utc>) {
+        self.log(format!(
+            "Attempt {} failed: {}. Retry at {}",
+            self.attempts,
+            error.to_string(),
+            retry_at
+        ));
+        self.status = JobStatus::Queued;
+        self.worker = None;
+        self.retry_at = Some(retry_at);
+        self.updated = Utc::now();
+    }
+
+    /// Check if the job is due to be started.
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.retry_at.map_or(true, |retry_at| retry_at <= now)
+    }
+
+    /// Mark the job as finished.
+    pub fn finish(&mut self, result: Option<Value>) {
+        let now = Utc::now();
+        self.status = JobStatus::Finished;
+        self.result = result;
+        self.error = None;
+        self.finished = Some(now);
+        self.updated = now;
+    }
+
+    /// Mark the job as cancelled by a user.
+    pub fn cancel(&mut self, user: &str) {
+        let now = Utc::now();
+        self.log(format!("Cancelled by {}", user));
+        self.status = JobStatus::Cancelled;
+        self.finished = Some(now);
+        self.updated = now;
+    }
+
+    /// Check if the job is still to be run or running.
+    pub fn is_open(&self) -> bool {
+        matches!(
+            self.status,
+            JobStatus::Held | JobStatus::Queued | JobStatus::Running
+        )
+    }
+
+    /// Mark the job as failed.
+    pub fn fail(&mut self, error: impl ToString) {
+        let now = Utc::now();
+        let error = error.to_string();
+        self.log(&error);
+        self.status = JobStatus::Failed;
+        self.error = Some(error);
+        self.finished = Some(now);
+        self.updated = now;
+    }
+}
+
+/// Store for the job records.
+#[derive(Debug, Clone)]
+pub struct JobStore {
+    db: CouchDB,
+}
+
+impl JobStore {
+    /// Create a new job store on a database.
+    pub fn new(db: CouchDB) -> Self {
+        Self { db }
+    }
+
+    /// Save a new job.
+    pub async fn create(&self, job: Record<Job>) -> CouchResult<()> {
+        self.db.put_record(job).await?;
+        Ok(())
+    }
+
+    /// Get a job by its id.
+    ///
+    /// Returns None if the job does not exist.
+    pub async fn get(&self, id: &str) -> CouchResult<Option<Record<Job>>> {
+        match self.db.table::<Job>().get(id).await {
+            Ok(record) => Ok(Some(record)),
+            Err(err) if err.status_code() == Some(404) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Create or update the views on the job records.
+    pub async fn init(&self) -> CouchResult<()> {
+        self.db.init_views(JOB_VIEWS).await
+    }
+
+    /// List jobs, optionally filtered by status and task name.
+    ///
+    /// The jobs are sorted by creation time, newest first. The first `offset` jobs are skipped
+    /// and at most `limit` jobs are returned.
+    pub async fn list(
+        &self,
+        status: Option<JobStatus>,
+        typ: Option<&str>,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> CouchResult<Vec<Record<Job>>> {
+        let (view, query) = match (status, typ) {
+            (None, None) => (&JOBS_BY_CREATED, ViewQuery::all()),
+            (Some(status), None) => (&JOBS_BY_STATUS, ViewQuery::prefix(vec![json!(status)])),
+            (None, Some(typ)) => (&JOBS_BY_TYP, ViewQuery::prefix(vec![json!(typ)])),
+            (Some(status), Some(typ)) => (
+                &JOBS_BY_TYP_STATUS,
+                ViewQuery::prefix(vec![json!(typ), json!(status)]),
+            ),
+        };
+        let query = query.descending().page(offset, limit);
+        self.db.query_view_records(view, &query).await
+    }
+
+    /// List the jobs of some feeds, optionally filtered by status and task name.
+    ///
+    /// Like [JobStore::list], but only jobs whose target belongs to one of `feeds` (by guid) are
+    /// listed. All jobs of the feeds are loaded and then filtered and paged.
+    pub async fn list_for_feeds(
+        &self,
+        feeds: &[String],
+        status: Option<JobStatus>,
+        typ: Option<&str>,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> CouchResult<Vec<Record<Job>>> {
+        let mut records = vec![];
+        for feed in feeds.iter() {
+            let query = ViewQuery::prefix(vec![json!(feed)]);
+            let jobs = self.db.query_view_records(&JOBS_BY_FEED, &query).await?;
+            records.extend(jobs.into_iter().filter(|record: &Record<Job>| {
+                status.map_or(true, |status| record.value.status == status)
+                    && typ.map_or(true, |typ| record.value.typ == typ)
+            }));
+        }
+        records.sort_by_key(|record| Reverse(record.value.created));
+        let records = records.into_iter().skip(offset);
+        Ok(match limit {
+            Some(limit) => records.take(limit).collect(),
+            None => records.collect(),
+        })
+    }
+
+    /// List all jobs with a status, oldest first.
+    pub async fn list_with_status(&self, status: JobStatus) -> CouchResult<Vec<Record<Job>>> {
+        let query = ViewQuery::prefix(vec![json!(status)]);
+        self.db.query_view_records(&JOBS_BY_STATUS, &query).await
+    }
+
+    /// List all jobs that were created at or after a time.
+    pub async fn list_created_since(&self, since: DateTime<Utc>) -> CouchResult<Vec<Record<Job>>> {
+        let query = ViewQuery::prefix_from(vec![], json!(since));
+        self.db.query_view_records(&JOBS_BY_CREATED, &query).await
+    }
+
+    /// List all jobs that were released from being held at or after a time.
+    pub async fn list_released_since(&self, since: DateTime<Utc>) -> CouchResult<Vec<Record<Job>>> {
+        let query = ViewQuery::prefix_from(vec![], json!(since));
+        self.db.query_view_records(&JOBS_BY_RELEASED, &query).await
+    }
+
+    /// List all jobs that finished or failed at or after a time.
+    pub async fn list_finished_since(&self, since: DateTime<Utc>) -> CouchResult<Vec<Record<Job>>> {
+        let query = ViewQuery::prefix_from(vec![], json!(since));
+        self.db.query_view_records(&JOBS_BY_FINISHED, &query).await
+    }
+
+    /// Update a job.
+    ///
+    /// The update function is applied to the current version of the job. See
+    /// [CouchDB::update_record].
+    pub async fn update<F>(&self, id: &str, mut update: F) -> anyhow::Result<Record<Job>>
+    where
+        F: FnMut(&mut Job) -> anyhow::Result<()> + Send,
+    {
+        self.db
+            .update_record::<Job, _>(&Job::guid(id), |record| update(&mut record.value))
+            .await
+    }
+
+    /// Claim the queued job with the highest priority for a worker.
+    ///
+    /// Jobs of the same priority are claimed oldest first. If `typs` is not empty, only jobs for
+    /// these tasks are claimed. Jobs that wait for a retry are skipped until they are due.
+    /// Returns None if there is no queued job.
+    ///
+    /// The queued jobs are loaded page by page from a view on their status, priority and creation
+    /// time, so that a claim usually only loads the first page of the highest priority.
+    ///
+    /// The job is claimed by updating the job record at the rev that was read. If two workers try
+    /// to claim the same job, only the first update succeeds, the second fails with a conflict
+    /// and moves on to the next job.
+    pub async fn claim(&self, worker: &str, typs: &[String]) -> CouchResult<Option<Record<Job>>> {
+        let now = Utc::now();
+        // Jobs that were saved before they had a priority have the normal priority.
+        let priorities = [
+            json!(TaskPriority::High),
+            json!(TaskPriority::Normal),
+            Value::Null,
+            json!(TaskPriority::Low),
+        ];
+        for priority in priorities.iter() {
+            let prefix = vec![json!(JobStatus::Queued), priority.clone()];
+            let mut skip = 0;
+            loop {
+                let query = ViewQuery::prefix(prefix.clone()).page(skip, Some(CLAIM_PAGE_SIZE));
+                let docs = self.db.query_view(&JOBS_BY_PRIORITY, &query).await?;
+                let len = docs.len();
+                skip += len;
+                let queued = docs
+                    .into_iter()
+                    .filter_map(|doc| {
+                        let rev = doc.rev()?.to_string();
+                        let record = doc.into_typed_record::<Job>().ok()?;
+                        Some((record, rev))
+                    })
+                    .filter(|(record, _rev)| {
+                        record.value.status == JobStatus::Queued
+                            && record.value.is_due(now)
+                            && (typs.is_empty() || typs.contains(&record.value.typ))
+                    });
+                for (mut record, rev) in queued {
+                    record.value.start(Some(worker));
+                    match self.db.put_record_at_rev(record.clone(), &rev).await {
+                        Ok(_) => {
+                            log::debug!("Job {} claimed by worker {}", record.id(), worker);
+                            return Ok(Some(record));
+                        }
+                        Err(err) if err.status_code() == Some(409) => continue,
+                        Err(err) => return Err(err),
+                    }
+                }
+                if len < CLAIM_PAGE_SIZE {
+                    break;
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn job(typ: &str, status: JobStatus, created: DateTime<Utc>) -> Record<Job> {
+        let mut job = Job::from_task(TaskMessage::new(typ, Value::Null, Value::Null));
+        job.status = status;
+        job.created = created;
+        job.into_record()
+    }
+
+    #[tokio::test]
+    async fn list_jobs() {
+        let jobs = JobStore::new(CouchDB::in_memory("meta"));
+        let now = Utc::now();
+        let ago = |minutes| now - Duration::minutes(minutes);
+        jobs.create(job("nlp2", JobStatus::Finished, ago(4)))
+            .await
+            .unwrap();
+        jobs.create(job("transcribe", JobStatus::Queued, ago(3)))
+            .await
+            .unwrap();
+        jobs.create(job("transcribe", JobStatus::Finished, ago(2)))
+            .await
+            .unwrap();
+        jobs.create(job("nlp2", JobStatus::Queued, ago(1)))
+            .await
+            .unwrap();
+
+        let created = |records: Vec<Record<Job>>| -> Vec<DateTime<Utc>> {
+            records.into_iter().map(|r| r.value.created).collect()
+        };
+        let all = jobs.list(None, None, 0, None).await.unwrap();
+        assert_eq!(created(all), vec![ago(1), ago(2), ago(3), ago(4)]);
+        let page = jobs.list(None, None, 1, Some(2)).await.unwrap();
+        assert_eq!(created(page), vec![ago(2), ago(3)]);
+        let queued = jobs.list(Some(JobStatus::Queued), None, 0, None).await;
+        assert_eq!(created(queued.unwrap()), vec![ago(1), ago(3)]);
+        let nlp = jobs.list(None, Some("nlp2"), 0, None).await.unwrap();
+        assert_eq!(created(nlp), vec![ago(1), ago(4)]);
+        let finished_asr = jobs
+            .list(Some(JobStatus::Finished), Some("transcribe"), 0, None)
+            .await
+            .unwrap();
+        assert_eq!(created(finished_asr), vec![ago(2)]);
+
+        let queued = jobs.list_with_status(JobStatus::Queued).await.unwrap();
+        assert_eq!(created(queued), vec![ago(3), ago(1)]);
+        let recent = jobs.list_created_since(ago(2)).await.unwrap();
+        assert_eq!(created(recent), vec![ago(2), ago(1)]);
+    }
+
+    #[tokio::test]
+    async fn list_jobs_for_feeds() {
+        let jobs = JobStore::new(CouchDB::in_memory("meta"));
+        let now = Utc::now();
+        let ago = |minutes| now - Duration::minutes(minutes);
+        let feed_job = |feed: Option<&str>, status, created| {
+            let mut record = job("transcribe", status, created);
+            record.value.feed = feed.map(|feed| feed.to_string());
+            record
+        };
+        let records = vec![
+            feed_job(Some("oas.Feed_a"), JobStatus::Finished, ago(4)),
+            feed_job(Some("oas.Feed_b"), JobStatus::Queued, ago(3)),
+            feed_job(None, JobStatus::Queued, ago(2)),
+            feed_job(Some("oas.Feed_a"), JobStatus::Queued, ago(1)),
+        ];
+        for record in records.into_iter() {
+            jobs.create(record).await.unwrap();
+        }
+
+        let created = |records: Vec<Record<Job>>| -> Vec<DateTime<Utc>> {
+            records.into_iter().map(|r| r.value.created).collect()
+        };
+        let feeds = vec!["oas.Feed_a".to_string(), "oas.Feed_b".to_string()];
+        let all = jobs.list_for_feeds(&feeds, None, None, 0, None).await;
+        assert_eq!(created(all.unwrap()), vec![ago(1), ago(3), ago(4)]);
+        let page = jobs.list_for_feeds(&feeds, None, None, 1, Some(1)).await;
+        assert_eq!(created(page.unwrap()), vec![ago(3)]);
+        let queued = jobs
+            .list_for_feeds(&feeds[..1], Some(JobStatus::Queued), None, 0, None)
+            .await;
+        assert_eq!(created(queued.unwrap()), vec![ago(1)]);
+    }
+
+    #[tokio::test]
+    async fn claim_by_priority_and_age() {
+        let jobs = JobStore::new(CouchDB::in_memory("meta"));
+        let now = Utc::now();
+        let old = job("nlp2", JobStatus::Queued, now - Duration::minutes(2));
+        let new = job("nlp2", JobStatus::Queued, now - Duration::minutes(1));
+        let mut high = job("transcribe", JobStatus::Queued, now);
+        high.value.priority = TaskPriority::High;
+        let mut waiting = job("nlp2", JobStatus::Queued, now - Duration::minutes(3));
+        waiting.value.retry_at = Some(now + Duration::minutes(10));
+        for record in [&old, &new, &high, &waiting].iter() {
+            jobs.create((*record).clone()).await.unwrap();
+        }
+
+        let claimed = jobs.claim("w1", &[]).await.unwrap().unwrap();
+        assert_eq!(claimed.id(), high.id());
+        assert_eq!(claimed.value.status, JobStatus::Running);
+        assert_eq!(claimed.value.worker.as_deref(), Some("w1"));
+        assert_eq!(claimed.value.attempts, 1);
+
+        let typs = vec!["transcribe".to_string()];
+        assert!(jobs.claim("w1", &typs).await.unwrap().is_none());
+        let claimed = jobs.claim("w2", &[]).await.unwrap().unwrap();
+        assert_eq!(claimed.id(), old.id());
+        let claimed = jobs.claim("w2", &[]).await.unwrap().unwrap();
+        assert_eq!(claimed.id(), new.id());
+        // The job that waits for a retry is not due yet.
+        assert!(jobs.claim("w2", &[]).await.unwrap().is_none());
+        let stored = jobs.get(old.id()).await.unwrap().unwrap();
+        assert_eq!(stored.value.status, JobStatus::Running);
+    }
+}
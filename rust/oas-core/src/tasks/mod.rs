@@ -1,5 +1,4 @@
 use celery::broker::RedisBroker;
-use celery::task::Signature;
 use celery::{broker::Broker, prelude::CeleryError, Celery};
 use clap::Clap;
 use oas_common::{
@@ -7,20 +6,50 @@ use oas_common::{
     Record, TypedValue,
 };
 use serde_json::{json, Value};
-use std::fmt;
 use std::sync::Arc;
 
+mod bulk;
+mod celery_queue;
+mod control;
+mod couch_queue;
+mod job;
+pub mod pipeline;
+pub mod queue;
+pub mod quota;
+mod registry;
+mod result;
+mod stats;
+mod supervisor;
 mod taskdefs;
 
-use crate::couch::CouchDB;
+use crate::couch::{CouchDB, CouchManager};
 use crate::State;
 
 // use self::changes::process_changes;
 
 pub mod changes;
 
+pub use bulk::{BulkSelector, BulkTaskRequest, BulkTaskResponse};
+pub use celery_queue::{celery_queue_name, CeleryQueue};
+pub use control::{CancelJobResponse, FeedAccess, StartTaskResponse, TaskControlError};
+pub use couch_queue::{CouchQueue, JobClaim};
+pub use job::{Job, JobLog, JobStatus, JobStore};
+pub use queue::{TaskMessage, TaskQueue};
+pub use registry::{TaskError, TaskRegistry, TaskSchema};
+pub use result::{
+    JobOutput, JobResult, JobResultError, JobStart, NlpOutput, TranscribeOutput,
+};
+pub use stats::{TaskStats, TaskStatsReport, DEFAULT_STATS_HOURS, MAX_STATS_HOURS};
+pub use supervisor::{SupervisorOpts, SupervisorStats, TaskSupervisor};
+pub use taskdefs::{AsrArgs, AsrOpts, AsrTask, DownloadTask, Empty, NlpOpts, NlpTask, Task};
+
 pub const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379/";
 
+/// Name of the Celery task queue backend.
+pub const CELERY_BACKEND: &str = "celery";
+/// Name of the CouchDB task queue backend.
+pub const COUCH_BACKEND: &str = "couch";
+
 pub type CeleryState = Arc<Celery<RedisBroker>>;
 
 #[derive(Debug, Clone)]
@@ -51,94 +80,218 @@ pub struct TaskOpts {
     missing: bool,
 }
 
-#[derive(Clone)]
-pub struct CeleryManager {
-    config: Config,
-    celery: Option<Arc<Celery<RedisBroker>>>,
+/// Task manager.
+///
+/// Creates tasks for records, tracks them as [Job] records and sends them to workers through a
+/// [TaskQueue] backend. Tasks are validated against the [TaskRegistry] before they are saved.
+#[derive(Debug, Clone)]
+pub struct TaskManager {
+    queue: Arc<dyn TaskQueue>,
+    jobs: JobStore,
+    registry: TaskRegistry,
 }
 
-impl fmt::Debug for CeleryManager {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CeleryManager")
+impl TaskManager {
+    /// Create a task manager with a queue backend and a job store.
+    pub fn with_queue(queue: impl TaskQueue + 'static, jobs: JobStore) -> Self {
+        Self {
+            queue: Arc::new(queue),
+            jobs,
+            registry: TaskRegistry::default(),
+        }
     }
-}
 
-impl CeleryManager {
-    pub fn with_config(config: Config) -> Self {
-        Self {
-            config,
-            celery: None,
+    /// Create a task manager for a queue backend by its name.
+    ///
+    /// The backend is either [CELERY_BACKEND] (with the Redis URL) or [COUCH_BACKEND]. Jobs are
+    /// saved in the meta database.
+    pub fn with_backend(
+        backend: &str,
+        redis_url: Option<&str>,
+        db_manager: &CouchManager,
+    ) -> anyhow::Result<Self> {
+        let jobs = JobStore::new(db_manager.meta_db().clone());
+        match backend {
+            CELERY_BACKEND => {
+                let config = Config::from_redis_url_or_default(redis_url);
+                Ok(Self::with_queue(CeleryQueue::with_config(config), jobs))
+            }
+            COUCH_BACKEND => Ok(Self::with_queue(CouchQueue::new(jobs.clone()), jobs)),
+            _ => anyhow::bail!("Unknown task queue backend: {}", backend),
         }
     }
 
-    pub fn with_url<S>(url: Option<S>) -> anyhow::Result<Self>
-    where
-        S: AsRef<str>,
-    {
-        let url = url.map(|s| s.as_ref().to_string());
-        let config = Config::from_redis_url_or_default(url.as_deref());
-        Ok(Self::with_config(config))
+    /// Get the queue backend.
+    pub fn queue(&self) -> &Arc<dyn TaskQueue> {
+        &self.queue
     }
 
-    pub async fn init(&mut self) -> Result<(), CeleryError> {
-        let celery = create_celery_app(&self.config).await?;
-        self.celery = Some(celery);
-        Ok(())
+    /// Get the job store.
+    pub fn jobs(&self) -> &JobStore {
+        &self.jobs
     }
 
-    pub fn celery(&self) -> Result<&Arc<Celery<RedisBroker>>, CeleryError> {
-        self.celery
-            .as_ref()
-            .ok_or_else(|| CeleryError::ForcedShutdown)
+    /// Get the registry of task types.
+    pub fn registry(&self) -> &TaskRegistry {
+        &self.registry
     }
 
-    pub async fn send_task<T: celery::task::Task>(
-        &self,
-        task_sig: Signature<T>,
-    ) -> Result<celery::task::AsyncResult, CeleryError> {
-        let res = self.celery()?.send_task(task_sig).await?;
-        log::debug!(
-            "created task {} with id {}",
-            Signature::<T>::task_name(),
-            res.task_id
-        );
-        Ok(res)
+    pub async fn init(&self) -> anyhow::Result<()> {
+        self.jobs.init().await?;
+        self.queue.init().await
     }
 
-    // pub async fn post_result(&self,
+    /// Create a job for a task and send it to the workers.
+    ///
+    /// Returns the id of the job. Invalid tasks are rejected with a [TaskError] before a job is
+    /// created. If the task cannot be sent, the job is marked as failed. Held tasks are only
+    /// saved, they are sent once they are [released](TaskManager::release_held).
+    pub async fn send_task(&self, task: TaskMessage) -> anyhow::Result<String> {
+        self.registry.validate(&task)?;
+        let record = Job::from_task(task).into_record();
+        let job_id = record.id().to_string();
+        self.jobs.create(record.clone()).await?;
+        if record.value.status == JobStatus::Held {
+            log::debug!("hold job {} for task {}", job_id, record.value.typ);
+        } else {
+            self.dispatch(&record).await?;
+        }
+        Ok(job_id)
+    }
 
-    pub async fn transcribe_media(&self, media: &Record<Media>) -> anyhow::Result<String> {
-        let celery = self.celery()?;
+    /// Send a re-queued job to the workers again.
+    ///
+    /// Does nothing if the workers claim jobs from the queue themselves.
+    pub async fn resend(&self, record: &Record<Job>) -> anyhow::Result<()> {
+        if self.queue.claims_jobs() {
+            return Ok(());
+        }
+        self.dispatch(record).await
+    }
 
-        let args = json!({
-            "media_url": &media.value.content_url,
-            "media_id": media.id()
-        });
-        let opts = Value::Object(Default::default());
-        let res = celery
-            .send_task(taskdefs::transcribe::new(args, opts))
-            .await?;
+    /// Claim the next queued job for a worker.
+    ///
+    /// Returns None if there is no queued job. Fails if the queue backend sends jobs to the
+    /// workers instead, see [TaskQueue::claims_jobs].
+    pub async fn claim(&self, claim: &JobClaim) -> anyhow::Result<Option<Record<Job>>> {
+        if !self.queue.claims_jobs() {
+            anyhow::bail!(
+                "Jobs cannot be claimed from the {} task queue",
+                self.queue.name()
+            );
+        }
+        let job = self.jobs.claim(&claim.worker, &claim.typs).await?;
+        Ok(job)
+    }
+
+    /// Send a saved job to the queue and save the outcome on the job.
+    ///
+    /// Unless workers claim jobs themselves, a job counts as running once it was sent, and as
+    /// started once a worker [reports its start](TaskManager::report_start).
+    async fn dispatch(&self, record: &Record<Job>) -> anyhow::Result<()> {
+        let job_id = record.id();
+        match self.queue.send(record).await {
+            Ok(task_id) => {
+                log::debug!(
+                    "sent job {} for task {} to {} (task id {})",
+                    job_id,
+                    record.value.typ,
+                    self.queue.name(),
+                    task_id
+                );
+                let claims_jobs = self.queue.claims_jobs();
+                if task_id != job_id || !claims_jobs {
+                    self.jobs
+                        .update(job_id, |job| {
+                            if task_id != job_id {
+                                job.task_id = Some(task_id.clone());
+                            }
+                            if !claims_jobs {
+                                job.send();
+                            }
+                            Ok(())
+                        })
+                        .await?;
+                }
+                Ok(())
+            }
+            Err(err) => {
+                let message = format!("Failed to send task: {:#}", err);
+                self.jobs
+                    .update(job_id, |job| {
+                        job.fail(&message);
+                        Ok(())
+                    })
+                    .await?;
+                Err(err)
+            }
+        }
+    }
+
+    pub async fn transcribe_media(&self, media: &Record<Media>) -> anyhow::Result<String> {
+        let task = transcribe_media_task(media)?;
+        let task_id = self.send_task(task).await?;
         log::debug!(
             "Create asr task for media {} (task id {})",
             media.id(),
-            res.task_id
+            task_id
         );
-        Ok(res.task_id)
+        Ok(task_id)
     }
 
     pub async fn nlp_post(&self, post: &Record<Post>) -> anyhow::Result<String> {
-        let celery = self.celery()?;
-
+        let args = serde_json::to_value(post)?;
         let opts = Value::Object(Default::default());
-        let res = celery
-            .send_task(taskdefs::nlp2::new(post.clone(), opts))
-            .await?;
+        let task = TaskMessage::new(queue::NLP, args, opts).with_target(post.guid());
+        let task_id = self.send_task(task).await?;
         log::debug!(
             "Create nlp task for post {} (task id {})",
             post.id(),
-            res.task_id
+            task_id
         );
-        Ok(res.task_id)
+        Ok(task_id)
+    }
+
+    pub async fn download_media(&self, media: &Record<Media>) -> anyhow::Result<String> {
+        let task = download_media_task(media)?;
+        let task_id = self.send_task(task).await?;
+        Ok(task_id)
+    }
+}
+
+/// Create a transcribe task for a media.
+pub fn transcribe_media_task(media: &Record<Media>) -> anyhow::Result<TaskMessage> {
+    let args = serde_json::to_value(AsrArgs {
+        media_url: media.value.content_url.clone(),
+        media_id: media.id().to_string(),
+    })?;
+    let opts = Value::Object(Default::default());
+    Ok(TaskMessage::new(AsrTask::NAME, args, opts).with_target(media.guid()))
+}
+
+/// Create a download task for a media.
+pub fn download_media_task(media: &Record<Media>) -> anyhow::Result<TaskMessage> {
+    let args = serde_json::to_value(media)?;
+    Ok(TaskMessage::new(queue::DOWNLOAD, args, Value::Null).with_target(media.guid()))
+}
+
+/// Create a task of a type for a media.
+pub fn media_task(typ: &str, media: &Record<Media>) -> anyhow::Result<TaskMessage> {
+    match typ {
+        queue::DOWNLOAD => download_media_task(media),
+        queue::TRANSCRIBE => transcribe_media_task(media),
+        _ => anyhow::bail!("Task {} can not run on a media", typ),
+    }
+}
+
+/// Create a task of a type for a post.
+pub fn post_task(typ: &str, post: &Record<Post>) -> anyhow::Result<TaskMessage> {
+    match typ {
+        queue::NLP => {
+            let args = serde_json::to_value(post)?;
+            Ok(TaskMessage::new(queue::NLP, args, Value::Null).with_target(post.guid()))
+        }
+        _ => anyhow::bail!("Task {} can not run on a post", typ),
     }
 }
 
@@ -151,8 +304,10 @@ pub async fn create_celery_app(config: &Config) -> Result<Arc<Celery<RedisBroker
             taskdefs::download2,
             taskdefs::nlp2,
         ],
+        // Default routes for tasks that are sent without a queue, see [celery_queue_name].
         task_routes = [
-            "*" => "celery",
+            "transcribe" => "oas.asr.normal",
+            "*" => "oas.default.normal",
         ],
         prefetch_count = 2,
         heartbeat = Some(10),
@@ -186,16 +341,6 @@ pub async fn load_medias_for_task_opts(
                 .filter_map(|r| r.into_record())
                 .collect()
         }
-        TaskOpts { missing: true, .. } => db
-            .table::<Media>()
-            .get_all()
-            .await?
-            .into_iter()
-            .filter_map(|r| match r.value.transcript {
-                None => Some(r),
-                Some(_) => None,
-            })
-            .collect(),
         TaskOpts { latest: true, .. } => db.table::<Media>().get_all().await?,
         _ => {
             anyhow::bail!("Invalid or ambigous options")
@@ -204,9 +349,12 @@ pub async fn load_medias_for_task_opts(
     Ok(medias)
 }
 
-pub async fn run_celery(mut state: State, opts: TaskOpts) -> anyhow::Result<()> {
+pub async fn run_celery(state: State, opts: TaskOpts) -> anyhow::Result<()> {
     state.tasks.init().await?;
     state.db.init().await?;
+    if opts.missing {
+        return run_bulk_missing(&state).await;
+    }
     let medias = load_medias_for_task_opts(&state.db, &opts).await?;
     if medias.is_empty() {
         anyhow::bail!("No media found")
@@ -231,6 +379,34 @@ pub async fn run_celery(mut state: State, opts: TaskOpts) -> anyhow::Result<()>
     Ok(())
 }
 
+/// Create transcribe tasks for all medias without a transcript, page by page.
+async fn run_bulk_missing(state: &State) -> anyhow::Result<()> {
+    let request = BulkTaskRequest {
+        task: queue::TRANSCRIBE.to_string(),
+        selector: BulkSelector {
+            missing_transcript: true,
+            ..Default::default()
+        },
+        opts: Value::Null,
+        dry_run: false,
+        limit: None,
+    };
+    let index = state.index_manager.post_index();
+    let res = state.tasks.submit_bulk(&state.db, index, request).await?;
+    if res.count == 0 {
+        anyhow::bail!("No media found")
+    }
+    for error in res.errors.iter() {
+        println!("error creating transcribe task for media {}", error);
+    }
+    println!(
+        "created {} transcribe tasks for {} medias",
+        res.jobs.len(),
+        res.count
+    );
+    Ok(())
+}
+
 pub async fn create_transcribe_task<B>(
     app: &Arc<Celery<B>>,
     media: &Record<Media>,
@@ -0,0 +1,190 @@
+//! Task pipelines.
+//!
+//! Tasks can depend on other tasks: a media is transcribed after it was downloaded, and the NLP
+//! of a post works on the transcripts of all its media. The [PIPELINE] declares these
+//! dependencies. A wanted task is only sent once all its dependencies are finished. When a task
+//! finishes, the changes stream brings the updated record back to the task processor, which then
+//! schedules the tasks that waited for it.
+
+use oas_common::task::TaskState;
+use oas_common::types::{Media, MediaTasks, Post, PostTasks};
+use oas_common::Record;
+
+use super::queue;
+
+/// The type of record a task works on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Media,
+    Post,
+}
+
+/// Where the state of a dependency is looked up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// The task on the same record.
+    Same,
+    /// The task on all media of a post.
+    PostMedia,
+}
+
+/// A task that has to finish before another task is started.
+#[derive(Debug, Clone, Copy)]
+pub struct Dependency {
+    pub typ: &'static str,
+    pub scope: Scope,
+}
+
+/// A task in the pipeline.
+#[derive(Debug, Clone, Copy)]
+pub struct Step {
+    pub typ: &'static str,
+    pub target: Target,
+    pub depends_on: &'static [Dependency],
+}
+
+/// All tasks with their dependencies, in the order they run.
+pub const PIPELINE: &[Step] = &[
+    Step {
+        typ: queue::DOWNLOAD,
+        target: Target::Media,
+        depends_on: &[],
+    },
+    Step {
+        typ: queue::TRANSCRIBE,
+        target: Target::Media,
+        depends_on: &[Dependency {
+            typ: queue::DOWNLOAD,
+            scope: Scope::Same,
+        }],
+    },
+    Step {
+        typ: queue::NLP,
+        target: Target::Post,
+        depends_on: &[Dependency {
+            typ: queue::TRANSCRIBE,
+            scope: Scope::PostMedia,
+        }],
+    },
+];
+
+/// Get the steps of the pipeline that work on a type of record.
+pub fn steps(target: Target) -> impl Iterator<Item = &'static Step> {
+    PIPELINE.iter().filter(move |step| step.target == target)
+}
+
+/// Whether the dependencies of a task allow to start it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Readiness {
+    /// All dependencies are finished or not wanted.
+    Ready,
+    /// Some dependencies are wanted or running.
+    Waiting(Vec<String>),
+    /// A dependency failed, so the task can't run.
+    Failed(String),
+}
+
+impl Step {
+    /// Check the dependencies of the task on a media.
+    pub fn check_media(&self, media: &Record<Media>) -> Readiness {
+        let states = self
+            .depends_on
+            .iter()
+            .filter(|dep| dep.scope == Scope::Same)
+            .filter_map(|dep| {
+                let state = media_task(&media.value.tasks, dep.typ)?;
+                Some((format!("{} of {}", dep.typ, media.guid()), state))
+            });
+        readiness(states)
+    }
+
+    /// Check the dependencies of the task on a post.
+    ///
+    /// The media of the post have to be resolved, unresolved media are not waited for.
+    pub fn check_post(&self, post: &Record<Post>) -> Readiness {
+        let states = self.depends_on.iter().flat_map(|dep| {
+            let records: Vec<_> = match dep.scope {
+                Scope::Same => post_task(&post.value.tasks, dep.typ)
+                    .map(|state| (post.guid(), state))
+                    .into_iter()
+                    .collect(),
+                Scope::PostMedia => post
+                    .value
+                    .media
+                    .iter()
+                    .filter_map(|media| media.record())
+                    .filter_map(|media| {
+                        Some((media.guid(), media_task(&media.value.tasks, dep.typ)?))
+                    })
+                    .collect(),
+            };
+            records
+                .into_iter()
+                .map(move |(guid, state)| (format!("{} of {}", dep.typ, guid), state))
+        });
+        readiness(states)
+    }
+}
+
+/// Check if a task on a media finished, and posts of the media have tasks that depend on it.
+pub fn media_has_finished_dependency(media: &Record<Media>) -> bool {
+    steps(Target::Post)
+        .flat_map(|step| step.depends_on.iter())
+        .filter(|dep| dep.scope == Scope::PostMedia)
+        .filter_map(|dep| media_task(&media.value.tasks, dep.typ))
+        .any(|state| matches!(state, TaskState::Finished(_)))
+}
+
+fn readiness<'a>(states: impl Iterator<Item = (String, &'a TaskState)>) -> Readiness {
+    let mut waiting = vec![];
+    for (name, state) in states {
+        match state {
+            TaskState::None => {}
+            TaskState::Finished(state) if state.success => {}
+            TaskState::Finished(state) => {
+                let error = state.error.as_deref().unwrap_or("unknown error");
+                return Readiness::Failed(format!("Dependency {} failed: {}", name, error));
+            }
+            TaskState::Wanted | TaskState::Running(_) => waiting.push(name),
+        }
+    }
+    if waiting.is_empty() {
+        Readiness::Ready
+    } else {
+        Readiness::Waiting(waiting)
+    }
+}
+
+/// Get the state of a task on a media.
+pub fn media_task<'a>(tasks: &'a MediaTasks, typ: &str) -> Option<&'a TaskState> {
+    match typ {
+        queue::DOWNLOAD => Some(&tasks.download),
+        queue::TRANSCRIBE => Some(&tasks.asr),
+        _ => None,
+    }
+}
+
+/// Get the state of a task on a post.
+pub fn post_task<'a>(tasks: &'a PostTasks, typ: &str) -> Option<&'a TaskState> {
+    match typ {
+        queue::NLP => Some(&tasks.nlp),
+        _ => None,
+    }
+}
+
+/// Get the mutable state of a task on a media.
+pub fn media_task_mut<'a>(tasks: &'a mut MediaTasks, typ: &str) -> Option<&'a mut TaskState> {
+    match typ {
+        queue::DOWNLOAD => Some(&mut tasks.download),
+        queue::TRANSCRIBE => Some(&mut tasks.asr),
+        _ => None,
+    }
+}
+
+/// Get the mutable state of a task on a post.
+pub fn post_task_mut<'a>(tasks: &'a mut PostTasks, typ: &str) -> Option<&'a mut TaskState> {
+    match typ {
+        queue::NLP => Some(&mut tasks.nlp),
+        _ => None,
+    }
+}
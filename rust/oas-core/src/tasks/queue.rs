@@ -0,0 +1,120 @@
+//! Task queue backends.
+//!
+//! A [TaskQueue] hands jobs over to workers. The [CeleryQueue](super::CeleryQueue) sends jobs to
+//! Celery workers through Redis. With the [CouchQueue](super::CouchQueue), workers claim the
+//! [Job] records directly from the database.
+
+use oas_common::task::TaskPriority;
+use oas_common::Record;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+use super::Job;
+
+/// Name of the transcribe (ASR) task.
+pub const TRANSCRIBE: &str = "transcribe";
+/// Name of the media download task.
+pub const DOWNLOAD: &str = "download2";
+/// Name of the NLP task.
+pub const NLP: &str = "nlp2";
+
+/// A task to be sent to a worker.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskMessage {
+    /// Name of the task, e.g. [TRANSCRIBE].
+    pub name: String,
+    /// Arguments of the task.
+    pub args: Value,
+    /// Options of the task.
+    pub opts: Value,
+    /// Guid of the record the task works on.
+    pub target: Option<String>,
+    /// Priority of the task.
+    #[serde(default)]
+    pub priority: TaskPriority,
+    /// Guid of the feed the target record belongs to.
+    pub feed: Option<String>,
+    /// Hold the task back until the quota of its feed allows to run it.
+    #[serde(default)]
+    pub hold: bool,
+    /// Name of the user that created the task through the API.
+    pub triggered_by: Option<String>,
+}
+
+impl TaskMessage {
+    pub fn new(name: impl ToString, args: Value, opts: Value) -> Self {
+        Self {
+            name: name.to_string(),
+            args,
+            opts,
+            target: None,
+            priority: TaskPriority::default(),
+            feed: None,
+            hold: false,
+            triggered_by: None,
+        }
+    }
+
+    /// Set the guid of the record the task works on.
+    pub fn with_target(mut self, guid: impl ToString) -> Self {
+        self.target = Some(guid.to_string());
+        self
+    }
+
+    /// Set the priority of the task.
+    pub fn with_priority(mut self, priority: TaskPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Set the guid of the feed the target record belongs to.
+    pub fn with_feed(mut self, guid: impl ToString) -> Self {
+        self.feed = Some(guid.to_string());
+        self
+    }
+
+    /// Hold the task back until the quota of its feed allows to run it.
+    pub fn hold(mut self) -> Self {
+        self.hold = true;
+        self
+    }
+
+    /// Set the name of the user that created the task.
+    pub fn with_triggered_by(mut self, user: impl ToString) -> Self {
+        self.triggered_by = Some(user.to_string());
+        self
+    }
+}
+
+/// A backend that passes tasks on to workers.
+#[async_trait::async_trait]
+pub trait TaskQueue: fmt::Debug + Send + Sync {
+    /// Name of the backend.
+    fn name(&self) -> &str;
+
+    /// Whether workers claim jobs from the queue themselves.
+    ///
+    /// Otherwise, a job counts as running as soon as it was sent, and as started once a worker
+    /// reports its start.
+    fn claims_jobs(&self) -> bool {
+        false
+    }
+
+    /// Connect to the backend.
+    async fn init(&self) -> anyhow::Result<()>;
+
+    /// Send a job to the workers.
+    ///
+    /// The job record is already saved when this is called. Returns the id of the task in the
+    /// backend.
+    async fn send(&self, job: &Record<Job>) -> anyhow::Result<String>;
+
+    /// Remove a cancelled job from the queue, so that no worker starts it.
+    ///
+    /// Returns false if the backend can't revoke jobs. Workers then still run the job, but its
+    /// result is rejected.
+    async fn revoke(&self, _job: &Record<Job>) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
@@ -0,0 +1,377 @@
+//! Task priorities and per-feed quotas.
+//!
+//! Tasks for records of a feed get their priority from the [FeedTaskDefaults] of the feed: new
+//! items get the priority of the feed (high by default), older items (e.g. from crawling a feed
+//! backwards) a low priority. If the feed has a [FeedTaskQuota], its tasks are held back as
+//! [JobStatus::Held] jobs. The [TaskSupervisor](super::TaskSupervisor) periodically releases
+//! held jobs to the queue as far as the quota of their feed allows.
+
+use chrono::{DateTime, Duration, Utc};
+use oas_common::task::TaskPriority;
+use oas_common::types::{Feed, FeedTaskDefaults, FeedTaskQuota, Media, Post};
+use oas_common::{Record, Reference};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use super::{Job, JobStatus, TaskManager, TaskMessage};
+use crate::couch::CouchDB;
+
+/// Items published longer ago than this are not new, so their tasks get a low priority.
+pub const NEW_ITEM_AGE_DAYS: i64 = 3;
+
+/// Get the priority of a task for an item.
+pub fn task_priority(
+    published: Option<DateTime<Utc>>,
+    defaults: Option<&FeedTaskDefaults>,
+    now: DateTime<Utc>,
+) -> TaskPriority {
+    let feed_priority = defaults.and_then(|defaults| defaults.priority);
+    match published {
+        Some(published) if now - published > Duration::days(NEW_ITEM_AGE_DAYS) => TaskPriority::Low,
+        Some(_) => feed_priority.unwrap_or(TaskPriority::High),
+        None => feed_priority.unwrap_or_default(),
+    }
+}
+
+/// The feeds and publication dates of the records in a batch of changes.
+///
+/// Used to set the priority and feed of the tasks for these records.
+#[derive(Debug, Default)]
+pub struct FeedContext {
+    feeds: HashMap<String, Record<Feed>>,
+    published: HashMap<String, DateTime<Utc>>,
+}
+
+impl FeedContext {
+    /// Load the feeds of posts and medias.
+    ///
+    /// Medias get the publication date of their post. The posts of medias are loaded if they are
+    /// not part of `posts`.
+    pub async fn load(
+        db: &CouchDB,
+        posts: &[Record<Post>],
+        medias: &[Record<Media>],
+    ) -> anyhow::Result<Self> {
+        let mut context = Self::default();
+        let missing_posts: Vec<&str> = medias
+            .iter()
+            .flat_map(|media| media.value.posts.iter().map(|post| post.guid()))
+            .filter(|guid| !posts.iter().any(|post| post.guid() == *guid))
+            .collect();
+        let other_posts = if missing_posts.is_empty() {
+            vec![]
+        } else {
+            db.get_many_records::<Post>(&missing_posts).await?
+        };
+        for post in posts.iter().chain(other_posts.iter()) {
+            if let Some(published) = post.value.date_published {
+                context.published.insert(post.guid().to_string(), published);
+                for media in post.value.media.iter() {
+                    context
+                        .published
+                        .insert(media.guid().to_string(), published);
+                }
+            }
+        }
+
+        let mut feed_guids: Vec<&str> = posts
+            .iter()
+            .flat_map(|post| post.value.feeds.iter())
+            .chain(medias.iter().flat_map(|media| media.value.feeds.iter()))
+            .map(|feed| feed.guid())
+            .collect();
+        feed_guids.sort_unstable();
+        feed_guids.dedup();
+        if !feed_guids.is_empty() {
+            let feeds = db.get_many_records::<Feed>(&feed_guids).await?;
+            for feed in feeds.into_iter() {
+                context.feeds.insert(feed.guid().to_string(), feed);
+            }
+        }
+        Ok(context)
+    }
+
+    /// Set the priority and feed of a task for a record.
+    ///
+    /// The task is held back if the feed has a quota.
+    pub fn apply(&self, task: TaskMessage, guid: &str, feeds: &[Reference<Feed>]) -> TaskMessage {
+        let feed = feeds.first().and_then(|feed| self.feeds.get(feed.guid()));
+        let defaults = feed.and_then(|feed| feed.value.task_defaults.as_ref());
+        let published = self.published.get(guid).copied();
+        let task = task.with_priority(task_priority(published, defaults, Utc::now()));
+        match feed {
+            Some(feed) => {
+                let task = task.with_feed(feed.guid());
+                match defaults.and_then(|defaults| defaults.quota) {
+                    Some(_) => task.hold(),
+                    None => task,
+                }
+            }
+            None => task,
+        }
+    }
+}
+
+/// Number of jobs of a feed that count against its quota.
+#[derive(Debug, Default, Clone, Copy)]
+struct FeedUsage {
+    active: u32,
+    today: u32,
+}
+
+impl FeedUsage {
+    /// Get the number of jobs that may be released within a quota.
+    fn available(&self, quota: &FeedTaskQuota) -> usize {
+        let running = quota
+            .max_running
+            .map_or(u32::MAX, |max| max.saturating_sub(self.active));
+        let today = quota
+            .max_per_day
+            .map_or(u32::MAX, |max| max.saturating_sub(self.today));
+        running.min(today) as usize
+    }
+}
+
+impl TaskManager {
+    /// Release held jobs to the queue, as far as the quotas of their feeds allow.
+    ///
+    /// Jobs with a higher priority are released first, then the oldest jobs. `db` is the database
+    /// of the feed records. Returns the number of released jobs.
+    pub async fn release_held(&self, db: &CouchDB) -> anyhow::Result<usize> {
+        let mut held: HashMap<String, Vec<Record<Job>>> = HashMap::new();
+        for job in self.jobs().list_with_status(JobStatus::Held).await? {
+            if let Some(feed) = job.value.feed.clone() {
+                held.entry(feed).or_default().push(job);
+            }
+        }
+        if held.is_empty() {
+            return Ok(0);
+        }
+
+        let now = Utc::now();
+        let day_ago = now - Duration::days(1);
+        let mut usage: HashMap<String, FeedUsage> = HashMap::new();
+        let mut active = self.jobs().list_with_status(JobStatus::Queued).await?;
+        active.extend(self.jobs().list_with_status(JobStatus::Running).await?);
+        for job in active.iter() {
+            if let Some(feed) = &job.value.feed {
+                usage.entry(feed.clone()).or_default().active += 1;
+            }
+        }
+        // Jobs that were queued in the last day were either created or released since then.
+        let mut recent = self.jobs().list_created_since(day_ago).await?;
+        recent.extend(self.jobs().list_released_since(day_ago).await?);
+        let mut counted = HashSet::new();
+        for job in recent.iter() {
+            let feed = match &job.value.feed {
+                Some(feed) => feed,
+                None => continue,
+            };
+            if job.value.status == JobStatus::Held
+                || job.value.queued_at() <= day_ago
+                || !counted.insert(job.id())
+            {
+                continue;
+            }
+            usage.entry(feed.clone()).or_default().today += 1;
+        }
+
+        let feed_guids: Vec<&str> = held.keys().map(|guid| guid.as_str()).collect();
+        let feeds: HashMap<String, Record<Feed>> = db
+            .get_many_records::<Feed>(&feed_guids)
+            .await?
+            .into_iter()
+            .map(|feed| (feed.guid().to_string(), feed))
+            .collect();
+
+        let mut released = 0;
+        for (feed, mut jobs) in held.into_iter() {
+            let quota = feeds
+                .get(&feed)
+                .and_then(|feed| feed.value.task_defaults.as_ref())
+                .and_then(|defaults| defaults.quota);
+            let available = match quota {
+                Some(quota) => usage
+                    .get(&feed)
+                    .copied()
+                    .unwrap_or_default()
+                    .available(&quota),
+                None => jobs.len(),
+            };
+            if available == 0 {
+                continue;
+            }
+            jobs.sort_by_key(|job| (Reverse(job.value.priority), job.value.created));
+            for job in jobs.into_iter().take(available) {
+                let record = self
+                    .jobs()
+                    .update(job.id(), |job| {
+                        if job.status != JobStatus::Held {
+                            anyhow::bail!("Job is not held anymore");
+                        }
+                        job.release();
+                        Ok(())
+                    })
+                    .await;
+                match record {
+                    Ok(record) => {
+                        released += 1;
+                        if let Err(err) = self.resend(&record).await {
+                            log::warn!("Failed to send released job {}: {:#}", record.id(), err);
+                        }
+                    }
+                    Err(err) => log::warn!("Failed to release job {}: {:#}", job.id(), err),
+                }
+            }
+        }
+        log::debug!("released {} held jobs", released);
+        Ok(released)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tasks::{CouchQueue, JobStore};
+    use serde_json::Value;
+
+    fn defaults(priority: Option<TaskPriority>, quota: Option<FeedTaskQuota>) -> FeedTaskDefaults {
+        FeedTaskDefaults {
+            priority,
+            quota,
+            ..Default::default()
+        }
+    }
+
+    fn job(feed: &str, status: JobStatus, priority: TaskPriority, age: i64) -> Record<Job> {
+        let task = TaskMessage::new("transcribe", Value::Null, Value::Null)
+            .with_feed(feed)
+            .with_priority(priority);
+        let mut job = Job::from_task(task);
+        job.status = status;
+        job.created = Utc::now() - Duration::minutes(age);
+        job.into_record()
+    }
+
+    #[test]
+    fn priority_of_items() {
+        let now = Utc::now();
+        let new = Some(now - Duration::hours(1));
+        let old = Some(now - Duration::days(NEW_ITEM_AGE_DAYS + 1));
+        let normal = defaults(Some(TaskPriority::Normal), None);
+
+        assert_eq!(task_priority(new, None, now), TaskPriority::High);
+        assert_eq!(task_priority(new, Some(&normal), now), TaskPriority::Normal);
+        assert_eq!(task_priority(old, None, now), TaskPriority::Low);
+        assert_eq!(task_priority(old, Some(&normal), now), TaskPriority::Low);
+        assert_eq!(task_priority(None, None, now), TaskPriority::Normal);
+        let high = defaults(Some(TaskPriority::High), None);
+        assert_eq!(task_priority(None, Some(&high), now), TaskPriority::High);
+    }
+
+    #[test]
+    fn available_within_quota() {
+        let usage = FeedUsage {
+            active: 2,
+            today: 5,
+        };
+        let quota = |max_running, max_per_day| FeedTaskQuota {
+            max_running,
+            max_per_day,
+        };
+        assert_eq!(usage.available(&quota(None, None)), u32::MAX as usize);
+        assert_eq!(usage.available(&quota(Some(3), None)), 1);
+        assert_eq!(usage.available(&quota(Some(1), None)), 0);
+        assert_eq!(usage.available(&quota(None, Some(8))), 3);
+        assert_eq!(usage.available(&quota(None, Some(5))), 0);
+        assert_eq!(usage.available(&quota(Some(4), Some(6))), 1);
+    }
+
+    #[tokio::test]
+    async fn release_held_within_quota() {
+        let db = CouchDB::in_memory("records");
+        let jobs = JobStore::new(CouchDB::in_memory("meta"));
+        let tasks = TaskManager::with_queue(CouchQueue::new(jobs.clone()), jobs.clone());
+        let feed = |id: &str, quota| {
+            let feed = Feed {
+                task_defaults: Some(defaults(None, quota)),
+                ..Default::default()
+            };
+            Record::from_id_and_value(id, feed)
+        };
+        let running = feed(
+            "running",
+            Some(FeedTaskQuota {
+                max_running: Some(2),
+                max_per_day: None,
+            }),
+        );
+        let daily = feed(
+            "daily",
+            Some(FeedTaskQuota {
+                max_running: None,
+                max_per_day: Some(2),
+            }),
+        );
+        let open = feed("open", None);
+        for feed in [&running, &daily, &open].iter() {
+            db.put_record((*feed).clone()).await.unwrap();
+        }
+
+        let low = job(running.guid(), JobStatus::Held, TaskPriority::Low, 30);
+        let old = job(running.guid(), JobStatus::Held, TaskPriority::Normal, 20);
+        let new = job(running.guid(), JobStatus::Held, TaskPriority::Normal, 10);
+        let high = job(running.guid(), JobStatus::Held, TaskPriority::High, 0);
+        let active = job(running.guid(), JobStatus::Running, TaskPriority::Normal, 40);
+        let done = job(daily.guid(), JobStatus::Finished, TaskPriority::Normal, 60);
+        let daily_held = [
+            job(daily.guid(), JobStatus::Held, TaskPriority::Normal, 20),
+            job(daily.guid(), JobStatus::Held, TaskPriority::Normal, 10),
+        ];
+        let open_held = [
+            job(open.guid(), JobStatus::Held, TaskPriority::Low, 20),
+            job(open.guid(), JobStatus::Held, TaskPriority::Low, 10),
+        ];
+        let all = [&low, &old, &new, &high, &active, &done]
+            .iter()
+            .map(|job| (*job).clone())
+            .chain(daily_held.iter().cloned())
+            .chain(open_held.iter().cloned());
+        for job in all {
+            jobs.create(job).await.unwrap();
+        }
+        let status = |job: &Record<Job>| {
+            let jobs = jobs.clone();
+            let id = job.id().to_string();
+            async move { jobs.get(&id).await.unwrap().unwrap().value.status }
+        };
+
+        // One job of the first feed is running, so one more may run: the one with the highest
+        // priority. One job of the second feed was queued today, so one more may be queued: the
+        // oldest one. The jobs of the feed without a quota are all released.
+        assert_eq!(tasks.release_held(&db).await.unwrap(), 4);
+        assert_eq!(status(&high).await, JobStatus::Queued);
+        for job in [&low, &old, &new].iter() {
+            assert_eq!(status(*job).await, JobStatus::Held);
+        }
+        assert_eq!(status(&daily_held[0]).await, JobStatus::Queued);
+        assert_eq!(status(&daily_held[1]).await, JobStatus::Held);
+        for job in open_held.iter() {
+            assert_eq!(status(job).await, JobStatus::Queued);
+        }
+
+        // Both quotas are used up now.
+        assert_eq!(tasks.release_held(&db).await.unwrap(), 0);
+
+        // Once the running job finished, the oldest job of the same priority is released next.
+        jobs.update(active.id(), |job| {
+            job.finish(None);
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(tasks.release_held(&db).await.unwrap(), 1);
+        assert_eq!(status(&old).await, JobStatus::Queued);
+        assert_eq!(status(&new).await, JobStatus::Held);
+    }
+}
@@ -0,0 +1,208 @@
+//! Registry of the task types.
+//!
+//! The [TaskRegistry] knows the [Task] types by their names. It validates the arguments and
+//! options of tasks before they are saved as jobs, provides the JSON schemas of the options and
+//! outputs, and sends jobs to Celery with typed arguments.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use thiserror::Error;
+
+use super::taskdefs::{AsrTask, DownloadTask, NlpTask, Task};
+use super::{CeleryQueue, TaskMessage};
+
+/// Error when validating a task.
+#[derive(Error, Debug)]
+pub enum TaskError {
+    #[error("Unknown task: {0}")]
+    Unknown(String),
+    #[error("Invalid {part} for task {task}: {message}")]
+    Invalid {
+        task: String,
+        part: &'static str,
+        message: String,
+    },
+}
+
+impl TaskError {
+    fn invalid(task: &str, part: &'static str, message: impl ToString) -> Self {
+        Self::Invalid {
+            task: task.to_string(),
+            part,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// JSON schemas of a task type.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct TaskSchema {
+    /// Name of the task.
+    pub name: String,
+    /// Schema of the task options.
+    pub opts: Value,
+    /// Schema of the task output.
+    pub output: Value,
+}
+
+/// A [Task] type without its types.
+#[async_trait::async_trait]
+trait TaskType: Send + Sync {
+    fn schema(&self) -> TaskSchema;
+    fn validate(&self, args: &Value, opts: &Value) -> Result<(), TaskError>;
+    async fn send_celery(
+        &self,
+        celery: &CeleryQueue,
+        args: Value,
+        opts: Value,
+        queue: &str,
+    ) -> anyhow::Result<String>;
+}
+
+struct Typed<T>(PhantomData<fn() -> T>);
+
+#[async_trait::async_trait]
+impl<T: Task> TaskType for Typed<T> {
+    fn schema(&self) -> TaskSchema {
+        TaskSchema {
+            name: T::NAME.to_string(),
+            opts: serde_json::to_value(schemars::schema_for!(T::Opts)).unwrap_or_default(),
+            output: serde_json::to_value(schemars::schema_for!(T::Output)).unwrap_or_default(),
+        }
+    }
+
+    fn validate(&self, args: &Value, opts: &Value) -> Result<(), TaskError> {
+        decode::<T::Args>(T::NAME, "arguments", args.clone())?;
+        decode_or_empty::<T::Opts>(T::NAME, "options", opts.clone())?;
+        Ok(())
+    }
+
+    async fn send_celery(
+        &self,
+        celery: &CeleryQueue,
+        args: Value,
+        opts: Value,
+        queue: &str,
+    ) -> anyhow::Result<String> {
+        let args = decode::<T::Args>(T::NAME, "arguments", args)?;
+        T::send_celery(celery, args, opts, queue).await
+    }
+}
+
+/// The registered task types.
+#[derive(Clone)]
+pub struct TaskRegistry {
+    tasks: HashMap<&'static str, Arc<dyn TaskType>>,
+}
+
+impl fmt::Debug for TaskRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskRegistry")
+            .field("tasks", &self.names())
+            .finish()
+    }
+}
+
+impl Default for TaskRegistry {
+    /// Create a registry with all tasks of the Python worker.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register::<DownloadTask>();
+        registry.register::<AsrTask>();
+        registry.register::<NlpTask>();
+        registry
+    }
+}
+
+impl TaskRegistry {
+    /// Create a registry without any task types.
+    pub fn empty() -> Self {
+        Self {
+            tasks: HashMap::new(),
+        }
+    }
+
+    /// Register a task type.
+    pub fn register<T: Task>(&mut self) {
+        self.tasks
+            .insert(T::NAME, Arc::new(Typed::<T>(PhantomData)));
+    }
+
+    /// Get the names of all task types, sorted.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.tasks.keys().copied().collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Check if a task type is registered.
+    pub fn contains(&self, name: &str) -> bool {
+        self.tasks.contains_key(name)
+    }
+
+    /// Get the schemas of all task types, sorted by name.
+    pub fn schemas(&self) -> Vec<TaskSchema> {
+        self.names()
+            .into_iter()
+            .map(|name| self.tasks[name].schema())
+            .collect()
+    }
+
+    /// Validate the arguments and options of a task.
+    ///
+    /// Empty options (null) are valid for all tasks.
+    pub fn validate(&self, task: &TaskMessage) -> Result<(), TaskError> {
+        self.get(&task.name)?.validate(&task.args, &task.opts)
+    }
+
+    /// Send a task to a Celery queue with typed arguments.
+    pub(super) async fn send_celery(
+        &self,
+        celery: &CeleryQueue,
+        name: &str,
+        args: Value,
+        opts: Value,
+        queue: &str,
+    ) -> anyhow::Result<String> {
+        let task = self.get(name)?;
+        task.send_celery(celery, args, opts, queue).await
+    }
+
+    fn get(&self, name: &str) -> Result<&Arc<dyn TaskType>, TaskError> {
+        self.tasks
+            .get(name)
+            .ok_or_else(|| TaskError::Unknown(name.to_string()))
+    }
+}
+
+/// Decode and validate the output of a task.
+pub fn decode_output<T: Task>(output: Value) -> Result<T::Output, TaskError> {
+    let output = decode_or_empty::<T::Output>(T::NAME, "output", output)?;
+    T::validate_output(&output).map_err(|err| TaskError::invalid(T::NAME, "output", err))?;
+    Ok(output)
+}
+
+fn decode<T: serde::de::DeserializeOwned>(
+    task: &str,
+    part: &'static str,
+    value: Value,
+) -> Result<T, TaskError> {
+    serde_json::from_value(value).map_err(|err| TaskError::invalid(task, part, err))
+}
+
+/// Decode a value, where null is decoded like an empty object.
+fn decode_or_empty<T: serde::de::DeserializeOwned>(
+    task: &str,
+    part: &'static str,
+    value: Value,
+) -> Result<T, TaskError> {
+    match value {
+        Value::Null => decode(task, part, Value::Object(Default::default())),
+        value => decode(task, part, value),
+    }
+}
@@ -0,0 +1,450 @@
+//! Results reported by workers.
+//!
+//! When a worker is done with a job, it submits a [JobResult]. The output is decoded into the
+//! typed output of the job's task and validated. Then the job is marked as finished (or failed),
+//! and the target record is updated with the output and the finished task state. The change of
+//! the target record is saved in the audit log, with [WORKER_USERNAME] as the user.
+
+use chrono::Utc;
+use oas_common::task::{TaskFinishedState, TaskState};
+use oas_common::types::{Media, Post, Transcript};
+use oas_common::{Record, TypedValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use super::registry::{decode_output, TaskError};
+use super::taskdefs::{AsrTask, DownloadTask, NlpTask, Task};
+use super::{pipeline, queue};
+use super::{Job, JobStatus, TaskManager};
+use crate::couch::{AuditEntry, AuditLog, CouchDB, CouchError};
+
+/// Username for audit entries of changes by workers.
+pub const WORKER_USERNAME: &str = "worker";
+
+/// The result of a job as submitted by a worker.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct JobResult {
+    /// Id of the worker that ran the job.
+    pub worker: Option<String>,
+    /// Output of the job. The type depends on the task (see [JobOutput]).
+    #[serde(default)]
+    pub output: Value,
+    /// Error message, if the job failed.
+    pub error: Option<String>,
+    /// Time the job took, in seconds.
+    pub took: Option<f32>,
+}
+
+/// The start of a job as reported by a worker, see [TaskManager::report_start].
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStart {
+    /// Id of the worker that started the job.
+    pub worker: Option<String>,
+}
+
+/// Output of the transcribe task.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TranscribeOutput {
+    /// The transcript of the media.
+    pub transcript: Transcript,
+    /// NLP results for the transcript.
+    pub nlp: Option<NlpOutput>,
+}
+
+/// Output of the NLP task.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct NlpOutput(pub serde_json::Map<String, Value>);
+
+/// The typed output of a job.
+#[derive(Debug, Clone)]
+pub enum JobOutput {
+    Transcribe(TranscribeOutput),
+    Nlp(NlpOutput),
+    Download,
+}
+
+impl JobOutput {
+    /// Decode and validate the output of a task.
+    pub fn decode(typ: &str, output: Value) -> Result<Self, JobResultError> {
+        let output = match typ {
+            AsrTask::NAME => Self::Transcribe(decode_output::<AsrTask>(output)?),
+            NlpTask::NAME => Self::Nlp(decode_output::<NlpTask>(output)?),
+            DownloadTask::NAME => {
+                decode_output::<DownloadTask>(output)?;
+                Self::Download
+            }
+            _ => {
+                return Err(JobResultError::Invalid(format!(
+                    "Results for task {} are not supported",
+                    typ
+                )))
+            }
+        };
+        Ok(output)
+    }
+
+    /// Get a short summary of the output to save on the job.
+    fn summary(&self) -> Value {
+        match self {
+            Self::Transcribe(output) => json!({
+                "words": output.transcript.parts.len(),
+                "nlp": output.nlp.is_some(),
+            }),
+            Self::Nlp(output) => json!({
+                "keys": output.0.keys().collect::<Vec<_>>(),
+            }),
+            Self::Download => Value::Null,
+        }
+    }
+}
+
+/// Error when submitting a job result.
+#[derive(Error, Debug)]
+pub enum JobResultError {
+    #[error("Job {0} not found")]
+    NotFound(String),
+    #[error("Job {0} is already {1:?}")]
+    Conflict(String, JobStatus),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0}")]
+    Couch(#[from] CouchError),
+    #[error("{0:#}")]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<TaskError> for JobResultError {
+    fn from(err: TaskError) -> Self {
+        Self::Invalid(err.to_string())
+    }
+}
+
+impl TaskManager {
+    /// Save the result of a job.
+    ///
+    /// The output is validated, then the job is marked as finished (or failed, if the result
+    /// contains an error). The job's status is checked again in this write, so only the first
+    /// result of a job is accepted. Finally, the target record is updated with the output and
+    /// the finished task state. If the target already has the result of the job, it is left as
+    /// it is. If the target cannot be updated, the job is marked as failed. Changes of the target
+    /// are added to `audit`.
+    pub async fn submit_result(
+        &self,
+        db: &CouchDB,
+        audit: &AuditLog,
+        id: &str,
+        result: JobResult,
+    ) -> Result<Record<Job>, JobResultError> {
+        let job = self
+            .jobs()
+            .get(id)
+            .await?
+            .ok_or_else(|| JobResultError::NotFound(id.to_string()))?;
+        check_open(&job)?;
+        if let Some(target) = &job.value.target {
+            check_target(&job.value.typ, target)?;
+        }
+
+        let output = match &result.error {
+            None => Some(JobOutput::decode(&job.value.typ, result.output.clone())?),
+            Some(_) => None,
+        };
+
+        let mut conflict = None;
+        let record = self
+            .jobs()
+            .update(id, |job| {
+                if !job.is_open() {
+                    conflict = Some(job.status);
+                    anyhow::bail!("Job {} is already {:?}", id, job.status);
+                }
+                if let JobStatus::Held | JobStatus::Queued = job.status {
+                    job.start(result.worker.as_deref());
+                } else if job.worker.is_none() {
+                    job.worker = result.worker.clone();
+                }
+                match (&output, &result.error) {
+                    (_, Some(error)) => job.fail(error),
+                    (Some(output), None) => {
+                        let mut summary = output.summary();
+                        if let (Value::Object(summary), Some(took)) = (&mut summary, result.took) {
+                            summary.insert("took".to_string(), json!(took));
+                        }
+                        job.finish(Some(summary))
+                    }
+                    (None, None) => job.finish(None),
+                }
+                Ok(())
+            })
+            .await;
+        let mut record = match (record, conflict) {
+            (_, Some(status)) => return Err(JobResultError::Conflict(id.to_string(), status)),
+            (record, None) => record?,
+        };
+
+        if let Some(target) = record.value.target.clone() {
+            let state = TaskFinishedState {
+                task_id: record.id().to_string(),
+                success: result.error.is_none(),
+                error: result.error.clone(),
+                start: record
+                    .value
+                    .started
+                    .or(record.value.sent)
+                    .or(Some(record.value.created)),
+                end: record.value.finished.or_else(|| Some(Utc::now())),
+                took: result.took.unwrap_or_default(),
+                history: vec![],
+            };
+            let endpoint = format!("POST /job/{}/result", id);
+            let typ = record.value.typ.clone();
+            match update_target(db, &typ, &target, &endpoint, output, state).await {
+                Ok(Some(entry)) => {
+                    if let Err(err) = audit.add(entry).await {
+                        log::error!("Failed to write audit entry for {}: {}", target, err);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    let message = format!("Failed to save the result on {}: {}", target, err);
+                    record = self
+                        .jobs()
+                        .update(id, |job| {
+                            job.fail(&message);
+                            Ok(())
+                        })
+                        .await?;
+                    log::warn!("Job {} ({}) {}", record.id(), record.value.typ, message);
+                    return Err(err);
+                }
+            }
+        }
+        log::debug!(
+            "Job {} ({}) {:?}",
+            record.id(),
+            record.value.typ,
+            record.value.status
+        );
+        Ok(record)
+    }
+}
+
+impl TaskManager {
+    /// Record that a worker started a job that was sent to it.
+    ///
+    /// Workers that claim jobs are started by the claim. Jobs that were sent to the workers only
+    /// count as started once a worker reports it, so that the time a job waits in the queue of
+    /// the backend does not count against the task timeout.
+    pub async fn report_start(
+        &self,
+        id: &str,
+        start: JobStart,
+    ) -> Result<Record<Job>, JobResultError> {
+        self.jobs()
+            .get(id)
+            .await?
+            .ok_or_else(|| JobResultError::NotFound(id.to_string()))?;
+        let mut conflict = None;
+        let record = self
+            .jobs()
+            .update(id, |job| {
+                if job.status != JobStatus::Running {
+                    conflict = Some(job.status);
+                    anyhow::bail!("Job {} is {:?}", id, job.status);
+                }
+                job.report_start(start.worker.as_deref());
+                Ok(())
+            })
+            .await;
+        match (record, conflict) {
+            (_, Some(status)) => Err(JobResultError::Conflict(id.to_string(), status)),
+            (record, None) => Ok(record?),
+        }
+    }
+}
+
+fn check_open(job: &Record<Job>) -> Result<(), JobResultError> {
+    if job.value.is_open() {
+        Ok(())
+    } else {
+        Err(JobResultError::Conflict(
+            job.id().to_string(),
+            job.value.status,
+        ))
+    }
+}
+
+/// Check that the target of a job is a record of the type the job's task works on.
+fn check_target(typ: &str, guid: &str) -> Result<(), JobResultError> {
+    match typ {
+        queue::TRANSCRIBE | queue::DOWNLOAD => check_target_typ::<Media>(guid),
+        queue::NLP => check_target_typ::<Post>(guid),
+        _ => Ok(()),
+    }
+}
+
+/// Save the output and the task state on the target record of a job.
+///
+/// This is idempotent per job: if the task state on the target is already the finished state of
+/// the job, the target is not changed. Returns an audit entry for the change, or None if
+/// nothing was changed.
+async fn update_target(
+    db: &CouchDB,
+    typ: &str,
+    guid: &str,
+    endpoint: &str,
+    output: Option<JobOutput>,
+    state: TaskFinishedState,
+) -> Result<Option<AuditEntry>, JobResultError> {
+    let entry = match typ {
+        queue::TRANSCRIBE | queue::DOWNLOAD => {
+            let update = db.update_record_tracked::<Media, _>(guid, |record| {
+                let media = &mut record.value;
+                let task_state = pipeline::media_task_mut(&mut media.tasks, typ);
+                if task_state
+                    .as_deref()
+                    .map_or(false, |current| is_saved(current, &state))
+                {
+                    return Ok(());
+                }
+                if let Some(task_state) = task_state {
+                    *task_state = finished_state(&state, task_state);
+                }
+                if let Some(JobOutput::Transcribe(output)) = &output {
+                    media.transcript = Some(output.transcript.clone());
+                    if let Some(nlp) = &output.nlp {
+                        media.nlp = Some(Value::Object(nlp.0.clone()));
+                    }
+                }
+                Ok(())
+            });
+            AuditEntry::from_update(WORKER_USERNAME, None, endpoint, update.await?)
+                .map_err(anyhow::Error::from)?
+        }
+        queue::NLP => {
+            let update = db.update_record_tracked::<Post, _>(guid, |record| {
+                let post = &mut record.value;
+                if is_saved(&post.tasks.nlp, &state) {
+                    return Ok(());
+                }
+                if let Some(JobOutput::Nlp(output)) = &output {
+                    post.nlp = Some(Value::Object(output.0.clone()));
+                }
+                post.tasks.nlp = finished_state(&state, &post.tasks.nlp);
+                Ok(())
+            });
+            AuditEntry::from_update(WORKER_USERNAME, None, endpoint, update.await?)
+                .map_err(anyhow::Error::from)?
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(entry).filter(|entry| !entry.is_empty()))
+}
+
+/// Check if a task state is already the finished state of a job.
+fn is_saved(current: &TaskState, state: &TaskFinishedState) -> bool {
+    matches!(current, TaskState::Finished(current) if current.task_id == state.task_id)
+}
+
+/// Update the state of a job's task on the job's target record.
+pub(super) async fn update_task_state<F>(
+    db: &CouchDB,
+    typ: &str,
+    guid: &str,
+    mut update: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(&mut TaskState) + Send,
+{
+    match typ {
+        queue::TRANSCRIBE | queue::DOWNLOAD => {
+            db.update_record::<Media, _>(guid, |record| {
+                if let Some(state) = pipeline::media_task_mut(&mut record.value.tasks, typ) {
+                    update(state);
+                }
+                Ok(())
+            })
+            .await?;
+        }
+        queue::NLP => {
+            db.update_record::<Post, _>(guid, |record| {
+                update(&mut record.value.tasks.nlp);
+                Ok(())
+            })
+            .await?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Create a finished task state that keeps the history of the current state.
+fn finished_state(state: &TaskFinishedState, current: &TaskState) -> TaskState {
+    TaskState::Finished(TaskFinishedState {
+        history: current.history().to_vec(),
+        ..state.clone()
+    })
+}
+
+fn check_target_typ<T: TypedValue>(guid: &str) -> Result<(), JobResultError> {
+    if guid.starts_with(&format!("{}_", T::NAME)) {
+        Ok(())
+    } else {
+        Err(JobResultError::Invalid(format!(
+            "Job target {} is not a {}",
+            guid,
+            T::NAME
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::couch::AuditFilter;
+    use crate::tasks::{CouchQueue, JobStore, TaskMessage};
+
+    #[tokio::test]
+    async fn audit_result() {
+        let db = CouchDB::in_memory("records");
+        let meta = CouchDB::in_memory("meta");
+        let audit = AuditLog::new(meta.clone());
+        let jobs = JobStore::new(meta);
+        let tasks = TaskManager::with_queue(CouchQueue::new(jobs.clone()), jobs.clone());
+
+        let media = Record::from_id_and_value("m1", Media::default());
+        let mut job = Job::from_task(TaskMessage::new(queue::TRANSCRIBE, json!({}), json!({})));
+        job.target = Some(media.guid().to_string());
+        let job = job.into_record();
+        db.put_record(media.clone()).await.unwrap();
+        jobs.create(job.clone()).await.unwrap();
+
+        let result = JobResult {
+            worker: Some("w1".to_string()),
+            output: Value::Null,
+            error: Some("No audio".to_string()),
+            took: None,
+        };
+        let record = tasks
+            .submit_result(&db, &audit, job.id(), result)
+            .await
+            .unwrap();
+        assert_eq!(record.value.status, JobStatus::Failed);
+
+        let filter = AuditFilter {
+            record_id: Some(media.guid().to_string()),
+            ..Default::default()
+        };
+        let entries = audit.list(&filter, 0, None).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0].value;
+        assert_eq!(entry.username, WORKER_USERNAME);
+        assert_eq!(entry.endpoint, format!("POST /job/{}/result", job.id()));
+        assert!(!entry.is_empty());
+    }
+}
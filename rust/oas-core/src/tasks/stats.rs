@@ -0,0 +1,253 @@
+//! Task cost and throughput statistics.
+//!
+//! The statistics are aggregated from the jobs that finished or failed within a time window.
+//! For each task type, and broken down by feed and by ASR engine, they give the failure rate,
+//! the throughput, the time the jobs waited in the queue, and the real-time factor: the time a
+//! job took divided by the duration of its media.
+
+use chrono::{DateTime, Duration, Utc};
+use oas_common::types::Media;
+use oas_common::Record;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use super::pipeline::{self, Target};
+use super::queue;
+use super::{Job, JobStatus, TaskManager};
+use crate::couch::CouchDB;
+
+/// Default time window of the statistics, in hours.
+pub const DEFAULT_STATS_HOURS: u32 = 24;
+
+/// Max time window of the statistics, in hours (about a year).
+pub const MAX_STATS_HOURS: u32 = 24 * 366;
+
+/// Number of media records that are loaded at once.
+const MEDIA_BATCH_SIZE: usize = 100;
+
+/// Statistics of the jobs of a task type.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskStats {
+    /// Name of the task.
+    pub typ: String,
+    /// Guid of the feed, if the statistics are broken down by feed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feed: Option<String>,
+    /// ASR engine, if the statistics are broken down by engine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub engine: Option<String>,
+    /// Number of finished jobs.
+    pub finished: u32,
+    /// Number of failed jobs.
+    pub failed: u32,
+    /// Share of failed jobs in all finished and failed jobs.
+    pub failure_rate: f64,
+    /// Finished jobs per hour.
+    pub throughput_per_hour: f64,
+    /// Mean time a job took, in seconds.
+    pub mean_took: Option<f64>,
+    /// Mean time a job waited in the queue before it was started, in seconds. Only jobs whose
+    /// start was claimed or reported by a worker are counted.
+    pub mean_queue_wait: Option<f64>,
+    /// Total duration of the media of the finished jobs, in seconds.
+    pub media_duration: f64,
+    /// Time the finished jobs took divided by the duration of their media.
+    pub real_time_factor: Option<f64>,
+}
+
+/// Task statistics for a time window.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskStatsReport {
+    /// Start of the time window.
+    pub since: DateTime<Utc>,
+    /// End of the time window.
+    pub until: DateTime<Utc>,
+    /// Statistics per task type.
+    pub tasks: Vec<TaskStats>,
+    /// Statistics per task type and feed, for jobs that belong to a feed.
+    pub feeds: Vec<TaskStats>,
+    /// Statistics of the transcribe task per ASR engine. Jobs without an engine option use the
+    /// default engine of the worker.
+    pub engines: Vec<TaskStats>,
+}
+
+/// Sums of the jobs in a group.
+#[derive(Debug, Default)]
+struct Totals {
+    finished: u32,
+    failed: u32,
+    took: f64,
+    took_count: u32,
+    wait: f64,
+    wait_count: u32,
+    media_duration: f64,
+    media_took: f64,
+}
+
+impl Totals {
+    fn add(&mut self, job: &Job, media_duration: Option<f64>) {
+        match job.status {
+            JobStatus::Finished => self.finished += 1,
+            JobStatus::Failed => self.failed += 1,
+            _ => return,
+        }
+        let took = job_took(job);
+        if let Some(took) = took {
+            self.took += took;
+            self.took_count += 1;
+        }
+        if let Some(started) = job.started {
+            self.wait += seconds(started - job.queued_at()).max(0.);
+            self.wait_count += 1;
+        }
+        if let (JobStatus::Finished, Some(took), Some(duration)) =
+            (job.status, took, media_duration)
+        {
+            if duration > 0. {
+                self.media_duration += duration;
+                self.media_took += took;
+            }
+        }
+    }
+
+    fn into_stats(self, typ: &str, hours: f64) -> TaskStats {
+        let total = self.finished + self.failed;
+        TaskStats {
+            typ: typ.to_string(),
+            feed: None,
+            engine: None,
+            finished: self.finished,
+            failed: self.failed,
+            failure_rate: ratio(self.failed as f64, total as f64).unwrap_or_default(),
+            throughput_per_hour: ratio(self.finished as f64, hours).unwrap_or_default(),
+            mean_took: ratio(self.took, self.took_count as f64),
+            mean_queue_wait: ratio(self.wait, self.wait_count as f64),
+            media_duration: self.media_duration,
+            real_time_factor: ratio(self.media_took, self.media_duration),
+        }
+    }
+}
+
+impl TaskManager {
+    /// Get the statistics of the jobs that finished or failed in the last `hours`.
+    ///
+    /// `db` is the database of the media records, whose durations are used for the real-time
+    /// factor.
+    pub async fn stats(&self, db: &CouchDB, hours: u32) -> anyhow::Result<TaskStatsReport> {
+        let until = Utc::now();
+        let since = until - Duration::hours(hours as i64);
+        let jobs: Vec<Record<Job>> = self.jobs().list_finished_since(since).await?;
+        let durations = load_media_durations(db, &jobs).await?;
+
+        let mut tasks: BTreeMap<&str, Totals> = BTreeMap::new();
+        let mut feeds: BTreeMap<(&str, &str), Totals> = BTreeMap::new();
+        let mut engines: BTreeMap<(&str, Option<&str>), Totals> = BTreeMap::new();
+        for job in jobs.iter() {
+            let job = &job.value;
+            let duration = job
+                .target
+                .as_ref()
+                .and_then(|target| durations.get(target))
+                .copied();
+            let typ = job.typ.as_str();
+            tasks.entry(typ).or_default().add(job, duration);
+            if let Some(feed) = &job.feed {
+                feeds
+                    .entry((typ, feed.as_str()))
+                    .or_default()
+                    .add(job, duration);
+            }
+            if typ == queue::TRANSCRIBE {
+                let engine = job.opts.get("engine").and_then(|engine| engine.as_str());
+                engines.entry((typ, engine)).or_default().add(job, duration);
+            }
+        }
+
+        let hours = hours as f64;
+        let tasks = tasks
+            .into_iter()
+            .map(|(typ, totals)| totals.into_stats(typ, hours))
+            .collect();
+        let feeds = feeds
+            .into_iter()
+            .map(|((typ, feed), totals)| TaskStats {
+                feed: Some(feed.to_string()),
+                ..totals.into_stats(typ, hours)
+            })
+            .collect();
+        let engines = engines
+            .into_iter()
+            .map(|((typ, engine), totals)| TaskStats {
+                engine: Some(engine.unwrap_or("default").to_string()),
+                ..totals.into_stats(typ, hours)
+            })
+            .collect();
+        Ok(TaskStatsReport {
+            since,
+            until,
+            tasks,
+            feeds,
+            engines,
+        })
+    }
+}
+
+/// Load the durations of the media that are the targets of jobs, by media guid.
+async fn load_media_durations(
+    db: &CouchDB,
+    jobs: &[Record<Job>],
+) -> anyhow::Result<HashMap<String, f64>> {
+    let mut guids: Vec<&str> = jobs
+        .iter()
+        .filter(|job| has_media_target(&job.value.typ))
+        .filter_map(|job| job.value.target.as_deref())
+        .collect();
+    guids.sort_unstable();
+    guids.dedup();
+    let mut durations = HashMap::new();
+    for chunk in guids.chunks(MEDIA_BATCH_SIZE) {
+        let medias = db.get_many_records::<Media>(chunk).await?;
+        for media in medias.into_iter() {
+            if let Some(duration) = media.value.duration {
+                durations.insert(media.guid().to_string(), duration as f64);
+            }
+        }
+    }
+    Ok(durations)
+}
+
+fn has_media_target(typ: &str) -> bool {
+    pipeline::PIPELINE
+        .iter()
+        .any(|step| step.typ == typ && step.target == Target::Media)
+}
+
+/// Get the time a job took, in seconds.
+///
+/// This is the time reported by the worker, or else the time from its start to its end.
+fn job_took(job: &Job) -> Option<f64> {
+    let reported = job
+        .result
+        .as_ref()
+        .and_then(|result| result.get("took"))
+        .and_then(|took| took.as_f64());
+    reported.or_else(|| match (job.started, job.finished) {
+        (Some(started), Some(finished)) => Some(seconds(finished - started)),
+        _ => None,
+    })
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.num_milliseconds() as f64 / 1000.
+}
+
+fn ratio(value: f64, total: f64) -> Option<f64> {
+    if total > 0. {
+        Some(value / total)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,373 @@
+//! Supervision of running jobs.
+//!
+//! Workers may die or lose a job without ever reporting a result. The [TaskSupervisor]
+//! periodically checks for jobs that have been running longer than the task timeout. Those jobs
+//! are queued again after a backoff delay, until the max number of attempts is reached. Then they
+//! are marked as failed. Each attempt is recorded in the task state history of the target record.
+//!
+//! The timeout counts from the start of a job by a worker. Jobs that were sent to Celery are only
+//! started once a worker reports it (see [TaskManager::report_start]), so jobs that still wait in
+//! a long queue don't time out.
+//!
+//! Only jobs that time out are retried. If a worker reports an error for a job, the job is marked
+//! as failed right away (see [TaskManager::submit_result]), because such errors usually repeat
+//! (e.g. media that cannot be decoded). Failed jobs can be started again through the API.
+
+use chrono::{DateTime, Utc};
+use clap::Clap;
+use oas_common::task::{TaskAttempt, TaskFinishedState, TaskRunningState, TaskState};
+use oas_common::Record;
+use std::time::Duration;
+
+use super::result::update_task_state;
+use super::{Job, JobStatus, TaskManager};
+use crate::couch::CouchDB;
+
+/// Max delay before a job is retried.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60 * 24);
+
+#[derive(Clap, Debug, Clone)]
+pub struct SupervisorOpts {
+    /// Time in seconds after which a running task is considered stale
+    #[clap(long, env = "TASK_TIMEOUT", default_value = "7200")]
+    pub task_timeout: u64,
+    /// Max number of attempts to run a task
+    #[clap(long, env = "TASK_MAX_ATTEMPTS", default_value = "3")]
+    pub task_max_attempts: u32,
+    /// Delay in seconds before a stale task is retried (doubled with each attempt)
+    #[clap(long, env = "TASK_RETRY_BACKOFF", default_value = "60")]
+    pub task_retry_backoff: u64,
+    /// Interval in seconds in which to check for stale tasks
+    #[clap(long, env = "TASK_CHECK_INTERVAL", default_value = "60")]
+    pub task_check_interval: u64,
+}
+
+impl Default for SupervisorOpts {
+    fn default() -> Self {
+        Self {
+            task_timeout: 7200,
+            task_max_attempts: 3,
+            task_retry_backoff: 60,
+            task_check_interval: 60,
+        }
+    }
+}
+
+impl SupervisorOpts {
+    /// Get the delay before a job is retried after a number of attempts.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+        let backoff = Duration::from_secs(self.task_retry_backoff.saturating_mul(factor));
+        backoff.min(MAX_BACKOFF)
+    }
+}
+
+/// Result of a supervisor check.
+#[derive(Debug, Default, Clone)]
+pub struct SupervisorStats {
+    /// Number of stale jobs that were queued again.
+    pub retried: usize,
+    /// Number of stale jobs that were marked as failed.
+    pub failed: usize,
+    /// Number of re-queued jobs that were sent to the workers again.
+    pub resent: usize,
+    /// Number of held jobs that were released to the queue.
+    pub released: usize,
+}
+
+/// Detects stale jobs and retries or fails them.
+#[derive(Debug, Clone)]
+pub struct TaskSupervisor {
+    tasks: TaskManager,
+    db: CouchDB,
+    opts: SupervisorOpts,
+}
+
+impl TaskSupervisor {
+    /// Create a new supervisor. `db` is the database of the target records.
+    pub fn new(tasks: TaskManager, db: CouchDB, opts: SupervisorOpts) -> Self {
+        Self { tasks, db, opts }
+    }
+
+    /// Check for stale jobs in an interval, forever.
+    pub async fn run(self) -> anyhow::Result<()> {
+        let duration = Duration::from_secs(self.opts.task_check_interval);
+        let mut interval = tokio::time::interval(duration);
+        loop {
+            interval.tick().await;
+            match self.check().await {
+                Ok(stats) if stats.retried + stats.failed + stats.resent + stats.released > 0 => {
+                    log::info!(
+                        "Task supervisor: {} retried, {} failed, {} resent, {} released",
+                        stats.retried,
+                        stats.failed,
+                        stats.resent,
+                        stats.released
+                    );
+                }
+                Ok(_) => {}
+                Err(err) => log::error!("Task supervisor check failed: {:#}", err),
+            }
+        }
+    }
+
+    /// Check all jobs once.
+    ///
+    /// Jobs that have been running longer than the timeout are retried or failed, re-queued jobs
+    /// that are due are sent again, and held jobs are released as far as the quotas of their
+    /// feeds allow.
+    pub async fn check(&self) -> anyhow::Result<SupervisorStats> {
+        let now = Utc::now();
+        let timeout = chrono::Duration::from_std(Duration::from_secs(self.opts.task_timeout))?;
+        let mut stats = SupervisorStats::default();
+        for job in self
+            .tasks
+            .jobs()
+            .list_with_status(JobStatus::Running)
+            .await?
+        {
+            let id = job.id().to_string();
+            let is_stale = job
+                .value
+                .started
+                .map_or(false, |started| started + timeout < now);
+            if is_stale {
+                match self.handle_stale(job, now).await {
+                    Ok(true) => stats.retried += 1,
+                    Ok(false) => stats.failed += 1,
+                    Err(err) => log::warn!("Failed to handle stale job {}: {:#}", id, err),
+                }
+            }
+        }
+        if !self.tasks.queue().claims_jobs() {
+            for job in self
+                .tasks
+                .jobs()
+                .list_with_status(JobStatus::Queued)
+                .await?
+            {
+                if job.value.retry_at.is_none() || !job.value.is_due(now) {
+                    continue;
+                }
+                match self.tasks.resend(&job).await {
+                    Ok(()) => stats.resent += 1,
+                    Err(err) => log::warn!("Failed to resend job {}: {:#}", job.id(), err),
+                }
+            }
+        }
+        stats.released = self.tasks.release_held(&self.db).await?;
+        Ok(stats)
+    }
+
+    /// Retry or fail a stale job.
+    ///
+    /// Returns true if the job was queued again.
+    async fn handle_stale(&self, job: Record<Job>, now: DateTime<Utc>) -> anyhow::Result<bool> {
+        let error = format!(
+            "Timed out after {}",
+            humantime::format_duration(Duration::from_secs(self.opts.task_timeout))
+        );
+        let retry = job.value.attempts < self.opts.task_max_attempts;
+        let retry_at = now + chrono::Duration::from_std(self.opts.backoff(job.value.attempts))?;
+        log::warn!(
+            "Job {} ({}) is stale after attempt {}",
+            job.id(),
+            job.value.typ,
+            job.value.attempts
+        );
+
+        self.tasks
+            .jobs()
+            .update(job.id(), |job| {
+                if job.status != JobStatus::Running {
+                    anyhow::bail!("Job is not running anymore");
+                }
+                if retry {
+                    job.retry(&error, retry_at);
+                } else {
+                    job.fail(&error);
+                }
+                Ok(())
+            })
+            .await?;
+
+        if let Some(target) = &job.value.target {
+            let attempt = TaskAttempt {
+                task_id: job.id().to_string(),
+                start: job.value.started,
+                end: now,
+                error: error.clone(),
+            };
+            update_task_state(&self.db, &job.value.typ, target, |state| {
+                let mut history = state.history().to_vec();
+                *state = if retry {
+                    // The task stays running until it is retried, so it keeps its first start.
+                    let start = match state {
+                        TaskState::Running(running) => running.start,
+                        _ => attempt.start.unwrap_or(now),
+                    };
+                    history.push(attempt.clone());
+                    TaskState::Running(TaskRunningState {
+                        task_id: attempt.task_id.clone(),
+                        start,
+                        history,
+                    })
+                } else {
+                    let took = attempt
+                        .start
+                        .map(|start| (now - start).num_milliseconds() as f32 / 1000.)
+                        .unwrap_or_default();
+                    TaskState::Finished(TaskFinishedState {
+                        task_id: attempt.task_id.clone(),
+                        success: false,
+                        error: Some(error.clone()),
+                        start: attempt.start,
+                        end: Some(now),
+                        took,
+                        history,
+                    })
+                };
+            })
+            .await?;
+        }
+        Ok(retry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tasks::{CouchQueue, JobStart, JobStore, TaskMessage, TaskQueue};
+    use oas_common::types::Media;
+    use serde_json::json;
+
+    /// A queue that sends jobs to workers that don't claim them, like Celery.
+    #[derive(Debug)]
+    struct SendingQueue;
+
+    #[async_trait::async_trait]
+    impl TaskQueue for SendingQueue {
+        fn name(&self) -> &str {
+            "sending"
+        }
+
+        async fn init(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn send(&self, job: &Record<Job>) -> anyhow::Result<String> {
+            Ok(job.id().to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_sent_job_until_started() {
+        let db = CouchDB::in_memory("records");
+        let jobs = JobStore::new(CouchDB::in_memory("meta"));
+        let tasks = TaskManager::with_queue(SendingQueue, jobs.clone());
+        let supervisor = TaskSupervisor::new(tasks.clone(), db, SupervisorOpts::default());
+
+        let task = TaskMessage::new("transcribe", json!({}), json!({}));
+        let id = tasks.send_task(task).await.unwrap();
+        let sent = Utc::now() - chrono::Duration::hours(3);
+        jobs.update(&id, |job| {
+            job.sent = Some(sent);
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        // The job still waits in the queue, so it does not time out.
+        let stats = supervisor.check().await.unwrap();
+        assert_eq!(stats.retried + stats.failed, 0);
+        let job = jobs.get(&id).await.unwrap().unwrap();
+        assert_eq!(job.value.status, JobStatus::Running);
+        assert_eq!(job.value.started, None);
+
+        // Once a worker started it, the timeout counts from the start.
+        let start = JobStart {
+            worker: Some("w1".to_string()),
+        };
+        let job = tasks.report_start(&id, start).await.unwrap();
+        assert_eq!(job.value.worker.as_deref(), Some("w1"));
+        assert!(job.value.started.is_some());
+        let stats = supervisor.check().await.unwrap();
+        assert_eq!(stats.retried + stats.failed, 0);
+        jobs.update(&id, |job| {
+            job.started = Some(sent);
+            Ok(())
+        })
+        .await
+        .unwrap();
+        let stats = supervisor.check().await.unwrap();
+        assert_eq!(stats.retried, 1);
+    }
+
+    #[tokio::test]
+    async fn retry_stale_job() {
+        let db = CouchDB::in_memory("records");
+        let jobs = JobStore::new(CouchDB::in_memory("meta"));
+        let tasks = TaskManager::with_queue(CouchQueue::new(jobs.clone()), jobs.clone());
+        let opts = SupervisorOpts {
+            task_max_attempts: 2,
+            ..Default::default()
+        };
+        let supervisor = TaskSupervisor::new(tasks, db.clone(), opts);
+
+        let started = Utc::now() - chrono::Duration::hours(3);
+        let mut media = Record::from_id_and_value("m1", Media::default());
+        let mut job = Job::from_task(TaskMessage::new("transcribe", json!({}), json!({})));
+        job.target = Some(media.guid().to_string());
+        let job = job.into_record();
+        media.value.tasks.asr = TaskState::Running(TaskRunningState {
+            task_id: job.id().to_string(),
+            start: started,
+            history: vec![],
+        });
+        db.put_record(media.clone()).await.unwrap();
+        jobs.create(job.clone()).await.unwrap();
+        let claimed = jobs.claim("w1", &[]).await.unwrap().unwrap();
+        jobs.update(claimed.id(), |job| {
+            job.started = Some(started);
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        // The first attempt timed out, so the job is queued again.
+        let stats = supervisor.check().await.unwrap();
+        assert_eq!(stats.retried, 1);
+        let retried = jobs.get(job.id()).await.unwrap().unwrap();
+        assert_eq!(retried.value.status, JobStatus::Queued);
+        assert!(retried.value.retry_at.is_some());
+        let target = db.table::<Media>().get("m1").await.unwrap();
+        match &target.value.tasks.asr {
+            TaskState::Running(state) => {
+                assert_eq!(state.start, started);
+                assert_eq!(state.history.len(), 1);
+            }
+            state => panic!("Unexpected task state {:?}", state),
+        }
+
+        // The second attempt is the last one, so the job fails.
+        jobs.update(job.id(), |job| {
+            job.start(Some("w1"));
+            job.started = Some(started);
+            Ok(())
+        })
+        .await
+        .unwrap();
+        let stats = supervisor.check().await.unwrap();
+        assert_eq!(stats.failed, 1);
+        let failed = jobs.get(job.id()).await.unwrap().unwrap();
+        assert_eq!(failed.value.status, JobStatus::Failed);
+        let target = db.table::<Media>().get("m1").await.unwrap();
+        match &target.value.tasks.asr {
+            TaskState::Finished(state) => {
+                assert!(!state.success);
+                assert_eq!(state.history.len(), 2);
+            }
+            state => panic!("Unexpected task state {:?}", state),
+        }
+    }
+}
@@ -1,11 +1,24 @@
+//! Task definitions.
+//!
+//! The Celery tasks declare the names and signatures of the tasks that are implemented by the
+//! Python worker. The [Task] trait adds the typed arguments, options and output of each task.
+
 #![allow(unused)]
 
+use async_trait::async_trait;
 use celery::task::TaskResult;
 use oas_common::{
     types::{Media, Post},
     Record,
 };
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::fmt;
+
+use super::queue;
+use super::{CeleryQueue, NlpOutput, TranscribeOutput};
 
 #[celery::task()]
 pub fn transcribe(args: Value, opts: Value) -> TaskResult<Value> {
@@ -22,88 +35,132 @@ pub fn nlp2(args: Record<Post>, opts: Value) -> TaskResult<Value> {
     Ok(Value::Null)
 }
 
-enum TaskNames {
-    Transcribe,
-    Nlp,
-    Download,
+/// A task type with typed arguments, options and output.
+#[async_trait]
+pub trait Task: Send + Sync + 'static {
+    /// Name of the task, e.g. [queue::TRANSCRIBE].
+    const NAME: &'static str;
+    type Args: fmt::Debug + Serialize + DeserializeOwned + Send + 'static;
+    type Opts: fmt::Debug + Serialize + DeserializeOwned + JsonSchema + Default + Send + 'static;
+    type Output: fmt::Debug + Serialize + DeserializeOwned + JsonSchema + Send + 'static;
+
+    /// Validate the output of the task beyond its type.
+    fn validate_output(_output: &Self::Output) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Send the task to a Celery queue.
+    ///
+    /// The options are passed as they were saved on the job, together with the job id.
+    async fn send_celery(
+        celery: &CeleryQueue,
+        args: Self::Args,
+        opts: Value,
+        queue: &str,
+    ) -> anyhow::Result<String>;
 }
 
-mod new {
-    use async_trait::async_trait;
-    use celery::prelude::CeleryError;
-    use oas_common::types::{Media, Post, Transcript};
-    use oas_common::Record;
-    use schemars::JsonSchema;
-    use serde::de::DeserializeOwned;
-    use serde::{Deserialize, Serialize};
-    use std::fmt;
-    use std::sync::Arc;
-
-    use crate::tasks::CeleryManager;
-
-    use super::nlp2;
-
-    // pub struct TaskRepo {
-    //     // tasks: HashMap<String,
-    // }
-
-    // impl TaskRepo {
-    //     fn from_name_and_typ::<T: TaskObject>(&self, name: &str) {
-    //         match name {
-    //             NlpTask::NAME =>
-    //         }
-    //     }
-    // }
-    #[async_trait]
-    pub trait Task {
-        const NAME: &'static str;
-        type Args: fmt::Debug + Serialize + DeserializeOwned + Send + 'static;
-        type Opts: fmt::Debug + Serialize + DeserializeOwned + Send + 'static;
-        type Output: fmt::Debug + Serialize + DeserializeOwned + Send + 'static;
-        async fn run_celery(
-            &self,
-            _celery: Arc<CeleryManager>,
-            _args: Self::Args,
-            _opts: Self::Opts,
-        ) -> Result<celery::task::AsyncResult, CeleryError> {
-            Err(CeleryError::NoQueueToConsume)
-        }
-    }
+/// Options or output of a task that has none.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Empty {}
+
+/// Transcribe a media with ASR, followed by NLP on the transcript.
+pub struct AsrTask;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AsrArgs {
+    pub media_url: String,
+    pub media_id: String,
+}
 
-    #[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
-    pub struct NlpOutput(serde_json::Value);
-
-    struct NlpTask;
-
-    #[async_trait]
-    impl Task for NlpTask {
-        const NAME: &'static str = "nlp";
-        type Args = Record<Post>;
-        type Opts = serde_json::Value;
-        type Output = NlpOutput;
-        async fn run_celery(
-            &self,
-            celery: Arc<CeleryManager>,
-            args: Self::Args,
-            opts: Self::Opts,
-        ) -> Result<celery::task::AsyncResult, CeleryError> {
-            celery.send_task(nlp2::new(args, opts)).await
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AsrOpts {
+    /// ASR engine to use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub engine: Option<String>,
+    /// Language of the media, if it is not detected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_language: Option<String>,
+}
+
+#[async_trait]
+impl Task for AsrTask {
+    const NAME: &'static str = queue::TRANSCRIBE;
+    type Args = AsrArgs;
+    type Opts = AsrOpts;
+    type Output = TranscribeOutput;
+
+    fn validate_output(output: &Self::Output) -> Result<(), String> {
+        let transcript = &output.transcript;
+        match transcript.parts.iter().find(|part| part.start > part.end) {
+            Some(part) => Err(format!(
+                "Invalid transcript part \"{}\": start {} is after end {}",
+                part.word, part.start, part.end
+            )),
+            None => Ok(()),
         }
     }
 
-    #[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
-    struct AsrTask;
+    async fn send_celery(
+        celery: &CeleryQueue,
+        args: Self::Args,
+        opts: Value,
+        queue: &str,
+    ) -> anyhow::Result<String> {
+        let args = serde_json::to_value(args)?;
+        let res = celery.send_task(transcribe::new(args, opts), queue).await?;
+        Ok(res.task_id)
+    }
+}
 
-    #[derive(Deserialize, Serialize, Debug, Clone, JsonSchema, Default)]
-    struct AsrOpts {
-        engine: Option<String>,
-        default_language: Option<String>,
+/// Download the file of a media.
+pub struct DownloadTask;
+
+#[async_trait]
+impl Task for DownloadTask {
+    const NAME: &'static str = queue::DOWNLOAD;
+    type Args = Record<Media>;
+    type Opts = Empty;
+    type Output = Empty;
+
+    async fn send_celery(
+        celery: &CeleryQueue,
+        args: Self::Args,
+        opts: Value,
+        queue: &str,
+    ) -> anyhow::Result<String> {
+        let res = celery.send_task(download2::new(args, opts), queue).await?;
+        Ok(res.task_id)
     }
+}
+
+/// Run NLP on a post.
+pub struct NlpTask;
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema, Default)]
+#[serde(deny_unknown_fields)]
+pub struct NlpOpts {
+    /// Name of the NLP pipeline.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<String>,
+}
 
-    impl Task for AsrTask {
-        const NAME: &'static str = "asr";
-        type Args = Record<Media>;
-        type Opts = AsrOpts;
-        type Output = Transcript;
+#[async_trait]
+impl Task for NlpTask {
+    const NAME: &'static str = queue::NLP;
+    type Args = Record<Post>;
+    type Opts = NlpOpts;
+    type Output = NlpOutput;
+
+    async fn send_celery(
+        celery: &CeleryQueue,
+        args: Self::Args,
+        opts: Value,
+        queue: &str,
+    ) -> anyhow::Result<String> {
+        let res = celery.send_task(nlp2::new(args, opts), queue).await?;
+        Ok(res.task_id)
     }
 }
@@ -0,0 +1,231 @@
+//! Tenants that share one OAS instance.
+//!
+//! Each tenant (e.g. a radio network) has its own record and meta databases and its own
+//! indexes, which are named with the tenant id after the configured prefixes (e.g.
+//! `oas$acme$records` and `oas.acme.data`). Tenants are saved in the meta database of the
+//! instance, and users are assigned to a tenant. Users without a tenant use the databases and
+//! indexes of the instance itself.
+//!
+//! The services of all tenants are started on boot (see [Tenants::start_all]), and those of new
+//! tenants when they are created. The indexer and the feed watcher of each tenant run in the
+//! background. If one of them stops, the others are stopped as well, and all are started again
+//! when the tenant is next used. Tasks (e.g. ASR) are not run for the records of tenants yet, so
+//! their feeds cannot have task defaults.
+
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use oas_common::{Record, TypedValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{OnceCell, RwLock};
+
+use crate::couch::{ChangesDispatcher, CouchDB, CouchResult};
+use crate::State;
+
+/// Max length of tenant ids.
+const MAX_ID_LEN: usize = 64;
+
+/// A tenant of the instance.
+///
+/// The id of the record is the tenant id.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Tenant {
+    /// Display name of the tenant.
+    pub name: String,
+}
+
+impl TypedValue for Tenant {
+    const NAME: &'static str = "oas.Tenant";
+}
+
+/// Check if a string can be used as a tenant id.
+///
+/// Tenant ids are part of database and index names, so they may only contain lowercase letters,
+/// digits, "-" and "_", and have to start with a letter.
+pub fn is_valid_tenant_id(id: &str) -> bool {
+    id.len() <= MAX_ID_LEN
+        && id.chars().next().map_or(false, |c| c.is_ascii_lowercase())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Registry of the tenants and their services.
+///
+/// The states of the tenants are kept in a map that is only locked for writing to add a tenant.
+/// Each state is initialized once, without blocking requests for other tenants.
+#[derive(Debug, Clone)]
+pub struct Tenants {
+    default: State,
+    states: Arc<RwLock<HashMap<String, Arc<OnceCell<State>>>>>,
+}
+
+impl Tenants {
+    /// Create a registry with the state of the instance.
+    pub fn new(default: State) -> Self {
+        Self {
+            default,
+            states: Default::default(),
+        }
+    }
+
+    /// Get the state of the instance, used for requests without a tenant.
+    pub fn default_state(&self) -> &State {
+        &self.default
+    }
+
+    fn meta_db(&self) -> &CouchDB {
+        self.default.db_manager.meta_db()
+    }
+
+    /// List all tenants.
+    pub async fn list(&self) -> CouchResult<Vec<Record<Tenant>>> {
+        self.meta_db().table::<Tenant>().get_all().await
+    }
+
+    /// Get a tenant, or None if it does not exist.
+    pub async fn get(&self, id: &str) -> CouchResult<Option<Record<Tenant>>> {
+        match self.meta_db().table::<Tenant>().get(id).await {
+            Ok(record) => Ok(Some(record)),
+            Err(err) if err.status_code() == Some(404) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Save a tenant and create its databases and indexes.
+    pub async fn create(&self, id: &str, tenant: Tenant) -> anyhow::Result<()> {
+        anyhow::ensure!(is_valid_tenant_id(id), "Invalid tenant id: {}", id);
+        self.meta_db()
+            .table::<Tenant>()
+            .put(Record::from_id_and_value(id, tenant))
+            .await?;
+        self.state(Some(id)).await?;
+        Ok(())
+    }
+
+    /// Start the services of all tenants.
+    ///
+    /// Tenants that fail to start are logged and skipped. They are started again when they are
+    /// used.
+    pub async fn start_all(&self) -> anyhow::Result<()> {
+        for tenant in self.list().await? {
+            if let Err(err) = self.state(Some(tenant.id())).await {
+                log::error!("Failed to start tenant {}: {:#}", tenant.id(), err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the state of a tenant, or of the instance if `tenant` is None.
+    ///
+    /// The services of the tenant are started on first use. Returns None if the tenant does not
+    /// exist.
+    pub async fn state(&self, tenant: Option<&str>) -> anyhow::Result<Option<State>> {
+        let id = match tenant {
+            Some(id) => id,
+            None => return Ok(Some(self.default.clone())),
+        };
+        let cell = self.states.read().await.get(id).cloned();
+        if let Some(state) = cell.as_ref().and_then(|cell| cell.get()) {
+            return Ok(Some(state.clone()));
+        }
+        if self.get(id).await?.is_none() {
+            return Ok(None);
+        }
+        let cell = match cell {
+            Some(cell) => cell,
+            None => self
+                .states
+                .write()
+                .await
+                .entry(id.to_string())
+                .or_default()
+                .clone(),
+        };
+        // Concurrent requests wait for the first one to initialize the tenant. If this fails, the
+        // next request tries again.
+        let state = cell.get_or_try_init(|| self.create_state(id)).await?;
+        Ok(Some(state.clone()))
+    }
+
+    async fn create_state(&self, id: &str) -> anyhow::Result<State> {
+        log::info!("Initializing tenant {}", id);
+        let db_manager = self.default.db_manager.for_tenant(id)?;
+        let index_manager = self.default.index_manager.for_tenant(id)?;
+        let feed_manager = self.default.feed_manager.with_same_opts().await;
+        let db = db_manager.record_db().clone();
+        db_manager.init().await?;
+        index_manager.init(Default::default()).await?;
+        feed_manager.init(&db).await?;
+
+        // Like for the instance, the services that consume the changes of the tenant's record
+        // database share a single dispatcher.
+        let mut dispatcher = ChangesDispatcher::new(db.clone(), true);
+        let index_changes = index_manager
+            .register_changes(&db_manager, &mut dispatcher)
+            .await?;
+        let feed_changes = feed_manager
+            .register_changes(&db_manager, &mut dispatcher)
+            .await?;
+        let services: Vec<(&'static str, BoxFuture<'static, anyhow::Result<()>>)> = vec![
+            ("changes", dispatcher.run().boxed()),
+            ("index", {
+                let index_manager = index_manager.clone();
+                let db = db.clone();
+                async move { index_manager.index_changes_from(&db, index_changes).await }.boxed()
+            }),
+            (
+                "feed_watcher",
+                feed_manager
+                    .clone()
+                    .run_watch_from(db.clone(), feed_changes)
+                    .boxed(),
+            ),
+        ];
+        self.spawn_services(id, services);
+
+        Ok(State::new(
+            db_manager,
+            db,
+            index_manager,
+            self.default.tasks.clone(),
+            feed_manager,
+        ))
+    }
+
+    /// Run the background services of a tenant.
+    ///
+    /// The services depend on each other, so if one of them stops, the others are stopped too.
+    /// The state of the tenant is then removed, so that its services are started again on the next
+    /// use.
+    fn spawn_services(
+        &self,
+        tenant: &str,
+        services: Vec<(&'static str, BoxFuture<'static, anyhow::Result<()>>)>,
+    ) {
+        let tenant = tenant.to_string();
+        let states = self.states.clone();
+        let (names, handles): (Vec<_>, Vec<_>) = services
+            .into_iter()
+            .map(|(name, service)| (name, tokio::spawn(service)))
+            .unzip();
+        tokio::spawn(async move {
+            let (result, index, others) = future::select_all(handles).await;
+            let name = names[index];
+            match result {
+                Ok(Ok(())) => log::warn!("Service {} of tenant {} stopped", name, tenant),
+                Ok(Err(err)) => {
+                    log::error!("Service {} of tenant {} failed: {:#}", name, tenant, err)
+                }
+                Err(err) => log::error!("Service {} of tenant {} panicked: {}", name, tenant, err),
+            }
+            for handle in others {
+                handle.abort();
+            }
+            states.write().await.remove(&tenant);
+        });
+    }
+}